@@ -0,0 +1,226 @@
+//! An [actix-web] extractor which runs a filter from a `FilterRegistry` over
+//! the request body and hands the handler its output as a typed value.
+//!
+//! The registry is shared with the app as `web::Data<FilterRegistry>`, and
+//! each route says which filter its `Jq<T>` runs with a `JqConfig` in its
+//! app data. The filter runs on actix's blocking thread pool, and its output
+//! is deserialized straight from jq's representation, like
+//! `JqProgram::run_as()`. It must produce exactly one output, so filters for
+//! several values should gather them into an array.
+//!
+//! Requires the `actix` feature.
+//!
+//! ```rust
+//! use actix_web::{web, App};
+//! use jq_rs::actix::{Jq, JqConfig};
+//! use jq_rs::FilterRegistry;
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Order {
+//!     id: u64,
+//!     skus: Vec<String>,
+//! }
+//!
+//! async fn place_order(order: Jq<Order>) -> String {
+//!     format!("order {} with {} items", order.id, order.skus.len())
+//! }
+//!
+//! let registry = web::Data::new(FilterRegistry::new());
+//! registry.add("order", "{id: .order_id, skus: [.lines[].sku]}");
+//!
+//! let app = App::new().app_data(registry.clone()).service(
+//!     web::resource("/orders")
+//!         .app_data(JqConfig::filter("order"))
+//!         .route(web::post().to(place_order)),
+//! );
+//! ```
+//!
+//! Failures are answered the same way as for any other `jq_rs::Error` a
+//! handler returns, see the `ResponseError` impl. A missing registry or
+//! `JqConfig` is a mistake in setting up the app, and gets a
+//! `500 Internal Server Error`.
+//!
+//! [actix-web]: https://crates.io/crates/actix-web
+
+use crate::errors::{Error, ErrorKind, Result};
+use crate::FilterRegistry;
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes};
+use actix_web::{FromRequest, HttpRequest, ResponseError};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+
+/// Which filter `Jq<T>` runs for a route, set in the route's app data.
+#[derive(Clone, Debug)]
+pub struct JqConfig {
+    filter: String,
+}
+
+impl JqConfig {
+    /// Run the filter stored under `name` in the app's `FilterRegistry`.
+    pub fn filter<N: Into<String>>(name: N) -> Self {
+        JqConfig {
+            filter: name.into(),
+        }
+    }
+}
+
+/// The output of a filter run over the json request body, deserialized as a
+/// `T`. See the module docs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Jq<T>(pub T);
+
+impl<T> Jq<T> {
+    /// Unwrap the output.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Jq<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Jq<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> FromRequest for Jq<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<Self, actix_web::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let registry = req.app_data::<web::Data<FilterRegistry>>().cloned();
+        let config = req.app_data::<JqConfig>().cloned();
+        let body = Bytes::from_request(req, payload);
+        Box::pin(async move {
+            let registry = registry.ok_or_else(|| {
+                actix_web::error::ErrorInternalServerError("No FilterRegistry in the app data")
+            })?;
+            let config = config.ok_or_else(|| {
+                actix_web::error::ErrorInternalServerError("No JqConfig in the app data")
+            })?;
+            let body = body.await?;
+            let output = web::block(move || run_one(&registry, &config.filter, &body)).await??;
+            Ok(Jq(output))
+        })
+    }
+}
+
+/// Run the filter stored under `name`, deserializing its only output.
+fn run_one<T: DeserializeOwned>(registry: &FilterRegistry, name: &str, body: &[u8]) -> Result<T> {
+    let data = std::str::from_utf8(body)?;
+    let mut program = registry.checkout(name)?;
+    let mut value = None;
+    let mut outputs = 0;
+    program.run_deserialized(data, |output| {
+        outputs += 1;
+        if outputs == 1 {
+            value = Some(T::deserialize(output)?);
+        }
+        Ok(())
+    })?;
+    match value {
+        Some(value) if outputs == 1 => Ok(value),
+        _ => Err(Error::Exec {
+            reason: format!(
+                "Expected the filter to give one output, it gave {}",
+                outputs
+            ),
+            payload: None,
+        }),
+    }
+}
+
+/// Lets handlers return `jq_rs::Error`, which is also how `Jq<T>` fails.
+///
+/// Input which doesn't parse or doesn't deserialize as the expected type
+/// is a `400 Bad Request`, and a body larger than the program takes (see
+/// `Compiler::max_input_bytes()`) is a `413 Payload Too Large`. A program
+/// which fails on the input (or halts with an error) is a
+/// `422 Unprocessable Entity`. Anything else, such as an unknown filter or
+/// one which doesn't compile, is a `500 Internal Server Error`.
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self.kind() {
+            ErrorKind::Parse | ErrorKind::StringConvert => StatusCode::BAD_REQUEST,
+            ErrorKind::InputTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::Exec | ErrorKind::Halted | ErrorKind::LimitExceeded => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Jq, JqConfig};
+    use crate::FilterRegistry;
+    use actix_web::http::StatusCode;
+    use actix_web::rt::System;
+    use actix_web::test::TestRequest;
+    use actix_web::{web, FromRequest};
+
+    fn extract(config: Option<JqConfig>, body: &'static str) -> Result<Jq<Vec<u32>>, StatusCode> {
+        let registry = web::Data::new(FilterRegistry::new());
+        registry.add("ids", "[.[].id]");
+        registry.add("each", ".[] | [.id]");
+        let mut request = TestRequest::post().app_data(registry).set_payload(body);
+        if let Some(config) = config {
+            request = request.app_data(config);
+        }
+        let (req, mut payload) = request.to_http_parts();
+        System::new()
+            .block_on(Jq::<Vec<u32>>::from_request(&req, &mut payload))
+            .map_err(|err| err.as_response_error().status_code())
+    }
+
+    #[test]
+    fn extracts_the_output() {
+        let ids = extract(Some(JqConfig::filter("ids")), r#"[{"id": 1}, {"id": 2}]"#);
+        assert_eq!(ids.unwrap().into_inner(), vec![1, 2]);
+    }
+
+    #[test]
+    fn failures() {
+        let ids = Some(JqConfig::filter("ids"));
+        assert_eq!(
+            extract(ids.clone(), "[{").unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            extract(ids.clone(), r#"[{"id": "a"}]"#).unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            extract(ids, "[1]").unwrap_err(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            extract(Some(JqConfig::filter("each")), r#"[{"id": 1}, {"id": 2}]"#).unwrap_err(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            extract(Some(JqConfig::filter("missing")), "[]").unwrap_err(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            extract(None, "[]").unwrap_err(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::cancel::Interrupt;
+use crate::errors::BatchError;
+use crate::JqProgram;
+use std::ops::Range;
+use std::result;
+
+/// A buffer which the results of a batch are rendered into back to back, for
+/// `JqProgram::run_batch_in()`.
+///
+/// Each batch run in the arena clears it first, so the results of the last
+/// batch are borrowed from it until the next. Once the arena has grown to fit
+/// a typical batch, running more batches doesn't allocate for the results.
+#[derive(Clone, Debug, Default)]
+pub struct OutputArena {
+    buf: String,
+}
+
+impl OutputArena {
+    /// An empty arena, which grows as needed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An empty arena with room for `bytes` of results before it has to
+    /// grow.
+    pub fn with_capacity(bytes: usize) -> Self {
+        OutputArena {
+            buf: String::with_capacity(bytes),
+        }
+    }
+
+    /// The number of bytes the arena can hold before it has to grow.
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// The total size of the results from the last batch.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Whether the last batch had no results, or there hasn't been one.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+impl JqProgram {
+    /// Runs several json string inputs against the pre-compiled jq program,
+    /// like `run_batch()`, with the results rendered into `arena` rather
+    /// than each into a `String` of its own.
+    ///
+    /// The results borrow from the arena, and are gone once it's used for
+    /// another batch.
+    ///
+    /// ```rust
+    /// use jq_rs::OutputArena;
+    ///
+    /// let mut program = jq_rs::compile(".name").unwrap();
+    /// let mut arena = OutputArena::new();
+    ///
+    /// for batch in &[[r#"{"name": "a"}"#, "{"], [r#"{"name": "b"}"#, "{}"]] {
+    ///     let results = program.run_batch_in(batch, &mut arena);
+    ///     // ... do something with the results.
+    ///     assert_eq!(results.len(), 2);
+    /// }
+    /// assert_eq!(arena.len(), "\"b\"\nnull\n".len());
+    /// ```
+    pub fn run_batch_in<'a, I>(
+        &mut self,
+        inputs: I,
+        arena: &'a mut OutputArena,
+    ) -> Vec<result::Result<&'a str, BatchError>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        arena.buf.clear();
+        let interrupt = Interrupt::default();
+        // The arena can't be borrowed from until it's done growing, so the
+        // results are kept as ranges until then.
+        let ranges: Vec<result::Result<Range<usize>, BatchError>> = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let start = arena.buf.len();
+                self.run_interruptible_into(data.as_ref(), &interrupt, &mut arena.buf)
+                    .map(|()| start..arena.buf.len())
+                    .map_err(|error| BatchError {
+                        index,
+                        line: None,
+                        error,
+                    })
+            })
+            .collect();
+
+        let buf = arena.buf.as_str();
+        ranges
+            .into_iter()
+            .map(|range| range.map(|range| &buf[range]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OutputArena;
+    use crate::{compile, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn results_share_the_arena() {
+        let mut program = compile(".[0]").unwrap();
+        let mut arena = OutputArena::with_capacity(64);
+        let capacity = arena.capacity();
+
+        let results = program.run_batch_in(&["[1]", "{}", "", "[[2]]"], &mut arena);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap(), &"1\n");
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_matches!(err.error, Error::Exec { .. });
+        assert_eq!(results[2].as_ref().unwrap(), &"");
+        assert_eq!(results[3].as_ref().unwrap(), &"[2]\n");
+        assert_eq!(arena.len(), "1\n[2]\n".len());
+
+        let results = program.run_batch_in(vec!["[3]".to_string()], &mut arena);
+        assert_eq!(results[0].as_ref().unwrap(), &"3\n");
+        assert_eq!(arena.capacity(), capacity);
+
+        assert!(program
+            .run_batch_in(Vec::<&str>::new(), &mut arena)
+            .is_empty());
+        assert!(arena.is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::compile;
+use crate::errors::Result;
+use std::collections::BTreeSet;
+
+/// Words jq reserves, which look like function calls but aren't.
+pub(crate) const KEYWORDS: &[&str] = &[
+    "__loc__", "and", "as", "break", "catch", "def", "elif", "else", "end", "foreach", "if",
+    "import", "include", "label", "module", "or", "reduce", "then", "try",
+];
+
+/// The builtins a program calls, as `name/arity` like jq's own `builtins`,
+/// for policy checks on programs before they're run.
+///
+/// `$ENV` is included too when the program uses it, since it reaches
+/// outside of the input like `env` does.
+///
+/// ```rust
+/// let used = jq_rs::referenced_builtins(r#"[.[] | select(.key | test("^a"))] | length"#).unwrap();
+/// assert_eq!(used, vec!["length/0", "select/1", "test/1"]);
+///
+/// let used = jq_rs::referenced_builtins(r#"{home: env.HOME, path: $ENV.PATH}"#).unwrap();
+/// assert_eq!(used, vec!["$ENV", "env/0"]);
+/// ```
+///
+/// This works from the text of the program rather than what jq compiled it
+/// to, so it errs on the side of reporting too much. A program which defines
+/// its own function with the same name and arity as a builtin is reported as
+/// using the builtin, and builtins used by imported modules aren't seen.
+///
+/// Each call runs jq's `builtins` to find out what the linked `libjq` provides,
+/// failing only if that does.
+pub fn referenced_builtins(program: &str) -> Result<Vec<String>> {
+    let output = compile("builtins[]")?.run("null")?;
+    let builtins: BTreeSet<&str> = output.lines().map(|line| line.trim_matches('"')).collect();
+
+    let mut scanner = Scanner {
+        src: program.as_bytes(),
+        pos: 0,
+        calls: BTreeSet::new(),
+    };
+    scanner.code(false);
+    Ok(scanner
+        .calls
+        .into_iter()
+        .filter(|call| call == "$ENV" || builtins.contains(call.as_str()))
+        .collect())
+}
+
+/// An open bracket in the program.
+enum Frame {
+    /// The arguments of a call, with the number of `;` seen so far.
+    Call { name: String, separators: usize },
+    /// An object, and whether the next thing in it is a key.
+    Object { expect_key: bool },
+    /// The parameters of a `def`.
+    Params,
+    /// Any other brackets.
+    Group,
+}
+
+/// Picks the function calls out of a program, skipping over strings (but not
+/// their interpolations), comments, fields, variables, object keys and the
+/// names being defined.
+struct Scanner<'a> {
+    src: &'a [u8],
+    pos: usize,
+    calls: BTreeSet<String>,
+}
+
+impl Scanner<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).cloned()
+    }
+
+    /// Scan code up to the end of the program, or when `interpolation` is
+    /// set, up to the `)` closing a string interpolation.
+    fn code(&mut self, interpolation: bool) {
+        let mut frames: Vec<Frame> = Vec::new();
+        // Set after `def` and `as`, which are followed by a name rather than
+        // a call.
+        let mut naming = false;
+
+        while let Some(c) = self.peek() {
+            match c {
+                b'#' => self.skip_trivia(),
+                b'"' => {
+                    self.pos += 1;
+                    self.string();
+                }
+                b'.' | b'$' | b'@' => {
+                    self.pos += 1;
+                    if c == b'$' {
+                        self.skip_trivia();
+                    }
+                    if self.next_is(is_ident_start) {
+                        let name = self.ident();
+                        if c == b'$' && name == "ENV" {
+                            self.calls.insert("$ENV".into());
+                        }
+                    }
+                }
+                b'0'..=b'9' => self.number(),
+                c if is_ident_start(c) => {
+                    let name = self.ident();
+                    let is_key = match frames.last() {
+                        Some(Frame::Object { expect_key }) => *expect_key,
+                        _ => false,
+                    };
+                    let in_params = matches!(frames.last(), Some(Frame::Params));
+                    if name == "def" || name == "as" {
+                        naming = true;
+                        continue;
+                    }
+                    if is_key || in_params || KEYWORDS.contains(&name.as_str()) {
+                        naming = false;
+                        continue;
+                    }
+                    let was_naming = std::mem::replace(&mut naming, false);
+                    self.skip_trivia();
+                    if self.src[self.pos..].starts_with(b"::") {
+                        // A function from a module.
+                        self.pos += 2;
+                        self.skip_trivia();
+                        if self.next_is(is_ident_start) {
+                            self.ident();
+                        }
+                        continue;
+                    }
+                    if self.peek() == Some(b'(') {
+                        self.pos += 1;
+                        frames.push(if was_naming {
+                            Frame::Params
+                        } else {
+                            Frame::Call {
+                                name,
+                                separators: 0,
+                            }
+                        });
+                    } else if !was_naming {
+                        self.calls.insert(format!("{}/0", name));
+                    }
+                }
+                b'(' | b'[' => {
+                    self.pos += 1;
+                    frames.push(Frame::Group);
+                }
+                b'{' => {
+                    self.pos += 1;
+                    frames.push(Frame::Object { expect_key: true });
+                }
+                b')' | b']' | b'}' => {
+                    self.pos += 1;
+                    match frames.pop() {
+                        Some(Frame::Call { name, separators }) => {
+                            self.calls.insert(format!("{}/{}", name, separators + 1));
+                        }
+                        Some(_) => {}
+                        // The end of the interpolation, or a stray bracket
+                        // for jq to complain about.
+                        None if interpolation => return,
+                        None => {}
+                    }
+                }
+                _ => {
+                    self.pos += 1;
+                    match (c, frames.last_mut()) {
+                        (b';', Some(Frame::Call { separators, .. })) => *separators += 1,
+                        (b':', Some(Frame::Object { expect_key })) => *expect_key = false,
+                        (b',', Some(Frame::Object { expect_key })) => *expect_key = true,
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Scan a string up to its closing quote, scanning interpolations as code.
+    fn string(&mut self) {
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                b'"' => return,
+                b'\\' if self.peek() == Some(b'(') => {
+                    self.pos += 1;
+                    self.code(true);
+                }
+                b'\\' => self.pos += 1,
+                _ => {}
+            }
+        }
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while self.next_is(|c| c.is_ascii_alphanumeric() || c == b'_') {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.src[start..self.pos]).into_owned()
+    }
+
+    fn number(&mut self) {
+        while self.next_is(|c| c.is_ascii_digit() || c == b'.') {
+            self.pos += 1;
+        }
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.pos += 1;
+            }
+            while self.next_is(|c| c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn next_is(&self, test: fn(u8) -> bool) -> bool {
+        match self.peek() {
+            Some(c) => test(c),
+            None => false,
+        }
+    }
+
+    /// Skip whitespace and comments.
+    fn skip_trivia(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                Some(b'#') => {
+                    while self.next_is(|c| c != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+pub(crate) fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+#[cfg(test)]
+mod test {
+    use super::referenced_builtins;
+
+    fn used(program: &str) -> Vec<String> {
+        referenced_builtins(program).unwrap()
+    }
+
+    #[test]
+    fn calls_and_arity() {
+        assert_eq!(
+            used(r#"map(select(.a)) | sort_by(.b) | test("a"; "g") | [limit(1; .[])] | not"#),
+            vec![
+                "limit/2",
+                "map/1",
+                "not/0",
+                "select/1",
+                "sort_by/1",
+                "test/2"
+            ]
+        );
+        assert_eq!(used("range ( 1 ; 2 ; 3 )"), vec!["range/3"]);
+        assert!(used(".").is_empty());
+    }
+
+    #[test]
+    fn not_calls() {
+        let program = r#"
+            # env is mentioned here, but only in a comment
+            import "length" as sort;
+            def keys(input; $path): $path;
+            . as {env: $env, $ENVIRON} | .input, ."tojson", $keys, @base64,
+            {length, add: 1, "map": 2, (tostring): 3, $__loc__},
+            reduce .[] as [$a] (0; . + $a) | 1e10, 2.5E-3, m::floor,
+            if . then . elif . then . else . end, try . catch .,
+            label $out | break $out
+        "#;
+        assert_eq!(used(program), vec!["tostring/0"]);
+    }
+
+    #[test]
+    fn strings_and_interpolation() {
+        assert_eq!(
+            used(r#""env \" input \(env.HOME + "\(input)") debug""#),
+            vec!["env/0", "input/0"]
+        );
+        assert_eq!(used("$ENV.HOME, $ ENV"), vec!["$ENV"]);
+    }
+
+    #[test]
+    fn own_definitions_are_still_reported() {
+        assert_eq!(used("def env: {}; env"), vec!["env/0"]);
+        assert_eq!(used("def f(g): g; f(.)"), Vec::<String>::new());
+    }
+}
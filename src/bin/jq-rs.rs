@@ -0,0 +1,89 @@
+//! A jq-compatible command line tool built on `jq_rs`.
+//!
+//! Supports the common flags of the jq cli, mostly as a way to check the
+//! library behaves the same as jq itself:
+//!
+//! ```text
+//! jq-rs [OPTIONS] FILTER [FILES...]
+//! ```
+
+use clap::Parser;
+use jq_rs::{stream, Error, JqCliOptions, JqProgram};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process;
+
+/// Everything given on the command line.
+#[derive(Parser)]
+#[command(name = "jq-rs", about = "Run a jq filter on json inputs")]
+struct Options {
+    #[command(flatten)]
+    jq: JqCliOptions,
+    /// The jq program to run
+    filter: String,
+    /// Files to read inputs from, rather than stdin
+    files: Vec<String>,
+}
+
+fn main() {
+    if let Err(err) = run(Options::parse()) {
+        match &err {
+            // Like jq, `halt_error` payloads are written as they are.
+            Error::Halted { payload, .. } => {
+                if let Some(payload) = payload {
+                    eprint!("{}", payload);
+                }
+            }
+            Error::Io { err } if err.kind() == io::ErrorKind::BrokenPipe => {}
+            _ => eprintln!("jq-rs: error: {}", err),
+        }
+        process::exit(err.exit_code().unwrap_or(5));
+    }
+}
+
+fn run(options: Options) -> jq_rs::Result<()> {
+    let mut program = options.jq.compiler().compile(&options.filter)?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    if options.jq.null_input {
+        out.write_all(program.run("null")?.as_bytes())?;
+    } else if options.jq.slurp {
+        let slurped = slurp(input(&options.files)?)?;
+        out.write_all(program.run(&slurped)?.as_bytes())?;
+    } else {
+        stream::transform(input(&options.files)?, &mut program, &mut out)?;
+    }
+    Ok(out.flush()?)
+}
+
+/// The named files one after another, or stdin when there are none.
+fn input(files: &[String]) -> io::Result<Box<dyn Read>> {
+    if files.iter().all(|file| file == "-") && files.len() <= 1 {
+        return Ok(Box::new(io::stdin()));
+    }
+    let mut input: Box<dyn Read> = Box::new(io::empty());
+    for file in files {
+        let next: Box<dyn Read> = if file == "-" {
+            Box::new(io::stdin())
+        } else {
+            Box::new(BufReader::new(File::open(file)?))
+        };
+        input = Box::new(input.chain(next));
+    }
+    Ok(input)
+}
+
+/// Gather every value in the input into a single array, as jq's `--slurp`.
+fn slurp<R: Read>(input: R) -> jq_rs::Result<String> {
+    let mut values = Vec::new();
+    let mut compact: JqProgram = jq_rs::compile(".")?;
+    stream::transform(input, &mut compact, &mut values)?;
+
+    let values =
+        String::from_utf8(values).map_err(|err| Error::StringConvert { err: Box::new(err) })?;
+    Ok(format!(
+        "[{}]",
+        values.lines().collect::<Vec<_>>().join(",")
+    ))
+}
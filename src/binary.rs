@@ -0,0 +1,144 @@
+use crate::cancel::Interrupt;
+use crate::errors::{Error, Result};
+use crate::jq::JV;
+use crate::JqProgram;
+use serde_json::Value;
+
+impl JqProgram {
+    /// Runs the program on a MessagePack encoded input, rendering the outputs
+    /// as json.
+    ///
+    /// Requires the `msgpack` feature.
+    ///
+    /// ```rust
+    /// let event = rmp_serde::to_vec(&serde_json::json!({"kind": "created", "id": 7})).unwrap();
+    /// let mut program = jq_rs::compile(".id").unwrap();
+    /// assert_eq!(program.run_msgpack(&event).unwrap(), "7\n");
+    /// ```
+    #[cfg(feature = "msgpack")]
+    pub fn run_msgpack(&mut self, data: &[u8]) -> Result<String> {
+        let value = rmp_serde::from_slice(data).map_err(decode_error)?;
+        self.run_decoded(&value)
+    }
+
+    /// Like `run_msgpack()`, with each output encoded as MessagePack.
+    ///
+    /// Requires the `msgpack` feature.
+    #[cfg(feature = "msgpack")]
+    pub fn run_msgpack_to_msgpack(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let value = rmp_serde::from_slice(data).map_err(decode_error)?;
+        self.run_encoded(&value, |output| {
+            rmp_serde::to_vec(output).map_err(encode_error)
+        })
+    }
+
+    /// Runs the program on a CBOR encoded input, rendering the outputs as
+    /// json.
+    ///
+    /// Requires the `cbor` feature.
+    ///
+    /// ```rust
+    /// let mut event = vec![];
+    /// ciborium::ser::into_writer(&serde_json::json!({"id": 7}), &mut event).unwrap();
+    /// let mut program = jq_rs::compile(".id").unwrap();
+    /// assert_eq!(program.run_cbor(&event).unwrap(), "7\n");
+    /// ```
+    #[cfg(feature = "cbor")]
+    pub fn run_cbor(&mut self, data: &[u8]) -> Result<String> {
+        let value = ciborium::de::from_reader(data).map_err(decode_error)?;
+        self.run_decoded(&value)
+    }
+
+    /// Like `run_cbor()`, with each output encoded as CBOR.
+    ///
+    /// Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn run_cbor_to_cbor(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let value = ciborium::de::from_reader(data).map_err(decode_error)?;
+        self.run_encoded(&value, |output| {
+            let mut buf = vec![];
+            ciborium::ser::into_writer(output, &mut buf).map_err(encode_error)?;
+            Ok(buf)
+        })
+    }
+
+    fn run_decoded(&mut self, value: &Value) -> Result<String> {
+        let mut buf = String::with_capacity(self.output_capacity);
+        self.jq
+            .execute_parsed(&JV::from_value(value), &Interrupt::default(), &mut buf)?;
+        Ok(buf)
+    }
+
+    fn run_encoded<F>(&mut self, value: &Value, encode: F) -> Result<Vec<Vec<u8>>>
+    where
+        F: Fn(&Value) -> Result<Vec<u8>>,
+    {
+        let outputs = self
+            .jq
+            .collect_values(JV::from_value(value), &Interrupt::default())?;
+        outputs.iter().map(encode).collect()
+    }
+}
+
+/// Binary formats have no lines to point at, so only the reason is given.
+fn decode_error<E: std::fmt::Display>(err: E) -> Error {
+    Error::Parse {
+        reason: err.to_string(),
+        line: 0,
+        column: 0,
+        offset: 0,
+        excerpt: String::new(),
+        caret: 0,
+    }
+}
+
+fn encode_error<E: std::error::Error + 'static>(err: E) -> Error {
+    Error::StringConvert { err: Box::new(err) }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{compile, Error};
+    use matches::assert_matches;
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack() {
+        let input = rmp_serde::to_vec(&json!({"a": [1, "b", null, 2.5]})).unwrap();
+        let mut program = compile(".a[]").unwrap();
+        assert_eq!(
+            program.run_msgpack(&input).unwrap(),
+            "1\n\"b\"\nnull\n2.5\n"
+        );
+
+        let outputs = program.run_msgpack_to_msgpack(&input).unwrap();
+        let decoded: Vec<serde_json::Value> = outputs
+            .iter()
+            .map(|output| rmp_serde::from_slice(output).unwrap())
+            .collect();
+        assert_eq!(decoded, vec![json!(1), json!("b"), json!(null), json!(2.5)]);
+
+        assert_matches!(program.run_msgpack(&[0xc1]), Err(Error::Parse { .. }));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        let mut input = vec![];
+        ciborium::ser::into_writer(&json!({"a": {"b": [true]}}), &mut input).unwrap();
+        let mut program = compile(".a | ., .b").unwrap();
+        assert_eq!(
+            program.run_cbor(&input).unwrap(),
+            "{\"b\":[true]}\n[true]\n"
+        );
+
+        let outputs = program.run_cbor_to_cbor(&input).unwrap();
+        let decoded: Vec<serde_json::Value> = outputs
+            .iter()
+            .map(|output| ciborium::de::from_reader(&output[..]).unwrap())
+            .collect();
+        assert_eq!(decoded, vec![json!({"b": [true]}), json!([true])]);
+
+        assert_matches!(program.run_cbor(&[0xff]), Err(Error::Parse { .. }));
+    }
+}
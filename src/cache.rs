@@ -0,0 +1,624 @@
+use crate::errors::{BatchError, Result};
+use crate::jq::Parser;
+use crate::{JqProgram, Settings};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::result;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Splitting the pools over several locks keeps threads working with different
+/// programs from contending with each other.
+const SHARD_COUNT: usize = 16;
+
+/// How many programs `ProgramCache::new()` holds on to.
+const DEFAULT_CAPACITY: usize = 1024;
+
+type Pools = HashMap<String, Pool>;
+
+/// The compiled instances of one program.
+#[derive(Default)]
+struct Pool {
+    programs: Vec<JqProgram>,
+    /// When the program was last checked out or handed back, for finding
+    /// the least recently used one.
+    last_used: u64,
+}
+
+/// The first line of the files written by `ProgramCache::save()`, which
+/// changes whenever their layout does.
+const SAVED_HEADER: &str = "jq-rs program cache v1";
+
+/// A thread-safe cache of compiled jq programs, keyed by their source.
+///
+/// Compiling a jq program is expensive, so services which see the same
+/// programs over and over can hold on to the compiled versions here. Since a
+/// `JqProgram` can only run one input at a time, the cache keeps a _pool_ of
+/// compiled instances for each program. The pool grows to match the number of
+/// threads concurrently running a given program.
+///
+/// The cache holds on to a limited number of programs, 1024 unless another
+/// limit is given with `with_capacity()`. Once there are more, the pool of
+/// the program used least recently is dropped.
+///
+/// ```rust
+/// use jq_rs::ProgramCache;
+///
+/// let cache = ProgramCache::new();
+///
+/// assert_eq!(cache.run(".a", r#"{"a": 1}"#).unwrap(), "1\n");
+/// assert_eq!(cache.run(".a", r#"{"a": 2}"#).unwrap(), "2\n");
+///
+/// let stats = cache.stats();
+/// assert_eq!(stats.misses, 1);
+/// assert_eq!(stats.hits, 1);
+/// ```
+pub struct ProgramCache {
+    shards: Vec<Mutex<Pools>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    capacity: usize,
+    /// How many programs there are pools for.
+    programs: AtomicUsize,
+    /// Counts up with each use, standing in for the time.
+    clock: AtomicU64,
+}
+
+/// Counters describing how effective a `ProgramCache` has been.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of times a compiled program was reused from the cache.
+    pub hits: u64,
+    /// Number of times a program had to be compiled.
+    pub misses: u64,
+}
+
+impl ProgramCache {
+    /// Create an empty cache, holding up to 1024 programs.
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Create an empty cache, holding pools for up to `programs` different
+    /// programs. Programs checked out when their pool is dropped start a new
+    /// one when they're handed back.
+    ///
+    /// ```rust
+    /// use jq_rs::ProgramCache;
+    ///
+    /// let cache = ProgramCache::with_capacity(2);
+    /// cache.run(".a", "{}").unwrap();
+    /// cache.run(".b", "{}").unwrap();
+    /// cache.run(".a", "{}").unwrap();
+    /// cache.run(".c", "{}").unwrap();
+    ///
+    /// // `.b` was used least recently.
+    /// assert!(cache.contains(".a") && cache.contains(".c"));
+    /// assert!(!cache.contains(".b"));
+    /// ```
+    pub fn with_capacity(programs: usize) -> Self {
+        ProgramCache {
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            capacity: programs,
+            programs: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    /// Run a jq program on a blob of json data, reusing a compiled version of
+    /// the program when one is available.
+    pub fn run(&self, program: &str, data: &str) -> Result<String> {
+        self.checkout(program)?.run(data)
+    }
+
+    /// Run a jq program on several blobs of json data, returning a result for
+    /// each.
+    ///
+    /// The program is checked out of the cache once for the whole batch. A
+    /// failure to compile the program is returned as the outer `Err`.
+    pub fn run_batch<I>(
+        &self,
+        program: &str,
+        inputs: I,
+    ) -> Result<Vec<result::Result<String, BatchError>>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        Ok(self.checkout(program)?.run_batch(inputs))
+    }
+
+    /// Take a compiled instance of the program out of the cache, compiling it
+    /// if none are available.
+    ///
+    /// The instance is returned to the cache when the `CachedProgram` is
+    /// dropped.
+    pub fn checkout(&self, program: &str) -> Result<CachedProgram<'_>> {
+        let pooled = self.lock_shard(program).get_mut(program).and_then(|pool| {
+            pool.last_used = self.tick();
+            pool.programs.pop()
+        });
+
+        let compiled = match pooled {
+            Some(compiled) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                compiled
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                // Compiling is slow, so it's done without holding the lock.
+                crate::compile(program)?
+            }
+        };
+
+        Ok(CachedProgram {
+            cache: self,
+            settings: compiled.settings(),
+            program: Some(compiled),
+        })
+    }
+
+    /// Report the hit/miss counters for the cache.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drop all the compiled programs held by the cache.
+    ///
+    /// Programs currently checked out are returned to the cache as usual.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            self.programs.fetch_sub(shard.len(), Ordering::Relaxed);
+            shard.clear();
+        }
+    }
+
+    /// Whether the cache holds compiled instances of `program`, such as after
+    /// `load()`.
+    ///
+    /// Only programs which compiled end up in the cache, so services which
+    /// check or lint the programs they're given can skip that for the ones
+    /// already here.
+    pub fn contains(&self, program: &str) -> bool {
+        match self.lock_shard(program).get(program) {
+            Some(pool) => !pool.programs.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Write the source of each program the cache holds to `path`, along with
+    /// how many compiled instances it had, so `load()` (or `load_pools()`)
+    /// can warm another cache with them.
+    ///
+    /// libjq has no way to save a compiled program, so it's only the sources
+    /// which are written, as a line each. The file is written next to `path`
+    /// then moved into place, so a cache being loaded from it never sees it
+    /// half written. Programs checked out while saving aren't counted.
+    ///
+    /// ```rust
+    /// use jq_rs::ProgramCache;
+    ///
+    /// let path = std::env::temp_dir().join(format!("jq-rs-doc-{}.cache", std::process::id()));
+    /// let cache = ProgramCache::new();
+    /// cache.run(".a", r#"{"a": 1}"#).unwrap();
+    /// cache.save(&path).unwrap();
+    ///
+    /// // At the next startup.
+    /// let cache = ProgramCache::new();
+    /// assert_eq!(cache.load(&path).unwrap(), 1);
+    /// assert!(cache.contains(".a"));
+    /// assert_eq!(cache.run(".a", r#"{"a": 2}"#).unwrap(), "2\n");
+    /// assert_eq!(cache.stats().misses, 0);
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut programs = vec![];
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            for (source, pool) in shard.iter().filter(|(_, pool)| !pool.programs.is_empty()) {
+                programs.push((source.clone(), pool.programs.len()));
+            }
+        }
+        programs.sort();
+
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let mut file = BufWriter::new(fs::File::create(&partial)?);
+        writeln!(file, "{}", SAVED_HEADER)?;
+        for (source, count) in programs {
+            let hash = program_hash(&source);
+            writeln!(file, "{} {} {}", hash, count, crate::escape_string(&source))?;
+        }
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        drop(file);
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Compile the programs `save()` wrote to `path` into the cache, one
+    /// instance of each, returning how many programs there were. The pools
+    /// grow from there as they're used, the same as for any other program.
+    ///
+    /// They're compiled the same way the cache compiles programs itself,
+    /// with `jq_rs::compile()`.
+    ///
+    /// A missing file loads nothing, which is what the first startup sees.
+    /// Programs which no longer compile (say after `libjq` was upgraded) are
+    /// left out, and a file which wasn't written by `save()`, or whose lines
+    /// don't match their hashes, fails with an `Error::Io`. The programs are
+    /// compiled one after another, so services with a lot of them may want to
+    /// load on a thread of its own and start serving straight away.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        self.load_instances(path.as_ref(), false)
+    }
+
+    /// Like `load()`, but compiles as many instances of each program as its
+    /// pool held when it was saved, for services which would rather pay for
+    /// them all at startup than as the load picks up.
+    pub fn load_pools<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        self.load_instances(path.as_ref(), true)
+    }
+
+    fn load_instances(&self, path: &Path, saved_counts: bool) -> Result<usize> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose()?.as_deref() != Some(SAVED_HEADER) {
+            return Err(invalid_saved_cache("it doesn't start with the header"));
+        }
+        let mut loaded = 0;
+        for (idx, line) in lines.enumerate() {
+            let line = line?;
+            let (source, count) = parse_saved_line(&line)
+                .ok_or_else(|| invalid_saved_cache(&format!("line {} is damaged", idx + 2)))?;
+            let count = if saved_counts { count } else { 1 };
+            let mut compiled = Vec::with_capacity(count);
+            while compiled.len() < count {
+                match crate::compile(&source) {
+                    Ok(program) => compiled.push(program),
+                    Err(_) => break,
+                }
+            }
+            if compiled.is_empty() {
+                continue;
+            }
+            self.put_back(source, compiled);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Add `programs` to the pool for `source`, dropping the pool of the
+    /// least recently used program if that makes too many.
+    fn put_back(&self, source: String, programs: Vec<JqProgram>) {
+        let last_used = self.tick();
+        let added = {
+            let mut shard = self.lock_shard(&source);
+            let added = !shard.contains_key(&source);
+            let pool = shard.entry(source).or_default();
+            pool.programs.extend(programs);
+            pool.last_used = last_used;
+            added
+        };
+        if added && self.programs.fetch_add(1, Ordering::Relaxed) >= self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Drop the pool of the program used least recently.
+    ///
+    /// The shards are searched one at a time, so another thread may have
+    /// used the program in the meantime. It's only dropped if it hasn't.
+    fn evict(&self) {
+        let mut oldest: Option<(usize, String, u64)> = None;
+        for (idx, shard) in self.shards.iter().enumerate() {
+            let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            for (source, pool) in shard.iter() {
+                match &oldest {
+                    Some((_, _, last_used)) if *last_used <= pool.last_used => {}
+                    _ => oldest = Some((idx, source.clone(), pool.last_used)),
+                }
+            }
+        }
+        if let Some((idx, source, last_used)) = oldest {
+            let mut shard = self.shards[idx]
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if shard.get(&source).map(|pool| pool.last_used) == Some(last_used) {
+                shard.remove(&source);
+                self.programs.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn lock_shard(&self, program: &str) -> MutexGuard<'_, Pools> {
+        let mut hasher = DefaultHasher::new();
+        program.hash(&mut hasher);
+        let idx = hasher.finish() as usize % self.shards.len();
+        // The pools are only ever pushed to or popped from, so a panic while
+        // the lock is held can't leave them in a broken state.
+        self.shards[idx]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A hash of the program's text, so spans for the same program can be
+/// grouped without recording the program itself, and saved caches can tell
+/// when a program has been garbled.
+///
+/// This is 64-bit FNV-1a as 16 hex digits, which (unlike `DefaultHasher`)
+/// stays the same between builds and releases.
+pub(crate) fn program_hash(program: &str) -> String {
+    let hash = program
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// The source and instance count from a line written by `save()`, or `None`
+/// when it's damaged.
+fn parse_saved_line(line: &str) -> Option<(String, usize)> {
+    let mut fields = line.splitn(3, ' ');
+    let hash = fields.next()?;
+    let count = fields.next()?.parse().ok()?;
+    let source = Parser::new().parse(fields.next()?).ok()?.as_string().ok()?;
+    if program_hash(&source) != hash {
+        return None;
+    }
+    Some((source, count))
+}
+
+fn invalid_saved_cache(reason: &str) -> crate::Error {
+    let reason = format!("Not a saved program cache: {}", reason);
+    io::Error::new(io::ErrorKind::InvalidData, reason).into()
+}
+
+impl Default for ProgramCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A compiled program checked out of a `ProgramCache`.
+///
+/// Derefs to `JqProgram`. The program goes back into the cache when this is
+/// dropped, with any settings changed through it (limits, handlers, the time
+/// zone and so on) put back as they were, so they don't carry over to
+/// whoever checks it out next.
+pub struct CachedProgram<'a> {
+    cache: &'a ProgramCache,
+    program: Option<JqProgram>,
+    settings: Settings,
+}
+
+impl Deref for CachedProgram<'_> {
+    type Target = JqProgram;
+
+    fn deref(&self) -> &JqProgram {
+        self.program
+            .as_ref()
+            .expect("program is only taken on drop")
+    }
+}
+
+impl DerefMut for CachedProgram<'_> {
+    fn deref_mut(&mut self) -> &mut JqProgram {
+        self.program
+            .as_mut()
+            .expect("program is only taken on drop")
+    }
+}
+
+impl Drop for CachedProgram<'_> {
+    fn drop(&mut self) {
+        if let Some(mut program) = self.program.take() {
+            program.reset();
+            program.restore(self.settings);
+            let source = program.source().to_string();
+            self.cache.put_back(source, vec![program]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{program_hash, CacheStats, ProgramCache};
+    use crate::Error;
+    use matches::assert_matches;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn pool_grows_with_concurrent_checkouts() {
+        let cache = ProgramCache::new();
+        {
+            let mut a = cache.checkout(".").unwrap();
+            let mut b = cache.checkout(".").unwrap();
+            assert_eq!(a.run("1").unwrap(), "1\n");
+            assert_eq!(b.run("2").unwrap(), "2\n");
+        }
+        // Both instances went back to the pool.
+        let _a = cache.checkout(".").unwrap();
+        let _b = cache.checkout(".").unwrap();
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 2 });
+    }
+
+    #[test]
+    fn settings_do_not_carry_over_to_the_next_checkout() {
+        let cache = ProgramCache::new();
+        {
+            let mut a = cache.checkout("debug | stderr").unwrap();
+            a.set_max_input_bytes(Some(1));
+            a.set_output_capacity(1024);
+            a.set_debug_handler(|_| panic!("debug handler carried over"));
+            a.set_stderr_handler(|_| panic!("stderr handler carried over"));
+            a.set_stats_handler(|_| panic!("stats handler carried over"));
+        }
+        let mut a = cache.checkout("debug | stderr").unwrap();
+        assert_eq!(a.run("[1, 2]").unwrap(), "[1,2]\n");
+        assert!(a.run("1").unwrap().capacity() < 1024);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn compile_errors_are_not_cached() {
+        let cache = ProgramCache::new();
+        assert_matches!(cache.run("[", "{}"), Err(Error::InvalidProgram { .. }));
+        assert_matches!(cache.run("[", "{}"), Err(Error::InvalidProgram { .. }));
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn run_batch_checks_out_once() {
+        let cache = ProgramCache::new();
+        let results = cache.run_batch(".", &["1", "2", "3"]).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 1 });
+        assert_matches!(
+            cache.run_batch("[", &["1"]).err(),
+            Some(Error::InvalidProgram { .. })
+        );
+    }
+
+    #[test]
+    fn clear() {
+        let cache = ProgramCache::new();
+        cache.run(".", "{}").unwrap();
+        cache.clear();
+        cache.run(".", "{}").unwrap();
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn least_recently_used_programs_are_dropped() {
+        let cache = ProgramCache::with_capacity(2);
+        cache.run(".a", "{}").unwrap();
+        cache.run(".b", "{}").unwrap();
+        // Checking out `.a` counts as using it, even before it's handed back.
+        let a = cache.checkout(".a").unwrap();
+        cache.run(".c", "{}").unwrap();
+        assert!(!cache.contains(".b"));
+        assert!(cache.contains(".c"));
+
+        // `.a`'s pool is empty while it's checked out, but still counts.
+        drop(a);
+        assert!(cache.contains(".a") && cache.contains(".c"));
+        cache.run(".d", "{}").unwrap();
+        assert!(!cache.contains(".c"));
+        assert!(cache.contains(".a") && cache.contains(".d"));
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 4 });
+
+        cache.clear();
+        cache.run(".e", "{}").unwrap();
+        cache.run(".f", "{}").unwrap();
+        assert!(cache.contains(".e") && cache.contains(".f"));
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let cache = Arc::new(ProgramCache::new());
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let cache = Arc::clone(&cache);
+                thread::spawn(move || {
+                    let program = if i % 2 == 0 { ".a" } else { ".b" };
+                    cache.run(program, r#"{"a": 1, "b": 2}"#).unwrap()
+                })
+            })
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let expected = if i % 2 == 0 { "1\n" } else { "2\n" };
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 8);
+    }
+
+    fn saved_cache_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("jq-rs-{}-{}.cache", name, std::process::id()))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = saved_cache_path("save_and_load");
+        let cache = ProgramCache::new();
+        {
+            let _a = cache.checkout(".a").unwrap();
+            let _b = cache.checkout(".a").unwrap();
+        }
+        cache.run(".b | \"tab\\t\\n\"", "{}").unwrap();
+        cache.save(&path).unwrap();
+
+        let loaded = ProgramCache::new();
+        assert_eq!(loaded.load(&path).unwrap(), 2);
+        assert!(loaded.contains(".a"));
+        assert!(loaded.contains(".b | \"tab\\t\\n\""));
+        assert!(!loaded.contains(".c"));
+        {
+            // Only one instance of each is compiled up front.
+            let _a = loaded.checkout(".a").unwrap();
+            let _b = loaded.checkout(".a").unwrap();
+        }
+        assert_eq!(loaded.stats(), CacheStats { hits: 1, misses: 1 });
+
+        let loaded = ProgramCache::new();
+        assert_eq!(loaded.load_pools(&path).unwrap(), 2);
+        {
+            let _a = loaded.checkout(".a").unwrap();
+            let _b = loaded.checkout(".a").unwrap();
+        }
+        assert_eq!(loaded.stats(), CacheStats { hits: 2, misses: 0 });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loading_missing_or_damaged_files() {
+        let path = saved_cache_path("damaged");
+        assert_eq!(ProgramCache::new().load(&path).unwrap(), 0);
+
+        let garbled = format!("{}\n{} 1 \".b\"\n", super::SAVED_HEADER, program_hash(".a"));
+        for contents in &["{}\n", garbled.as_str()] {
+            std::fs::write(&path, contents).unwrap();
+            assert_matches!(ProgramCache::new().load(&path), Err(Error::Io { .. }));
+        }
+
+        // Programs which stopped compiling are left out.
+        let contents = format!(
+            "{}\n{} 1 \"[\"\n{} 1 \".a\"\n",
+            super::SAVED_HEADER,
+            program_hash("["),
+            program_hash(".a")
+        );
+        std::fs::write(&path, contents).unwrap();
+        let cache = ProgramCache::new();
+        assert_eq!(cache.load(&path).unwrap(), 1);
+        assert!(!cache.contains("["));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use crate::errors::{Error, Result};
+use crate::jq::JV;
+use jq_sys::{jq_halt, jq_halted, jq_state, jv, jv_free, jv_invalid};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A flag used to stop a jq program which is already running.
+///
+/// Tokens are cheap to clone, and all clones share the same flag, so one can
+/// be handed to a running program while another is kept around to call
+/// `cancel()` from elsewhere (a different thread, a request timeout, etc).
+///
+/// The flag is checked before the program starts and between each output the
+/// program produces. A run which is cancelled returns `Error::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a new token which has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Signal any runs using this token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if `cancel()` has been called on this token (or any of its clones).
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl From<Arc<AtomicBool>> for CancellationToken {
+    /// Use an existing flag as a token. Storing `true` cancels the run.
+    fn from(cancelled: Arc<AtomicBool>) -> Self {
+        CancellationToken { cancelled }
+    }
+}
+
+/// Stops a program part way through a run, from another thread.
+///
+/// Handles come from `JqProgram::interrupt_handle()`, and can be cloned and
+/// sent to other threads, typically a watchdog enforcing a time budget. A
+/// run which is interrupted returns `Error::Interrupted`.
+///
+/// ```rust
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let mut program = jq_rs::compile("range(infinite)").unwrap();
+/// let handle = program.interrupt_handle();
+/// thread::spawn(move || {
+///     thread::sleep(Duration::from_millis(50));
+///     handle.interrupt();
+/// });
+///
+/// assert!(program.run("null").unwrap_err().is_interrupted());
+/// ```
+///
+/// `interrupt()` only sets a flag, which the thread running the program
+/// checks between each output, and whenever the program calls `debug`,
+/// `stderr` or a function implemented in Rust. libjq 1.6 can't be stopped
+/// safely from another thread, so a step which never does any of those (like
+/// `def f: f; f` or `[range(1e9)] | length`) runs until it's done.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    running: Arc<Running>,
+}
+
+/// The program's state while it's running.
+#[derive(Debug, Default)]
+struct Running {
+    /// Only used from the thread running the program.
+    state: AtomicPtr<jq_state>,
+    status: AtomicU8,
+}
+
+const IDLE: u8 = 0;
+const RUNNING: u8 = 1;
+const INTERRUPTED: u8 = 2;
+
+impl InterruptHandle {
+    /// Stop the run in progress, if there is one, giving back whether there
+    /// was. Interrupting a program which isn't running does nothing, so it
+    /// won't affect the next run.
+    pub fn interrupt(&self) -> bool {
+        match self.running.status.compare_exchange(
+            RUNNING,
+            INTERRUPTED,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => true,
+            Err(status) => status == INTERRUPTED,
+        }
+    }
+
+    /// Whether the run in progress has been interrupted.
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.running.status.load(Ordering::SeqCst) == INTERRUPTED
+    }
+
+    /// Halt the run in progress if it has been interrupted, from the thread
+    /// running it while it's in one of our callbacks. jq checks whether it
+    /// has been halted before each instruction, so the run stops as soon as
+    /// the callback returns.
+    pub(crate) fn halt_if_interrupted(&self) {
+        if self.is_interrupted() {
+            self.halt_with(unsafe { jv_invalid() }, unsafe { jv_invalid() });
+        }
+    }
+
+    /// Halt the run in progress the way `halt_error(exit_code)` would, from
+    /// the thread running it while it's waiting on an output to be taken, as
+    /// pipelines do when a later stage halts. Does nothing when there's no
+    /// run, or it has already halted.
+    pub(crate) fn halt(&self, exit_code: i32, payload: Option<&str>) {
+        let payload = match payload {
+            Some(payload) => JV::string(payload).into_raw(),
+            None => unsafe { jv_invalid() },
+        };
+        self.halt_with(JV::number(f64::from(exit_code)).into_raw(), payload);
+    }
+
+    fn halt_with(&self, exit_code: jv, payload: jv) {
+        let state = self.running.state.load(Ordering::SeqCst);
+        // `jq_halt()` asserts the program hasn't already halted.
+        if state.is_null() || unsafe { jq_halted(state) } != 0 {
+            unsafe {
+                jv_free(exit_code);
+                jv_free(payload);
+            }
+            return;
+        }
+        unsafe { jq_halt(state, exit_code, payload) };
+    }
+
+    /// Mark the run of `state` as in progress.
+    pub(crate) fn begin(&self, state: *mut jq_state) {
+        self.running.state.store(state, Ordering::SeqCst);
+        self.running.status.store(RUNNING, Ordering::SeqCst);
+    }
+
+    /// Mark the run as finished, giving back whether it was interrupted.
+    pub(crate) fn end(&self) -> bool {
+        self.running
+            .state
+            .store(std::ptr::null_mut(), Ordering::SeqCst);
+        self.running.status.swap(IDLE, Ordering::SeqCst) == INTERRUPTED
+    }
+}
+
+/// Call `run`, with a thread standing by to interrupt it through `handle`
+/// once `timeout` has passed. Gives back what `run` did, and whether it was
+/// interrupted by the watchdog.
+pub(crate) fn with_watchdog<T, F>(handle: InterruptHandle, timeout: Duration, run: F) -> (T, bool)
+where
+    F: FnOnce() -> T,
+{
+    let (done, finished) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || match finished.recv_timeout(timeout) {
+        Err(RecvTimeoutError::Timeout) => handle.interrupt(),
+        _ => false,
+    });
+    let result = run();
+    // Wakes the watchdog, if it's still waiting.
+    drop(done);
+    (result, watchdog.join().unwrap_or(false))
+}
+
+/// The conditions which can stop a run part way through.
+///
+/// These are only checked before the program starts, and between each
+/// output, along with `InterruptHandle`.
+#[derive(Default)]
+pub(crate) struct Interrupt<'a> {
+    pub token: Option<&'a CancellationToken>,
+    pub deadline: Option<Instant>,
+}
+
+impl Interrupt<'_> {
+    pub fn check(&self) -> Result<()> {
+        if self.token.map(CancellationToken::is_cancelled) == Some(true) {
+            return Err(Error::Cancelled);
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::Timeout),
+            _ => Ok(()),
+        }
+    }
+}
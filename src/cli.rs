@@ -0,0 +1,158 @@
+use crate::Compiler;
+use clap::Args;
+
+/// The flags of the jq cli which map onto a `Compiler`, for embedding a
+/// jq-like command in another [clap] based cli.
+///
+/// Like jq, outputs are pretty printed with an indent of 2 unless
+/// `--compact-output` or `--indent` say otherwise. Reading the input (and
+/// whether to `--slurp` it, or use `--null-input` instead) is left to the
+/// embedding cli.
+///
+/// ```rust
+/// use clap::Parser;
+/// use jq_rs::JqCliOptions;
+///
+/// #[derive(Parser)]
+/// struct Query {
+///     #[command(flatten)]
+///     jq: JqCliOptions,
+///     filter: String,
+/// }
+///
+/// let query = Query::parse_from(["query", "-r", "--arg", "name", "Rodan", "$name"]);
+/// let mut program = query.jq.compiler().compile(&query.filter).unwrap();
+/// assert_eq!(program.run("null").unwrap(), "Rodan\n");
+/// ```
+///
+/// [clap]: https://crates.io/crates/clap
+#[derive(Args, Clone, Debug, Default, PartialEq, Eq)]
+pub struct JqCliOptions {
+    /// Write each output on a single line
+    #[arg(short = 'c', long)]
+    pub compact_output: bool,
+    /// Write strings without quotes
+    #[arg(short = 'r', long)]
+    pub raw_output: bool,
+    /// Like --raw-output, without a newline after each output
+    #[arg(short = 'j', long)]
+    pub join_output: bool,
+    /// Escape everything outside of ASCII
+    #[arg(short = 'a', long)]
+    pub ascii_output: bool,
+    /// Color the output with ANSI escape codes
+    #[arg(short = 'C', long)]
+    pub color_output: bool,
+    /// Write the keys of objects in sorted order
+    #[arg(short = 'S', long)]
+    pub sort_keys: bool,
+    /// Read all inputs into an array and run the filter on that
+    #[arg(short = 's', long)]
+    pub slurp: bool,
+    /// Run the filter once, with null as its input
+    #[arg(short = 'n', long)]
+    pub null_input: bool,
+    /// Indent with tabs
+    #[arg(long)]
+    pub tab: bool,
+    /// Indent with this many spaces
+    #[arg(long, value_name = "n", value_parser = clap::value_parser!(u8).range(0..=7))]
+    pub indent: Option<u8>,
+    /// Bind $NAME to the string VALUE
+    #[arg(long = "arg", num_args = 2, value_names = ["NAME", "VALUE"])]
+    pub args: Vec<String>,
+    /// Bind $NAME to the json VALUE
+    #[arg(long = "argjson", num_args = 2, value_names = ["NAME", "VALUE"])]
+    pub json_args: Vec<String>,
+    /// Search DIRECTORY for modules
+    #[arg(short = 'L', value_name = "DIRECTORY")]
+    pub library_paths: Vec<String>,
+}
+
+impl JqCliOptions {
+    /// A `Compiler` set up with these options.
+    pub fn compiler(&self) -> Compiler {
+        let indent = if self.compact_output {
+            0
+        } else {
+            self.indent.unwrap_or(2)
+        };
+        let mut compiler = Compiler::new()
+            .raw_output(self.raw_output)
+            .join_output(self.join_output)
+            .ascii_output(self.ascii_output)
+            .color_output(self.color_output)
+            .sort_keys(self.sort_keys)
+            .tab(self.tab)
+            .indent(indent);
+        // clap makes sure each flag is followed by both a name and a value.
+        for pair in self.args.chunks(2) {
+            compiler = compiler.arg(pair[0].as_str(), pair[1].as_str());
+        }
+        for pair in self.json_args.chunks(2) {
+            compiler = compiler.arg_json(pair[0].as_str(), pair[1].as_str());
+        }
+        for directory in &self.library_paths {
+            compiler = compiler.library_path(directory.as_str());
+        }
+        compiler
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::JqCliOptions;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        options: JqCliOptions,
+        filter: String,
+    }
+
+    fn run(args: &[&str], input: &str) -> String {
+        let cli = Cli::parse_from(std::iter::once("jq").chain(args.iter().cloned()));
+        let mut program = cli.options.compiler().compile(&cli.filter).unwrap();
+        program.run(input).unwrap()
+    }
+
+    #[test]
+    fn defaults_match_jq() {
+        assert_eq!(run(&["."], "[1]"), "[\n  1\n]\n");
+        assert_eq!(run(&["-c", "."], "[1]"), "[1]\n");
+        assert_eq!(run(&["--indent", "0", "."], "[1]"), "[1]\n");
+        assert_eq!(run(&["--tab", "."], "[1]"), "[\n\t1\n]\n");
+        assert_eq!(run(&["-C", "."], "null"), "\u{1b}[1;30mnull\u{1b}[0m\n");
+    }
+
+    #[test]
+    fn combined_flags_and_args() {
+        let args = [
+            "-rcS",
+            "--arg",
+            "a",
+            "x",
+            "--argjson",
+            "b",
+            "{\"d\": 1, \"c\": 2}",
+            "--arg",
+            "e",
+            "y",
+            "$a, $b, $e",
+        ];
+        assert_eq!(run(&args, "null"), "x\n{\"c\":2,\"d\":1}\ny\n");
+    }
+
+    #[test]
+    fn library_paths() {
+        let cli = Cli::parse_from(["jq", "-L", "a", "-Lb", "."]);
+        assert_eq!(cli.options.library_paths, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn invalid_indent() {
+        let res = Cli::try_parse_from(["jq", "--indent", "8", "."]);
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! Writing colored outputs through `termcolor`, so they show up on consoles
+//! which don't understand ANSI escape codes.
+
+use crate::errors::Result;
+use termcolor::{Color, ColorSpec, WriteColor};
+
+/// Write outputs from a program compiled with `Compiler::color_output()` to a
+/// `termcolor` writer.
+///
+/// jq colors its outputs with ANSI escape codes, which older Windows consoles
+/// print as they are. Here each escape code is turned into a `ColorSpec` for
+/// the writer instead, and when the writer doesn't support color (say a
+/// `StandardStream` made with `ColorChoice::Auto` while `NO_COLOR` is set, or
+/// output isn't going to a terminal) the escape codes are dropped, leaving
+/// plain json.
+///
+/// Requires the `termcolor` feature.
+///
+/// ```rust
+/// use jq_rs::Compiler;
+/// use termcolor::{ColorChoice, StandardStream};
+///
+/// let mut program = Compiler::new().color_output(true).compile(".name").unwrap();
+/// let output = program.run(r#"{"name": "Mothra"}"#).unwrap();
+///
+/// let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+/// jq_rs::write_colored(&output, &mut stdout).unwrap();
+/// ```
+///
+/// Only "select graphic rendition" escape codes are understood, which is all
+/// jq writes, including for colors set through `JQ_COLORS`. Other escape
+/// codes are dropped.
+pub fn write_colored<W>(output: &str, out: &mut W) -> Result<()>
+where
+    W: WriteColor + ?Sized,
+{
+    let mut spec = ColorSpec::new();
+    let mut rest = output;
+    while let Some(start) = rest.find('\u{1b}') {
+        out.write_all(&rest.as_bytes()[..start])?;
+        rest = &rest[start + 1..];
+
+        if !rest.starts_with('[') {
+            // A lone escape, which isn't something jq writes.
+            continue;
+        }
+        let sequence = &rest[1..];
+        // The parameters run up to a final byte between `@` and `~`.
+        let end = match sequence.find(|c: char| ('@'..='~').contains(&c)) {
+            Some(end) => end,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        rest = &sequence[end + 1..];
+        if !sequence[end..].starts_with('m') {
+            continue;
+        }
+
+        apply(&mut spec, &sequence[..end]);
+        if spec.is_none() {
+            out.reset()?;
+        } else {
+            out.set_color(&spec)?;
+        }
+    }
+    out.write_all(rest.as_bytes())?;
+    Ok(())
+}
+
+/// Update `spec` with the parameters of a "select graphic rendition" escape
+/// code, like `1;34`.
+fn apply(spec: &mut ColorSpec, params: &str) {
+    let mut codes = params.split(';').map(|code| code.parse::<u8>().ok());
+    while let Some(code) = codes.next() {
+        match code.unwrap_or(0) {
+            0 => {
+                spec.clear();
+                continue;
+            }
+            1 => spec.set_bold(true),
+            2 => spec.set_dimmed(true),
+            3 => spec.set_italic(true),
+            4 => spec.set_underline(true),
+            22 => spec.set_bold(false).set_dimmed(false),
+            23 => spec.set_italic(false),
+            24 => spec.set_underline(false),
+            code @ 30..=37 => spec.set_fg(Some(basic(code - 30))),
+            38 => spec.set_fg(extended(&mut codes)),
+            39 => spec.set_fg(None),
+            code @ 40..=47 => spec.set_bg(Some(basic(code - 40))),
+            48 => spec.set_bg(extended(&mut codes)),
+            49 => spec.set_bg(None),
+            code @ 90..=97 => spec.set_fg(Some(basic(code - 90))).set_intense(true),
+            code @ 100..=107 => spec.set_bg(Some(basic(code - 100))).set_intense(true),
+            _ => spec,
+        };
+    }
+}
+
+fn basic(code: u8) -> Color {
+    match code {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        _ => Color::White,
+    }
+}
+
+/// The color after a `38` or `48`, as `5;<n>` for one of 256 colors or
+/// `2;<r>;<g>;<b>`.
+fn extended<I>(codes: &mut I) -> Option<Color>
+where
+    I: Iterator<Item = Option<u8>>,
+{
+    match codes.next()?? {
+        5 => Some(Color::Ansi256(codes.next()??)),
+        2 => Some(Color::Rgb(codes.next()??, codes.next()??, codes.next()??)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_colored;
+    use crate::Compiler;
+    use std::io::Write;
+    use termcolor::{Buffer, Color, ColorSpec, WriteColor};
+
+    fn colored(output: &str) -> String {
+        let mut buf = Buffer::ansi();
+        write_colored(output, &mut buf).unwrap();
+        String::from_utf8(buf.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn jq_colors() {
+        let mut program = Compiler::new().color_output(true).compile(".").unwrap();
+        let output = program.run(r#"{"a": [1, "b", null]}"#).unwrap();
+
+        // Translating jq's escape codes and back again only changes the way
+        // the bold ones are spelled.
+        assert_eq!(
+            colored(&output),
+            output
+                .replace("\u{1b}[1;39m", "\u{1b}[0m\u{1b}[1m")
+                .replace("\u{1b}[34;1m", "\u{1b}[0m\u{1b}[1m\u{1b}[34m")
+                .replace("\u{1b}[1;30m", "\u{1b}[0m\u{1b}[1m\u{1b}[30m")
+                .replace("\u{1b}[0;39m", "\u{1b}[0m")
+                .replace("\u{1b}[0;32m", "\u{1b}[0m\u{1b}[32m")
+        );
+
+        let mut buf = Buffer::no_color();
+        write_colored(&output, &mut buf).unwrap();
+        assert_eq!(buf.into_inner(), b"{\"a\":[1,\"b\",null]}\n");
+    }
+
+    #[test]
+    fn escape_codes() {
+        let mut expected = Buffer::ansi();
+        expected
+            .set_color(
+                ColorSpec::new()
+                    .set_fg(Some(Color::Ansi256(208)))
+                    .set_bg(Some(Color::Rgb(1, 2, 3)))
+                    .set_underline(true),
+            )
+            .unwrap();
+        expected.write_all(b"x").unwrap();
+        expected.reset().unwrap();
+        expected.write_all(b"y").unwrap();
+
+        assert_eq!(
+            colored("\u{1b}[4;38;5;208;48;2;1;2;3mx\u{1b}[mz\u{1b}[2Ky"),
+            String::from_utf8(expected.into_inner())
+                .unwrap()
+                .replace('y', "zy")
+        );
+        assert_eq!(colored("a\u{1b}b\u{1b}[1"), "ab");
+    }
+}
@@ -0,0 +1,1251 @@
+use crate::errors::{Error, Result};
+use crate::jq::{
+    imported_modules, ColorPalette, Format, Functions, HtmlClasses, Jq, Limits, NonFinite,
+    Overrides, Parser, JV,
+};
+use crate::metrics::{Metrics, SharedMetrics};
+use crate::processors::{PostProcessor, PreProcessor, SharedPostProcessor, SharedPreProcessor};
+use crate::{JqProgram, Scope};
+use jq_sys::{
+    jv_print_flags_JV_PRINT_ASCII, jv_print_flags_JV_PRINT_COLOR, jv_print_flags_JV_PRINT_PRETTY,
+    jv_print_flags_JV_PRINT_SORTED, jv_print_flags_JV_PRINT_TAB,
+};
+use std::fmt;
+use std::os::raw::c_int;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The largest indent jq supports.
+const MAX_INDENT: u8 = 7;
+
+/// Options for compiling jq programs, covering the jq cli flags which change
+/// how a program is compiled or how its outputs are rendered.
+///
+/// `jq_rs::compile()` is the same as `Compiler::new().compile()`. A
+/// `Compiler` can be reused to compile any number of programs with the same
+/// options.
+///
+/// ```rust
+/// use jq_rs::Compiler;
+///
+/// let mut program = Compiler::new()
+///     .arg("kind", "kaiju")
+///     .raw_output(true)
+///     .compile(r#".[] | select(.kind == $kind) | .name"#)
+///     .unwrap();
+///
+/// let data = r#"[{"name": "Godzilla", "kind": "kaiju"}, {"name": "Ultraman", "kind": "hero"}]"#;
+/// assert_eq!(program.run(data).unwrap(), "Godzilla\n");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Compiler {
+    args: Vec<(String, Arg)>,
+    raw_output: bool,
+    join_output: bool,
+    indent: u8,
+    tab: bool,
+    sort_keys: bool,
+    ascii_output: bool,
+    color_output: bool,
+    color_palette: Option<ColorPalette>,
+    canonical_output: bool,
+    stable_output: bool,
+    html_output: Option<HtmlClasses>,
+    overrides: Overrides,
+    limits: Limits,
+    non_finite: NonFinite,
+    library_paths: Vec<String>,
+    /// The only modules programs may import, when restricted.
+    allowed_modules: Option<Vec<String>>,
+    /// Values for `register_const()`, as json.
+    constants: Vec<(String, String)>,
+    functions: Functions,
+    scope: Scope,
+    metrics: SharedMetrics,
+    preprocessor: Option<SharedPreProcessor>,
+    postprocessor: Option<SharedPostProcessor>,
+    #[cfg(feature = "locale")]
+    c_locale: bool,
+}
+
+/// What programs are compared and hashed by, besides their source: the
+/// settings from the `Compiler` which change what a program does.
+///
+/// Those held behind `Arc`s (functions and processors) are compared by
+/// address. Each program keeps its own alive, so the addresses can't be
+/// reused for something else while it's around. Metrics don't change what a
+/// program does, so they're left out.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CompileKey {
+    /// The plain settings, written out.
+    settings: String,
+    functions: Vec<usize>,
+    preprocessor: Option<usize>,
+    postprocessor: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Arg {
+    String(String),
+    Json(String),
+}
+
+impl Compiler {
+    /// Start with the same options as `jq_rs::compile()`, which renders each
+    /// output as compact json followed by a newline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind `$name` to a string value, like `--arg name value`.
+    pub fn arg<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.args.push((name.into(), Arg::String(value.into())));
+        self
+    }
+
+    /// Bind `$name` to a json value, like `--argjson name json`.
+    ///
+    /// The json is parsed when a program is compiled, failing with
+    /// `Error::Parse` if it's invalid.
+    pub fn arg_json<N, V>(mut self, name: N, json: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.args.push((name.into(), Arg::Json(json.into())));
+        self
+    }
+
+    /// Declare `$name` as a variable bound each time the program is run,
+    /// with `JqProgram::run_with_vars()`, rather than when it's compiled.
+    ///
+    /// A compiled program can then be run with different values without being
+    /// compiled again. Variables which aren't given a value for a run are
+    /// `null`. Unlike `arg()`, they don't show up in `$ARGS`.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let mut program = Compiler::new()
+    ///     .var("min")
+    ///     .compile("map(select(. >= $min))")
+    ///     .unwrap();
+    /// assert_eq!(program.run_with_vars("[1, 5, 10]", &[("min", "5")]).unwrap(), "[5,10]\n");
+    /// assert_eq!(program.run_with_vars("[1, 5, 10]", &[("min", "10")]).unwrap(), "[10]\n");
+    /// ```
+    pub fn var<N: Into<String>>(mut self, name: N) -> Self {
+        self.overrides.vars.push(name.into());
+        self
+    }
+
+    /// Define `name` as a function which takes no arguments and gives back a
+    /// json value, the same as if each program started with
+    /// `def name: json;`.
+    ///
+    /// Unlike `arg_json()`, the value doesn't show up in `$ARGS`, and it's
+    /// used without a `$`. Like any other definition, programs can replace
+    /// it with one of their own, and it replaces any builtin of the same
+    /// name. The json is parsed when a program is compiled, failing with
+    /// `Error::Parse` if it's invalid.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let mut program = Compiler::new()
+    ///     .register_const("config", r#"{"units": "m"}"#)
+    ///     .compile(r#""\(.height)\(config.units)""#)
+    ///     .unwrap();
+    /// assert_eq!(program.run(r#"{"height": 50}"#).unwrap(), "\"50m\"\n");
+    /// ```
+    pub fn register_const<N, V>(mut self, name: N, json: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.constants.push((name.into(), json.into()));
+        self
+    }
+
+    /// Write outputs which are strings as plain text rather than as json,
+    /// like `-r`.
+    pub fn raw_output(mut self, enabled: bool) -> Self {
+        self.raw_output = enabled;
+        self
+    }
+
+    /// Like `raw_output()`, but without a newline after each output, like
+    /// `-j`.
+    pub fn join_output(mut self, enabled: bool) -> Self {
+        self.join_output = enabled;
+        self
+    }
+
+    /// Pretty print outputs with this many spaces of indentation, like
+    /// `--indent n`. Zero (the default) gives compact output, and jq supports
+    /// at most 7.
+    pub fn indent(mut self, spaces: u8) -> Self {
+        self.indent = spaces.min(MAX_INDENT);
+        self
+    }
+
+    /// Pretty print outputs indented with tabs, like `--tab`. This overrides
+    /// `indent()`.
+    pub fn tab(mut self, enabled: bool) -> Self {
+        self.tab = enabled;
+        self
+    }
+
+    /// Write the keys of objects in sorted order, like `-S`.
+    ///
+    /// Otherwise, as with jq, keys are written in the order they were added
+    /// to the object: the order of the input for objects read from it, with
+    /// keys the program adds coming after the ones already there. This only
+    /// changes how outputs are rendered, not the values handed out by
+    /// `JqProgram::run_values()` and `JqProgram::run_as()`.
+    pub fn sort_keys(mut self, enabled: bool) -> Self {
+        self.sort_keys = enabled;
+        self
+    }
+
+    /// Escape everything outside of ASCII in strings, like `-a`.
+    pub fn ascii_output(mut self, enabled: bool) -> Self {
+        self.ascii_output = enabled;
+        self
+    }
+
+    /// Color outputs with ANSI escape codes, like `-C`.
+    ///
+    /// The escape codes are written as they are, so use `write_colored()`
+    /// (with the `termcolor` feature) to show them on consoles which don't
+    /// understand them, or to leave them out when colors aren't wanted.
+    /// Strings written by `raw_output()` aren't colored.
+    pub fn color_output(mut self, enabled: bool) -> Self {
+        self.color_output = enabled;
+        self
+    }
+
+    /// Colors for `color_output()` to use in place of jq's own, such as the
+    /// 256 colors or 24-bit colors of modern terminals, or a palette read from
+    /// `JQ_COLORS` with `ColorPalette::from_env()`.
+    pub fn color_palette(mut self, palette: ColorPalette) -> Self {
+        self.color_palette = Some(palette);
+        self
+    }
+
+    /// Write outputs as canonical json, following the JSON Canonicalization
+    /// Scheme ([RFC 8785]), for signing or hashing them.
+    ///
+    /// Outputs are compact, with object keys sorted by their UTF-16 code
+    /// units, strings escaped as little as possible and numbers written the
+    /// way JavaScript writes them. This overrides `indent()`, `tab()`,
+    /// `sort_keys()`, `ascii_output()`, `color_output()` and
+    /// `stable_output()`, but `raw_output()` still writes strings as plain
+    /// text.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let mut program = Compiler::new().canonical_output(true).compile(".").unwrap();
+    /// let data = r#"{"b": [1.50, 1E30, 0.000001], "a": "\u00e9\u007f"}"#;
+    ///
+    /// assert_eq!(
+    ///     program.run(data).unwrap(),
+    ///     "{\"a\":\"\u{e9}\u{7f}\",\"b\":[1.5,1e+30,0.000001]}\n"
+    /// );
+    /// ```
+    ///
+    /// [RFC 8785]: https://www.rfc-editor.org/rfc/rfc8785
+    pub fn canonical_output(mut self, enabled: bool) -> Self {
+        self.canonical_output = enabled;
+        self
+    }
+
+    /// Write outputs the same way whichever version of libjq is linked, for
+    /// snapshot tests and golden files.
+    ///
+    /// Outputs are pretty printed with an indent of two spaces, with object
+    /// keys sorted and numbers and strings written the way
+    /// `canonical_output()` writes them. jq's own rendering changes between
+    /// versions (how many digits numbers get, which characters are escaped),
+    /// which this avoids. This overrides `indent()`, `tab()`, `sort_keys()`,
+    /// `ascii_output()` and `color_output()`, but `raw_output()` still writes
+    /// strings as plain text.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let mut program = Compiler::new().stable_output(true).compile(".").unwrap();
+    ///
+    /// assert_eq!(
+    ///     program.run(r#"{"b": 1.10, "a": [3.0]}"#).unwrap(),
+    ///     "{\n  \"a\": [\n    3\n  ],\n  \"b\": 1.1\n}\n"
+    /// );
+    /// ```
+    pub fn stable_output(mut self, enabled: bool) -> Self {
+        self.stable_output = enabled;
+        self
+    }
+
+    /// Write outputs as HTML for showing on a web page, with each token in a
+    /// `<span>` with one of the `classes` for highlighting.
+    ///
+    /// Outputs are laid out the same as they would be otherwise, following
+    /// `indent()`, `tab()`, `sort_keys()` and `ascii_output()`, with
+    /// everything escaped for HTML. This overrides `color_output()`,
+    /// `canonical_output()` and `stable_output()`. Strings written by
+    /// `raw_output()` are escaped, but aren't put in a `<span>`.
+    ///
+    /// ```rust
+    /// use jq_rs::{Compiler, HtmlClasses};
+    ///
+    /// let mut program = Compiler::new()
+    ///     .html_output(HtmlClasses::default().punctuation(""))
+    ///     .compile(".")
+    ///     .unwrap();
+    ///
+    /// assert_eq!(
+    ///     program.run(r#"{"name": "Mothra"}"#).unwrap(),
+    ///     concat!(
+    ///         r#"{<span class="jq-key">&quot;name&quot;</span>:"#,
+    ///         r#"<span class="jq-string">&quot;Mothra&quot;</span>}"#,
+    ///         "\n"
+    ///     )
+    /// );
+    /// ```
+    pub fn html_output(mut self, classes: HtmlClasses) -> Self {
+        self.html_output = Some(classes);
+        self
+    }
+
+    /// Run programs in a sandbox, for filters which come from someone you
+    /// don't trust.
+    ///
+    /// Sandboxed programs only see their input and the `arg()`s they're
+    /// given:
+    ///
+    /// - `env`, `input_filename`, `modulemeta`, `get_search_list`,
+    ///   `get_prog_origin` and `get_jq_origin` fail with `Error::Policy`.
+    /// - `$ENV` is an empty object.
+    /// - Programs with `import` or `include` directives fail to compile with
+    ///   `Error::Policy`.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let sandbox = Compiler::new().sandbox(true);
+    /// let mut program = sandbox.compile("env.HOME").unwrap();
+    /// assert!(program.run("null").unwrap_err().is_policy_error());
+    ///
+    /// let mut program = sandbox.compile("$ENV").unwrap();
+    /// assert_eq!(program.run("null").unwrap(), "{}\n");
+    /// ```
+    pub fn sandbox(mut self, enabled: bool) -> Self {
+        self.overrides.sandbox = enabled;
+        self
+    }
+
+    /// Add a directory to search for modules imported by programs, like
+    /// `-L directory`.
+    ///
+    /// Unlike the jq cli, `~/.jq` and the directories next to the executable
+    /// aren't searched by default. jq does always search the current
+    /// directory, though, so use `allowed_modules()` when that matters.
+    /// `$ORIGIN/` at the start of a directory stands for the directory the
+    /// executable is in.
+    ///
+    /// Only modules written in jq (`.jq` files) and data (`.json` files,
+    /// imported as `$name`) can be loaded. libjq 1.6 has no support for
+    /// modules compiled to shared objects, so C plugins can't be imported;
+    /// the functions they provide can be called through `register_fn()`
+    /// instead.
+    pub fn library_path<P: Into<String>>(mut self, directory: P) -> Self {
+        self.library_paths.push(directory.into());
+        self
+    }
+
+    /// Allow programs to `import` and `include` modules. When disabled,
+    /// programs which do fail to compile with `Error::Policy`.
+    ///
+    /// Imports are allowed by default, except in the `sandbox()`.
+    pub fn allow_imports(mut self, enabled: bool) -> Self {
+        self.allowed_modules = if enabled { None } else { Some(vec![]) };
+        self
+    }
+
+    /// Only allow programs to import the modules named here, going by the
+    /// path in the `import` or `include` directive. Programs importing any
+    /// other module fail to compile with `Error::Policy`.
+    ///
+    /// This only applies to the directives in the program itself. Modules
+    /// can import whatever they like from the `library_path()`.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let compiler = Compiler::new().allowed_modules(vec!["formats/dates"]);
+    /// let res = compiler.compile(r#"import "secrets" as s; s::all"#);
+    /// assert!(res.err().unwrap().is_policy_error());
+    /// ```
+    pub fn allowed_modules<I, S>(mut self, modules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_modules = Some(modules.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Add definitions to the start of every program, such as helpers shared
+    /// by a team. They can `import` and `include` modules too, which are
+    /// trusted the same as modules imported from the `library_path()`, so
+    /// `allowed_modules()` and the `sandbox()` don't apply to them.
+    ///
+    /// The definitions are added to the first line of each program (after its
+    /// own directives), so line numbers in compile errors still match the
+    /// program as written. When the definitions themselves fail to compile,
+    /// the error says so. Calling this again adds more definitions after
+    /// the ones before.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let compiler = Compiler::new().prelude(
+    ///     r#"
+    ///     def trim: sub("^\\s+"; "") | sub("\\s+$"; ""); # both ends
+    ///     def names: map(.name | trim);
+    ///     "#,
+    /// );
+    ///
+    /// let mut program = compiler.compile(".name | trim").unwrap();
+    /// assert_eq!(program.run(r#"{"name": "  Mothra "}"#).unwrap(), "\"Mothra\"\n");
+    ///
+    /// let err = compiler.compile(".name |\n trim(").err().unwrap();
+    /// assert!(err.to_string().contains("line 2"));
+    /// ```
+    pub fn prelude<S: Into<String>>(mut self, definitions: S) -> Self {
+        let shared = &mut self.overrides.shared;
+        if !shared.is_empty() {
+            shared.push('\n');
+        }
+        shared.push_str(&definitions.into());
+        self
+    }
+
+    /// Stop the clock at `time`, so `now` always gives the same answer. This
+    /// keeps tests of programs which stamp the time from being flaky.
+    ///
+    /// The results of `localtime` and `strflocaltime` would still depend on
+    /// the time zone of the machine, so they're given in UTC instead, the
+    /// same as `gmtime` and `strftime`.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    /// use std::time::{Duration, UNIX_EPOCH};
+    ///
+    /// let mut program = Compiler::new()
+    ///     .fixed_time(UNIX_EPOCH + Duration::from_secs(1_500_000_000))
+    ///     .compile("now | todate")
+    ///     .unwrap();
+    /// assert_eq!(program.run("null").unwrap(), "\"2017-07-14T02:40:00Z\"\n");
+    /// ```
+    pub fn fixed_time(mut self, time: SystemTime) -> Self {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs_f64(),
+            Err(err) => -err.duration().as_secs_f64(),
+        };
+        self.overrides.fixed_time = Some(seconds);
+        self
+    }
+
+    /// Refuse inputs larger than `max` bytes with `Error::InputTooLarge`,
+    /// before parsing them. `JqProgram::set_max_input_bytes()` changes the
+    /// cap for a program once it's compiled.
+    ///
+    /// The cap covers each input handed to a run, such as each line for
+    /// `JqProgram::run_lines()`. The streaming functions count the whole of
+    /// what they read rather than each value in it, and fail once it goes
+    /// over, after running the program on the values before that point.
+    /// Inputs parsed ahead of time with `jq_rs::parse()` aren't counted.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let mut program = Compiler::new().max_input_bytes(16).compile(".").unwrap();
+    /// assert_eq!(program.run("[1, 2, 3]").unwrap(), "[1,2,3]\n");
+    /// assert!(program.run("[1, 2, 3, 4, 5, 6, 7]").unwrap_err().is_input_too_large());
+    /// ```
+    pub fn max_input_bytes(mut self, max: usize) -> Self {
+        self.limits.input_bytes = Some(max);
+        self
+    }
+
+    /// Fail runs which produce more than `max` outputs with
+    /// `Error::LimitExceeded`.
+    ///
+    /// Like the other limits, this applies to each run separately. When
+    /// streaming, each value read from the input is a run of its own.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let mut program = Compiler::new()
+    ///     .max_outputs(1000)
+    ///     .compile("[range(1e7)] | .[]")
+    ///     .unwrap();
+    /// assert!(program.run("null").unwrap_err().is_limit_exceeded());
+    /// ```
+    pub fn max_outputs(mut self, max: usize) -> Self {
+        self.limits.outputs = Some(max);
+        self
+    }
+
+    /// Fail runs once their rendered outputs add up to more than `max` bytes,
+    /// with `Error::LimitExceeded`.
+    ///
+    /// The limit is checked after each output is rendered, so the output
+    /// which goes over it has already been rendered (and when streaming,
+    /// written) by the time the run fails. Outputs which aren't
+    /// rendered as text, like those from `JqProgram::run_values()`, aren't
+    /// counted.
+    pub fn max_output_bytes(mut self, max: usize) -> Self {
+        self.limits.output_bytes = Some(max);
+        self
+    }
+
+    /// Choose what happens to NaN and the infinities in outputs, which jq's
+    /// arithmetic can produce (`nan`, `infinite`, `infinite - infinite`).
+    ///
+    /// By default they're left to jq, which writes NaN as `null` and the
+    /// infinities as `±1.7976931348623157e+308`, so the output stays valid
+    /// json but no longer says what the number was. The policy applies to
+    /// numbers anywhere in an output, before it's rendered or converted, so
+    /// `JqProgram::run_values()` and the like see the same values.
+    ///
+    /// ```rust
+    /// use jq_rs::{Compiler, NonFinite};
+    ///
+    /// let mut program = Compiler::new()
+    ///     .non_finite(NonFinite::Strings)
+    ///     .compile("[.[] * infinite, nan]")
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     program.run("[1, -1]").unwrap(),
+    ///     "[\"Infinity\",\"-Infinity\",\"NaN\"]\n"
+    /// );
+    /// ```
+    pub fn non_finite(mut self, policy: NonFinite) -> Self {
+        self.non_finite = policy;
+        self
+    }
+
+    /// Make a function implemented in Rust callable from programs as
+    /// `name`, taking `arity` arguments. It replaces any builtin (or other
+    /// registered function) with the same name and arity.
+    ///
+    /// The arguments are evaluated before the call, like the `$name`
+    /// parameters of a jq function, so when they produce several outputs
+    /// the function is called once for each combination. An `Err` is raised
+    /// as a jq error, which the program can catch with `try`.
+    ///
+    /// Requires the `serde_json` feature.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    /// use serde_json::{json, Value};
+    ///
+    /// let mut program = Compiler::new()
+    ///     .register_fn("lookup_user", 1, |args: &[Value]| match args[0].as_u64() {
+    ///         Some(1) => Ok(json!({"name": "Godzilla"})),
+    ///         _ => Err(format!("No user {}", args[0])),
+    ///     })
+    ///     .compile(r#".[] | try lookup_user(.id).name catch "unknown""#)
+    ///     .unwrap();
+    ///
+    /// let data = r#"[{"id": 1}, {"id": 2}]"#;
+    /// assert_eq!(program.run(data).unwrap(), "\"Godzilla\"\n\"unknown\"\n");
+    /// ```
+    ///
+    /// The function runs on the thread running the program, while the run is
+    /// paused. If it panics, the call fails and the panic carries on once
+    /// the run returns.
+    #[cfg(feature = "serde_json")]
+    pub fn register_fn<N, F>(mut self, name: N, arity: usize, function: F) -> Self
+    where
+        N: Into<String>,
+        F: Fn(&[serde_json::Value]) -> std::result::Result<serde_json::Value, String>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.functions
+            .register(name.into(), arity, json_function(function));
+        self
+    }
+
+    /// Compile programs against the args, environment, library paths and
+    /// functions in `scope`, replacing any scope set before.
+    ///
+    /// Those set on the `Compiler` itself go on top: args with the same name
+    /// replace the scope's, the compiler's library paths are searched after
+    /// the scope's, and its functions replace the scope's with the same
+    /// name and arity. See `Scope`.
+    pub fn scope(mut self, scope: Scope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Run programs in the C locale, whatever locale the process is in.
+    ///
+    /// Numbers are always read and written the same way: libjq parses and
+    /// formats them with its own code rather than the C library's, so a
+    /// locale with `,` as the decimal separator makes no difference to them.
+    /// The date builtins do go through the C library though, so under a
+    /// locale set with `setlocale()`, `strftime("%A")` gives the day's name
+    /// in that language and `strptime` expects it. With this set they work
+    /// like they would in the C locale.
+    ///
+    /// The locale is only switched for the thread running the program, and
+    /// only while it's running. It isn't switched on platforms without
+    /// `uselocale()`, such as Windows.
+    ///
+    /// Requires the `locale` feature.
+    #[cfg(feature = "locale")]
+    pub fn c_locale(mut self, enabled: bool) -> Self {
+        self.c_locale = enabled;
+        self
+    }
+
+    /// Report the numbers for each run of the programs compiled here to
+    /// `metrics`, replacing any set before. See `Metrics`.
+    ///
+    /// Pass an `Arc` to share the same metrics between compilers.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = SharedMetrics(Arc::new(metrics));
+        self
+    }
+
+    /// Run each input through `preprocessor` before it's parsed, replacing
+    /// any set before. See `PreProcessor`.
+    pub fn preprocessor<P: PreProcessor + 'static>(mut self, preprocessor: P) -> Self {
+        self.preprocessor = Some(SharedPreProcessor(Arc::new(preprocessor)));
+        self
+    }
+
+    /// Run each output through `postprocessor` once it's rendered, replacing
+    /// any set before. See `PostProcessor`.
+    pub fn postprocessor<P: PostProcessor + 'static>(mut self, postprocessor: P) -> Self {
+        self.postprocessor = Some(SharedPostProcessor(Arc::new(postprocessor)));
+        self
+    }
+
+    /// Compile a jq program with these options.
+    pub fn compile(&self, program: &str) -> Result<JqProgram> {
+        #[cfg(feature = "otel")]
+        let started = std::time::SystemTime::now();
+        let result = self.compile_program(program);
+        #[cfg(feature = "otel")]
+        crate::otel::record_compile(program, started, &result);
+        result
+    }
+
+    fn compile_program(&self, program: &str) -> Result<JqProgram> {
+        self.check_imports(program)?;
+        let scope = self.scope.settings();
+        let mut functions = scope.functions.clone();
+        functions.extend(&self.functions);
+        for (name, json) in scope.constants.iter().chain(&self.constants) {
+            let mut rendered = String::new();
+            Parser::new().parse(json)?.dump_into(&mut rendered);
+            functions.define_constant(name.clone(), rendered);
+        }
+        let mut overrides = self.overrides.clone();
+        overrides.env = scope.env.clone();
+        overrides.timezones = cfg!(feature = "tz");
+        let library_paths: Vec<String> = scope
+            .library_paths
+            .iter()
+            .chain(&self.library_paths)
+            .cloned()
+            .collect();
+        let compile = |program: &str| {
+            Jq::compile_with_args(
+                program,
+                self.named_args()?,
+                &overrides,
+                &library_paths,
+                &functions,
+            )
+        };
+        let mut jq = match compile(program) {
+            Ok(jq) => jq,
+            // Blame the prelude when it doesn't compile on its own.
+            Err(Error::InvalidProgram { reason }) if !self.overrides.shared.is_empty() => {
+                return Err(match compile(".") {
+                    Err(Error::InvalidProgram { reason }) => Error::InvalidProgram {
+                        reason: format!("the prelude failed to compile:\n{}", reason),
+                    },
+                    _ => Error::InvalidProgram { reason },
+                });
+            }
+            Err(err) => return Err(err),
+        };
+        jq.set_format(self.format());
+        jq.set_limits(self.limits);
+        jq.set_non_finite(self.non_finite);
+        jq.set_metrics(self.metrics.clone());
+        jq.set_preprocessor(self.preprocessor.clone());
+        #[cfg(feature = "locale")]
+        jq.set_c_locale(self.c_locale);
+        Ok(JqProgram {
+            jq,
+            output_capacity: 0,
+            source: program.to_string(),
+            key: self.key(),
+        })
+    }
+
+    fn key(&self) -> CompileKey {
+        let scope = self.scope.settings();
+        #[cfg(feature = "locale")]
+        let c_locale = self.c_locale;
+        #[cfg(not(feature = "locale"))]
+        let c_locale = false;
+        let settings: [&dyn fmt::Debug; 24] = [
+            &self.args,
+            &self.raw_output,
+            &self.join_output,
+            &self.indent,
+            &self.tab,
+            &self.sort_keys,
+            &self.ascii_output,
+            &self.color_output,
+            &self.color_palette,
+            &self.canonical_output,
+            &self.stable_output,
+            &self.html_output,
+            &self.overrides,
+            &self.limits,
+            &self.non_finite,
+            &self.library_paths,
+            &self.allowed_modules,
+            &self.constants,
+            &self.functions,
+            &scope.args,
+            &scope.env,
+            &scope.library_paths,
+            &scope.constants,
+            &scope.functions,
+        ];
+        CompileKey {
+            settings: format!("{:?} {:?}", settings, c_locale),
+            functions: scope
+                .functions
+                .addresses()
+                .chain(self.functions.addresses())
+                .collect(),
+            preprocessor: self
+                .preprocessor
+                .as_ref()
+                .map(|pre| &*pre.0 as *const _ as *const () as usize),
+            postprocessor: self
+                .postprocessor
+                .as_ref()
+                .map(|post| &*post.0 as *const _ as *const () as usize),
+        }
+    }
+
+    fn named_args(&self) -> Result<Vec<(String, JV)>> {
+        let args = &self.scope.settings().args;
+        let mut named = Vec::with_capacity(args.len() + self.args.len());
+        for (name, arg) in args.iter().chain(&self.args) {
+            let value = match arg {
+                Arg::String(value) => JV::string(value),
+                Arg::Json(json) => Parser::new().parse(json)?,
+            };
+            named.push((name.clone(), value));
+        }
+        Ok(named)
+    }
+
+    fn check_imports(&self, program: &str) -> Result<()> {
+        for module in imported_modules(program) {
+            if self.overrides.sandbox {
+                return Err(Error::Policy {
+                    reason: "Modules can't be imported in the sandbox".into(),
+                });
+            }
+            if let Some(allowed) = &self.allowed_modules {
+                if !allowed.contains(&module) {
+                    return Err(Error::Policy {
+                        reason: format!("Importing the module {:?} isn't allowed", module),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn format(&self) -> Format {
+        let mut dump_flags = 0;
+        if self.tab {
+            dump_flags |= jv_print_flags_JV_PRINT_TAB | jv_print_flags_JV_PRINT_PRETTY;
+        } else if self.indent > 0 {
+            dump_flags |= (u32::from(self.indent) << 8) | jv_print_flags_JV_PRINT_PRETTY;
+        }
+        if self.sort_keys {
+            dump_flags |= jv_print_flags_JV_PRINT_SORTED;
+        }
+        if self.ascii_output {
+            dump_flags |= jv_print_flags_JV_PRINT_ASCII;
+        }
+        if self.color_output {
+            dump_flags |= jv_print_flags_JV_PRINT_COLOR;
+        }
+        Format {
+            dump_flags: dump_flags as c_int,
+            raw: self.raw_output || self.join_output,
+            join: self.join_output,
+            canonical: self.canonical_output,
+            stable: self.stable_output,
+            html: self.html_output.clone().map(Arc::new),
+            palette: match &self.color_palette {
+                Some(palette) if self.color_output => Some(Arc::new(palette.clone())),
+                _ => None,
+            },
+            postprocessor: self.postprocessor.clone(),
+        }
+    }
+}
+
+/// Adapt a function on `serde_json` values to one on `JV`s.
+#[cfg(feature = "serde_json")]
+pub(crate) fn json_function<F>(function: F) -> crate::jq::Function
+where
+    F: Fn(&[serde_json::Value]) -> std::result::Result<serde_json::Value, String>
+        + Send
+        + Sync
+        + 'static,
+{
+    Arc::new(move |args: JV| match args.to_value() {
+        serde_json::Value::Array(args) => function(&args).map(|result| JV::from_value(&result)),
+        _ => unreachable!("Arguments are always passed as an array"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::Compiler;
+    use crate::{BatchError, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn args() {
+        let mut program = Compiler::new()
+            .arg("a", "text")
+            .arg_json("b", r#"{"c": [1]}"#)
+            .compile("[$a, $b.c[0], $ARGS.named.a]")
+            .unwrap();
+        assert_eq!(
+            program.run("null").unwrap(),
+            r#"["text",1,"text"]"#.to_owned() + "\n"
+        );
+
+        let mut program = crate::compile("$ARGS").unwrap();
+        assert_eq!(
+            program.run("null").unwrap(),
+            "{\"positional\":[],\"named\":{}}\n"
+        );
+    }
+
+    #[test]
+    fn vars() {
+        let mut program = Compiler::new()
+            .var("a")
+            .var("b")
+            .sandbox(true)
+            .prelude("def add_a: . + $a;")
+            .compile(".[] | [add_a, $b] | debug")
+            .unwrap();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let log = seen.clone();
+        program.set_debug_handler(move |value| log.lock().unwrap().push(value.to_string()));
+
+        assert_eq!(
+            program
+                .run_with_vars("[1, 2]", &[("a", "10"), ("b", r#"{"c": []}"#), ("d", "1")])
+                .unwrap(),
+            "[11,{\"c\":[]}]\n[12,{\"c\":[]}]\n"
+        );
+        // Values only last for the run they were given to.
+        assert_eq!(program.run("[1]").unwrap(), "[1,null]\n");
+        assert_eq!(seen.lock().unwrap().len(), 3);
+
+        assert_matches!(
+            program.run_with_vars("[1]", &[("a", "{")]),
+            Err(Error::Parse { .. })
+        );
+        assert_matches!(
+            Compiler::new().compile("$a").err(),
+            Some(Error::InvalidProgram { .. })
+        );
+    }
+
+    #[test]
+    fn constants() {
+        let compiler = Compiler::new()
+            .register_const("config", r#"{"a": [1, "\u0000\"\\(x)"]}"#)
+            .register_const("length", "-1.5e300")
+            .register_const("config", "2");
+        let mut program = compiler.compile("config, length").unwrap();
+        assert_eq!(program.run("null").unwrap(), "2\n-1.5e+300\n");
+
+        let mut program = compiler
+            .compile(r#"def config: 3; config, $ARGS.named"#)
+            .unwrap();
+        assert_eq!(program.run("null").unwrap(), "3\n{}\n");
+
+        let mut program = Compiler::new()
+            .register_const("config", r#"{"a": [1, "\u0000\"\\(x)"]}"#)
+            .compile("config")
+            .unwrap();
+        assert_eq!(
+            program.run("null").unwrap(),
+            r#"{"a":[1,"\u0000\"\\(x)"]}"#.to_owned() + "\n"
+        );
+
+        let res = Compiler::new().register_const("x", "{").compile("x");
+        assert_matches!(res.err(), Some(Error::Parse { .. }));
+    }
+
+    #[test]
+    fn invalid_args() {
+        let res = Compiler::new().arg_json("b", "{").compile("$b");
+        assert_matches!(res.err(), Some(Error::Parse { .. }));
+        let res = Compiler::new().compile("$missing");
+        assert_matches!(res.err(), Some(Error::InvalidProgram { .. }));
+    }
+
+    #[test]
+    fn raw_and_join() {
+        let data = r#"["a\u0000b", 1, {"c": "d"}]"#;
+        let mut raw = Compiler::new().raw_output(true).compile(".[]").unwrap();
+        assert_eq!(raw.run(data).unwrap(), "a\u{0}b\n1\n{\"c\":\"d\"}\n");
+        let mut join = Compiler::new().join_output(true).compile(".[]").unwrap();
+        assert_eq!(join.run(data).unwrap(), "a\u{0}b1{\"c\":\"d\"}");
+    }
+
+    #[test]
+    fn layout() {
+        let data = r#"{"b": [1], "a": "\u00e9"}"#;
+        let mut program = Compiler::new().indent(2).compile(".").unwrap();
+        assert_eq!(
+            program.run(data).unwrap(),
+            "{\n  \"b\": [\n    1\n  ],\n  \"a\": \"\u{e9}\"\n}\n"
+        );
+        let mut program = Compiler::new()
+            .indent(2)
+            .tab(true)
+            .sort_keys(true)
+            .ascii_output(true)
+            .compile(".")
+            .unwrap();
+        assert_eq!(
+            program.run(data).unwrap(),
+            "{\n\t\"a\": \"\\u00e9\",\n\t\"b\": [\n\t\t1\n\t]\n}\n"
+        );
+    }
+
+    #[test]
+    fn key_order() {
+        let data = r#"{"b": 1, "a": 2, "c": 3}"#;
+        let mut program = Compiler::new()
+            .compile(r#"del(.a) | .a = 4 | .["0"] = 5"#)
+            .unwrap();
+        assert_eq!(
+            program.run(data).unwrap(),
+            "{\"b\":1,\"c\":3,\"a\":4,\"0\":5}\n"
+        );
+        let mut program = Compiler::new()
+            .sort_keys(true)
+            .compile(r#"del(.a) | .a = 4 | .["0"] = 5"#)
+            .unwrap();
+        assert_eq!(
+            program.run(data).unwrap(),
+            "{\"0\":5,\"a\":4,\"b\":1,\"c\":3}\n"
+        );
+    }
+
+    #[test]
+    fn canonical_output() {
+        let mut program = Compiler::new()
+            .indent(2)
+            .sort_keys(true)
+            .ascii_output(true)
+            .canonical_output(true)
+            .compile(".[]")
+            .unwrap();
+        assert_eq!(
+            program.run(r#"[{"b": 1e-7, "a": "\u00e9"}, -0]"#).unwrap(),
+            "{\"a\":\"\u{e9}\",\"b\":1e-7}\n0\n"
+        );
+
+        let mut program = Compiler::new()
+            .canonical_output(true)
+            .raw_output(true)
+            .compile(".[]")
+            .unwrap();
+        assert_eq!(
+            program.run(r#"["a\"b", ["a\"b"]]"#).unwrap(),
+            "a\"b\n[\"a\\\"b\"]\n"
+        );
+    }
+
+    #[test]
+    fn sandbox() {
+        let sandbox = Compiler::new().sandbox(true);
+        for builtin in &[
+            "env",
+            "input_filename",
+            "\"a\" | modulemeta",
+            "get_jq_origin",
+        ] {
+            let mut program = sandbox.compile(builtin).unwrap();
+            match program.run("null") {
+                Err(Error::Policy { reason }) => assert!(reason.contains('`'), "{}", reason),
+                res => panic!("{}: {:?}", builtin, res),
+            }
+        }
+
+        // Errors can still be caught, but reveal nothing.
+        let mut program = sandbox
+            .compile("[try env catch .], $ENV, ([$ENV[]] | length)")
+            .unwrap();
+        assert_eq!(
+            program.run("null").unwrap(),
+            "[{\"jq-rs:policy\":\"env\"}]\n{}\n0\n"
+        );
+
+        // Definitions in the program (and `$__loc__`) work as usual.
+        let mut program = sandbox
+            .compile("module {};\ndef f: $__loc__.line; f, (. as [$a] | $a)")
+            .unwrap();
+        assert_eq!(program.run("[3]").unwrap(), "2\n3\n");
+
+        for program in &["import \"a\" as a; .", "# a\ninclude \"b\"; ."] {
+            assert_matches!(sandbox.compile(program).err(), Some(Error::Policy { .. }));
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let mut program = Compiler::new().max_outputs(2).compile(".[]").unwrap();
+        assert_eq!(program.run("[1, 2]").unwrap(), "1\n2\n");
+        assert_matches!(program.run("[1, 2, 3]"), Err(Error::LimitExceeded { .. }));
+
+        let mut program = Compiler::new().max_output_bytes(4).compile(".[]").unwrap();
+        assert_eq!(program.run("[1, 2]").unwrap(), "1\n2\n");
+        assert_matches!(
+            program.run(r#"[1, "abc"]"#),
+            Err(Error::LimitExceeded { .. })
+        );
+
+        // Each value streamed in is a run of its own, and anything written
+        // before the limit was hit stays written.
+        let mut program = Compiler::new().max_outputs(2).compile(".[]").unwrap();
+        let mut output = vec![];
+        let input = b"[1, 2] [3, 4, 5]";
+        let res = crate::stream::transform(&input[..], &mut program, &mut output);
+        assert_matches!(res, Err(Error::LimitExceeded { .. }));
+        assert_eq!(output, b"1\n2\n3\n4\n");
+    }
+
+    #[test]
+    fn input_limit() {
+        let compiler = Compiler::new().max_input_bytes(6);
+        let mut program = compiler.compile(".").unwrap();
+        assert_eq!(program.run("[1, 2]").unwrap(), "[1,2]\n");
+        assert_matches!(
+            program.run("[1, 2 ]"),
+            Err(Error::InputTooLarge { bytes: 7, max: 6 })
+        );
+
+        // Each line is an input of its own.
+        let results = program.run_lines("[1, 2]\n[1, 2, 3]\n");
+        assert_eq!(results[0].as_ref().unwrap(), "[1,2]\n");
+        assert_matches!(
+            &results[1],
+            Err(BatchError {
+                error: Error::InputTooLarge { .. },
+                ..
+            })
+        );
+
+        let mut pipeline = program.then(compiler.compile(".").unwrap());
+        assert_matches!(pipeline.run("[1, 2, 3]"), Err(Error::InputTooLarge { .. }));
+    }
+
+    #[test]
+    fn fixed_time() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let compiler = Compiler::new().fixed_time(UNIX_EPOCH + Duration::from_millis(86_400_500));
+        let mut program = compiler
+            .compile("now, (now | localtime | mktime), (now | strflocaltime(\"%F %T %Z\"))")
+            .unwrap();
+        assert_eq!(
+            program.run("null").unwrap(),
+            "86400.5\n86400\n\"1970-01-02 00:00:00 UTC\"\n"
+        );
+
+        // Before the epoch, and along with the sandbox.
+        let mut program = Compiler::new()
+            .fixed_time(UNIX_EPOCH - Duration::from_secs(60))
+            .sandbox(true)
+            .compile("now | todate")
+            .unwrap();
+        assert_eq!(program.run("null").unwrap(), "\"1969-12-31T23:59:00Z\"\n");
+    }
+
+    #[test]
+    fn prelude() {
+        let compiler = Compiler::new()
+            .arg("greeting", "Hello")
+            .prelude("def greet: \"\\($greeting), \\(.)\"; # to whoever\n")
+            .prelude("def shout:\n  ascii_upcase;");
+        let mut program = compiler.compile("greet | shout").unwrap();
+        assert_eq!(program.run("\"Mothra\"").unwrap(), "\"HELLO, MOTHRA\"\n");
+
+        // Line numbers in errors are the program's own.
+        let reason = match compiler.compile("greet |\n\n)") {
+            Err(Error::InvalidProgram { reason }) => reason,
+            res => panic!("{:?}", res.map(|_| ())),
+        };
+        assert!(reason.contains("line 3"), "{}", reason);
+        assert!(!reason.contains("prelude"), "{}", reason);
+
+        let reason = match Compiler::new().prelude("def f: 1 +;").compile(".") {
+            Err(Error::InvalidProgram { reason }) => reason,
+            res => panic!("{:?}", res.map(|_| ())),
+        };
+        assert!(reason.starts_with("the prelude failed to compile:\n"));
+        assert!(reason.contains("syntax error"), "{}", reason);
+    }
+
+    #[test]
+    fn imports() {
+        let dir = std::env::temp_dir().join(format!("jq-rs-imports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("kaiju.jq"), "def names: .[].name;").unwrap();
+        std::fs::write(dir.join("other.jq"), "def x: 1;").unwrap();
+        let compiler = Compiler::new().library_path(dir.to_str().unwrap());
+
+        let program = r#"import "kaiju" as k; [k::names]"#;
+        let mut imported = compiler.clone().compile(program).unwrap();
+        assert_eq!(
+            imported.run(r#"[{"name": "Rodan"}]"#).unwrap(),
+            "[\"Rodan\"]\n"
+        );
+
+        let allowlisted = compiler.clone().allowed_modules(vec!["kaiju"]);
+        assert!(allowlisted.compile(program).is_ok());
+        assert_matches!(
+            allowlisted.compile("include \"other\"; x").err(),
+            Some(Error::Policy { .. })
+        );
+        assert_matches!(
+            compiler.clone().allow_imports(false).compile(program).err(),
+            Some(Error::Policy { .. })
+        );
+        // The prelude is trusted to import what it likes.
+        let shared = compiler
+            .clone()
+            .allow_imports(false)
+            .prelude("import \"kaiju\" as k; def names: [k::names];");
+        let mut names = shared.compile("names").unwrap();
+        assert_eq!(
+            names.run(r#"[{"name": "Rodan"}]"#).unwrap(),
+            "[\"Rodan\"]\n"
+        );
+        assert_matches!(
+            shared.compile("include \"other\"; x").err(),
+            Some(Error::Policy { .. })
+        );
+
+        assert_matches!(
+            compiler
+                .sandbox(true)
+                .allowed_modules(vec!["kaiju"])
+                .compile(program)
+                .err(),
+            Some(Error::Policy { .. })
+        );
+
+        // Without a library path, there's nothing to import.
+        assert_matches!(
+            Compiler::new().compile(program).err(),
+            Some(Error::InvalidProgram { .. })
+        );
+        assert_matches!(
+            Compiler::new()
+                .library_path("$ORIGIN/missing")
+                .compile(program)
+                .err(),
+            Some(Error::InvalidProgram { .. })
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn functions() {
+        use serde_json::{json, Value};
+
+        let compiler = Compiler::new()
+            .register_fn("answer", 0, |_: &[Value]| Ok(json!(42)))
+            .register_fn("add", 2, |args: &[Value]| {
+                match (args[0].as_f64(), args[1].as_f64()) {
+                    (Some(a), Some(b)) => Ok(json!(a + b)),
+                    _ => Err("Can only add numbers".to_string()),
+                }
+            })
+            .register_fn("length", 0, |_: &[Value]| Ok(json!("replaced")))
+            .register_fn("explode", 1, |_: &[Value]| panic!("boom"));
+
+        let mut program = compiler
+            .compile(r#"answer, add(1, 2; 10), length, ([1] | length)"#)
+            .unwrap();
+        assert_eq!(
+            program.run("null").unwrap(),
+            "42\n11\n12\n\"replaced\"\n\"replaced\"\n"
+        );
+
+        let mut program = compiler
+            .compile(r#"try add("a"; 1) catch ., add("a"; 1)"#)
+            .unwrap();
+        match program.run("null") {
+            Err(Error::Exec { reason, .. }) => assert!(reason.ends_with("Can only add numbers")),
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut program = compiler.compile(r#"try add("a"; 1) catch ."#).unwrap();
+        assert_eq!(program.run("null").unwrap(), "\"Can only add numbers\"\n");
+
+        // `input` carries on working as if there are no more inputs, and the
+        // sandbox doesn't stand in the way.
+        let mut program = compiler
+            .clone()
+            .sandbox(true)
+            .compile(r#"answer, (try input catch .), explode(1)?, answer"#)
+            .unwrap();
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| program.run("null")));
+        assert_eq!(res.unwrap_err().downcast_ref::<&str>(), Some(&"boom"));
+        let mut program = compiler
+            .sandbox(true)
+            .compile(r#"answer, (try input catch .)"#)
+            .unwrap();
+        assert_eq!(program.run("null").unwrap(), "42\n\"No more inputs\"\n");
+    }
+}
@@ -0,0 +1,164 @@
+use crate::cancel::Interrupt;
+use crate::errors::{Error, Result};
+use crate::jq::JV;
+use crate::JqProgram;
+
+/// How `JqProgram::run_csv()` reads its input.
+///
+/// The first row is always a header, naming the keys of the objects built
+/// from the rows after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CsvOptions {
+    delimiter: u8,
+    per_row: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            per_row: false,
+        }
+    }
+}
+
+impl CsvOptions {
+    /// Comma separated values, with every row collected into one array.
+    pub fn csv() -> Self {
+        Self::default()
+    }
+
+    /// Tab separated values, with every row collected into one array.
+    pub fn tsv() -> Self {
+        Self::default().delimiter(b'\t')
+    }
+
+    /// Separate fields with this byte instead.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Run the program once for each row, rather than once on an array of
+    /// every row. This keeps memory use down for large inputs.
+    pub fn per_row(mut self, enabled: bool) -> Self {
+        self.per_row = enabled;
+        self
+    }
+}
+
+impl JqProgram {
+    /// Runs the program on delimited text with a header row, such as CSV or
+    /// TSV, rendering the outputs as json.
+    ///
+    /// Each row becomes an object keyed by the headers. Every field is a
+    /// string, since CSV has no types, so use `tonumber` in the program where
+    /// needed. By default the program runs once on an array of all the rows;
+    /// see `CsvOptions::per_row()` to run it on each row instead.
+    ///
+    /// Requires the `csv` feature.
+    ///
+    /// ```rust
+    /// use jq_rs::CsvOptions;
+    ///
+    /// let data = "name,height\nGodzilla,50\nMothra,0.5\n";
+    /// let mut program = jq_rs::compile("map(.height | tonumber) | add").unwrap();
+    /// assert_eq!(program.run_csv(data, CsvOptions::csv()).unwrap(), "50.5\n");
+    ///
+    /// let mut program = jq_rs::compile(".name").unwrap();
+    /// let options = CsvOptions::tsv().per_row(true);
+    /// let data = "name\theight\nGodzilla\t50\nMothra\t0.5\n";
+    /// assert_eq!(program.run_csv(data, options).unwrap(), "\"Godzilla\"\n\"Mothra\"\n");
+    /// ```
+    pub fn run_csv(&mut self, data: &str, options: CsvOptions) -> Result<String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(options.delimiter)
+            .from_reader(data.as_bytes());
+        let headers: Vec<JV> = reader
+            .headers()
+            .map_err(parse_error)?
+            .iter()
+            .map(JV::string)
+            .collect();
+
+        let mut buf = String::with_capacity(self.output_capacity);
+        let mut rows = JV::array();
+        for record in reader.records() {
+            let record = record.map_err(parse_error)?;
+            let row = headers
+                .iter()
+                .zip(record.iter())
+                .fold(JV::object(), |row, (header, field)| {
+                    row.object_set(header.clone(), JV::string(field))
+                });
+            if options.per_row {
+                self.jq
+                    .execute_parsed(&row, &Interrupt::default(), &mut buf)?;
+            } else {
+                rows = rows.array_append(row);
+            }
+        }
+        if !options.per_row {
+            self.jq
+                .execute_parsed(&rows, &Interrupt::default(), &mut buf)?;
+        }
+        Ok(buf)
+    }
+}
+
+/// Report a CSV problem the same way as a json one from jq.
+fn parse_error(err: csv::Error) -> Error {
+    let (line, offset) = err
+        .position()
+        .map_or((0, 0), |pos| (pos.line() as usize, pos.byte() as usize));
+    Error::Parse {
+        reason: match err.kind() {
+            csv::ErrorKind::UnequalLengths {
+                expected_len, len, ..
+            } => format!(
+                "Row has {} fields, but the header has {}",
+                len, expected_len
+            ),
+            _ => err.to_string(),
+        },
+        line,
+        column: 0,
+        offset,
+        excerpt: String::new(),
+        caret: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CsvOptions;
+    use crate::{compile, Error};
+
+    #[test]
+    fn quoting_and_delimiters() {
+        let data = "a,b\n\"x, y\",\"say \"\"hi\"\"\"\n,\n";
+        let mut program = compile(".").unwrap();
+        assert_eq!(
+            program.run_csv(data, CsvOptions::csv()).unwrap(),
+            "[{\"a\":\"x, y\",\"b\":\"say \\\"hi\\\"\"},{\"a\":\"\",\"b\":\"\"}]\n"
+        );
+        let options = CsvOptions::csv().delimiter(b';').per_row(true);
+        assert_eq!(
+            program.run_csv("a;b\n1;2\n", options).unwrap(),
+            "{\"a\":\"1\",\"b\":\"2\"}\n"
+        );
+        assert_eq!(program.run_csv("a,b\n", CsvOptions::csv()).unwrap(), "[]\n");
+    }
+
+    #[test]
+    fn ragged_rows() {
+        let mut program = compile(".").unwrap();
+        match program.run_csv("a,b\n1,2\n3\n", CsvOptions::csv()) {
+            Err(Error::Parse { reason, line, .. }) => {
+                assert_eq!(reason, "Row has 1 fields, but the header has 2");
+                assert_eq!(line, 3);
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
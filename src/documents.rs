@@ -0,0 +1,415 @@
+use crate::cancel::Interrupt;
+use crate::errors::{BatchError, Error};
+use crate::jq::Parser;
+use crate::JqProgram;
+use std::ops::Range;
+use std::result;
+
+/// The outputs from one json document of several, from
+/// `JqProgram::run_documents()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DocumentOutput {
+    /// The position of the document in the input, starting from `0`. This is
+    /// also the position of this output in the results.
+    pub index: usize,
+    /// The line the document starts on, starting from `1`.
+    pub line: usize,
+    /// The rendered outputs.
+    pub output: String,
+}
+
+impl JqProgram {
+    /// Runs the program on each json document in `data`, which can hold any
+    /// number of them (pretty printed or not), giving back the outputs for
+    /// each along with the line it started on.
+    ///
+    /// As with `run_batch()`, a document which fails to run doesn't stop the
+    /// rest, and the `BatchError` says which document and line it was. Input
+    /// which fails to parse can't be read past, so it ends the results with
+    /// an `Error::Parse`. `data` counts as a single input for
+    /// `Compiler::max_input_bytes()`, and when it's too large the only result
+    /// is an `Error::InputTooLarge`.
+    ///
+    /// ```rust
+    /// let data = "{\"level\": \"info\"}\n{\n  \"level\": 3\n}\n{\"level\": \"warn\"}\n";
+    /// let mut program = jq_rs::compile(r#""level: " + .level"#).unwrap();
+    /// let results = program.run_documents(data);
+    ///
+    /// assert_eq!(results[0].as_ref().unwrap().output, "\"level: info\"\n");
+    /// assert_eq!(results[1].as_ref().unwrap_err().line, Some(2));
+    /// assert_eq!(results[2].as_ref().unwrap().line, 5);
+    /// ```
+    pub fn run_documents(&mut self, data: &str) -> Vec<result::Result<DocumentOutput, BatchError>> {
+        if let Err(error) = self.jq.check_input_bytes(data.len()) {
+            return vec![Err(BatchError {
+                index: 0,
+                line: None,
+                error,
+            })];
+        }
+        let mut starts = DocumentStarts::new(data);
+        let mut results = vec![];
+        let interrupt = Interrupt::default();
+        let parsed = Parser::new().feed(data.as_bytes(), true, |value| {
+            let index = results.len();
+            let line = starts.next().map_or(0, |document| document.line);
+            let mut output = String::with_capacity(self.output_capacity);
+            let result = match self.jq.execute_parsed(&value, &interrupt, &mut output) {
+                Ok(()) => Ok(DocumentOutput {
+                    index,
+                    line,
+                    output,
+                }),
+                Err(error) => Err(BatchError {
+                    index,
+                    line: Some(line),
+                    error,
+                }),
+            };
+            results.push(result);
+            Ok(())
+        });
+        if let Err(error) = parsed {
+            let line = match (starts.next(), &error) {
+                (Some(document), _) => document.line,
+                // Nothing but the end of the input was left to read.
+                (None, Error::Parse { line, .. }) => *line,
+                (None, _) => 0,
+            };
+            results.push(Err(BatchError {
+                index: results.len(),
+                line: Some(line),
+                error,
+            }));
+        }
+        results
+    }
+
+    /// Like `run_documents()`, but a document which fails to parse is
+    /// skipped rather than ending the results, for input where a few
+    /// documents are bound to be mangled, such as log files.
+    ///
+    /// The documents are found by matching up brackets and quotes, which
+    /// splits the input the same way jq does when it's all valid json. A
+    /// document which doesn't parse gets an `Error::Parse` in its place in the
+    /// results, whose `line`, `column` and `offset` are for the whole of
+    /// `data`, and the next document carries on after it. A stray closing
+    /// bracket counts as a document of its own. For newline delimited json,
+    /// `run_lines()` resumes at the next line instead.
+    ///
+    /// ```rust
+    /// let data = "{\"level\": \"info\"}\n{\"level\": nope}\n{\n  \"level\": \"warn\"\n}\n";
+    /// let mut program = jq_rs::compile(".level").unwrap();
+    /// let results = program.run_documents_lenient(data);
+    ///
+    /// assert_eq!(results.len(), 3);
+    /// assert!(results[1].as_ref().unwrap_err().error.is_parse_error());
+    /// assert_eq!(results[2].as_ref().unwrap().output, "\"warn\"\n");
+    /// ```
+    pub fn run_documents_lenient(
+        &mut self,
+        data: &str,
+    ) -> Vec<result::Result<DocumentOutput, BatchError>> {
+        if let Err(error) = self.jq.check_input_bytes(data.len()) {
+            return vec![Err(BatchError {
+                index: 0,
+                line: None,
+                error,
+            })];
+        }
+        let interrupt = Interrupt::default();
+        DocumentStarts::new(data)
+            .enumerate()
+            .map(|(index, document)| {
+                let mut output = String::with_capacity(self.output_capacity);
+                let text = &data[document.range.clone()];
+                match self.jq.execute(text, &interrupt, &mut output) {
+                    Ok(()) => Ok(DocumentOutput {
+                        index,
+                        line: document.line,
+                        output,
+                    }),
+                    Err(error) => Err(BatchError {
+                        index,
+                        line: Some(document.line),
+                        error: document.locate(error),
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Where a json document in some text is.
+struct Document {
+    /// The line it starts on, from `1`.
+    line: usize,
+    /// How many bytes into that line it starts.
+    column: usize,
+    range: Range<usize>,
+}
+
+impl Document {
+    /// Move the location of a parse error in the document's own text to
+    /// where it is in the whole text.
+    fn locate(&self, error: Error) -> Error {
+        match error {
+            Error::Parse {
+                reason,
+                line,
+                column,
+                offset,
+                excerpt,
+                caret,
+            } => Error::Parse {
+                reason,
+                column: if line == 1 {
+                    column + self.column
+                } else {
+                    column
+                },
+                line: line + self.line - 1,
+                offset: offset + self.range.start,
+                excerpt,
+                caret,
+            },
+            error => error,
+        }
+    }
+}
+
+/// Each json document in some text, found by skipping over strings and
+/// matching brackets.
+///
+/// jq's parser doesn't say where the values it reads came from, so this
+/// follows along separately. It agrees with jq about where each document
+/// starts and ends for any input jq can parse.
+struct DocumentStarts<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// The line `pos` is on.
+    line: usize,
+    /// Where that line starts.
+    line_start: usize,
+}
+
+impl<'a> DocumentStarts<'a> {
+    fn new(text: &'a str) -> Self {
+        let bytes = text.as_bytes();
+        // jq skips a byte order mark at the start of its input.
+        let pos = if bytes.starts_with(b"\xef\xbb\xbf") {
+            3
+        } else {
+            0
+        };
+        DocumentStarts {
+            bytes,
+            pos,
+            line: 1,
+            line_start: 0,
+        }
+    }
+
+    /// Move past the byte at `pos`.
+    fn bump(&mut self) {
+        if self.bytes[self.pos] == b'\n' {
+            self.line += 1;
+            self.line_start = self.pos + 1;
+        }
+        self.pos += 1;
+    }
+
+    /// Move past the string starting at `pos`.
+    fn skip_string(&mut self) {
+        self.bump();
+        while self.pos < self.bytes.len() {
+            match self.bytes[self.pos] {
+                b'"' => return self.bump(),
+                b'\\' if self.pos + 1 < self.bytes.len() => {
+                    self.bump();
+                    self.bump();
+                }
+                _ => self.bump(),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for DocumentStarts<'a> {
+    type Item = Document;
+
+    fn next(&mut self) -> Option<Document> {
+        while self.pos < self.bytes.len() && is_space(self.bytes[self.pos]) {
+            self.bump();
+        }
+        if self.pos == self.bytes.len() {
+            return None;
+        }
+        let line = self.line;
+        let column = self.pos - self.line_start;
+        let start = self.pos;
+        match self.bytes[self.pos] {
+            b'"' => self.skip_string(),
+            b'[' | b'{' => {
+                let mut depth = 0;
+                while self.pos < self.bytes.len() {
+                    match self.bytes[self.pos] {
+                        b'"' => {
+                            self.skip_string();
+                            continue;
+                        }
+                        b'[' | b'{' => depth += 1,
+                        b']' | b'}' => depth -= 1,
+                        _ => {}
+                    }
+                    self.bump();
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            // Never the start of a valid document, but it has to be got past.
+            b']' | b'}' => self.bump(),
+            // Numbers, `true` and so on run up to the next space or bracket.
+            _ => {
+                while self.pos < self.bytes.len()
+                    && !is_space(self.bytes[self.pos])
+                    && !b"[]{}\"".contains(&self.bytes[self.pos])
+                {
+                    self.bump();
+                }
+            }
+        }
+        Some(Document {
+            line,
+            column,
+            range: start..self.pos,
+        })
+    }
+}
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DocumentOutput, DocumentStarts};
+    use crate::{compile, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn starts() {
+        let starts = |text: &str| {
+            DocumentStarts::new(text)
+                .map(|document| document.line)
+                .collect::<Vec<_>>()
+        };
+        assert!(starts("").is_empty());
+        assert!(starts(" \n\t\r\n").is_empty());
+        assert_eq!(starts("1 2\n3"), vec![1, 1, 2]);
+        assert_eq!(starts("[1][2]{}\"a\"\"b\"true"), vec![1; 6]);
+        assert_eq!(
+            starts("\u{feff}\n{\"a\": \"]}\\\"\\n\",\n \"b\": [\n[]]}\n\n-1.5e3 null\"\"\n"),
+            vec![2, 6, 6, 6]
+        );
+    }
+
+    #[test]
+    fn run_documents() {
+        let mut program = compile(".a[]").unwrap();
+        let results = program.run_documents("{\"a\": [1, 2]}\n\n{\n\"a\": 3\n}  {\"a\": []}\n");
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].as_ref().unwrap(),
+            &DocumentOutput {
+                index: 0,
+                line: 1,
+                output: "1\n2\n".into()
+            }
+        );
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!((err.index, err.line), (1, Some(3)));
+        assert_matches!(err.error, Error::Exec { .. });
+        assert_eq!(results[2].as_ref().unwrap().line, 5);
+        assert_eq!(results[2].as_ref().unwrap().output, "");
+
+        assert!(program.run_documents(" \n").is_empty());
+    }
+
+    #[test]
+    fn parse_errors_end_the_results() {
+        let mut program = compile(".").unwrap();
+        let results = program.run_documents("1\n\n{\"a\":\n  nope}\n2\n");
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().output, "1\n");
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!((err.index, err.line), (1, Some(3)));
+        assert_matches!(err.error, Error::Parse { line: 4, .. });
+
+        let results = program.run_documents("[1,\n2");
+        let err = results[0].as_ref().unwrap_err();
+        assert_eq!(err.line, Some(1));
+        assert!(err.error.is_parse_error());
+    }
+
+    #[test]
+    fn lenient() {
+        let mut program = compile(".").unwrap();
+        let data = "1 {\"a\":\n  nope} [2]\n} \"\u{e9}\" [3,\n";
+        let results = program.run_documents_lenient(data);
+        let outputs: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().map(|output| output.output.as_str()))
+            .collect();
+        assert_eq!(outputs.len(), 6);
+        assert_eq!(outputs[0].unwrap(), "1\n");
+        assert_eq!(outputs[2].unwrap(), "[2]\n");
+        assert_eq!(outputs[4].unwrap(), "\"\u{e9}\"\n");
+        for idx in &[1, 3, 5] {
+            assert!(results[*idx].as_ref().unwrap_err().error.is_parse_error());
+        }
+
+        // Errors point at the spot in the whole input, like `run_documents()`.
+        let strict = program.run_documents(data);
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!((err.index, err.line), (1, Some(1)));
+        match (&err.error, &strict[1].as_ref().unwrap_err().error) {
+            (
+                Error::Parse {
+                    line,
+                    column,
+                    offset,
+                    ..
+                },
+                Error::Parse {
+                    line: strict_line,
+                    column: strict_column,
+                    offset: strict_offset,
+                    ..
+                },
+            ) => assert_eq!(
+                (line, column, offset),
+                (strict_line, strict_column, strict_offset)
+            ),
+            _ => unreachable!(),
+        }
+        assert_eq!(results[3].as_ref().unwrap_err().line, Some(3));
+
+        // Valid input gives the same results either way.
+        let data = "{\"a\": [1, 2]}\n\n{\n\"a\": 3\n}  {\"a\": []}\n";
+        let mut program = compile(".a[]").unwrap();
+        let lenient = program.run_documents_lenient(data);
+        let strict = program.run_documents(data);
+        assert_eq!(lenient.len(), strict.len());
+        for (lenient, strict) in lenient.iter().zip(&strict) {
+            match (lenient, strict) {
+                (Ok(lenient), Ok(strict)) => assert_eq!(lenient, strict),
+                (Err(lenient), Err(strict)) => {
+                    assert_eq!((lenient.index, lenient.line), (strict.index, strict.line))
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
@@ -1,10 +1,23 @@
 use std::error;
-use std::fmt;
+use std::fmt::{self, Write as _};
 use std::result;
 
 const ERR_UNKNOWN: &str = "JQ: Unknown error";
 const ERR_COMPILE: &str = "JQ: Program failed to compile";
 const ERR_STRING_CONV: &str = "JQ: Failed to convert string";
+const ERR_CANCELLED: &str = "JQ: Program run was cancelled";
+const ERR_TIMEOUT: &str = "JQ: Program run timed out";
+const ERR_PARSE: &str = "JQ: Parse error";
+const ERR_HALTED: &str = "JQ: Program halted";
+const ERR_IO: &str = "JQ: Failed to read input or write output";
+const ERR_POLICY: &str = "JQ: Not allowed";
+const ERR_LIMIT: &str = "JQ: Limit exceeded";
+const ERR_INTERRUPTED: &str = "JQ: Program run was interrupted";
+const ERR_PATH: &str = "JQ: Invalid path";
+const ERR_COLORS: &str = "JQ: Invalid colors";
+const ERR_UNKNOWN_FILTER: &str = "JQ: No filter by that name";
+const ERR_INPUT_TOO_LARGE: &str = "JQ: Input too large";
+const ERR_UNSUPPORTED_VERSION: &str = "JQ: Unsupported version of libjq";
 
 /// This is the common Result type for the crate. Fallible operations will
 /// return this.
@@ -12,16 +25,56 @@ pub type Result<T> = result::Result<T, Error>;
 
 /// There are many potential causes for failure when running jq programs.
 /// This enum attempts to unify them all under a single type.
+///
+/// New variants may be added in minor releases, so matches need a wildcard
+/// arm. `Error::kind()` and the `is_*()` helpers cover the common checks.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// The jq program failed to compile.
     InvalidProgram {
         /// JQ's explanation of the compilation error
         reason: String,
     },
-    /// System errors are raised by the internal jq state machine. These can
-    /// indicate problems parsing input, or even failures while initializing
-    /// the state machine itself.
+    /// The json input couldn't be parsed.
+    Parse {
+        /// jq's explanation of what went wrong.
+        reason: String,
+        /// The line jq was reading when it found the problem, starting from
+        /// `1`.
+        line: usize,
+        /// How many bytes into the line jq was when it found the problem.
+        ///
+        /// jq only notices some problems once it reads past them, for
+        /// example an invalid literal is reported at the byte following it.
+        column: usize,
+        /// The byte offset into the input of the same spot as `line` and
+        /// `column`.
+        offset: usize,
+        /// A few dozen bytes of the input around `offset`, from the same line.
+        ///
+        /// Empty when jq didn't say where the problem was.
+        excerpt: String,
+        /// How many characters into `excerpt` the spot at `offset` is.
+        caret: usize,
+    },
+    /// The program failed while running, for example with `error("...")` or
+    /// by indexing a value of the wrong type. Also used when the jq state
+    /// machine can't be set up to run the program at all.
+    Exec {
+        /// Feedback from jq about what went wrong.
+        reason: String,
+        /// The value the program passed to `error`, as json, when it wasn't a
+        /// string. Strings are only included in `reason`.
+        ///
+        /// With the `serde_json` feature, `Error::payload_value()` gives this
+        /// back as a `serde_json::Value`.
+        payload: Option<String>,
+    },
+    /// System errors used to cover both input which failed to parse and
+    /// programs which failed while running. Those are now reported as
+    /// `Error::Parse` and `Error::Exec`, and this is no longer returned.
+    #[deprecated(note = "use `Error::Parse` or `Error::Exec`")]
     System {
         /// Feedback from jq about what went wrong, when available.
         reason: Option<String>,
@@ -32,21 +85,329 @@ pub enum Error {
         /// The original error which lead to this.
         err: Box<dyn error::Error + 'static>,
     },
+    /// The program stopped itself with a non-zero exit code, usually via
+    /// `halt_error`.
+    Halted {
+        /// The exit code given to `halt_error` (`5` when none was given).
+        exit_code: i32,
+        /// The value passed to `halt_error`, as the jq cli would print it:
+        /// strings as they are, other values rendered as json. `None` when
+        /// the value was `null`.
+        payload: Option<String>,
+    },
+    /// Reading input or writing output failed while streaming.
+    Io {
+        /// The original error which lead to this.
+        err: std::io::Error,
+    },
+    /// The program tried to do something its compile options don't allow,
+    /// like reading the environment when sandboxed. See `Compiler::sandbox()`.
+    Policy {
+        /// What the program wasn't allowed to do.
+        reason: String,
+    },
+    /// The run went over one of the limits set with `Compiler`, such as
+    /// `Compiler::max_outputs()`.
+    LimitExceeded {
+        /// Which limit was exceeded.
+        reason: String,
+    },
+    /// The run was stopped part way through evaluation via an
+    /// `InterruptHandle`.
+    Interrupted,
+    /// A path couldn't be read or written in the form asked for. See
+    /// `JqPath`.
+    InvalidPath {
+        /// What was wrong with the path.
+        reason: String,
+    },
+    /// Colors in the format of `JQ_COLORS` couldn't be read. See
+    /// `ColorPalette`.
+    InvalidColors {
+        /// What was wrong with the colors.
+        reason: String,
+    },
+    /// There was no program stored under a name. See `FilterRegistry`.
+    UnknownFilter {
+        /// The name which was asked for.
+        name: String,
+    },
+    /// The run was stopped via a `CancellationToken`.
+    Cancelled,
+    /// The run didn't finish before its deadline.
+    Timeout,
+    /// The input was larger than the cap set with
+    /// `Compiler::max_input_bytes()`, so the program wasn't run on it.
+    InputTooLarge {
+        /// The size of the input in bytes. When streaming, this is how much
+        /// had been read when it went over.
+        bytes: usize,
+        /// The most bytes allowed.
+        max: usize,
+    },
+    /// The linked `libjq` is from a release of jq this crate doesn't work
+    /// with. See `jq_rs::ensure_compatible()`.
+    UnsupportedVersion {
+        /// The release it's from, as reported by `jq_rs::version()`.
+        version: String,
+    },
     /// Something bad happened, but it was unexpected.
     Unknown,
 }
 
 unsafe impl Send for Error {}
 
+/// The broad category of an `Error`, for branching on without needing to
+/// match the variants (and their fields) directly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The program failed to compile. See `Error::InvalidProgram`.
+    Compile,
+    /// The input couldn't be parsed. See `Error::Parse`.
+    Parse,
+    /// The program failed while running. See `Error::Exec`.
+    Exec,
+    /// A string couldn't be converted. See `Error::StringConvert`.
+    StringConvert,
+    /// The program halted with a non-zero exit code. See `Error::Halted`.
+    Halted,
+    /// Reading input or writing output failed. See `Error::Io`.
+    Io,
+    /// The program did something it isn't allowed to. See `Error::Policy`.
+    Policy,
+    /// The run went over a limit. See `Error::LimitExceeded`.
+    LimitExceeded,
+    /// The run was interrupted. See `Error::Interrupted`.
+    Interrupted,
+    /// A path was invalid. See `Error::InvalidPath`.
+    InvalidPath,
+    /// Colors were invalid. See `Error::InvalidColors`.
+    InvalidColors,
+    /// A filter name was unknown. See `Error::UnknownFilter`.
+    UnknownFilter,
+    /// The run was cancelled. See `Error::Cancelled`.
+    Cancelled,
+    /// The run timed out. See `Error::Timeout`.
+    Timeout,
+    /// The input was too large. See `Error::InputTooLarge`.
+    InputTooLarge,
+    /// The linked `libjq` isn't supported. See `Error::UnsupportedVersion`.
+    UnsupportedVersion,
+    /// See `Error::Unknown`.
+    Unknown,
+}
+
+impl ErrorKind {
+    /// A number identifying the kind of error, for logs and metrics.
+    ///
+    /// These won't change between releases, and new kinds get new numbers.
+    pub fn code(self) -> u32 {
+        match self {
+            ErrorKind::Unknown => 0,
+            ErrorKind::Compile => 1,
+            ErrorKind::Parse => 2,
+            ErrorKind::Exec => 3,
+            ErrorKind::StringConvert => 4,
+            ErrorKind::Halted => 5,
+            ErrorKind::Io => 6,
+            ErrorKind::Cancelled => 7,
+            ErrorKind::Timeout => 8,
+            ErrorKind::Policy => 9,
+            ErrorKind::LimitExceeded => 10,
+            ErrorKind::Interrupted => 11,
+            ErrorKind::InvalidPath => 12,
+            ErrorKind::InvalidColors => 13,
+            ErrorKind::UnknownFilter => 14,
+            ErrorKind::InputTooLarge => 15,
+            ErrorKind::UnsupportedVersion => 16,
+        }
+    }
+}
+
+// `System` still has to be handled until it is removed.
+#[allow(deprecated)]
+impl Error {
+    /// The category this error falls into.
+    ///
+    /// ```rust
+    /// use jq_rs::ErrorKind;
+    ///
+    /// let err = jq_rs::run(".", "{").unwrap_err();
+    /// assert_eq!(err.kind(), ErrorKind::Parse);
+    /// assert_eq!(err.kind().code(), 2);
+    /// ```
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::InvalidProgram { .. } => ErrorKind::Compile,
+            Error::Parse { .. } => ErrorKind::Parse,
+            Error::Exec { .. } | Error::System { .. } => ErrorKind::Exec,
+            Error::StringConvert { .. } => ErrorKind::StringConvert,
+            Error::Halted { .. } => ErrorKind::Halted,
+            Error::Io { .. } => ErrorKind::Io,
+            Error::Policy { .. } => ErrorKind::Policy,
+            Error::LimitExceeded { .. } => ErrorKind::LimitExceeded,
+            Error::Interrupted => ErrorKind::Interrupted,
+            Error::InvalidPath { .. } => ErrorKind::InvalidPath,
+            Error::InvalidColors { .. } => ErrorKind::InvalidColors,
+            Error::UnknownFilter { .. } => ErrorKind::UnknownFilter,
+            Error::Cancelled => ErrorKind::Cancelled,
+            Error::Timeout => ErrorKind::Timeout,
+            Error::InputTooLarge { .. } => ErrorKind::InputTooLarge,
+            Error::UnsupportedVersion { .. } => ErrorKind::UnsupportedVersion,
+            Error::Unknown => ErrorKind::Unknown,
+        }
+    }
+
+    /// Shorthand for `self.kind().code()`.
+    pub fn code(&self) -> u32 {
+        self.kind().code()
+    }
+
+    /// True when the program failed to compile.
+    pub fn is_compile_error(&self) -> bool {
+        self.kind() == ErrorKind::Compile
+    }
+
+    /// True when the input couldn't be parsed.
+    pub fn is_parse_error(&self) -> bool {
+        self.kind() == ErrorKind::Parse
+    }
+
+    /// True when the program failed while running.
+    pub fn is_exec_error(&self) -> bool {
+        self.kind() == ErrorKind::Exec
+    }
+
+    /// True when the program halted with a non-zero exit code.
+    pub fn is_halted(&self) -> bool {
+        self.kind() == ErrorKind::Halted
+    }
+
+    /// True when reading input or writing output failed.
+    pub fn is_io_error(&self) -> bool {
+        self.kind() == ErrorKind::Io
+    }
+
+    /// True when the program did something it isn't allowed to.
+    pub fn is_policy_error(&self) -> bool {
+        self.kind() == ErrorKind::Policy
+    }
+
+    /// True when the run went over a limit.
+    pub fn is_limit_exceeded(&self) -> bool {
+        self.kind() == ErrorKind::LimitExceeded
+    }
+
+    /// True when the run was interrupted.
+    pub fn is_interrupted(&self) -> bool {
+        self.kind() == ErrorKind::Interrupted
+    }
+
+    /// True when a path was invalid.
+    pub fn is_invalid_path(&self) -> bool {
+        self.kind() == ErrorKind::InvalidPath
+    }
+
+    /// True when colors were invalid.
+    pub fn is_invalid_colors(&self) -> bool {
+        self.kind() == ErrorKind::InvalidColors
+    }
+
+    /// True when there was no filter by the name asked for.
+    pub fn is_unknown_filter(&self) -> bool {
+        self.kind() == ErrorKind::UnknownFilter
+    }
+
+    /// True when the run was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.kind() == ErrorKind::Cancelled
+    }
+
+    /// True when the run timed out.
+    pub fn is_timeout(&self) -> bool {
+        self.kind() == ErrorKind::Timeout
+    }
+
+    /// True when the input was too large.
+    pub fn is_input_too_large(&self) -> bool {
+        self.kind() == ErrorKind::InputTooLarge
+    }
+
+    /// True when the linked `libjq` isn't supported.
+    pub fn is_unsupported_version(&self) -> bool {
+        self.kind() == ErrorKind::UnsupportedVersion
+    }
+
+    /// The exit code the jq cli would finish with after this error, if it's
+    /// one the cli could run into.
+    ///
+    /// Halted programs report their own exit code. Otherwise the cli uses
+    /// `2` when it can't read its input, `3` when the program fails to
+    /// compile, and `5` for just about everything else.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Error::Halted { exit_code, .. } => Some(*exit_code),
+            Error::InvalidProgram { .. } => Some(3),
+            Error::Parse { .. } | Error::StringConvert { .. } | Error::Io { .. } => Some(2),
+            Error::Exec { .. } | Error::System { .. } | Error::Unknown => Some(5),
+            Error::Policy { .. }
+            | Error::LimitExceeded { .. }
+            | Error::Interrupted
+            | Error::InvalidPath { .. }
+            | Error::InvalidColors { .. }
+            | Error::UnknownFilter { .. }
+            | Error::Cancelled
+            | Error::Timeout
+            | Error::InputTooLarge { .. }
+            | Error::UnsupportedVersion { .. } => None,
+        }
+    }
+
+    /// The value a program passed to `error` when it wasn't a string, such as
+    /// `error({"kind": "validation"})`.
+    ///
+    /// ```rust
+    /// let err = jq_rs::run(r#"error({"kind": "validation"})"#, "null").unwrap_err();
+    /// assert_eq!(err.payload_value().unwrap()["kind"], "validation");
+    /// ```
+    #[cfg(feature = "serde_json")]
+    pub fn payload_value(&self) -> Option<serde_json::Value> {
+        match self {
+            // The payload was rendered by jq, so it's always valid json.
+            Error::Exec {
+                payload: Some(payload),
+                ..
+            } => serde_json::from_str(payload).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[allow(deprecated)]
 impl error::Error for Error {
     fn description(&self) -> &str {
         match self {
             Error::StringConvert { .. } => ERR_STRING_CONV,
             Error::InvalidProgram { reason } => reason,
+            Error::Parse { reason, .. } => reason,
+            Error::Exec { reason, .. } => reason,
             Error::System { reason } => reason
                 .as_ref()
                 .map(|x| x.as_str())
                 .unwrap_or_else(|| ERR_UNKNOWN),
+            Error::Halted { .. } => ERR_HALTED,
+            Error::Io { .. } => ERR_IO,
+            Error::Policy { reason } => reason,
+            Error::LimitExceeded { reason } => reason,
+            Error::Interrupted => ERR_INTERRUPTED,
+            Error::InvalidPath { reason } => reason,
+            Error::InvalidColors { reason } => reason,
+            Error::UnknownFilter { .. } => ERR_UNKNOWN_FILTER,
+            Error::Cancelled => ERR_CANCELLED,
+            Error::Timeout => ERR_TIMEOUT,
+            Error::InputTooLarge { .. } => ERR_INPUT_TOO_LARGE,
+            Error::UnsupportedVersion { .. } => ERR_UNSUPPORTED_VERSION,
             Error::Unknown => ERR_UNKNOWN,
         }
     }
@@ -62,6 +423,7 @@ impl error::Error for Error {
                     None
                 }
             }
+            Error::Io { err } => Some(err),
             _ => None,
         }
     }
@@ -79,17 +441,176 @@ impl From<std::str::Utf8Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    /// I/O errors which were converted from an `Error` (say by a reader which
+    /// itself runs a jq program) are unwrapped, giving back the original.
+    fn from(err: std::io::Error) -> Self {
+        match err.get_ref() {
+            Some(inner) if inner.is::<Wrapped>() => {}
+            _ => return Error::Io { err },
+        }
+        match err.into_inner().map(|inner| inner.downcast::<Wrapped>()) {
+            Some(Ok(wrapped)) => wrapped.0,
+            // Checked above.
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    /// Errors which came from I/O in the first place are unwrapped. Others
+    /// keep the `Error` as their inner error, with a kind picked to match:
+    /// `InvalidData` for bad input (or too much of it), `InvalidInput` for
+    /// programs which fail to compile and invalid paths, `NotFound` for
+    /// unknown filters, `PermissionDenied` for policy errors, `TimedOut` for
+    /// timeouts, and `Other` for anything else.
+    fn from(err: Error) -> Self {
+        use std::io::ErrorKind as IoKind;
+
+        let kind = match err.kind() {
+            ErrorKind::Io => match err {
+                Error::Io { err } => return err,
+                _ => unreachable!(),
+            },
+            ErrorKind::Parse | ErrorKind::StringConvert | ErrorKind::InputTooLarge => {
+                IoKind::InvalidData
+            }
+            ErrorKind::Compile | ErrorKind::InvalidPath | ErrorKind::InvalidColors => {
+                IoKind::InvalidInput
+            }
+            ErrorKind::UnknownFilter => IoKind::NotFound,
+            ErrorKind::Policy => IoKind::PermissionDenied,
+            ErrorKind::Timeout => IoKind::TimedOut,
+            _ => IoKind::Other,
+        };
+        let err = match err {
+            // The source isn't necessarily `Sync`, so only its message is
+            // kept.
+            Error::StringConvert { err } => Error::StringConvert {
+                err: err.to_string().into(),
+            },
+            err => err,
+        };
+        std::io::Error::new(kind, Wrapped(err))
+    }
+}
+
+/// An `Error` on its way through a `std::io::Error`, which needs its inner
+/// error to be `Sync`.
+#[derive(Debug)]
+struct Wrapped(Error);
+
+// The only part of an `Error` which might not be `Sync` is the source of a
+// `StringConvert`, and those are replaced with a `String` before wrapping.
+unsafe impl Sync for Wrapped {}
+
+impl error::Error for Wrapped {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl fmt::Display for Wrapped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[allow(deprecated)]
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let detail: String = match self {
             Error::InvalidProgram { reason } => format!("{}: {}", ERR_COMPILE, reason),
+            Error::Parse {
+                reason,
+                line,
+                column,
+                excerpt,
+                caret,
+                ..
+            } => {
+                let mut detail = format!(
+                    "{}: {} at line {}, column {}",
+                    ERR_PARSE, reason, line, column
+                );
+                if !excerpt.is_empty() {
+                    write!(detail, "\n{}\n{:>2$}", excerpt, "^", caret + 1)?;
+                }
+                detail
+            }
+            Error::Exec { reason, .. } => reason.clone(),
             Error::System { reason } => reason
                 .as_ref()
                 .cloned()
                 .unwrap_or_else(|| ERR_UNKNOWN.into()),
             Error::StringConvert { err } => format!("{} - `{}`", ERR_STRING_CONV, err),
+            Error::Halted { exit_code, payload } => match payload {
+                Some(payload) => format!(
+                    "{} with exit code {}: {}",
+                    ERR_HALTED,
+                    exit_code,
+                    payload.trim_end()
+                ),
+                None => format!("{} with exit code {}", ERR_HALTED, exit_code),
+            },
+            Error::Io { err } => format!("{} - `{}`", ERR_IO, err),
+            Error::Policy { reason } => format!("{}: {}", ERR_POLICY, reason),
+            Error::LimitExceeded { reason } => format!("{}: {}", ERR_LIMIT, reason),
+            Error::Interrupted => ERR_INTERRUPTED.into(),
+            Error::InvalidPath { reason } => format!("{}: {}", ERR_PATH, reason),
+            Error::InvalidColors { reason } => format!("{}: {}", ERR_COLORS, reason),
+            Error::UnknownFilter { name } => format!("{}: {:?}", ERR_UNKNOWN_FILTER, name),
+            Error::Cancelled => ERR_CANCELLED.into(),
+            Error::Timeout => ERR_TIMEOUT.into(),
+            Error::InputTooLarge { bytes, max } => format!(
+                "{}: {} bytes, more than the limit of {}",
+                ERR_INPUT_TOO_LARGE, bytes, max
+            ),
+            Error::UnsupportedVersion { version } => format!(
+                "{}: jq {}, where jq_rs needs jq {}",
+                ERR_UNSUPPORTED_VERSION,
+                version,
+                crate::version::SUPPORTED.join(" or ")
+            ),
             Error::Unknown => ERR_UNKNOWN.into(),
         };
         write!(f, "{}", detail)
     }
 }
+
+/// The failure of one input in a batch run, such as by
+/// `JqProgram::run_batch()`, along with which input it was.
+#[derive(Debug)]
+pub struct BatchError {
+    /// The position of the input in the batch, starting from `0`. This is
+    /// also the position of this error in the results.
+    pub index: usize,
+    /// For `JqProgram::run_lines()`, the line the input was on, and for
+    /// `JqProgram::run_documents()` the line it started on, starting from
+    /// `1`.
+    pub line: Option<usize>,
+    /// What went wrong with the input.
+    pub error: Error,
+}
+
+impl BatchError {
+    /// Drop the position, leaving just the error.
+    pub fn into_inner(self) -> Error {
+        self.error
+    }
+}
+
+impl error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "Line {}: {}", line, self.error),
+            None => write!(f, "Input {}: {}", self.index, self.error),
+        }
+    }
+}
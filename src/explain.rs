@@ -0,0 +1,1002 @@
+//! A structured view of a jq program, for tooling which wants to look inside
+//! programs without reimplementing jq's grammar.
+//!
+//! libjq 1.6 doesn't expose the tree it parses programs into, so once jq has
+//! accepted a program it's parsed again here, following jq 1.6's grammar.
+
+use crate::errors::{Error, Result};
+use serde_json::{json, Value};
+
+/// Describe a jq program as a tree of json objects, for visualizing programs
+/// or checking them against policies of your own.
+///
+/// The program is compiled first, so programs jq rejects fail the same way
+/// as with `compile()`. Each node in the tree has a `"kind"`, along with
+/// fields depending on the kind:
+///
+/// | kind | fields |
+/// |------|--------|
+/// | `program` | `module` (metadata or `null`), `imports`, `body` |
+/// | `import`, `include` | `path`, `as` (`"name"` or `"$name"`, for imports), `metadata` |
+/// | `identity`, `recurse` | (`.` and `..`) |
+/// | `literal` | `value` |
+/// | `string` | `format` (such as `"@base64"`, or `null`), `parts` (strings and interpolated nodes) |
+/// | `format` | `format` |
+/// | `variable` | `name` (without the `$`) |
+/// | `call` | `name` (including any `module::`), `args` |
+/// | `index` | `target`, `index` |
+/// | `slice` | `target`, `from`, `to` |
+/// | `iterate` | `target` |
+/// | `optional` | `body` (`body?`) |
+/// | `array` | `body` (or `null` for `[]`) |
+/// | `object` | `entries`, each with a `key` node and a `value` (`null` for `{a}`, `{$a}`, etc) |
+/// | `binary` | `op` (`"|"`, `","`, `"+"`, `"and"`, `"|="`, etc), `left`, `right` |
+/// | `negate` | `body` |
+/// | `bind` | `source`, `patterns`, `body` (`source as $x \| body`) |
+/// | `reduce` | `source`, `patterns`, `init`, `update` |
+/// | `foreach` | `source`, `patterns`, `init`, `update`, `extract` |
+/// | `if` | `cond`, `then`, `else` (`elif` is a nested `if`) |
+/// | `try` | `body`, `catch` |
+/// | `label` | `name`, `body` |
+/// | `break` | `name` |
+/// | `def` | `name`, `params` (`"f"` or `"$x"`), `body`, `rest` (the expression the definition is for) |
+///
+/// Destructuring patterns are `variable` nodes, `array_pattern` nodes with
+/// `items`, or `object_pattern` nodes with `entries` like an `object`'s.
+///
+/// Requires the `serde_json` feature.
+///
+/// ```rust
+/// use serde_json::json;
+///
+/// let tree = jq_rs::explain(".[] | select(.age > 30)").unwrap();
+/// assert_eq!(
+///     tree["body"],
+///     json!({
+///         "kind": "binary",
+///         "op": "|",
+///         "left": {"kind": "iterate", "target": {"kind": "identity"}},
+///         "right": {
+///             "kind": "call",
+///             "name": "select",
+///             "args": [{
+///                 "kind": "binary",
+///                 "op": ">",
+///                 "left": {
+///                     "kind": "index",
+///                     "target": {"kind": "identity"},
+///                     "index": {"kind": "literal", "value": "age"}
+///                 },
+///                 "right": {"kind": "literal", "value": 30}
+///             }]
+///         }
+///     })
+/// );
+/// ```
+pub fn explain(program: &str) -> Result<Value> {
+    crate::compile(program)?;
+    let tokens = Lexer::tokenize(program).map_err(unexplained)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let tree = parser.program().map_err(unexplained)?;
+    Ok(tree)
+}
+
+fn unexplained(reason: String) -> Error {
+    Error::InvalidProgram {
+        reason: format!(
+            "jq accepted the program, but it couldn't be explained: {}",
+            reason
+        ),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    /// A name or keyword, including any `module::` prefix.
+    Ident(String),
+    /// `.name`.
+    Field(String),
+    /// `$name`.
+    Var(String),
+    /// `@name`.
+    Format(String),
+    Number(f64),
+    Str(Vec<Part>),
+    /// Operators and punctuation.
+    Op(&'static str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Code(Vec<Tok>),
+}
+
+/// Operators, longest first so they're matched before their prefixes.
+const OPS: &[&str] = &[
+    "?//", "//=", "|=", "+=", "-=", "*=", "/=", "%=", "==", "!=", "<=", ">=", "//", "..", ".", "[",
+    "]", "(", ")", "{", "}", "|", ",", ":", ";", "=", "<", ">", "+", "-", "*", "/", "%", "?",
+];
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Lexer<'_> {
+    fn tokenize(program: &str) -> Parsed<Vec<Tok>> {
+        let mut lexer = Lexer {
+            src: program,
+            pos: 0,
+        };
+        lexer.code(false)
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    /// Read code up to the end of the program, or when `interpolation` is
+    /// set, up to the `)` closing a string interpolation.
+    fn code(&mut self, interpolation: bool) -> Parsed<Vec<Tok>> {
+        let mut tokens = vec![];
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.pos += c.len_utf8();
+            } else if c == '#' {
+                self.pos += self.rest().find('\n').unwrap_or_else(|| self.rest().len());
+            } else if c == '"' {
+                self.pos += 1;
+                tokens.push(self.string()?);
+            } else if c == '$' {
+                self.pos += 1;
+                self.pos += self.rest().len() - self.rest().trim_start().len();
+                tokens.push(Tok::Var(self.name()?));
+            } else if c == '@' {
+                self.pos += 1;
+                tokens.push(Tok::Format(format!("@{}", self.ident()?)));
+            } else if c == '.' && self.rest()[1..].starts_with(is_ident_start) {
+                self.pos += 1;
+                tokens.push(Tok::Field(self.ident()?));
+            } else if c.is_ascii_digit()
+                || (c == '.' && self.rest()[1..].starts_with(|c: char| c.is_ascii_digit()))
+            {
+                tokens.push(Tok::Number(self.number()?));
+            } else if is_ident_start(c) {
+                tokens.push(Tok::Ident(self.name()?));
+            } else if c == ')' && interpolation && depth == 0 {
+                self.pos += 1;
+                return Ok(tokens);
+            } else {
+                let op = OPS
+                    .iter()
+                    .find(|op| self.rest().starts_with(**op))
+                    .ok_or_else(|| format!("unexpected {:?}", c))?;
+                match *op {
+                    "(" => depth += 1,
+                    ")" => depth -= 1,
+                    _ => {}
+                }
+                self.pos += op.len();
+                tokens.push(Tok::Op(op));
+            }
+        }
+        if interpolation {
+            return Err("unterminated string interpolation".into());
+        }
+        Ok(tokens)
+    }
+
+    /// Read a string after its opening quote.
+    fn string(&mut self) -> Parsed<Tok> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        loop {
+            let c = self.peek().ok_or("unterminated string")?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => break,
+                '\\' => {
+                    let escape = self.peek().ok_or("unterminated string")?;
+                    self.pos += escape.len_utf8();
+                    match escape {
+                        '(' => {
+                            if !text.is_empty() {
+                                parts.push(Part::Text(std::mem::take(&mut text)));
+                            }
+                            parts.push(Part::Code(self.code(true)?));
+                        }
+                        'b' => text.push('\u{8}'),
+                        'f' => text.push('\u{c}'),
+                        'n' => text.push('\n'),
+                        'r' => text.push('\r'),
+                        't' => text.push('\t'),
+                        'u' => text.push(self.unicode_escape()?),
+                        other => text.push(other),
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() || parts.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Tok::Str(parts))
+    }
+
+    /// Read the `XXXX` of a `\uXXXX` escape, along with the low half of a
+    /// surrogate pair.
+    fn unicode_escape(&mut self) -> Parsed<char> {
+        let high = self.hex4()?;
+        if (0xd800..0xdc00).contains(&high) && self.rest().starts_with("\\u") {
+            let pos = self.pos;
+            self.pos += 2;
+            let low = self.hex4()?;
+            if (0xdc00..0xe000).contains(&low) {
+                let c = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+                return Ok(std::char::from_u32(c).unwrap_or('\u{fffd}'));
+            }
+            self.pos = pos;
+        }
+        Ok(std::char::from_u32(high).unwrap_or('\u{fffd}'))
+    }
+
+    fn hex4(&mut self) -> Parsed<u32> {
+        let digits = self.rest().get(..4).ok_or("invalid \\u escape")?;
+        let n = u32::from_str_radix(digits, 16).map_err(|_| "invalid \\u escape")?;
+        self.pos += 4;
+        Ok(n)
+    }
+
+    fn number(&mut self) -> Parsed<f64> {
+        let start = self.pos;
+        let digits = |lexer: &mut Self| {
+            while lexer.rest().starts_with(|c: char| c.is_ascii_digit()) {
+                lexer.pos += 1;
+            }
+        };
+        digits(self);
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            digits(self);
+        }
+        if let Some('e') | Some('E') = self.peek() {
+            self.pos += 1;
+            if let Some('+') | Some('-') = self.peek() {
+                self.pos += 1;
+            }
+            digits(self);
+        }
+        let text = &self.src[start..self.pos];
+        text.parse()
+            .map_err(|_| format!("invalid number {:?}", text))
+    }
+
+    /// A name, which may be prefixed by modules as in `module::name`.
+    fn name(&mut self) -> Parsed<String> {
+        let mut name = self.ident()?;
+        while self.rest().starts_with("::") {
+            self.pos += 2;
+            name.push_str("::");
+            name.push_str(&self.ident()?);
+        }
+        Ok(name)
+    }
+
+    fn ident(&mut self) -> Parsed<String> {
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or_else(|| self.rest().len());
+        if len == 0 || !self.rest().starts_with(is_ident_start) {
+            return Err("expected a name".into());
+        }
+        let name = self.rest()[..len].to_string();
+        self.pos += len;
+        Ok(name)
+    }
+}
+
+/// Errors are only reported as text, since they'd mean jq and this parser
+/// disagree.
+type Parsed<T> = std::result::Result<T, String>;
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+/// Binary operators as `(operator, precedence, right associative)`, from
+/// jq 1.6's `parser.y`.
+const BINARY: &[(&str, u8, bool)] = &[
+    ("|", 0, true),
+    (",", 1, false),
+    ("//", 2, true),
+    ("=", 3, false),
+    ("|=", 3, false),
+    ("+=", 3, false),
+    ("-=", 3, false),
+    ("*=", 3, false),
+    ("/=", 3, false),
+    ("%=", 3, false),
+    ("//=", 3, false),
+    ("or", 4, false),
+    ("and", 5, false),
+    ("==", 6, false),
+    ("!=", 6, false),
+    ("<", 6, false),
+    ("<=", 6, false),
+    (">", 6, false),
+    (">=", 6, false),
+    ("+", 7, false),
+    ("-", 7, false),
+    ("*", 8, false),
+    ("/", 8, false),
+    ("%", 8, false),
+];
+
+/// The precedence of `*`, which unary `-` binds as tightly as.
+const PRODUCT: u8 = 8;
+
+struct Parser {
+    tokens: Vec<Tok>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_is(&self, op: &str) -> bool {
+        match self.peek() {
+            Some(Tok::Op(other)) => *other == op,
+            Some(Tok::Ident(name)) => name == op,
+            _ => false,
+        }
+    }
+
+    fn next(&mut self) -> Parsed<Tok> {
+        let tok = self.peek().cloned().ok_or("unexpected end of program")?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let found = self.peek_is(op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, op: &str) -> Parsed<()> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(format!("expected `{}`, found {:?}", op, self.peek()))
+        }
+    }
+
+    fn program(&mut self) -> Parsed<Value> {
+        let mut module = Value::Null;
+        if self.eat("module") {
+            module = self.postfix_term()?;
+            self.expect(";")?;
+        }
+        let mut imports = vec![];
+        while self.peek_is("import") || self.peek_is("include") {
+            imports.push(self.import()?);
+        }
+        let body = if self.peek().is_some() {
+            self.pipe()?
+        } else {
+            Value::Null
+        };
+        if let Some(tok) = self.peek() {
+            return Err(format!("unexpected {:?}", tok));
+        }
+        Ok(json!({"kind": "program", "module": module, "imports": imports, "body": body}))
+    }
+
+    fn import(&mut self) -> Parsed<Value> {
+        let include = self.eat("include");
+        if !include {
+            self.expect("import")?;
+        }
+        let path = match self.next()? {
+            Tok::Str(parts) => match parts.as_slice() {
+                [Part::Text(path)] => path.clone(),
+                _ => return Err("module paths can't be interpolated".into()),
+            },
+            other => return Err(format!("expected a module path, found {:?}", other)),
+        };
+        let mut node = json!({"kind": if include { "include" } else { "import" }, "path": path});
+        if !include {
+            self.expect("as")?;
+            node["as"] = match self.next()? {
+                Tok::Var(name) => format!("${}", name).into(),
+                Tok::Ident(name) => name.into(),
+                other => return Err(format!("expected a name, found {:?}", other)),
+            };
+        }
+        node["metadata"] = if self.peek_is(";") {
+            Value::Null
+        } else {
+            self.postfix_term()?
+        };
+        self.expect(";")?;
+        Ok(node)
+    }
+
+    fn pipe(&mut self) -> Parsed<Value> {
+        self.binary(0)
+    }
+
+    /// Read operators of at least precedence `min`, and their operands.
+    fn binary(&mut self, min: u8) -> Parsed<Value> {
+        let mut left = self.unary()?;
+        loop {
+            let op = BINARY
+                .iter()
+                .find(|(op, prec, _)| *prec >= min && self.peek_is(op));
+            let (op, prec, right_assoc) = match op {
+                Some(op) => *op,
+                None => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.binary(if right_assoc { prec } else { prec + 1 })?;
+            left = json!({"kind": "binary", "op": op, "left": left, "right": right});
+        }
+    }
+
+    /// An operand of a binary operator, including the constructs which take
+    /// in everything to their right, like `def` and `as`.
+    fn unary(&mut self) -> Parsed<Value> {
+        if self.eat("-") {
+            let body = self.binary(PRODUCT)?;
+            return Ok(json!({"kind": "negate", "body": body}));
+        }
+        if self.eat("def") {
+            let mut def = self.def()?;
+            def["rest"] = if self.peek().is_some() {
+                self.pipe()?
+            } else {
+                Value::Null
+            };
+            return Ok(def);
+        }
+        if self.eat("reduce") || self.peek_is("foreach") {
+            let foreach = self.eat("foreach");
+            let source = self.postfix_term()?;
+            self.expect("as")?;
+            let patterns = self.patterns()?;
+            self.expect("(")?;
+            let init = self.pipe()?;
+            self.expect(";")?;
+            let update = self.pipe()?;
+            let mut node = json!({
+                "kind": if foreach { "foreach" } else { "reduce" },
+                "source": source,
+                "patterns": patterns,
+                "init": init,
+                "update": update,
+            });
+            if foreach {
+                node["extract"] = if self.eat(";") {
+                    self.pipe()?
+                } else {
+                    Value::Null
+                };
+            }
+            self.expect(")")?;
+            return Ok(node);
+        }
+        if self.eat("if") {
+            return self.if_body();
+        }
+        if self.eat("try") {
+            let body = self.postfix_term()?;
+            let catch = if self.eat("catch") {
+                self.postfix_term()?
+            } else {
+                Value::Null
+            };
+            return Ok(json!({"kind": "try", "body": body, "catch": catch}));
+        }
+        if self.eat("label") {
+            let name = match self.next()? {
+                Tok::Var(name) => name,
+                other => return Err(format!("expected a label, found {:?}", other)),
+            };
+            self.expect("|")?;
+            let body = self.pipe()?;
+            return Ok(json!({"kind": "label", "name": name, "body": body}));
+        }
+
+        let term = self.postfix_term()?;
+        if !self.eat("as") {
+            return Ok(term);
+        }
+        let patterns = self.patterns()?;
+        self.expect("|")?;
+        let body = self.pipe()?;
+        Ok(json!({"kind": "bind", "source": term, "patterns": patterns, "body": body}))
+    }
+
+    /// The rest of a `def`, after the keyword and up to its `;`.
+    fn def(&mut self) -> Parsed<Value> {
+        let name = match self.next()? {
+            Tok::Ident(name) => name,
+            other => return Err(format!("expected a name, found {:?}", other)),
+        };
+        let mut params = vec![];
+        if self.eat("(") {
+            loop {
+                params.push(match self.next()? {
+                    Tok::Ident(name) => name,
+                    Tok::Var(name) => format!("${}", name),
+                    other => return Err(format!("expected a parameter, found {:?}", other)),
+                });
+                if !self.eat(";") {
+                    break;
+                }
+            }
+            self.expect(")")?;
+        }
+        self.expect(":")?;
+        let body = self.pipe()?;
+        self.expect(";")?;
+        Ok(json!({"kind": "def", "name": name, "params": params, "body": body}))
+    }
+
+    /// The rest of an `if`, after the keyword or an `elif`.
+    fn if_body(&mut self) -> Parsed<Value> {
+        let cond = self.pipe()?;
+        self.expect("then")?;
+        let then = self.pipe()?;
+        let otherwise = if self.eat("elif") {
+            return Ok(json!({"kind": "if", "cond": cond, "then": then, "else": self.if_body()?}));
+        } else if self.eat("else") {
+            self.pipe()?
+        } else {
+            Value::Null
+        };
+        self.expect("end")?;
+        Ok(json!({"kind": "if", "cond": cond, "then": then, "else": otherwise}))
+    }
+
+    /// A term followed by any number of fields, indexes, slices, `[]` or `?`.
+    fn postfix_term(&mut self) -> Parsed<Value> {
+        if ["if", "reduce", "foreach", "try", "label", "def"]
+            .iter()
+            .any(|keyword| self.peek_is(keyword))
+        {
+            return self.unary();
+        }
+        let mut term = self.term()?;
+        loop {
+            match self.peek().cloned() {
+                Some(Tok::Field(name)) => {
+                    self.pos += 1;
+                    term = index(term, json!({"kind": "literal", "value": name}));
+                }
+                Some(Tok::Op(".")) => {
+                    self.pos += 1;
+                    let key = self.string_term(Value::Null)?;
+                    term = index(term, key);
+                }
+                Some(Tok::Op("[")) => {
+                    self.pos += 1;
+                    term = self.brackets(term)?;
+                }
+                Some(Tok::Op("?")) => {
+                    self.pos += 1;
+                    term = json!({"kind": "optional", "body": term});
+                }
+                _ => return Ok(term),
+            }
+        }
+    }
+
+    /// The rest of `[]`, `[i]` or `[from:to]` after `target`.
+    fn brackets(&mut self, target: Value) -> Parsed<Value> {
+        if self.eat("]") {
+            return Ok(json!({"kind": "iterate", "target": target}));
+        }
+        let from = if self.peek_is(":") {
+            Value::Null
+        } else {
+            self.pipe()?
+        };
+        if !self.eat(":") {
+            self.expect("]")?;
+            return Ok(index(target, from));
+        }
+        let to = if self.peek_is("]") {
+            Value::Null
+        } else {
+            self.pipe()?
+        };
+        self.expect("]")?;
+        Ok(json!({"kind": "slice", "target": target, "from": from, "to": to}))
+    }
+
+    fn term(&mut self) -> Parsed<Value> {
+        match self.next()? {
+            Tok::Op(".") => match self.peek() {
+                Some(Tok::Str(_)) => {
+                    let key = self.string_term(Value::Null)?;
+                    Ok(index(json!({"kind": "identity"}), key))
+                }
+                _ => Ok(json!({"kind": "identity"})),
+            },
+            Tok::Op("..") => Ok(json!({"kind": "recurse"})),
+            Tok::Field(name) => Ok(index(
+                json!({"kind": "identity"}),
+                json!({"kind": "literal", "value": name}),
+            )),
+            Tok::Number(n) => Ok(json!({"kind": "literal", "value": number(n)})),
+            Tok::Str(parts) => string(parts, Value::Null),
+            Tok::Format(format) => match self.peek() {
+                Some(Tok::Str(_)) => self.string_term(format.into()),
+                _ => Ok(json!({"kind": "format", "format": format})),
+            },
+            Tok::Var(name) => Ok(json!({"kind": "variable", "name": name})),
+            Tok::Op("(") => {
+                let body = self.pipe()?;
+                self.expect(")")?;
+                Ok(body)
+            }
+            Tok::Op("[") => {
+                let body = if self.peek_is("]") {
+                    Value::Null
+                } else {
+                    self.pipe()?
+                };
+                self.expect("]")?;
+                Ok(json!({"kind": "array", "body": body}))
+            }
+            Tok::Op("{") => self.object(),
+            Tok::Ident(name) => match name.as_str() {
+                "true" => Ok(json!({"kind": "literal", "value": true})),
+                "false" => Ok(json!({"kind": "literal", "value": false})),
+                "null" => Ok(json!({"kind": "literal", "value": null})),
+                "break" => match self.next()? {
+                    Tok::Var(label) => Ok(json!({"kind": "break", "name": label})),
+                    other => Err(format!("expected a label, found {:?}", other)),
+                },
+                _ => {
+                    let mut args = vec![];
+                    if self.eat("(") {
+                        loop {
+                            args.push(self.pipe()?);
+                            if !self.eat(";") {
+                                break;
+                            }
+                        }
+                        self.expect(")")?;
+                    }
+                    Ok(json!({"kind": "call", "name": name, "args": args}))
+                }
+            },
+            other => Err(format!("unexpected {:?}", other)),
+        }
+    }
+
+    fn string_term(&mut self, format: Value) -> Parsed<Value> {
+        match self.next()? {
+            Tok::Str(parts) => string(parts, format),
+            other => Err(format!("expected a string, found {:?}", other)),
+        }
+    }
+
+    /// The rest of an object after its `{`.
+    fn object(&mut self) -> Parsed<Value> {
+        let mut entries = vec![];
+        while !self.eat("}") {
+            let key = match self.next()? {
+                Tok::Var(name) => json!({"kind": "variable", "name": name}),
+                Tok::Ident(name) => json!({"kind": "literal", "value": name}),
+                Tok::Str(parts) => string(parts, Value::Null)?,
+                Tok::Format(format) => self.string_term(format.into())?,
+                Tok::Op("(") => {
+                    let key = self.pipe()?;
+                    self.expect(")")?;
+                    key
+                }
+                other => return Err(format!("expected an object key, found {:?}", other)),
+            };
+            let value = if self.eat(":") {
+                self.object_value()?
+            } else {
+                Value::Null
+            };
+            entries.push(json!({"key": key, "value": value}));
+            if !self.eat(",") {
+                self.expect("}")?;
+                break;
+            }
+        }
+        Ok(json!({"kind": "object", "entries": entries}))
+    }
+
+    /// A value in an object, which can only use `|` and `-` without
+    /// brackets.
+    fn object_value(&mut self) -> Parsed<Value> {
+        let left = if self.eat("-") {
+            json!({"kind": "negate", "body": self.object_value()?})
+        } else {
+            self.postfix_term()?
+        };
+        if self.eat("|") {
+            let right = self.object_value()?;
+            return Ok(json!({"kind": "binary", "op": "|", "left": left, "right": right}));
+        }
+        Ok(left)
+    }
+
+    fn patterns(&mut self) -> Parsed<Vec<Value>> {
+        let mut patterns = vec![self.pattern()?];
+        while self.eat("?//") {
+            patterns.push(self.pattern()?);
+        }
+        Ok(patterns)
+    }
+
+    fn pattern(&mut self) -> Parsed<Value> {
+        match self.next()? {
+            Tok::Var(name) => Ok(json!({"kind": "variable", "name": name})),
+            Tok::Op("[") => {
+                let mut items = vec![];
+                loop {
+                    items.push(self.pattern()?);
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+                self.expect("]")?;
+                Ok(json!({"kind": "array_pattern", "items": items}))
+            }
+            Tok::Op("{") => {
+                let mut entries = vec![];
+                loop {
+                    let key = match self.next()? {
+                        Tok::Var(name) => json!({"kind": "variable", "name": name}),
+                        Tok::Ident(name) => json!({"kind": "literal", "value": name}),
+                        Tok::Str(parts) => string(parts, Value::Null)?,
+                        Tok::Op("(") => {
+                            let key = self.pipe()?;
+                            self.expect(")")?;
+                            key
+                        }
+                        other => return Err(format!("expected a key, found {:?}", other)),
+                    };
+                    let value = if self.eat(":") {
+                        self.pattern()?
+                    } else {
+                        Value::Null
+                    };
+                    entries.push(json!({"key": key, "value": value}));
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+                self.expect("}")?;
+                Ok(json!({"kind": "object_pattern", "entries": entries}))
+            }
+            other => Err(format!("expected a pattern, found {:?}", other)),
+        }
+    }
+}
+
+fn index(target: Value, index: Value) -> Value {
+    json!({"kind": "index", "target": target, "index": index})
+}
+
+/// Plain strings are literals, others list their parts.
+fn string(parts: Vec<Part>, format: Value) -> Parsed<Value> {
+    if let (Value::Null, [Part::Text(text)]) = (&format, parts.as_slice()) {
+        return Ok(json!({"kind": "literal", "value": text}));
+    }
+    let parts = parts
+        .into_iter()
+        .map(|part| match part {
+            Part::Text(text) => Ok(text.into()),
+            Part::Code(tokens) => {
+                let mut parser = Parser { tokens, pos: 0 };
+                let node = parser.pipe()?;
+                match parser.peek() {
+                    Some(tok) => Err(format!("unexpected {:?}", tok)),
+                    None => Ok(node),
+                }
+            }
+        })
+        .collect::<Parsed<Vec<Value>>>()?;
+    Ok(json!({"kind": "string", "format": format, "parts": parts}))
+}
+
+/// Whole numbers are kept as integers, as jq prints them.
+fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 {
+        json!(n as i64)
+    } else {
+        json!(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::explain;
+    use crate::Error;
+    use matches::assert_matches;
+    use serde_json::{json, Value};
+
+    fn body(program: &str) -> Value {
+        explain(program).unwrap()["body"].take()
+    }
+
+    fn kinds(program: &str) -> Vec<String> {
+        fn walk(value: &Value, out: &mut Vec<String>) {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(kind)) = map.get("kind") {
+                        out.push(kind.clone());
+                    }
+                    map.values().for_each(|value| walk(value, out));
+                }
+                Value::Array(items) => items.iter().for_each(|value| walk(value, out)),
+                _ => {}
+            }
+        }
+        let mut out = vec![];
+        walk(&explain(program).unwrap(), &mut out);
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    #[test]
+    fn everything_jq_accepts() {
+        for program in &[
+            "1 + reduce (1,2) as $x (0; .+$x)",
+            "foreach .[] as [$a, {b: $b, $c, \"d\": [$d], (\"e\"): $e}] (0; 1; 2)",
+            "foreach .[] as $x (0; 1)",
+            "try error(\"x\") catch . | length",
+            "- 1 * 3 + 1, -(1), {a: - 1}",
+            ".a?.b, .[1:], .[:2], .[1:2], .[]?, .[\"a\"]?, .a.\"b\", .\"a\", ..",
+            "{(1|tostring): 2, \"a\\(1)\": 2, a: .b | length, b, \"c\", @text \"d\": 1}",
+            "\"x\" as $x | {$x, a: $x | - ., b: $__loc__}",
+            ". as {\"a\\(1)\": $v, $w} | $v, 1 - -1",
+            "{if: 1, then: 2} | .if",
+            "def f: 1; def g(a; $b): a + $b; f + g(1; 2)",
+            "@base64 \"x\\(1)\", @text",
+            "1, 2 // 3, . = 1, .a += 1, .a |= 1, .a //= 1",
+            "label $f | 1, break $f, 2",
+            "[1] as [$a] ?// $a | $a",
+            "true and false or not",
+            "if . then 1 elif 1 then 2 else 3 end",
+            "\"\\u00e9\\ud83e\\udd96 \\\\ \\\" \\/ \\t\" | length",
+            ".5, 1., 1e2, 1.5E-3",
+            "[limit(1; 1, 2)] | .[0] | {\"a\": 1}.a",
+            "# a comment\n. # another",
+            "def f: 1;",
+        ] {
+            assert!(explain(program).is_ok(), "program: {}", program);
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let shape = |program: &str| -> String {
+            fn render(value: &Value) -> String {
+                match value["kind"].as_str() {
+                    Some("binary") => format!(
+                        "({} {} {})",
+                        render(&value["left"]),
+                        value["op"].as_str().unwrap(),
+                        render(&value["right"])
+                    ),
+                    Some("negate") => format!("-{}", render(&value["body"])),
+                    Some("literal") => value["value"].to_string(),
+                    Some("bind") => format!("(bind {})", render(&value["body"])),
+                    other => format!("{:?}", other),
+                }
+            }
+            render(&body(program))
+        };
+        assert_eq!(shape("1 | 2 | 3"), "(1 | (2 | 3))");
+        assert_eq!(shape("1, 2, 3"), "((1 , 2) , 3)");
+        assert_eq!(shape("1, 2 | 3"), "((1 , 2) | 3)");
+        assert_eq!(shape("1 // 2, 3"), "((1 // 2) , 3)");
+        assert_eq!(shape("1 + 2 * 3 - 4"), "((1 + (2 * 3)) - 4)");
+        assert_eq!(shape("1 < 2 and 3 or 4"), "(((1 < 2) and 3) or 4)");
+        assert_eq!(shape("- 1 * 3 + 1"), "(-(1 * 3) + 1)");
+        assert_eq!(shape("1 + 2 as $x | 3, 4"), "(1 + (bind (3 , 4)))");
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(
+            body(r#"."a b"[0][1:]?"#),
+            json!({"kind": "optional", "body": {
+                "kind": "slice",
+                "target": {
+                    "kind": "index",
+                    "target": {
+                        "kind": "index",
+                        "target": {"kind": "identity"},
+                        "index": {"kind": "literal", "value": "a b"}
+                    },
+                    "index": {"kind": "literal", "value": 0}
+                },
+                "from": {"kind": "literal", "value": 1},
+                "to": null
+            }})
+        );
+        assert_eq!(
+            body(r#"@csv "x\(.)""#),
+            json!({"kind": "string", "format": "@csv", "parts": ["x", {"kind": "identity"}]})
+        );
+        assert_eq!(
+            body("def f($a; g): g; f(1; .)"),
+            json!({
+                "kind": "def",
+                "name": "f",
+                "params": ["$a", "g"],
+                "body": {"kind": "call", "name": "g", "args": []},
+                "rest": {
+                    "kind": "call",
+                    "name": "f",
+                    "args": [{"kind": "literal", "value": 1}, {"kind": "identity"}]
+                }
+            })
+        );
+        assert_eq!(
+            body(". as $x | {a, $x, (.b): 1}")["body"]["entries"],
+            json!([
+                {"key": {"kind": "literal", "value": "a"}, "value": null},
+                {"key": {"kind": "variable", "name": "x"}, "value": null},
+                {
+                    "key": {
+                        "kind": "index",
+                        "target": {"kind": "identity"},
+                        "index": {"kind": "literal", "value": "b"}
+                    },
+                    "value": {"kind": "literal", "value": 1}
+                }
+            ])
+        );
+        assert_eq!(
+            kinds(". as {$a, b: [$c]} ?// $a | if . then try $a else $c end"),
+            vec![
+                "array_pattern",
+                "bind",
+                "identity",
+                "if",
+                "literal",
+                "object_pattern",
+                "program",
+                "try",
+                "variable",
+            ]
+        );
+    }
+
+    #[test]
+    fn module_and_imports() {
+        let tree = explain("module {a: 1}; def f: 1;").unwrap();
+        assert_eq!(tree["module"]["kind"], "object");
+        assert_eq!(tree["body"]["rest"], Value::Null);
+    }
+
+    #[test]
+    fn invalid_programs() {
+        assert_matches!(explain("1 +"), Err(Error::InvalidProgram { .. }));
+        assert_matches!(explain("$nope"), Err(Error::InvalidProgram { .. }));
+    }
+}
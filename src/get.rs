@@ -0,0 +1,144 @@
+//! Plucking a single value out of a json document by its path, without
+//! writing (or holding on to) a jq program.
+
+use crate::errors::Result;
+use crate::path::JqPath;
+use crate::JqProgram;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Once a thread has compiled this many paths, the lot are dropped and it
+/// starts again, so programs which build paths on the fly don't grow the
+/// cache forever.
+const MAX_CACHED_PATHS: usize = 64;
+
+thread_local! {
+    static PROGRAMS: RefCell<HashMap<String, JqProgram>> = RefCell::new(HashMap::new());
+}
+
+/// Get the value at `path` in a blob of json data, rendered as json.
+///
+/// The path is a jq path expression made up of fields and indexes, as read by
+/// `JqPath::parse()`. Anything else fails with `Error::InvalidPath`, so use
+/// `run()` for more involved programs. As in jq, a path which goes through a
+/// missing field or index gives `null`.
+///
+/// The program for each path is compiled once per thread, then reused.
+///
+/// ```rust
+/// let data = r#"{"kaiju": [{"name": "Godzilla"}, {"name": "Rodan"}]}"#;
+///
+/// assert_eq!(jq_rs::get(data, ".kaiju[1].name").unwrap(), "\"Rodan\"\n");
+/// assert_eq!(jq_rs::get(data, ".kaiju[2].name").unwrap(), "null\n");
+/// assert!(jq_rs::get(data, ".kaiju[] | .name").is_err());
+/// ```
+pub fn get(data: &str, path: &str) -> Result<String> {
+    with_program(path, |program| program.run(data))
+}
+
+/// Get the value at `path` in a blob of json data, deserialized as a `T`.
+///
+/// Paths are read and cached the same as for `get()`. Since a missing value
+/// is `null`, use an `Option<T>` for values which might not be there. Empty
+/// data is read as `null` too.
+///
+/// Requires the `serde` feature.
+///
+/// ```rust
+/// let data = r#"{"kaiju": [{"name": "Godzilla", "year": 1954}]}"#;
+///
+/// assert_eq!(jq_rs::get_as::<u16>(data, ".kaiju[0].year").unwrap(), 1954);
+/// assert_eq!(jq_rs::get_as::<Option<u16>>(data, ".kaiju[1].year").unwrap(), None);
+/// ```
+#[cfg(feature = "serde")]
+pub fn get_as<T>(data: &str, path: &str) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let data = if data.trim().is_empty() { "null" } else { data };
+    with_program(path, |program| {
+        let mut value = None;
+        program.run_deserialized(data, |output| {
+            value = Some(T::deserialize(output)?);
+            Ok(())
+        })?;
+        // A path always gives exactly one output.
+        Ok(value.expect("path programs give one output"))
+    })
+}
+
+fn with_program<T, F>(path: &str, run: F) -> Result<T>
+where
+    F: FnOnce(&mut JqProgram) -> Result<T>,
+{
+    // Paths are cached in their normal form, so `.a["b"]` and `.a.b` share a
+    // program.
+    let expression = JqPath::parse(path)?.to_expression();
+    PROGRAMS.with(|programs| {
+        let mut programs = programs.borrow_mut();
+        if !programs.contains_key(&expression) {
+            if programs.len() >= MAX_CACHED_PATHS {
+                programs.clear();
+            }
+            let program = crate::compile(&expression)?;
+            programs.insert(expression.clone(), program);
+        }
+        let program = programs
+            .get_mut(&expression)
+            .expect("the program was just added");
+        run(program)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{get, PROGRAMS};
+    use crate::Error;
+    use matches::assert_matches;
+
+    #[test]
+    fn paths() {
+        let data = r#"{"a": {"b c": [1, {"d": true}]}}"#;
+        assert_eq!(
+            get(data, ".").unwrap(),
+            "{\"a\":{\"b c\":[1,{\"d\":true}]}}\n"
+        );
+        assert_eq!(get(data, r#".a."b c"[-1].d"#).unwrap(), "true\n");
+        assert_eq!(get(data, ".a.missing[3]").unwrap(), "null\n");
+        assert_eq!(get("", ".a").unwrap(), "");
+    }
+
+    #[test]
+    fn errors() {
+        assert_matches!(get("{}", ".a | env"), Err(Error::InvalidPath { .. }));
+        assert_matches!(get("[1]", ".a"), Err(Error::Exec { .. }));
+        assert_matches!(get("{", ".a"), Err(Error::Parse { .. }));
+    }
+
+    #[test]
+    fn programs_are_shared_between_spellings() {
+        PROGRAMS.with(|programs| programs.borrow_mut().clear());
+        get("{}", ".a.b").unwrap();
+        get("{}", r#".["a"]."b""#).unwrap();
+        get("{}", " .a .b ").unwrap();
+        PROGRAMS.with(|programs| {
+            let programs = programs.borrow();
+            assert_eq!(programs.keys().collect::<Vec<_>>(), vec![".a.b"]);
+        });
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn typed() {
+        use super::get_as;
+
+        let data = r#"{"kaiju": [{"name": "Godzilla", "heads": 1}]}"#;
+        assert_eq!(
+            get_as::<String>(data, ".kaiju[0].name").unwrap(),
+            "Godzilla"
+        );
+        assert_eq!(get_as::<Option<u8>>(data, ".kaiju[0].tails").unwrap(), None);
+        assert_eq!(get_as::<Option<u8>>("", ".kaiju").unwrap(), None);
+        assert!(get_as::<u8>(data, ".kaiju[0].name").is_err());
+    }
+}
@@ -3,136 +3,1044 @@
 //!
 //! These are building blocks and not intended for use from the public API.
 
-use crate::errors::{Error, Result};
+use crate::cancel::{Interrupt, InterruptHandle};
+use crate::errors::{Error, ErrorKind, Result};
+use crate::metrics::SharedMetrics;
+use crate::processors::{SharedPostProcessor, SharedPreProcessor};
+use crate::RunStats;
 use jq_sys::{
-    jq_compile, jq_format_error, jq_get_exit_code, jq_halted, jq_init, jq_next, jq_set_error_cb,
-    jq_start, jq_state, jq_teardown, jv, jv_copy, jv_dump_string, jv_free, jv_get_kind,
-    jv_invalid_get_msg, jv_invalid_has_msg, jv_kind_JV_KIND_INVALID, jv_kind_JV_KIND_NUMBER,
-    jv_kind_JV_KIND_STRING, jv_number_value, jv_parser, jv_parser_free, jv_parser_new,
-    jv_parser_next, jv_parser_set_buf, jv_string_value,
+    jq_compile_args, jq_format_error, jq_get_error_message, jq_get_exit_code, jq_halt, jq_halted,
+    jq_init, jq_next, jq_set_attr, jq_set_debug_cb, jq_set_error_cb, jq_set_input_cb, jq_start,
+    jq_state, jq_teardown, jv, jv_array, jv_array_append, jv_array_get, jv_array_length, jv_copy,
+    jv_dump_string, jv_free, jv_get_kind, jv_invalid_get_msg, jv_invalid_has_msg,
+    jv_kind_JV_KIND_ARRAY, jv_kind_JV_KIND_INVALID, jv_kind_JV_KIND_NULL, jv_kind_JV_KIND_NUMBER,
+    jv_kind_JV_KIND_STRING, jv_number_value, jv_object, jv_object_set, jv_parser, jv_parser_free,
+    jv_parser_new, jv_parser_next, jv_parser_set_buf, jv_string_length_bytes, jv_string_sized,
+    jv_string_value, JV_PARSE_STREAMING,
 };
+use std::any::Any;
+use std::borrow::Cow;
 use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_void};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod canonical;
+#[cfg(feature = "serde")]
+mod de;
+mod functions;
+mod html;
+mod layout;
+#[cfg(feature = "locale")]
+mod locale;
+mod non_finite;
+mod palette;
+mod prelude;
+mod reassemble;
+#[cfg(feature = "serde_json")]
+mod value;
+
+#[cfg(feature = "serde")]
+pub use de::OutputDeserializer;
+pub use functions::{Function, Functions};
+pub use html::HtmlClasses;
+pub use non_finite::NonFinite;
+pub use palette::{ColorPalette, PaletteToken, TokenStyle};
+pub use prelude::{imported_modules, Overrides};
+pub use reassemble::Reassembler;
 
 pub struct Jq {
     state: *mut jq_state,
-    err_buf: String,
+    /// Messages jq reports through the error callback.
+    ///
+    /// The callback is handed a pointer to this, so it's boxed to keep the
+    /// address stable when the `Jq` is moved.
+    #[allow(clippy::box_collection)]
+    reported: Box<Vec<String>>,
+    /// Anything jq reported while compiling a program which still compiled.
+    warnings: Vec<String>,
+    /// Where the values passed to `debug` and `stderr` end up, boxed for the
+    /// same reason as `reported`.
+    hooks: Box<Hooks>,
+    /// How long it took to parse the input for the run about to start.
+    parse_time: Duration,
+    /// Numbers from the last run.
+    stats: RunStats,
+    stats_handler: Option<StatsHandler>,
+    /// How outputs are rendered as text.
+    format: Format,
+    limits: Limits,
+    /// What happens to outputs holding NaN or the infinities.
+    non_finite: NonFinite,
+    metrics: SharedMetrics,
+    /// What each input goes through before it's parsed.
+    preprocessor: Option<SharedPreProcessor>,
+    interrupt_handle: InterruptHandle,
+    /// Whether runs switch the thread to the C locale.
+    #[cfg(feature = "locale")]
+    c_locale: bool,
+    /// Identifies the program in the spans recorded for its runs.
+    #[cfg(feature = "otel")]
+    program_hash: String,
+}
+
+/// Handlers for the builtins which would otherwise write to stderr.
+struct Hooks {
+    debug: Option<Handler>,
+    stderr: Option<Handler>,
+    /// Whatever went to `stderr` during the current run, when there's no
+    /// handler for it.
+    stderr_buf: String,
+    /// A panic raised by a handler, held until jq has returned so it doesn't
+    /// unwind through C.
+    panic: Option<Box<dyn Any + Send>>,
+    /// Functions implemented in Rust, and the result of the last call to one
+    /// waiting for the input callback.
+    functions: Functions,
+    result: Option<JV>,
+    /// The object the variables declared with `Compiler::var()` are bound
+    /// from, for the current run.
+    vars: JV,
+    /// The zone `localtime` and `strflocaltime` use, instead of the
+    /// machine's.
+    #[cfg(feature = "tz")]
+    timezone: Option<crate::TimeZone>,
+    /// Checked whenever jq calls back, so a run can be interrupted without
+    /// producing an output.
+    interrupt_handle: InterruptHandle,
+}
+
+pub type Handler = Box<dyn FnMut(&str) + Send>;
+
+pub type StatsHandler = Box<dyn FnMut(&RunStats) + Send>;
+
+/// The target used for everything logged with the `log` feature.
+#[cfg(feature = "log")]
+const LOG_TARGET: &str = "jq_rs";
+
+impl Hooks {
+    fn call(&mut self, stderr: bool, value: &JV) {
+        if self.panic.is_some() {
+            return;
+        }
+        let handler = match (stderr, &mut self.debug, &mut self.stderr) {
+            (false, Some(handler), _) | (true, _, Some(handler)) => handler,
+            (true, _, None) => return value.dump_into(&mut self.stderr_buf),
+            (false, None, _) => {
+                // Logged in the same form the jq cli writes to stderr.
+                #[cfg(feature = "log")]
+                {
+                    if log::log_enabled!(target: LOG_TARGET, log::Level::Debug) {
+                        let mut rendered = String::new();
+                        value.dump_into(&mut rendered);
+                        log::debug!(target: LOG_TARGET, "[\"DEBUG:\",{}]", rendered);
+                    }
+                }
+                return;
+            }
+        };
+        let mut rendered = String::new();
+        value.dump_into(&mut rendered);
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| handler(&rendered))) {
+            self.panic = Some(panic);
+        }
+    }
+
+    fn call_function(&mut self, function: Function, args: JV) {
+        let result = if self.panic.is_some() {
+            Err("Not called after an earlier panic".to_string())
+        } else {
+            match panic::catch_unwind(AssertUnwindSafe(|| function(args))) {
+                Ok(result) => result,
+                Err(panic) => {
+                    self.panic = Some(panic);
+                    Err("Function panicked".to_string())
+                }
+            }
+        };
+        self.result = Some(result.unwrap_or_else(|msg| JV::invalid_with_msg(&msg)));
+    }
 }
 
 impl Jq {
-    pub fn compile_program(program: CString) -> Result<Self> {
+    /// Compile a program with `$name` variables bound to the given values,
+    /// like the jq cli's `--arg` and `--argjson`. They're also available via
+    /// `$ARGS.named`.
+    ///
+    /// Modules are imported from the directories in `library_paths`.
+    pub fn compile_with_args(
+        program: &str,
+        named: Vec<(String, JV)>,
+        overrides: &Overrides,
+        library_paths: &[String],
+        functions: &Functions,
+    ) -> Result<Self> {
+        let interrupt_handle = InterruptHandle::default();
         let mut jq = Jq {
             state: {
                 // jq's master branch shows this can be a null pointer, in
-                // which case the binary will exit with a `Error::System`.
+                // which case the binary will exit with a `Error::Exec`.
                 let ptr = unsafe { jq_init() };
                 if ptr.is_null() {
-                    return Err(Error::System {
-                        reason: Some("Failed to init".into()),
+                    return Err(Error::Exec {
+                        reason: "Failed to init".into(),
+                        payload: None,
                     });
                 } else {
                     ptr
                 }
             },
-            err_buf: "".to_string(),
+            reported: Box::new(Vec::new()),
+            warnings: Vec::new(),
+            hooks: Box::new(Hooks {
+                debug: None,
+                stderr: None,
+                stderr_buf: String::new(),
+                panic: None,
+                functions: functions.clone(),
+                result: None,
+                vars: JV::object(),
+                #[cfg(feature = "tz")]
+                timezone: None,
+                interrupt_handle: interrupt_handle.clone(),
+            }),
+            parse_time: Duration::default(),
+            stats: RunStats::default(),
+            stats_handler: None,
+            format: Format::default(),
+            limits: Limits::default(),
+            non_finite: NonFinite::default(),
+            metrics: SharedMetrics::default(),
+            preprocessor: None,
+            interrupt_handle,
+            #[cfg(feature = "locale")]
+            c_locale: false,
+            #[cfg(feature = "otel")]
+            program_hash: crate::cache::program_hash(program),
         };
 
         extern "C" fn err_cb(data: *mut c_void, msg: jv) {
             unsafe {
                 let formatted = jq_format_error(msg);
-                let jq = &mut *(data as *mut Jq);
-                jq.err_buf += &(CStr::from_ptr(jv_string_value(formatted))
+                let reported = &mut *(data as *mut Vec<String>);
+                let msg = CStr::from_ptr(jv_string_value(formatted))
                     .to_str()
                     .unwrap_or("")
-                    .to_string()
-                    + "\n");
+                    .to_string();
                 jv_free(formatted);
+                #[cfg(feature = "log")]
+                log::warn!(target: LOG_TARGET, "{}", msg);
+                reported.push(msg);
             }
         }
         unsafe {
-            jq_set_error_cb(jq.state, Some(err_cb), &mut jq as *mut Jq as *mut c_void);
+            let reported: *mut Vec<String> = &mut *jq.reported;
+            jq_set_error_cb(jq.state, Some(err_cb), reported as *mut c_void);
         }
 
-        if unsafe { jq_compile(jq.state, program.as_ptr()) } == 0 {
-            Err(Error::InvalidProgram {
-                reason: jq.err_buf.clone(),
-            })
-        } else {
+        // The prelude sends the values passed to `stderr` and the arguments
+        // for functions through `debug`, wrapped so they can be told apart
+        // here.
+        extern "C" fn debug_cb(data: *mut c_void, value: jv) {
+            // The callback owns the value it's given.
+            let value = JV { ptr: value };
+            let hooks = unsafe { &mut *(data as *mut Hooks) };
+            hooks.interrupt_handle.halt_if_interrupted();
+            if let Some((function, args)) = hooks.functions.unwrap_call(&value) {
+                return hooks.call_function(function, args);
+            }
+            if prelude::is_vars_request(&value) {
+                hooks.result = Some(hooks.vars.clone());
+                return;
+            }
+            #[cfg(feature = "tz")]
+            {
+                if let Some(request) = prelude::unwrap_timezone_request(&value) {
+                    let reply = crate::timezone::reply(hooks.timezone.as_ref(), &request);
+                    hooks.result = Some(reply);
+                    return;
+                }
+            }
+            match prelude::unwrap_stderr(&value) {
+                Some(value) => hooks.call(true, &value),
+                None => hooks.call(false, &value),
+            }
+        }
+        // ... and then read the result of the function with `input`. Without
+        // one waiting, this gives the same error `input` raises when there's
+        // no callback at all.
+        unsafe extern "C" fn input_cb(_: *mut jq_state, data: *mut c_void) -> jv {
+            let hooks = &mut *(data as *mut Hooks);
+            hooks.interrupt_handle.halt_if_interrupted();
+            match hooks.result.take() {
+                Some(result) => result.into_raw(),
+                None => JV::invalid_with_msg("No more inputs").into_raw(),
+            }
+        }
+        unsafe {
+            let hooks: *mut Hooks = &mut *jq.hooks;
+            jq_set_debug_cb(jq.state, Some(debug_cb), hooks as *mut c_void);
+            jq_set_input_cb(jq.state, Some(input_cb), hooks as *mut c_void);
+        }
+
+        // libjq trips an assertion when a program imports a module without a
+        // search path having been set, so there always is one.
+        let search = library_paths.iter().fold(JV::array(), |search, path| {
+            search.array_append(JV::string(path))
+        });
+        unsafe {
+            jq_set_attr(
+                jq.state,
+                JV::string("JQ_LIBRARY_PATH").into_raw(),
+                search.into_raw(),
+            );
+        }
+        // The jq cli uses its own directory for `$ORIGIN` in search paths,
+        // and libjq asserts there is one when it's used.
+        if library_paths
+            .iter()
+            .any(|path| path.starts_with("$ORIGIN/"))
+        {
+            let exe = std::env::current_exe().ok();
+            let origin = exe
+                .as_ref()
+                .and_then(|exe| exe.parent()?.to_str())
+                .unwrap_or(".");
+            unsafe {
+                jq_set_attr(
+                    jq.state,
+                    JV::string("JQ_ORIGIN").into_raw(),
+                    JV::string(origin).into_raw(),
+                );
+            }
+        }
+
+        let definitions = functions.definitions();
+        let compiled = prelude::with_prelude(program, overrides, &definitions);
+
+        // Built the same way as the `program_arguments` in jq's `main.c`.
+        let mut named_args = JV::object();
+        for (name, value) in named {
+            named_args = named_args.object_set(JV::string(&name), value);
+        }
+        let args_value = JV::object()
+            .object_set(JV::string("positional"), JV::array())
+            .object_set(JV::string("named"), named_args.clone());
+        let args = named_args.object_set(JV::string("ARGS"), args_value);
+
+        let compiled_c = CString::new(compiled.as_str())?;
+        let ok = unsafe { jq_compile_args(jq.state, compiled_c.as_ptr(), args.into_raw()) } != 0;
+        // The prelude is an implementation detail, so it's kept out of what
+        // libjq reported.
+        let reported = jq
+            .reported
+            .drain(..)
+            .map(|msg| prelude::strip_from_message(&msg, program, &compiled));
+        if ok {
+            jq.warnings = reported.collect();
             Ok(jq)
+        } else {
+            let reason = reported.map(|msg| format!("{}\n", msg)).collect();
+            Err(Error::InvalidProgram { reason })
         }
     }
 
+    pub fn format(&self) -> Format {
+        self.format.clone()
+    }
+
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Bind the variables declared with `Compiler::var()` from `vars`, an
+    /// object, for the runs which follow.
+    pub fn set_vars(&mut self, vars: JV) {
+        self.hooks.vars = vars;
+    }
+
+    /// Have `localtime` and `strflocaltime` use `timezone` for the runs
+    /// which follow, or the machine's zone when it's `None`.
+    #[cfg(feature = "tz")]
+    pub fn set_timezone(&mut self, timezone: Option<crate::TimeZone>) {
+        self.hooks.timezone = timezone;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Fail with `Error::InputTooLarge` when `bytes` of input is more than
+    /// the program takes.
+    pub fn check_input_bytes(&self, bytes: usize) -> Result<()> {
+        self.limits.check_input_bytes(bytes)
+    }
+
+    pub fn set_non_finite(&mut self, policy: NonFinite) {
+        self.non_finite = policy;
+    }
+
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics = metrics;
+    }
+
+    pub fn set_preprocessor(&mut self, preprocessor: Option<SharedPreProcessor>) {
+        self.preprocessor = preprocessor;
+    }
+
+    #[cfg(feature = "locale")]
+    pub fn set_c_locale(&mut self, enabled: bool) {
+        self.c_locale = enabled;
+    }
+
+    /// Drop whatever the last run left behind, leaving the settings alone.
+    ///
+    /// Every run starts by resetting jq's state anyway, so this is only
+    /// needed to let go of it sooner. A run which stopped early (failing,
+    /// halting, or going over a limit) leaves jq holding on to the rest of
+    /// its work, including references to its input.
+    pub fn reset(&mut self) {
+        self.reported.clear();
+        self.hooks.stderr_buf.clear();
+        self.hooks.result = None;
+        self.hooks.vars = JV::object();
+        self.parse_time = Duration::default();
+        self.stats = RunStats::default();
+        unsafe {
+            // libjq 1.6 doesn't export `jq_reset()`, but it's the first thing
+            // `jq_start()` does. The frame this leaves for `null` is never run,
+            // and goes with the next reset.
+            jq_start(self.state, jq_sys::jv_null(), 0);
+        }
+    }
+
+    /// Free the jq state, giving back an error with anything libjq reported
+    /// along the way.
+    pub fn close(mut self) -> Result<()> {
+        self.reported.clear();
+        self.teardown();
+        if self.reported.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Exec {
+                reason: format!(
+                    "libjq reported problems while tearing down: {}",
+                    self.reported.join("; ")
+                ),
+                payload: None,
+            })
+        }
+    }
+
+    /// Free the jq state, unless that's already been done.
+    fn teardown(&mut self) {
+        if self.state.is_null() {
+            return;
+        }
+        // This also sets the pointer to null.
+        unsafe { jq_teardown(&mut self.state) }
+    }
+
+    /// A handle for stopping runs of this program from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt_handle.clone()
+    }
+
+    /// Have `handler` called with the stats for each run once it's done.
+    pub fn set_stats_handler(&mut self, handler: StatsHandler) {
+        self.stats_handler = Some(handler);
+    }
+
+    /// Numbers from the last run.
+    pub fn stats(&self) -> RunStats {
+        self.stats
+    }
+
+    /// Take the warnings jq reported while compiling the program.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Have `handler` called with each value the program passes to jq's
+    /// `debug` builtin, rendered as json.
+    pub fn set_debug_handler(&mut self, handler: Handler) {
+        self.hooks.debug = Some(handler);
+    }
+
+    /// Have `handler` called with each value the program passes to jq's
+    /// `stderr` builtin, rendered as json, rather than collecting them for
+    /// `take_stderr()`.
+    pub fn set_stderr_handler(&mut self, handler: Handler) {
+        self.hooks.stderr = Some(handler);
+    }
+
+    /// Go back to having no `debug`, `stderr` or stats handlers.
+    pub fn clear_handlers(&mut self) {
+        self.hooks.debug = None;
+        self.hooks.stderr = None;
+        self.stats_handler = None;
+    }
+
+    /// Take what the last run wrote with `stderr`, when there's no handler
+    /// for it.
+    pub fn take_stderr(&mut self) -> String {
+        std::mem::take(&mut self.hooks.stderr_buf)
+    }
+
+    /// Build an error for a failed run, including anything jq reported
+    /// through the error callback along the way.
+    fn runtime_error(&self, reason: String, payload: Option<String>) -> Error {
+        let reason = if self.reported.is_empty() {
+            reason
+        } else {
+            format!("{}\n{}", self.reported.join("\n"), reason)
+        };
+        Error::Exec { reason, payload }
+    }
+
     fn is_halted(&self) -> bool {
         unsafe { jq_halted(self.state) != 0 }
     }
 
-    fn get_exit_code(&self) -> ExitCode {
+    /// Let go of the exit code and message of a halt once they've been read.
+    ///
+    /// libjq 1.6's reset frees them without clearing them, so every later
+    /// reset (the start of each run, and the teardown) would free them again.
+    /// Resetting once here and halting with nothing leaves values which are
+    /// safe to free any number of times.
+    fn forget_halt(&mut self) {
+        unsafe {
+            jq_start(self.state, jq_sys::jv_null(), 0);
+            jq_halt(self.state, jq_sys::jv_invalid(), jq_sys::jv_invalid());
+        }
+    }
+
+    /// The exit status the jq cli would use after the program halted.
+    ///
+    /// This follows the `if (jq_halted(jq))` block in the `process()` function
+    /// of jq's `main.c`: `halt` leaves no exit code (a clean exit), while
+    /// `halt_error` defaults to `5`.
+    fn get_exit_code(&self) -> i32 {
         let exit_code = JV {
             ptr: unsafe { jq_get_exit_code(self.state) },
         };
 
-        // The rules for this seem odd, but I'm trying to model this after the
-        // similar block in the jq `main.c`s `process()` function.
-
-        if exit_code.is_valid() {
-            ExitCode::JQ_OK
+        if !exit_code.is_valid() {
+            0
         } else {
             exit_code
                 .as_number()
-                .map(|i| (i as isize).into())
-                .unwrap_or(ExitCode::JQ_ERROR_UNKNOWN)
+                .map(|n| n as i32)
+                .unwrap_or(JQ_ERROR_UNKNOWN)
+        }
+    }
+
+    /// The value given to `halt_error`, rendered the way the jq cli prints it
+    /// to stderr: strings as they are, other values as json.
+    ///
+    /// `None` after a plain `halt`, or when the value was `null`.
+    fn get_error_message(&self) -> Option<String> {
+        let msg = JV {
+            ptr: unsafe { jq_get_error_message(self.state) },
+        };
+        if !msg.is_valid() || msg.is_null() {
+            None
+        } else if let Ok(text) = msg.as_string() {
+            Some(text)
+        } else {
+            let mut rendered = String::new();
+            msg.dump_into(&mut rendered);
+            Some(rendered)
         }
     }
 
-    /// Run the jq program against an input.
-    pub fn execute(&mut self, input: CString) -> Result<String> {
+    /// Run the jq program against an input, appending the rendered results to
+    /// `buf`.
+    ///
+    /// The `interrupt` is checked between each output produced by the program.
+    pub fn execute(&mut self, input: &str, interrupt: &Interrupt, buf: &mut String) -> Result<()> {
+        or_restore(buf, |buf| {
+            self.execute_full(input, interrupt, buf)?.into_result()
+        })
+    }
+
+    /// Like `execute()`, but when the run fails the outputs it produced
+    /// before failing are left in `buf`.
+    pub fn execute_partial(
+        &mut self,
+        input: &str,
+        interrupt: &Interrupt,
+        buf: &mut String,
+    ) -> Result<()> {
+        let value = self.parse_input(input)?;
+        self.render_outputs(value, interrupt, buf)?.into_result()
+    }
+
+    /// Like `execute()`, but a halt with a non-zero exit code is reported
+    /// as a `Completion` rather than an error, keeping the outputs produced
+    /// before the halt.
+    pub fn execute_full(
+        &mut self,
+        input: &str,
+        interrupt: &Interrupt,
+        buf: &mut String,
+    ) -> Result<Completion> {
+        let value = self.parse_input(input)?;
+        self.process(value, interrupt, buf)
+    }
+
+    /// Parse the input for a run, after checking it isn't too large and
+    /// running it through the pre-processor.
+    fn parse_input(&mut self, input: &str) -> Result<JV> {
+        self.check_input_bytes(input.len())?;
+        let input = match &self.preprocessor {
+            Some(preprocessor) => preprocessor.apply(input)?,
+            None => Cow::Borrowed(input),
+        };
+        // libjq 1.6 offers no way to reset a `jv_parser`. Once a value has been
+        // read, the parser hangs on to state from the end of the buffer (so
+        // the next buffer is read as a continuation), and setting a new buffer
+        // before the old one is exhausted trips an assertion. A fresh parser
+        // per run is the only safe option.
         let mut parser = Parser::new();
-        self.process(parser.parse(input)?)
+        let started = Instant::now();
+        let value = parser.parse(&input)?;
+        self.parse_time = started.elapsed();
+        Ok(value)
+    }
+
+    /// Run the jq program against an input which has already been parsed,
+    /// appending the rendered results to `buf`.
+    ///
+    /// jq is handed its own reference to the value, so `input` is untouched
+    /// and can be run again.
+    pub fn execute_parsed(
+        &mut self,
+        input: &JV,
+        interrupt: &Interrupt,
+        buf: &mut String,
+    ) -> Result<()> {
+        or_restore(buf, |buf| {
+            self.process(input.clone(), interrupt, buf)?.into_result()
+        })
+    }
+
+    /// Run the jq program against an input, collecting the results as
+    /// `serde_json::Value`s.
+    ///
+    /// The values are converted straight from jq's representation, skipping
+    /// the render/re-parse round trip.
+    #[cfg(feature = "serde_json")]
+    pub fn execute_values(
+        &mut self,
+        input: &str,
+        interrupt: &Interrupt,
+    ) -> Result<Vec<serde_json::Value>> {
+        let value = self.parse_input(input)?;
+        self.collect_values(value, interrupt)
+    }
+
+    /// Like `execute_values()`, for an input which is already a `JV`.
+    #[cfg(feature = "serde_json")]
+    pub fn collect_values(
+        &mut self,
+        input: JV,
+        interrupt: &Interrupt,
+    ) -> Result<Vec<serde_json::Value>> {
+        let mut values = vec![];
+        self.start(input, interrupt, |value| {
+            values.push(value.to_value());
+            Ok(0)
+        })?
+        .into_result()?;
+        Ok(values)
+    }
+
+    /// Run the jq program against an input, handing each output to `emit` as
+    /// a `JV` instead of rendering it.
+    #[cfg(feature = "serde")]
+    pub fn execute_each<F>(&mut self, input: &str, interrupt: &Interrupt, mut emit: F) -> Result<()>
+    where
+        F: FnMut(&JV) -> Result<()>,
+    {
+        let value = self.parse_input(input)?;
+        self.start(value, interrupt, |value| emit(&value).map(|_| 0))?
+            .into_result()
+    }
+
+    /// Run the jq program against an input, handing each output to `emit`
+    /// rendered on its own, without the newline which would follow it.
+    pub fn execute_split<F>(
+        &mut self,
+        input: &str,
+        interrupt: &Interrupt,
+        mut emit: F,
+    ) -> Result<()>
+    where
+        F: FnMut(String),
+    {
+        let value = self.parse_input(input)?;
+        let format = Format {
+            join: true,
+            ..self.format.clone()
+        };
+        self.start(value, interrupt, |value| {
+            let mut rendered = String::new();
+            let len = format.render(&value, &mut rendered)?;
+            emit(rendered);
+            Ok(len)
+        })?
+        .into_result()
     }
 
-    /// Unwind the parser and return the rendered result.
+    /// Unwind the parser and push the rendered result into the buffer.
     ///
     /// When this results in `Err`, the String value should contain a message about
-    /// what failed.
-    fn process(&mut self, initial_value: JV) -> Result<String> {
-        let mut buf = String::new();
+    /// what failed, and the buffer is left as it was found.
+    fn process(
+        &mut self,
+        initial_value: JV,
+        interrupt: &Interrupt,
+        buf: &mut String,
+    ) -> Result<Completion> {
+        or_restore(buf, |buf| {
+            self.render_outputs(initial_value, interrupt, buf)
+        })
+    }
+
+    /// Run the program, pushing each output into the buffer as it's rendered.
+    fn render_outputs(
+        &mut self,
+        initial_value: JV,
+        interrupt: &Interrupt,
+        buf: &mut String,
+    ) -> Result<Completion> {
+        let format = self.format.clone();
+        self.start(initial_value, interrupt, |value| format.render(&value, buf))
+    }
+
+    /// Start the program on a parsed input, handing each output to `emit`,
+    /// which gives back the number of bytes it rendered the output as (if
+    /// any) for the stats.
+    ///
+    /// Each `Jq` has its own state, so `emit` is free to run a different `Jq`
+    /// while this one is paused between outputs.
+    pub fn start<F>(
+        &mut self,
+        initial_value: JV,
+        interrupt: &Interrupt,
+        mut emit: F,
+    ) -> Result<Completion>
+    where
+        F: FnMut(JV) -> Result<usize>,
+    {
+        // Anything left over from a previous run is stale.
+        self.reported.clear();
+        self.hooks.stderr_buf.clear();
+        // Held until the run is over, including the calls to `emit`.
+        #[cfg(feature = "locale")]
+        let _locale = if self.c_locale {
+            locale::CLocale::enter()
+        } else {
+            None
+        };
 
         unsafe {
-            // `jq_start` seems to be a consuming call.
-            // In order to avoid a double-free, when `initial_value` is dropped,
-            // we have to use `jv_copy` on the inner `jv`.
-            jq_start(self.state, jv_copy(initial_value.ptr), 0);
-            // After, we can manually free the `initial_value` with `drop` since
-            // it is no longer needed.
-            drop(initial_value);
+            // `jq_start` is a consuming call, so ownership of the value is
+            // handed over without running the `JV`'s `Drop`.
+            jq_start(self.state, initial_value.into_raw(), 0);
+        }
+        self.interrupt_handle.begin(self.state);
+        let mut stats = RunStats {
+            parse_time: std::mem::take(&mut self.parse_time),
+            ..RunStats::default()
+        };
+        let started = Instant::now();
+        let limits = self.limits;
+        let non_finite = self.non_finite;
+        let completion = unsafe {
+            drain(self, interrupt, |value| {
+                limits.check_outputs(stats.outputs + 1)?;
+                stats.outputs += 1;
+                stats.output_bytes += emit(non_finite.apply(value)?)?;
+                limits.check_output_bytes(stats.output_bytes)
+            })
+        };
+        // Time spent in `emit` is included, which can't be helped for
+        // pipelines where it's running the next stage.
+        stats.eval_time = started.elapsed();
+        let interrupted = self.interrupt_handle.end();
+        if self.is_halted() {
+            self.forget_halt();
+        }
+
+        if let Some(panic) = self.hooks.panic.take() {
+            panic::resume_unwind(panic);
+        }
+        self.stats = stats;
+        if let Some(handler) = &mut self.stats_handler {
+            handler(&stats);
+        }
+        let completion = if interrupted {
+            // The program can't tell an interrupt from a plain `halt`.
+            Err(Error::Interrupted)
+        } else {
+            completion
+        };
+        #[cfg(feature = "otel")]
+        crate::otel::record_run(&self.program_hash, &stats, &completion);
+        self.report_metrics(&stats, &completion);
+        completion
+    }
+
+    fn report_metrics(&self, stats: &RunStats, completion: &Result<Completion>) {
+        let metrics = &self.metrics.0;
+        metrics.count_run();
+        match completion {
+            Ok(Completion::Halted { exit_code, .. }) if *exit_code != 0 => {
+                metrics.count_error(ErrorKind::Halted)
+            }
+            Ok(_) => {}
+            Err(err) => metrics.count_error(err.kind()),
+        }
+        metrics.observe_duration(stats.parse_time + stats.eval_time);
+        metrics.observe_output_bytes(stats.output_bytes);
+    }
+}
+
+/// Caps on what a single run can take in and produce.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Checked before the input is parsed, or as it's read when streaming.
+    pub input_bytes: Option<usize>,
+    pub outputs: Option<usize>,
+    /// Counted as the outputs are rendered, so outputs which are converted
+    /// to values rather than text don't count.
+    pub output_bytes: Option<usize>,
+}
+
+impl Limits {
+    fn check_input_bytes(&self, bytes: usize) -> Result<()> {
+        match self.input_bytes {
+            Some(max) if bytes > max => Err(Error::InputTooLarge { bytes, max }),
+            _ => Ok(()),
+        }
+    }
 
-            dump(self, &mut buf)?;
+    fn check_outputs(&self, outputs: usize) -> Result<()> {
+        match self.outputs {
+            Some(max) if outputs > max => Err(Error::LimitExceeded {
+                reason: format!("More than {} outputs", max),
+            }),
+            _ => Ok(()),
         }
+    }
 
-        Ok(buf)
+    fn check_output_bytes(&self, bytes: usize) -> Result<()> {
+        match self.output_bytes {
+            Some(max) if bytes > max => Err(Error::LimitExceeded {
+                reason: format!("More than {} bytes of output", max),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How outputs are rendered as text.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Format {
+    /// Flags for `jv_dump_string`.
+    pub dump_flags: c_int,
+    /// Write strings as they are rather than as json, like `jq -r`.
+    pub raw: bool,
+    /// Leave out the newline after each output, like `jq -j`.
+    pub join: bool,
+    /// Write canonical json rather than using `dump_flags`.
+    pub canonical: bool,
+    /// Write pretty printed canonical json rather than using `dump_flags`.
+    pub stable: bool,
+    /// Write HTML with these classes, laid out following `dump_flags`.
+    pub html: Option<Arc<HtmlClasses>>,
+    /// Color outputs from this palette rather than with `JV_PRINT_COLOR`,
+    /// laid out following `dump_flags`.
+    pub palette: Option<Arc<ColorPalette>>,
+    /// What each rendered output goes through before it's kept.
+    pub postprocessor: Option<SharedPostProcessor>,
+}
+
+impl Format {
+    /// Push the rendering of `value` into `buf`, after the post-processor
+    /// has had it, giving back the number of bytes added.
+    pub fn render(&self, value: &JV, buf: &mut String) -> Result<usize> {
+        let len = buf.len();
+        if self.raw && value.is_string() {
+            let text = unsafe { value.as_str_lossy() };
+            if self.html.is_some() {
+                html::escape_into(&text, buf);
+            } else {
+                buf.push_str(&text);
+            }
+        } else if let Some(classes) = &self.html {
+            value.dump_html(self.dump_flags, classes, buf);
+        } else if self.canonical {
+            value.dump_canonical(buf);
+        } else if self.stable {
+            value.dump_stable(buf);
+        } else if let Some(palette) = &self.palette {
+            value.dump_colored(self.dump_flags, palette, buf);
+        } else {
+            value.dump_with(self.dump_flags, buf);
+        }
+        if !self.join {
+            buf.push('\n');
+        }
+        if let Some(postprocessor) = &self.postprocessor {
+            postprocessor.apply(buf, len)?;
+        }
+        Ok(buf.len() - len)
     }
 }
 
+/// How a run which didn't fail came to an end.
+pub enum Completion {
+    /// The program produced all of its outputs.
+    Finished,
+    /// The program stopped itself with `halt` or `halt_error`.
+    Halted {
+        exit_code: i32,
+        payload: Option<String>,
+    },
+}
+
+impl Completion {
+    /// Like the jq cli, a halt is only a failure when it comes with a
+    /// non-zero exit code.
+    pub fn into_result(self) -> Result<()> {
+        match self {
+            Completion::Halted { exit_code, payload } if exit_code != 0 => {
+                Err(Error::Halted { exit_code, payload })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Hands `buf` to `f`, putting it back the way it was found if `f` fails.
+fn or_restore<T, F>(buf: &mut String, f: F) -> Result<T>
+where
+    F: FnOnce(&mut String) -> Result<T>,
+{
+    let start = buf.len();
+    let res = f(buf);
+    if res.is_err() {
+        buf.truncate(start);
+    }
+    res
+}
+
 impl Drop for Jq {
     fn drop(&mut self) {
-        unsafe { jq_teardown(&mut self.state) }
+        self.teardown();
     }
 }
 
-struct JV {
+// The jq state isn't safe for concurrent use, but nothing ties it to the
+// thread that created it. libjq keeps its thread-specific bits (the nomem
+// handler, dtoa context) in thread local storage and re-installs them on each
+// call, so moving the whole state to another thread is fine.
+//
+// The callbacks are handed pointers to `reported` and `hooks`, which are boxed
+// so those pointers stay good wherever the `Jq` itself is moved to.
+unsafe impl Send for Jq {}
+
+pub struct JV {
     ptr: jv,
 }
 
 impl JV {
-    /// Convert the current `JV` into the "dump string" rendering of itself.
-    pub fn as_dump_string(&self) -> Result<String> {
+    /// Give up ownership of the inner `jv` without freeing it.
+    ///
+    /// Use this when passing the value to a jq function which consumes its
+    /// arguments, otherwise the value will be freed twice.
+    pub fn into_raw(self) -> jv {
+        let ptr = self.ptr;
+        std::mem::forget(self);
+        ptr
+    }
+
+    /// Push the "dump string" rendering of the current `JV` into the buffer.
+    pub fn dump_into(&self, buf: &mut String) {
+        self.dump_with(0, buf)
+    }
+
+    /// Like `dump_into()`, with flags for `jv_dump_string` such as
+    /// `JV_PRINT_PRETTY`.
+    fn dump_with(&self, flags: c_int, buf: &mut String) {
         let dump = JV {
-            ptr: unsafe { jv_dump_string(jv_copy(self.ptr), 0) },
+            ptr: unsafe { jv_dump_string(jv_copy(self.ptr), flags) },
         };
-        unsafe { get_string_value(jv_string_value(dump.ptr)) }
+        buf.push_str(&unsafe { dump.as_str_lossy() });
+    }
+
+    /// Build a string `JV`, which may hold nul bytes.
+    pub fn string(text: &str) -> JV {
+        JV {
+            ptr: unsafe { jv_string_sized(text.as_ptr() as *const c_char, text.len() as c_int) },
+        }
+    }
+
+    pub fn object() -> JV {
+        JV {
+            ptr: unsafe { jv_object() },
+        }
     }
 
-    /// Attempts to extract feedback from jq if the JV is invalid.
+    pub fn array() -> JV {
+        JV {
+            ptr: unsafe { jv_array() },
+        }
+    }
+
+    /// Add an item to the end of an array `JV`, consuming both like jq does.
+    pub fn array_append(self, item: JV) -> JV {
+        JV {
+            ptr: unsafe { jv_array_append(self.into_raw(), item.into_raw()) },
+        }
+    }
+
+    /// Set a key on an object `JV`, consuming all three like jq does.
+    pub fn object_set(self, key: JV, value: JV) -> JV {
+        JV {
+            ptr: unsafe { jv_object_set(self.into_raw(), key.into_raw(), value.into_raw()) },
+        }
+    }
+
+    /// The bytes of a string `JV`, including any nul bytes.
+    ///
+    /// Safety: the `JV` must be a string.
+    unsafe fn string_bytes(&self) -> &[u8] {
+        let len = jv_string_length_bytes(jv_copy(self.ptr)) as usize;
+        std::slice::from_raw_parts(jv_string_value(self.ptr) as *const u8, len)
+    }
+
+    /// Borrow the text of a string `JV`, replacing any invalid UTF-8.
+    ///
+    /// jq replaces invalid sequences while parsing, but `implode` can still
+    /// build strings holding lone surrogates, so the bytes are always
+    /// checked. Valid text (the usual case) is borrowed without a copy.
+    ///
+    /// Safety: the `JV` must be a string.
+    unsafe fn as_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.string_bytes())
+    }
+
+    /// Attempts to extract feedback from jq if the JV is invalid (such as the
+    /// reason the parser gave up).
     pub fn get_msg(&self) -> Option<String> {
         if self.invalid_has_msg() {
             let reason = {
@@ -146,10 +1054,7 @@ impl JV {
                     },
                 };
 
-                format!(
-                    "JQ: Parse error: {}",
-                    msg.as_string().unwrap_or_else(|_| "unknown".into())
-                )
+                msg.as_string().unwrap_or_else(|_| "unknown".into())
             };
             Some(reason)
         } else {
@@ -157,6 +1062,31 @@ impl JV {
         }
     }
 
+    /// Describes the error carried by an invalid `JV` which came out of a
+    /// running program.
+    ///
+    /// Programs can raise errors with any value (`error({"code": 1})`), so
+    /// those which aren't strings are rendered as json, same as the jq cli.
+    /// That json is also handed back as the second item so the value isn't
+    /// lost in the message.
+    pub fn get_error_msg(&self) -> Option<(String, Option<String>)> {
+        if !self.invalid_has_msg() {
+            return None;
+        }
+        let msg = JV {
+            ptr: unsafe { jv_invalid_get_msg(jv_copy(self.ptr)) },
+        };
+        match msg.as_string() {
+            Ok(text) => Some((format!("JQ: Error: {}", text), None)),
+            Err(_) => {
+                let mut rendered = String::new();
+                msg.dump_into(&mut rendered);
+                let reason = format!("JQ: Error (not a string): {}", rendered);
+                Some((reason, Some(rendered)))
+            }
+        }
+    }
+
     pub fn as_number(&self) -> Option<f64> {
         unsafe {
             if jv_get_kind(self.ptr) == jv_kind_JV_KIND_NUMBER {
@@ -167,6 +1097,22 @@ impl JV {
         }
     }
 
+    /// The items of an array, or `None` for anything else.
+    pub fn array_items(&self) -> Option<Vec<JV>> {
+        unsafe {
+            if jv_get_kind(self.ptr) != jv_kind_JV_KIND_ARRAY {
+                return None;
+            }
+            let len = jv_array_length(jv_copy(self.ptr));
+            let items = (0..len)
+                .map(|idx| JV {
+                    ptr: jv_array_get(jv_copy(self.ptr), idx),
+                })
+                .collect();
+            Some(items)
+        }
+    }
+
     pub fn as_string(&self) -> Result<String> {
         unsafe {
             if jv_get_kind(self.ptr) == jv_kind_JV_KIND_STRING {
@@ -177,6 +1123,14 @@ impl JV {
         }
     }
 
+    pub fn is_null(&self) -> bool {
+        unsafe { jv_get_kind(self.ptr) == jv_kind_JV_KIND_NULL }
+    }
+
+    pub fn is_string(&self) -> bool {
+        unsafe { jv_get_kind(self.ptr) == jv_kind_JV_KIND_STRING }
+    }
+
     pub fn is_valid(&self) -> bool {
         unsafe { jv_get_kind(self.ptr) != jv_kind_JV_KIND_INVALID }
     }
@@ -184,6 +1138,46 @@ impl JV {
     pub fn invalid_has_msg(&self) -> bool {
         unsafe { jv_invalid_has_msg(jv_copy(self.ptr)) == 1 }
     }
+
+    /// An invalid value carrying a message, which jq raises as an error.
+    pub fn invalid_with_msg(msg: &str) -> JV {
+        JV {
+            ptr: unsafe { jq_sys::jv_invalid_with_msg(JV::string(msg).into_raw()) },
+        }
+    }
+}
+
+/// Builders for values converted from other formats. Which of these get used
+/// depends on the features enabled.
+#[allow(dead_code)]
+impl JV {
+    pub fn bool(value: bool) -> JV {
+        JV {
+            ptr: unsafe { jq_sys::jv_bool(value as c_int) },
+        }
+    }
+
+    pub fn number(value: f64) -> JV {
+        JV {
+            ptr: unsafe { jq_sys::jv_number(value) },
+        }
+    }
+
+    pub fn null() -> JV {
+        JV {
+            ptr: unsafe { jq_sys::jv_null() },
+        }
+    }
+}
+
+impl Clone for JV {
+    /// jv values are immutable and reference counted, so this only bumps the
+    /// count.
+    fn clone(&self) -> Self {
+        JV {
+            ptr: unsafe { jv_copy(self.ptr) },
+        }
+    }
 }
 
 impl Drop for JV {
@@ -192,18 +1186,171 @@ impl Drop for JV {
     }
 }
 
-struct Parser {
+pub struct Parser {
     ptr: *mut jv_parser,
+    /// How much of the input came before the current buffer, for working out
+    /// where errors happened.
+    position: Position,
 }
 
+/// A spot in the input the parser is reading.
+#[derive(Default)]
+struct Position {
+    /// Bytes read so far.
+    offset: usize,
+    /// Newlines read so far.
+    lines: usize,
+    /// Offset of the first byte of the current line.
+    line_start: usize,
+}
+
+// Like `Jq`, nothing ties the parser to the thread it was created on, and the
+// values it holds while partway through an input belong to it alone.
+unsafe impl Send for Parser {}
+
 impl Parser {
     pub fn new() -> Self {
+        Self::with_flags(0)
+    }
+
+    /// A parser for jq's `--stream` mode, which breaks values up into
+    /// `[path, leaf]` events as they're read.
+    pub fn streaming() -> Self {
+        Self::with_flags(JV_PARSE_STREAMING as c_int)
+    }
+
+    fn with_flags(flags: c_int) -> Self {
         Self {
-            ptr: unsafe { jv_parser_new(0) },
+            ptr: unsafe { jv_parser_new(flags) },
+            position: Position::default(),
+        }
+    }
+
+    /// Build an `Error::Parse` from the message jq gave for `value`, which
+    /// came out of the parser while it was working through `buf`.
+    fn error(&self, value: &JV, buf: &[u8]) -> Error {
+        let msg = value
+            .get_msg()
+            .unwrap_or_else(|| "No json value in the input".to_string());
+
+        // jq ends every message with the location.
+        let location = msg.rfind(" at line ").and_then(|idx| {
+            let mut numbers = msg[idx + " at line ".len()..].split(", column ");
+            let line = numbers.next()?.parse().ok()?;
+            let column = numbers.next()?.parse().ok()?;
+            Some((idx, line, column))
+        });
+
+        match location {
+            Some((idx, line, column)) => {
+                let offset = self.offset_of(line, column, buf);
+                let (excerpt, caret) = excerpt(buf, offset.saturating_sub(self.position.offset));
+                Error::Parse {
+                    reason: msg[..idx].to_string(),
+                    line,
+                    column,
+                    offset,
+                    excerpt,
+                    caret,
+                }
+            }
+            None => Error::Parse {
+                reason: msg,
+                line: 0,
+                column: 0,
+                offset: self.position.offset,
+                excerpt: String::new(),
+                caret: 0,
+            },
+        }
+    }
+
+    /// Work out the byte offset of a line and column reported by jq, where
+    /// the line is either the current line or starts within `buf`.
+    ///
+    /// jq notices a problem as it reads the byte _after_ the offending text,
+    /// and its column counts the bytes read on the line including that one.
+    /// So the result is the offset of the byte which gave the problem away
+    /// (and with column `0`, that's the newline ending the previous line).
+    fn offset_of(&self, line: usize, column: usize, buf: &[u8]) -> usize {
+        // How many of the newlines in `buf` come before the line.
+        let skip = line.saturating_sub(self.position.lines + 1);
+        let line_start = buf
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte == b'\n')
+            .take(skip)
+            .last()
+            .map_or(self.position.line_start, |(idx, _)| {
+                self.position.offset + idx + 1
+            });
+        (line_start + column)
+            .saturating_sub(1)
+            .min(self.position.offset + buf.len())
+    }
+
+    /// Move the position past a buffer which has been parsed.
+    fn advance(&mut self, buf: &[u8]) {
+        let newlines = buf.iter().filter(|byte| **byte == b'\n').count();
+        if newlines > 0 {
+            let last = buf.iter().rposition(|byte| *byte == b'\n').unwrap_or(0);
+            self.position.lines += newlines;
+            self.position.line_start = self.position.offset + last + 1;
+        }
+        self.position.offset += buf.len();
+    }
+
+    /// Feed the next chunk of a larger input to the parser, handing each
+    /// complete value to `emit`.
+    ///
+    /// A value can span several chunks, in which case the parser holds on to
+    /// the start of it until the rest arrives. Set `is_last` for the final
+    /// chunk (which can be empty) so a value at the very end of the input is
+    /// finished off.
+    ///
+    /// The parser can't be fed again after this returns an error.
+    pub fn feed<F>(&mut self, chunk: &[u8], is_last: bool, mut emit: F) -> Result<()>
+    where
+        F: FnMut(JV) -> Result<()>,
+    {
+        // jq's flag is the other way around, marking the chunks which are
+        // _not_ the last.
+        let is_partial = !is_last;
+        unsafe {
+            jv_parser_set_buf(
+                self.ptr,
+                chunk.as_ptr() as *const c_char,
+                chunk.len() as c_int,
+                is_partial as c_int,
+            )
+        };
+
+        // The chunk has to be used up before returning, since the parser only
+        // keeps a pointer to it.
+        loop {
+            let value = JV {
+                ptr: unsafe { jv_parser_next(self.ptr) },
+            };
+            if value.is_valid() {
+                emit(value)?;
+            } else if value.invalid_has_msg() {
+                return Err(self.error(&value, chunk));
+            } else {
+                // Invalid without a message means the parser needs more input.
+                self.advance(chunk);
+                return Ok(());
+            }
         }
     }
 
-    pub fn parse(&mut self, input: CString) -> Result<JV> {
+    pub fn parse(&mut self, input: &str) -> Result<JV> {
+        // The parser reads the buffer as a pointer/length pair so there's no
+        // need to copy the input into a `CString`, but to keep behaving the
+        // same as when we did, interior nul bytes are still rejected.
+        if input.as_bytes().contains(&0) {
+            return Err(CString::new(input).unwrap_err().into());
+        }
+
         // For a single run, we could set this to `1` (aka `true`) but this will
         // break the repeated `JqProgram` usage.
         // It may be worth exposing this to the caller so they can set it for each
@@ -218,8 +1365,8 @@ impl Parser {
         unsafe {
             jv_parser_set_buf(
                 self.ptr,
-                input.as_ptr(),
-                input.as_bytes().len() as i32,
+                input.as_ptr() as *const c_char,
+                input.len() as i32,
                 is_last,
             )
         };
@@ -230,17 +1377,47 @@ impl Parser {
         if value.is_valid() {
             Ok(value)
         } else {
-            Err(Error::System {
-                reason: Some(
-                    value
-                        .get_msg()
-                        .unwrap_or_else(|| "JQ: Parser error".to_string()),
-                ),
-            })
+            Err(self.error(&value, input.as_bytes()))
         }
     }
 }
 
+/// Bytes of context to include either side of a parse error.
+const EXCERPT_CONTEXT: usize = 32;
+
+/// Cut the text around `at` out of `buf`, staying on the same line, for
+/// showing where a parse error happened. Also gives the number of characters
+/// in the excerpt before `at`.
+pub fn excerpt(buf: &[u8], at: usize) -> (String, usize) {
+    let at = at.min(buf.len());
+    let is_char_start = |idx: usize| !matches!(buf.get(idx), Some(b) if b & 0xC0 == 0x80);
+
+    let mut start = at.saturating_sub(EXCERPT_CONTEXT);
+    if let Some(newline) = buf[start..at].iter().rposition(|b| *b == b'\n') {
+        start += newline + 1;
+    }
+    while !is_char_start(start) {
+        start += 1;
+    }
+    let mut end = (at + EXCERPT_CONTEXT).min(buf.len());
+    if let Some(newline) = buf[at..end].iter().position(|b| *b == b'\n') {
+        end = at + newline;
+    }
+    while !is_char_start(end) {
+        end -= 1;
+    }
+
+    // Tabs and other control characters would throw off the caret.
+    let clean = |c: char| if c.is_control() { ' ' } else { c };
+    let before = String::from_utf8_lossy(&buf[start..at.max(start)]);
+    let after = String::from_utf8_lossy(&buf[at.max(start)..end.max(at)]);
+    let caret = before.chars().count();
+    (
+        before.chars().chain(after.chars()).map(clean).collect(),
+        caret,
+    )
+}
+
 impl Drop for Parser {
     fn drop(&mut self) {
         unsafe {
@@ -255,18 +1432,25 @@ unsafe fn get_string_value(value: *const c_char) -> Result<String> {
     Ok(s.to_owned())
 }
 
-/// Renders the data from the parser and pushes it into the buffer.
-unsafe fn dump(jq: &Jq, buf: &mut String) -> Result<()> {
+/// Pulls each output from the running program and hands it to `emit`.
+unsafe fn drain<F>(jq: &Jq, interrupt: &Interrupt, mut emit: F) -> Result<Completion>
+where
+    F: FnMut(JV) -> Result<()>,
+{
     // Looks a lot like an iterator...
 
+    interrupt.check()?;
+    check_interrupted(jq)?;
+
     let mut value = JV {
         ptr: jq_next(jq.state),
     };
 
     while value.is_valid() {
-        let s = value.as_dump_string()?;
-        buf.push_str(&s);
-        buf.push('\n');
+        emit(value)?;
+
+        interrupt.check()?;
+        check_interrupted(jq)?;
 
         value = JV {
             ptr: jq_next(jq.state),
@@ -274,65 +1458,295 @@ unsafe fn dump(jq: &Jq, buf: &mut String) -> Result<()> {
     }
 
     if jq.is_halted() {
-        use ExitCode::*;
-        match jq.get_exit_code() {
-            JQ_ERROR_SYSTEM => Err(Error::System {
-                reason: value.get_msg(),
-            }),
-            // As far as I know, we should not be able to see a compile error
-            // this deep into the execution of a jq program (it would need to be
-            // compiled already, right?)
-            // Still, compile failure is represented by an exit code, so in
-            // order to be exhaustive we have to check for it.
-            JQ_ERROR_COMPILE => Err(Error::InvalidProgram {
-                reason: jq.err_buf.clone(),
-            }),
-            // Any of these `OK_` variants are "success" cases.
-            // I suppose the jq program can halt successfully, or not, or not at
-            // all and still terminate some other way?
-            JQ_OK | JQ_OK_NULL_KIND | JQ_OK_NO_OUTPUT => Ok(()),
-            JQ_ERROR_UNKNOWN => Err(Error::Unknown),
-        }
-    } else if let Some(reason) = value.get_msg() {
-        Err(Error::System {
-            reason: Some(reason),
+        Ok(Completion::Halted {
+            exit_code: jq.get_exit_code(),
+            payload: jq.get_error_message(),
+        })
+    } else if let Some(builtin) = prelude::policy_violation(&value) {
+        Err(Error::Policy {
+            reason: format!("`{}` can't be used in the sandbox", builtin),
         })
+    } else if let Some((reason, payload)) = value.get_error_msg() {
+        Err(jq.runtime_error(reason, payload))
     } else {
-        Ok(())
+        Ok(Completion::Finished)
     }
 }
 
-/// Various exit codes jq checks for during the `if (jq_halted(jq))` branch of
-/// their processing loop.
-///
-/// Adapted from the enum seen in jq's master branch right now.
-/// The numbers seem to line up with the magic numbers seen in
-/// the 1.6 release, though there's no enum that I saw at that point in the git
-/// history.
-#[allow(non_camel_case_types, dead_code)]
-#[rustfmt::skip]
-enum ExitCode {
-    JQ_OK               =  0,
-    JQ_OK_NULL_KIND     = -1,
-    JQ_ERROR_SYSTEM     =  2,
-    JQ_ERROR_COMPILE    =  3,
-    JQ_OK_NO_OUTPUT     = -4,
-    JQ_ERROR_UNKNOWN    =  5,
+/// Stop with `Error::Interrupted` if `interrupt()` was called on the handle
+/// for the run.
+fn check_interrupted(jq: &Jq) -> Result<()> {
+    if jq.interrupt_handle.is_interrupted() {
+        Err(Error::Interrupted)
+    } else {
+        Ok(())
+    }
 }
 
-impl From<isize> for ExitCode {
-    #[rustfmt::skip]
-    fn from(number: isize) -> Self {
-        use ExitCode::*;
-        match number {
-             0 => JQ_OK,
-            -1 => JQ_OK_NULL_KIND,
-             2 => JQ_ERROR_SYSTEM,
-             3 => JQ_ERROR_COMPILE,
-            -4 => JQ_OK_NO_OUTPUT,
-             // `5` is called out explicitly in the jq source, but also "unknown"
-             // seems to make good sense for other unexpected number.
-             5 | _ => JQ_ERROR_UNKNOWN,
+/// The exit code used by the jq cli for `halt_error` without an explicit code,
+/// and when the exit code isn't a number.
+const JQ_ERROR_UNKNOWN: i32 = 5;
+
+#[cfg(test)]
+mod test {
+    use super::{Format, Functions, Jq, Limits, Overrides, Parser, JV};
+    use crate::cancel::Interrupt;
+    use crate::Error;
+    use jq_sys::{
+        jq_report_error, jv, jv_array, jv_array_append, jv_copy, jv_free, jv_get_refcnt, jv_number,
+        jv_string,
+    };
+    use std::ffi::CString;
+
+    fn refcnt(value: &JV) -> i32 {
+        unsafe { jv_get_refcnt(value.ptr) }
+    }
+
+    extern "C" {
+        // Not among the bindings `jq-sys` generates.
+        fn jv_string_implode(codepoints: jv) -> jv;
+    }
+
+    /// A string holding a lone surrogate, which libjq 1.6's `implode` encodes
+    /// as invalid UTF-8. The prelude stops programs building these.
+    pub(crate) fn lone_surrogate() -> JV {
+        JV {
+            ptr: unsafe { jv_string_implode(jv_array_append(jv_array(), jv_number(55296.0))) },
         }
     }
+
+    /// Run `f` with the thread in a German locale, which writes numbers with
+    /// a decimal comma.
+    ///
+    /// The tests using this are ignored by default, since the locale isn't
+    /// installed everywhere. Run them with `cargo test -- --ignored` where it
+    /// is.
+    #[cfg(unix)]
+    fn in_german<T, F: FnOnce() -> T>(f: F) -> T {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let name = b"de_DE.UTF-8\0";
+        unsafe {
+            let german = libc::newlocale(
+                libc::LC_ALL_MASK,
+                name.as_ptr() as *const libc::c_char,
+                std::ptr::null_mut(),
+            );
+            assert!(!german.is_null(), "the de_DE.UTF-8 locale isn't installed");
+            let previous = libc::uselocale(german);
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            libc::uselocale(previous);
+            libc::freelocale(german);
+            result.unwrap_or_else(|panic| panic::resume_unwind(panic))
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs the de_DE.UTF-8 locale"]
+    fn numbers_ignore_the_locale() {
+        let output = in_german(|| {
+            let mut program = crate::compile(
+                r#"., (. * 2 | tostring), ("2.5" | tonumber), "\(.)", (0 | strftime("%A"))"#,
+            )
+            .unwrap();
+            program.run("1.25").unwrap()
+        });
+        // The day's name shows the locale was in effect.
+        assert_eq!(output, "1.25\n\"2.5\"\n2.5\n\"1.25\"\n\"Donnerstag\"\n");
+    }
+
+    #[cfg(all(unix, feature = "locale"))]
+    #[test]
+    #[ignore = "needs the de_DE.UTF-8 locale"]
+    fn c_locale() {
+        let (c, german) = in_german(|| {
+            let program = r#"0 | strftime("%A"), (. * 1.5)"#;
+            let mut c = crate::Compiler::new()
+                .c_locale(true)
+                .compile(program)
+                .unwrap();
+            let mut german = crate::compile(program).unwrap();
+            (c.run("null").unwrap(), german.run("null").unwrap())
+        });
+        assert_eq!(c, "\"Thursday\"\n0\n");
+        // Only switched for the run.
+        assert_eq!(german, "\"Donnerstag\"\n0\n");
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn logging() {
+        use std::sync::Mutex;
+
+        struct Capture(Mutex<Vec<String>>);
+
+        impl log::Log for Capture {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.target() == super::LOG_TARGET
+            }
+
+            fn log(&self, record: &log::Record) {
+                if self.enabled(record.metadata()) {
+                    let line = format!("{} {}", record.level(), record.args());
+                    self.0.lock().unwrap().push(line);
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: Capture = Capture(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut jq = Jq::compile_with_args(
+            r#"{"logged": .} | debug | .logged"#,
+            vec![],
+            &Overrides::default(),
+            &[],
+            &Functions::default(),
+        )
+        .unwrap();
+        let value = Parser::new().parse("1").unwrap();
+        let mut output = String::new();
+        jq.execute_parsed(&value, &Interrupt::default(), &mut output)
+            .unwrap();
+        let msg = CString::new("logged too").unwrap();
+        unsafe { jq_report_error(jq.state, jv_string(msg.as_ptr())) };
+
+        let logged = LOGGER.0.lock().unwrap();
+        assert!(logged.contains(&r#"DEBUG ["DEBUG:",{"logged":1}]"#.to_string()));
+        assert!(logged.contains(&"WARN logged too".to_string()));
+    }
+
+    #[test]
+    fn error_callback_survives_a_move() {
+        let jq = Jq::compile_with_args(
+            ".",
+            vec![],
+            &Overrides::default(),
+            &[],
+            &Functions::default(),
+        )
+        .unwrap();
+        // Move the state somewhere else entirely.
+        let mut moved = Box::new(jq);
+        let jq = &mut *moved;
+        let msg = CString::new("reported").unwrap();
+        unsafe { jq_report_error(jq.state, jv_string(msg.as_ptr())) };
+        assert_eq!(*jq.reported, vec!["reported"]);
+
+        // Reported messages are attached to the next failure.
+        match jq.runtime_error("JQ: Error: boom".into(), None) {
+            Error::Exec { reason, .. } => assert_eq!(reason, "reported\nJQ: Error: boom"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn into_raw_keeps_the_reference() {
+        let value = Parser::new().parse("[1, 2, 3]").unwrap();
+        assert_eq!(refcnt(&value), 1);
+        let raw = value.into_raw();
+        unsafe {
+            assert_eq!(jv_get_refcnt(raw), 1);
+            jv_free(raw);
+        }
+    }
+
+    #[test]
+    fn raw_output_replaces_invalid_utf8() {
+        let value = lone_surrogate();
+        let mut buf = String::new();
+        let raw = Format {
+            raw: true,
+            ..Format::default()
+        };
+        raw.render(&value, &mut buf).unwrap();
+        assert_eq!(buf, "\u{FFFD}\u{FFFD}\u{FFFD}\n");
+    }
+
+    #[test]
+    fn execute_parsed_leaves_the_input_alone() {
+        let mut jq = Jq::compile_with_args(
+            ".[1]",
+            vec![],
+            &Overrides::default(),
+            &[],
+            &Functions::default(),
+        )
+        .unwrap();
+        let value = Parser::new().parse("[1, 2, 3]").unwrap();
+
+        let mut output = String::new();
+        for _ in 0..2 {
+            jq.execute_parsed(&value, &Interrupt::default(), &mut output)
+                .unwrap();
+            assert_eq!(refcnt(&value), 1);
+        }
+        assert_eq!(output, "2\n2\n");
+    }
+
+    #[test]
+    fn reset_lets_go_of_the_input() {
+        let mut jq = Jq::compile_with_args(
+            ".[]",
+            vec![],
+            &Overrides::default(),
+            &[],
+            &Functions::default(),
+        )
+        .unwrap();
+        jq.set_limits(Limits {
+            outputs: Some(1),
+            ..Limits::default()
+        });
+        let value = Parser::new().parse("[1, 2, 3]").unwrap();
+
+        let mut output = String::new();
+        let res = jq.execute_parsed(&value, &Interrupt::default(), &mut output);
+        assert!(res.unwrap_err().is_limit_exceeded());
+        // Stopped part way through `.[]`, which still has the array.
+        assert!(refcnt(&value) > 1);
+        assert_eq!(jq.stats().outputs, 1);
+
+        jq.reset();
+        assert_eq!(refcnt(&value), 1);
+        assert_eq!(jq.stats().outputs, 0);
+        jq.set_limits(Limits::default());
+        jq.execute_parsed(&value, &Interrupt::default(), &mut output)
+            .unwrap();
+        assert_eq!(output, "1\n2\n3\n");
+    }
+
+    #[test]
+    fn process_consumes_the_input_once() {
+        let mut jq = Jq::compile_with_args(
+            ".[0]",
+            vec![],
+            &Overrides::default(),
+            &[],
+            &Functions::default(),
+        )
+        .unwrap();
+        let value = Parser::new().parse("[1, 2, 3]").unwrap();
+        // Hang on to a second reference so we can see what jq does with the
+        // one it's given.
+        let observer = JV {
+            ptr: unsafe { jv_copy(value.ptr) },
+        };
+        assert_eq!(refcnt(&observer), 2);
+
+        let mut output = String::new();
+        jq.process(value, &Interrupt::default(), &mut output)
+            .unwrap()
+            .into_result()
+            .unwrap();
+        assert_eq!(output, "1\n");
+
+        // jq released its reference when the run finished, and nothing else
+        // was left holding (or over-freeing) one.
+        assert_eq!(refcnt(&observer), 1);
+    }
 }
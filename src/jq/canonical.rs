@@ -0,0 +1,328 @@
+//! Rendering values as canonical json, following the JSON Canonicalization
+//! Scheme ([RFC 8785]).
+//!
+//! jq's own `jv_dump_string()` gets most of the way with `JV_PRINT_SORTED`,
+//! but sorts keys by their UTF-8 bytes rather than UTF-16 code units, escapes
+//! DEL, and writes numbers with up to 17 significant digits rather than the
+//! shortest form which round trips, as JCS requires.
+//!
+//! The same rendering, pretty printed, makes for outputs which don't change
+//! from one version of jq to the next.
+//!
+//! [RFC 8785]: https://www.rfc-editor.org/rfc/rfc8785
+
+use super::JV;
+use jq_sys::{
+    jv_array_get, jv_array_length, jv_copy, jv_get_kind, jv_kind_JV_KIND_ARRAY,
+    jv_kind_JV_KIND_FALSE, jv_kind_JV_KIND_NUMBER, jv_kind_JV_KIND_OBJECT, jv_kind_JV_KIND_STRING,
+    jv_kind_JV_KIND_TRUE, jv_number_value, jv_object_iter, jv_object_iter_key, jv_object_iter_next,
+    jv_object_iter_valid, jv_object_iter_value,
+};
+use std::fmt::Write;
+use std::os::raw::c_int;
+
+impl JV {
+    /// Push the canonical json rendering of the current `JV` into the buffer.
+    pub fn dump_canonical(&self, buf: &mut String) {
+        self.dump_canonical_at(None, buf);
+    }
+
+    /// Like `dump_canonical()`, but pretty printed with an indent of two
+    /// spaces, for outputs which stay the same whichever version of jq wrote
+    /// them.
+    pub fn dump_stable(&self, buf: &mut String) {
+        self.dump_canonical_at(Some(0), buf);
+    }
+
+    /// `level` is how deeply nested the value is when pretty printing.
+    fn dump_canonical_at(&self, level: Option<usize>, buf: &mut String) {
+        let inner = level.map(|level| level + 1);
+        unsafe {
+            #[allow(non_upper_case_globals)]
+            match jv_get_kind(self.ptr) {
+                jv_kind_JV_KIND_FALSE => buf.push_str("false"),
+                jv_kind_JV_KIND_TRUE => buf.push_str("true"),
+                jv_kind_JV_KIND_NUMBER => write_number(jv_number_value(self.ptr), buf),
+                jv_kind_JV_KIND_STRING => write_string(&self.as_str_lossy(), buf),
+                jv_kind_JV_KIND_ARRAY => {
+                    let len = jv_array_length(jv_copy(self.ptr));
+                    buf.push('[');
+                    for idx in 0..len {
+                        if idx > 0 {
+                            buf.push(',');
+                        }
+                        newline(inner, buf);
+                        JV {
+                            ptr: jv_array_get(jv_copy(self.ptr), idx),
+                        }
+                        .dump_canonical_at(inner, buf);
+                    }
+                    if len > 0 {
+                        newline(level, buf);
+                    }
+                    buf.push(']');
+                }
+                jv_kind_JV_KIND_OBJECT => {
+                    let mut entries = vec![];
+                    // The object iterator functions borrow the object rather
+                    // than consuming it.
+                    let mut iter: c_int = jv_object_iter(self.ptr);
+                    while jv_object_iter_valid(self.ptr, iter) != 0 {
+                        let key = JV {
+                            ptr: jv_object_iter_key(self.ptr, iter),
+                        };
+                        let value = JV {
+                            ptr: jv_object_iter_value(self.ptr, iter),
+                        };
+                        let text = key.as_str_lossy().into_owned();
+                        let units: Vec<u16> = text.encode_utf16().collect();
+                        entries.push((units, text, value));
+                        iter = jv_object_iter_next(self.ptr, iter);
+                    }
+                    entries.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+
+                    buf.push('{');
+                    for (idx, (_, key, value)) in entries.iter().enumerate() {
+                        if idx > 0 {
+                            buf.push(',');
+                        }
+                        newline(inner, buf);
+                        write_string(key, buf);
+                        buf.push(':');
+                        if level.is_some() {
+                            buf.push(' ');
+                        }
+                        value.dump_canonical_at(inner, buf);
+                    }
+                    if !entries.is_empty() {
+                        newline(level, buf);
+                    }
+                    buf.push('}');
+                }
+                // Null, and invalid (which never shows up as an output).
+                _ => buf.push_str("null"),
+            }
+        }
+    }
+}
+
+/// When pretty printing, start a new line indented to `level`.
+fn newline(level: Option<usize>, buf: &mut String) {
+    if let Some(level) = level {
+        buf.push('\n');
+        buf.extend((0..level * 2).map(|_| ' '));
+    }
+}
+
+/// Only quotes, backslashes and control characters are escaped, using the
+/// short forms where json has them.
+fn write_string(text: &str, buf: &mut String) {
+    buf.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\u{8}' => buf.push_str("\\b"),
+            '\u{c}' => buf.push_str("\\f"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+/// Numbers are written the way ECMAScript's `Number.prototype.toString()`
+/// writes them: the shortest digits which round trip, without an exponent
+/// between 1e-6 and 1e21.
+///
+/// jq writes `nan` as `null` and the infinities as the largest finite
+/// doubles, which JCS has no say on, so those are kept.
+fn write_number(n: f64, buf: &mut String) {
+    if n.is_nan() {
+        buf.push_str("null");
+        return;
+    }
+    let n = if n.is_infinite() {
+        f64::MAX.copysign(n)
+    } else {
+        n
+    };
+    if n == 0.0 {
+        // Including negative zero.
+        buf.push('0');
+        return;
+    }
+    if n < 0.0 {
+        buf.push('-');
+    }
+
+    // Rust's `{:e}` gives the shortest digits which round trip, as
+    // `d.ddde<exponent>`, but when two are as short it doesn't always take the
+    // closest, which ECMAScript requires. Rounding to that many digits does.
+    let shortest = format!("{:e}", n.abs());
+    let precision = shortest.find('e').unwrap_or(0).saturating_sub(2);
+    let scientific = format!("{:.*e}", precision, n.abs());
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap_or(0));
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let exponent: i32 = exponent[1..].parse().unwrap_or(0);
+    // The number is `0.<digits>` times 10 to the power of `point`.
+    let point = exponent + 1;
+    let len = digits.len() as i32;
+
+    if len <= point && point <= 21 {
+        buf.push_str(&digits);
+        buf.extend((len..point).map(|_| '0'));
+    } else if 0 < point && point <= 21 {
+        let (whole, fraction) = digits.split_at(point as usize);
+        buf.push_str(whole);
+        buf.push('.');
+        buf.push_str(fraction);
+    } else if -6 < point && point <= 0 {
+        buf.push_str("0.");
+        buf.extend((point..0).map(|_| '0'));
+        buf.push_str(&digits);
+    } else {
+        let (first, rest) = digits.split_at(1);
+        buf.push_str(first);
+        if !rest.is_empty() {
+            buf.push('.');
+            buf.push_str(rest);
+        }
+        let _ = write!(
+            buf,
+            "e{}{}",
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::lone_surrogate;
+    use super::write_number;
+    use crate::jq::{Parser, JV};
+
+    fn canonical(json: &str) -> String {
+        let mut buf = String::new();
+        Parser::new().parse(json).unwrap().dump_canonical(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        let object = JV::object().object_set(lone_surrogate(), lone_surrogate());
+        let mut buf = String::new();
+        object.dump_canonical(&mut buf);
+        assert_eq!(
+            buf,
+            "{\"\u{FFFD}\u{FFFD}\u{FFFD}\":\"\u{FFFD}\u{FFFD}\u{FFFD}\"}"
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        // From appendix B of RFC 8785.
+        for (bits, expected) in &[
+            (0x0000000000000000u64, "0"),
+            (0x8000000000000000, "0"),
+            (0x0000000000000001, "5e-324"),
+            (0x8000000000000001, "-5e-324"),
+            (0x7fefffffffffffff, "1.7976931348623157e+308"),
+            (0xffefffffffffffff, "-1.7976931348623157e+308"),
+            (0x4340000000000000, "9007199254740992"),
+            (0xc340000000000000, "-9007199254740992"),
+            (0x4430000000000000, "295147905179352830000"),
+            (0x44b52d02c7e14af5, "9.999999999999997e+22"),
+            (0x44b52d02c7e14af6, "1e+23"),
+            (0x44b52d02c7e14af7, "1.0000000000000001e+23"),
+            (0x444b1ae4d6e2ef4e, "999999999999999700000"),
+            (0x444b1ae4d6e2ef4f, "999999999999999900000"),
+            (0x444b1ae4d6e2ef50, "1e+21"),
+            (0x3eb0c6f7a0b5ed8c, "9.999999999999997e-7"),
+            (0x3eb0c6f7a0b5ed8d, "0.000001"),
+            (0x41b3de4355555553, "333333333.3333332"),
+            (0x41b3de4355555554, "333333333.33333325"),
+            (0x41b3de4355555555, "333333333.3333333"),
+            (0x41b3de4355555556, "333333333.3333334"),
+            (0x41b3de4355555557, "333333333.33333343"),
+            (0xbecbf647612f3696, "-0.0000033333333333333333"),
+            (0x43143ff3c1cb0959, "1424953923781206.2"),
+        ] {
+            let mut buf = String::new();
+            write_number(f64::from_bits(*bits), &mut buf);
+            assert_eq!(buf, *expected, "bits: {:x}", bits);
+        }
+
+        let mut buf = String::new();
+        write_number(f64::NAN, &mut buf);
+        write_number(f64::NEG_INFINITY, &mut buf);
+        assert_eq!(buf, "null-1.7976931348623157e+308");
+    }
+
+    #[test]
+    fn rfc_example() {
+        // From section 3.2.2 of RFC 8785.
+        let input = r#"{
+            "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+            "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+            "literals": [null, true, false]
+        }"#;
+        assert_eq!(
+            canonical(input),
+            r#"{"literals":[null,true,false],"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"string":"€$\u000f\nA'B\"\\\\\"/"}"#
+        );
+    }
+
+    #[test]
+    fn keys_sort_by_utf16() {
+        // From section 3.2.3 of RFC 8785.
+        let input = r#"{
+            "\u20ac": "Euro Sign",
+            "\r": "Carriage Return",
+            "\ufb33": "Hebrew Letter Dalet With Dagesh",
+            "1": "One",
+            "\ud83d\ude00": "Emoji: Grinning Face",
+            "\u0080": "Control",
+            "\u00f6": "Latin Small Letter O With Diaeresis"
+        }"#;
+        let order: Vec<String> = canonical(input)
+            .split(',')
+            .map(|entry| {
+                let value = &entry[entry.find("\":\"").unwrap() + 3..];
+                value.trim_end_matches('}').trim_end_matches('"').into()
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                "Carriage Return",
+                "One",
+                "Control",
+                "Latin Small Letter O With Diaeresis",
+                "Euro Sign",
+                "Emoji: Grinning Face",
+                "Hebrew Letter Dalet With Dagesh",
+            ]
+        );
+        assert_eq!(canonical("[{}, [], \"\\u007f\"]"), "[{},[],\"\u{7f}\"]");
+    }
+
+    #[test]
+    fn stable() {
+        let mut buf = String::new();
+        Parser::new()
+            .parse(r#"{"b": [1.0, {}, [], {"d": 1e21}], "a": "\u007f"}"#)
+            .unwrap()
+            .dump_stable(&mut buf);
+        assert_eq!(
+            buf,
+            "{\n  \"a\": \"\u{7f}\",\n  \"b\": [\n    1,\n    {},\n    [],\n    {\n      \"d\": 1e+21\n    }\n  ]\n}"
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! Deserializing straight from jq's `jv` values with serde.
+
+use super::JV;
+use crate::errors::Error;
+use jq_sys::{
+    jv_array_get, jv_array_length, jv_copy, jv_get_kind, jv_kind_JV_KIND_ARRAY,
+    jv_kind_JV_KIND_FALSE, jv_kind_JV_KIND_NULL, jv_kind_JV_KIND_NUMBER, jv_kind_JV_KIND_OBJECT,
+    jv_kind_JV_KIND_STRING, jv_kind_JV_KIND_TRUE, jv_number_value, jv_object_iter,
+    jv_object_iter_key, jv_object_iter_next, jv_object_iter_valid, jv_object_iter_value,
+};
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use std::fmt::Display;
+use std::os::raw::c_int;
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::StringConvert {
+            err: msg.to_string().into(),
+        }
+    }
+}
+
+/// A `serde::Deserializer` reading one output of a jq program, as handed out
+/// by `JqProgram::run_deserialized()`.
+///
+/// The output is read directly from jq's representation, so it can be fed to
+/// `Deserialize::deserialize()` or transcoded into another serde format
+/// without rendering it as json first.
+pub struct OutputDeserializer<'a> {
+    value: &'a JV,
+}
+
+impl<'a> OutputDeserializer<'a> {
+    pub(crate) fn new(value: &'a JV) -> Self {
+        OutputDeserializer { value }
+    }
+
+    fn kind(&self) -> u32 {
+        unsafe { jv_get_kind(self.value.ptr) }
+    }
+
+    /// The text of a string output. Strings holding invalid UTF-8 (which
+    /// `implode` can build out of lone surrogates) can't be borrowed as a
+    /// `&str`, so they fail to deserialize.
+    fn str(&self) -> Result<&'a str, Error> {
+        let bytes = unsafe { self.value.string_bytes() };
+        std::str::from_utf8(bytes).map_err(de::Error::custom)
+    }
+}
+
+/// Integers up to 2^53 are exact as doubles, so only those are handed to
+/// visitors as integers.
+const MAX_EXACT: f64 = 9_007_199_254_740_992.0;
+
+impl<'de, 'a> de::Deserializer<'de> for OutputDeserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        #[allow(non_upper_case_globals)]
+        match self.kind() {
+            jv_kind_JV_KIND_NULL => visitor.visit_unit(),
+            jv_kind_JV_KIND_FALSE => visitor.visit_bool(false),
+            jv_kind_JV_KIND_TRUE => visitor.visit_bool(true),
+            jv_kind_JV_KIND_NUMBER => {
+                let n = unsafe { jv_number_value(self.value.ptr) };
+                if n.fract() == 0.0 && n.abs() <= MAX_EXACT {
+                    if n >= 0.0 {
+                        visitor.visit_u64(n as u64)
+                    } else {
+                        visitor.visit_i64(n as i64)
+                    }
+                } else {
+                    visitor.visit_f64(n)
+                }
+            }
+            jv_kind_JV_KIND_STRING => visitor.visit_str(self.str()?),
+            jv_kind_JV_KIND_ARRAY => {
+                let len = unsafe { jv_array_length(jv_copy(self.value.ptr)) };
+                visitor.visit_seq(Items {
+                    array: self.value,
+                    idx: 0,
+                    len,
+                })
+            }
+            jv_kind_JV_KIND_OBJECT => visitor.visit_map(Entries {
+                object: self.value,
+                iter: unsafe { jv_object_iter(self.value.ptr) },
+            }),
+            _ => Err(de::Error::custom("jq produced an invalid value")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.value.is_null() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Enums are read the same way serde_json writes them: unit variants as
+    /// strings, and the others as an object with the variant as its only key.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        #[allow(non_upper_case_globals)]
+        match self.kind() {
+            jv_kind_JV_KIND_STRING => visitor.visit_enum(self.str()?.into_deserializer()),
+            jv_kind_JV_KIND_OBJECT => {
+                let ptr = self.value.ptr;
+                let iter = unsafe { jv_object_iter(ptr) };
+                if unsafe { jv_object_iter_valid(ptr, iter) } == 0 {
+                    return Err(de::Error::custom("expected an object with one key"));
+                }
+                let (key, value) = unsafe { entry(ptr, iter) };
+                if unsafe { jv_object_iter_valid(ptr, jv_object_iter_next(ptr, iter)) } != 0 {
+                    return Err(de::Error::custom("expected an object with one key"));
+                }
+                visitor.visit_enum(Variant { key, value })
+            }
+            _ => Err(de::Error::custom("expected a string or an object")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// Copy out the key and value of an object entry.
+unsafe fn entry(object: jq_sys::jv, iter: c_int) -> (JV, JV) {
+    // Unlike most of the jv api, the object iterator functions borrow the
+    // object rather than consuming it.
+    (
+        JV {
+            ptr: jv_object_iter_key(object, iter),
+        },
+        JV {
+            ptr: jv_object_iter_value(object, iter),
+        },
+    )
+}
+
+struct Items<'a> {
+    array: &'a JV,
+    idx: c_int,
+    len: c_int,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Items<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.idx >= self.len {
+            return Ok(None);
+        }
+        let item = JV {
+            ptr: unsafe { jv_array_get(jv_copy(self.array.ptr), self.idx) },
+        };
+        self.idx += 1;
+        seed.deserialize(OutputDeserializer::new(&item)).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len - self.idx) as usize)
+    }
+}
+
+struct Entries<'a> {
+    object: &'a JV,
+    iter: c_int,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for Entries<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if unsafe { jv_object_iter_valid(self.object.ptr, self.iter) } == 0 {
+            return Ok(None);
+        }
+        let key = JV {
+            ptr: unsafe { jv_object_iter_key(self.object.ptr, self.iter) },
+        };
+        seed.deserialize(OutputDeserializer::new(&key)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = JV {
+            ptr: unsafe { jv_object_iter_value(self.object.ptr, self.iter) },
+        };
+        self.iter = unsafe { jv_object_iter_next(self.object.ptr, self.iter) };
+        seed.deserialize(OutputDeserializer::new(&value))
+    }
+}
+
+struct Variant {
+    key: JV,
+    value: JV,
+}
+
+impl<'de> de::EnumAccess<'de> for Variant {
+    type Error = Error;
+    type Variant = VariantValue;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantValue), Error> {
+        let variant = seed.deserialize(OutputDeserializer::new(&self.key))?;
+        Ok((variant, VariantValue { value: self.value }))
+    }
+}
+
+struct VariantValue {
+    value: JV,
+}
+
+impl<'de> de::VariantAccess<'de> for VariantValue {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(OutputDeserializer::new(&self.value))
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(OutputDeserializer::new(&self.value))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(OutputDeserializer::new(&self.value), visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(OutputDeserializer::new(&self.value), visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::lone_surrogate;
+    use super::OutputDeserializer;
+    use crate::{compile, Error};
+    use matches::assert_matches;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Kaiju {
+        name: String,
+        height: f64,
+        year: Option<u16>,
+        kind: Kind,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum Kind {
+        Reptile,
+        Insect { stage: String },
+        Robot(u8),
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_error() {
+        let value = lone_surrogate();
+        assert_matches!(
+            String::deserialize(OutputDeserializer::new(&value)),
+            Err(Error::StringConvert { .. })
+        );
+    }
+
+    #[test]
+    fn structs_and_enums() {
+        let input = r#"[
+            {"name": "Godzilla", "height": 50, "year": 1954, "kind": "Reptile"},
+            {"name": "Mothra", "height": 0.5, "kind": {"Insect": {"stage": "larva"}}},
+            {"name": "Mechagodzilla", "height": 50, "year": null, "kind": {"Robot": 2}}
+        ]"#;
+        let kaiju: Vec<Kaiju> = compile(".[]").unwrap().run_as(input).unwrap();
+        assert_eq!(
+            kaiju,
+            vec![
+                Kaiju {
+                    name: "Godzilla".into(),
+                    height: 50.0,
+                    year: Some(1954),
+                    kind: Kind::Reptile,
+                },
+                Kaiju {
+                    name: "Mothra".into(),
+                    height: 0.5,
+                    year: None,
+                    kind: Kind::Insect {
+                        stage: "larva".into()
+                    },
+                },
+                Kaiju {
+                    name: "Mechagodzilla".into(),
+                    height: 50.0,
+                    year: None,
+                    kind: Kind::Robot(2),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_serde_json() {
+        let input = r#"{"a": [1, -2, 2.5, "x\u0000y", true, null], "b": {"c": {}}}"#;
+        let mut program = compile(".[]").unwrap();
+        let values: Vec<serde_json::Value> = program.run_as(input).unwrap();
+        let rendered = program.run(input).unwrap();
+        let expected: Vec<serde_json::Value> = rendered
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(values, expected);
+
+        let maps: Vec<BTreeMap<String, i32>> = compile(".b.c, {d: 1, e: -1}")
+            .unwrap()
+            .run_as(input)
+            .unwrap();
+        assert_eq!(maps.len(), 2);
+        assert_eq!(maps[1]["e"], -1);
+    }
+
+    #[test]
+    fn mismatched_types() {
+        let mut program = compile(".").unwrap();
+        assert_matches!(program.run_as::<u8>("-1"), Err(Error::StringConvert { .. }));
+        assert_matches!(
+            program.run_as::<u8>("1.5"),
+            Err(Error::StringConvert { .. })
+        );
+        assert_matches!(
+            program.run_as::<Kind>(r#"{"Robot": 1, "Reptile": null}"#),
+            Err(Error::StringConvert { .. })
+        );
+        assert!(program.run_as::<u8>("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn stops_at_the_first_error() {
+        let mut seen = 0;
+        let res = compile(".[]")
+            .unwrap()
+            .run_deserialized("[1, 2, 3]", |output| {
+                seen += 1;
+                match u8::deserialize(output)? {
+                    2 => Err(Error::Timeout),
+                    _ => Ok(()),
+                }
+            });
+        assert_matches!(res, Err(Error::Timeout));
+        assert_eq!(seen, 2);
+    }
+}
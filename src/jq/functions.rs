@@ -0,0 +1,177 @@
+//! Functions implemented in Rust, callable from jq programs.
+//!
+//! libjq 1.6 has no way to add native builtins from outside, so each function
+//! is defined in the prelude as a jq function which passes its arguments to
+//! `debug`, tagged with `CALL_TAG` and the function's index, then reads the
+//! result back with `input`. The debug callback runs the Rust function and
+//! holds on to its result for the input callback to hand over.
+//!
+//! Constants are plain jq definitions, with the value written out as json.
+
+use super::JV;
+use jq_sys::{
+    jv_array_get, jv_array_length, jv_copy, jv_get_kind, jv_kind_JV_KIND_ARRAY,
+    jv_kind_JV_KIND_NUMBER, jv_kind_JV_KIND_STRING, jv_number_value, jv_string_length_bytes,
+    jv_string_value,
+};
+use std::fmt;
+use std::sync::Arc;
+
+const CALL_TAG: &str = "jq-rs:call";
+
+/// Called with an array of the arguments, giving back the result or the
+/// message for a jq error.
+pub type Function = Arc<dyn Fn(JV) -> Result<JV, String> + Send + Sync>;
+
+/// The functions and constants registered with a `Compiler`.
+#[derive(Clone, Default)]
+pub struct Functions {
+    defs: Vec<(String, usize, Function)>,
+    /// Names and values, rendered as json.
+    constants: Vec<(String, String)>,
+}
+
+impl Functions {
+    /// Add a function, replacing any with the same name and arity.
+    pub fn register(&mut self, name: String, arity: usize, function: Function) {
+        self.defs
+            .retain(|(other, other_arity, _)| *other != name || *other_arity != arity);
+        self.defs.push((name, arity, function));
+    }
+
+    /// Add a function with no arguments which gives back `json`.
+    ///
+    /// The json must be valid, and on one line.
+    pub fn define_constant(&mut self, name: String, json: String) {
+        self.constants.push((name, json));
+    }
+
+    /// Add the functions and constants from `other`, which replace any here
+    /// with the same names.
+    pub fn extend(&mut self, other: &Functions) {
+        for (name, arity, function) in &other.defs {
+            self.register(name.clone(), *arity, function.clone());
+        }
+        self.constants.extend(other.constants.iter().cloned());
+    }
+
+    /// The jq definitions for the constants and functions, on one line for
+    /// the prelude.
+    pub fn definitions(&self) -> String {
+        let mut out = String::new();
+        for (name, json) in &self.constants {
+            out.push_str(&format!("def {}: {}; ", name, json));
+        }
+        for (idx, (name, arity, _)) in self.defs.iter().enumerate() {
+            let params: Vec<String> = (0..*arity).map(|n| format!("$a{}", n)).collect();
+            out.push_str("def ");
+            out.push_str(name);
+            if *arity > 0 {
+                out.push_str(&format!("({})", params.join("; ")));
+            }
+            out.push_str(&format!(
+                r#": ["{}", {}, [{}]] | debug | input; "#,
+                CALL_TAG,
+                idx,
+                params.join(", ")
+            ));
+        }
+        out
+    }
+
+    /// The addresses of the Rust functions, which tell apart functions with
+    /// the same name and arity.
+    pub fn addresses(&self) -> impl Iterator<Item = usize> + '_ {
+        self.defs
+            .iter()
+            .map(|(_, _, function)| &**function as *const _ as *const () as usize)
+    }
+
+    /// The function and its arguments, if `value` is one of the calls the
+    /// definitions send to `debug`.
+    pub fn unwrap_call(&self, value: &JV) -> Option<(Function, JV)> {
+        unsafe {
+            if jv_get_kind(value.ptr) != jv_kind_JV_KIND_ARRAY
+                || jv_array_length(jv_copy(value.ptr)) != 3
+            {
+                return None;
+            }
+            let tag = JV {
+                ptr: jv_array_get(jv_copy(value.ptr), 0),
+            };
+            if jv_get_kind(tag.ptr) != jv_kind_JV_KIND_STRING {
+                return None;
+            }
+            let len = jv_string_length_bytes(jv_copy(tag.ptr)) as usize;
+            let bytes = std::slice::from_raw_parts(jv_string_value(tag.ptr) as *const u8, len);
+            if bytes != CALL_TAG.as_bytes() {
+                return None;
+            }
+            let idx = JV {
+                ptr: jv_array_get(jv_copy(value.ptr), 1),
+            };
+            if jv_get_kind(idx.ptr) != jv_kind_JV_KIND_NUMBER {
+                return None;
+            }
+            let (_, _, function) = self.defs.get(jv_number_value(idx.ptr) as usize)?;
+            let args = JV {
+                ptr: jv_array_get(jv_copy(value.ptr), 2),
+            };
+            Some((function.clone(), args))
+        }
+    }
+}
+
+impl fmt::Debug for Functions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let constants = self.constants.iter().map(|(name, _)| format!("{}/0", name));
+        let functions = self
+            .defs
+            .iter()
+            .map(|(name, arity, _)| format!("{}/{}", name, arity));
+        f.debug_list().entries(constants.chain(functions)).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Function, Functions};
+    use crate::jq::{Parser, JV};
+    use std::sync::Arc;
+
+    fn constant(n: f64) -> Function {
+        Arc::new(move |_| Ok(JV::number(n)))
+    }
+
+    #[test]
+    fn definitions() {
+        let mut functions = Functions::default();
+        functions.register("a".into(), 0, constant(1.0));
+        functions.register("b".into(), 2, constant(2.0));
+        functions.define_constant("c".into(), "[3]".into());
+        assert_eq!(
+            functions.definitions(),
+            concat!(
+                r#"def c: [3]; "#,
+                r#"def a: ["jq-rs:call", 0, []] | debug | input; "#,
+                r#"def b($a0; $a1): ["jq-rs:call", 1, [$a0, $a1]] | debug | input; "#,
+            )
+        );
+    }
+
+    #[test]
+    fn replaced() {
+        let mut functions = Functions::default();
+        functions.register("a".into(), 0, constant(1.0));
+        functions.register("a".into(), 1, constant(2.0));
+        functions.register("a".into(), 0, constant(3.0));
+        assert_eq!(format!("{:?}", functions), r#"["a/1", "a/0"]"#);
+
+        let call = Parser::new().parse(r#"["jq-rs:call", 1, []]"#).unwrap();
+        let (function, _) = functions.unwrap_call(&call).unwrap();
+        assert_eq!(function(JV::array()).unwrap().as_number(), Some(3.0));
+
+        let stderr = Parser::new().parse(r#"["jq-rs:stderr", 1]"#).unwrap();
+        assert!(functions.unwrap_call(&stderr).is_none());
+    }
+}
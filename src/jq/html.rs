@@ -0,0 +1,189 @@
+//! Rendering values as HTML, with each token wrapped in a `<span>` for
+//! highlighting.
+
+use super::layout::Token;
+use super::JV;
+use std::os::raw::c_int;
+
+/// The CSS classes `Compiler::html_output()` puts on each kind of token.
+///
+/// By default these are `jq-key`, `jq-string`, `jq-number`, `jq-boolean`,
+/// `jq-null` and `jq-punctuation`. Tokens with an empty class are written
+/// without a `<span>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HtmlClasses {
+    key: String,
+    string: String,
+    number: String,
+    boolean: String,
+    null: String,
+    punctuation: String,
+}
+
+impl Default for HtmlClasses {
+    fn default() -> Self {
+        HtmlClasses {
+            key: "jq-key".into(),
+            string: "jq-string".into(),
+            number: "jq-number".into(),
+            boolean: "jq-boolean".into(),
+            null: "jq-null".into(),
+            punctuation: "jq-punctuation".into(),
+        }
+    }
+}
+
+impl HtmlClasses {
+    /// The class for the keys of objects.
+    pub fn key<S: Into<String>>(mut self, class: S) -> Self {
+        self.key = class.into();
+        self
+    }
+
+    /// The class for strings, other than keys.
+    pub fn string<S: Into<String>>(mut self, class: S) -> Self {
+        self.string = class.into();
+        self
+    }
+
+    /// The class for numbers.
+    pub fn number<S: Into<String>>(mut self, class: S) -> Self {
+        self.number = class.into();
+        self
+    }
+
+    /// The class for `true` and `false`.
+    pub fn boolean<S: Into<String>>(mut self, class: S) -> Self {
+        self.boolean = class.into();
+        self
+    }
+
+    /// The class for `null`.
+    pub fn null<S: Into<String>>(mut self, class: S) -> Self {
+        self.null = class.into();
+        self
+    }
+
+    /// The class for brackets, braces, commas and colons.
+    pub fn punctuation<S: Into<String>>(mut self, class: S) -> Self {
+        self.punctuation = class.into();
+        self
+    }
+}
+
+impl JV {
+    /// Push the HTML rendering of the current `JV` into the buffer, laid out
+    /// the way `jv_dump_string` would with the same flags.
+    pub fn dump_html(&self, flags: c_int, classes: &HtmlClasses, buf: &mut String) {
+        self.dump_tokens(flags, &mut |token, text| {
+            let class = match token {
+                Token::Null => &classes.null,
+                Token::False | Token::True => &classes.boolean,
+                Token::Number => &classes.number,
+                Token::String => &classes.string,
+                Token::Key => &classes.key,
+                Token::Array | Token::Object => &classes.punctuation,
+                Token::Space => "",
+            };
+            if class.is_empty() {
+                escape_into(text, buf);
+            } else {
+                buf.push_str("<span class=\"");
+                escape_into(class, buf);
+                buf.push_str("\">");
+                escape_into(text, buf);
+                buf.push_str("</span>");
+            }
+        });
+    }
+}
+
+/// Escape text for use in HTML, either between tags or in a quoted
+/// attribute.
+pub fn escape_into(text: &str, buf: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => buf.push_str("&amp;"),
+            '<' => buf.push_str("&lt;"),
+            '>' => buf.push_str("&gt;"),
+            '"' => buf.push_str("&quot;"),
+            '\'' => buf.push_str("&#39;"),
+            c => buf.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HtmlClasses;
+    use crate::Compiler;
+
+    /// Take the tags back out of the HTML, leaving the text.
+    fn text(html: &str) -> String {
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in html.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                c if !in_tag => text.push(c),
+                _ => {}
+            }
+        }
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&")
+    }
+
+    #[test]
+    fn layout_matches_jq() {
+        let data = r#"{"b": [1.5, "<&>", [], {}, [null, {"c": false}]], "a": "é'", "d": true}"#;
+        let layouts = vec![
+            Compiler::new(),
+            Compiler::new().indent(2),
+            Compiler::new().indent(7).sort_keys(true),
+            Compiler::new().tab(true).ascii_output(true),
+        ];
+        for compiler in layouts {
+            let plain = compiler.clone().compile(".").unwrap().run(data).unwrap();
+            let html = compiler
+                .html_output(HtmlClasses::default())
+                .compile(".")
+                .unwrap()
+                .run(data)
+                .unwrap();
+            assert_eq!(text(&html), plain);
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let mut program = Compiler::new()
+            .html_output(HtmlClasses::default().punctuation("").key("k\"ey"))
+            .compile(".")
+            .unwrap();
+        assert_eq!(
+            program.run(r#"{"a": [1, "<b>", true, null]}"#).unwrap(),
+            concat!(
+                r#"{<span class="k&quot;ey">&quot;a&quot;</span>:"#,
+                r#"[<span class="jq-number">1</span>,"#,
+                r#"<span class="jq-string">&quot;&lt;b&gt;&quot;</span>,"#,
+                r#"<span class="jq-boolean">true</span>,"#,
+                r#"<span class="jq-null">null</span>]}"#,
+                "\n"
+            )
+        );
+
+        let mut program = Compiler::new()
+            .raw_output(true)
+            .html_output(HtmlClasses::default())
+            .compile(".[]")
+            .unwrap();
+        assert_eq!(
+            program.run(r#"["<b>", 1]"#).unwrap(),
+            "&lt;b&gt;\n<span class=\"jq-number\">1</span>\n"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Laying values out the way `jv_dump_string` does, one token at a time, for
+//! renderings which mark up each token.
+
+use super::JV;
+use jq_sys::{
+    jv_array_get, jv_array_length, jv_copy, jv_get_kind, jv_kind_JV_KIND_ARRAY,
+    jv_kind_JV_KIND_FALSE, jv_kind_JV_KIND_NULL, jv_kind_JV_KIND_NUMBER, jv_kind_JV_KIND_OBJECT,
+    jv_kind_JV_KIND_STRING, jv_kind_JV_KIND_TRUE, jv_object_iter, jv_object_iter_key,
+    jv_object_iter_next, jv_object_iter_valid, jv_object_iter_value, jv_print_flags_JV_PRINT_ASCII,
+    jv_print_flags_JV_PRINT_PRETTY, jv_print_flags_JV_PRINT_SORTED, jv_print_flags_JV_PRINT_TAB,
+};
+use std::os::raw::c_int;
+
+/// What a piece of the rendering is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Null,
+    False,
+    True,
+    Number,
+    String,
+    /// The key of an object entry.
+    Key,
+    /// The brackets and commas of an array.
+    Array,
+    /// The braces, commas and colons of an object.
+    Object,
+    /// Newlines and indentation.
+    Space,
+}
+
+impl JV {
+    /// Hand each token of the rendering to `emit`, laid out the way
+    /// `jv_dump_string` would with the same flags.
+    pub fn dump_tokens<F>(&self, flags: c_int, emit: &mut F)
+    where
+        F: FnMut(Token, &str),
+    {
+        self.dump_tokens_at(flags, 0, emit);
+    }
+
+    fn dump_tokens_at<F>(&self, flags: c_int, level: usize, emit: &mut F)
+    where
+        F: FnMut(Token, &str),
+    {
+        let pretty = flags & jv_print_flags_JV_PRINT_PRETTY as c_int != 0;
+        unsafe {
+            #[allow(non_upper_case_globals)]
+            match jv_get_kind(self.ptr) {
+                jv_kind_JV_KIND_ARRAY => {
+                    let len = jv_array_length(jv_copy(self.ptr));
+                    emit(Token::Array, "[");
+                    for idx in 0..len {
+                        if idx > 0 {
+                            emit(Token::Array, ",");
+                        }
+                        if pretty {
+                            newline(flags, level + 1, emit);
+                        }
+                        JV {
+                            ptr: jv_array_get(jv_copy(self.ptr), idx),
+                        }
+                        .dump_tokens_at(flags, level + 1, emit);
+                    }
+                    if pretty && len > 0 {
+                        newline(flags, level, emit);
+                    }
+                    emit(Token::Array, "]");
+                }
+                jv_kind_JV_KIND_OBJECT => {
+                    let mut entries = vec![];
+                    // The object iterator functions borrow the object rather
+                    // than consuming it.
+                    let mut iter: c_int = jv_object_iter(self.ptr);
+                    while jv_object_iter_valid(self.ptr, iter) != 0 {
+                        let key = JV {
+                            ptr: jv_object_iter_key(self.ptr, iter),
+                        };
+                        let value = JV {
+                            ptr: jv_object_iter_value(self.ptr, iter),
+                        };
+                        entries.push((key, value));
+                        iter = jv_object_iter_next(self.ptr, iter);
+                    }
+                    if flags & jv_print_flags_JV_PRINT_SORTED as c_int != 0 {
+                        entries.sort_by(|(a, _), (b, _)| a.string_bytes().cmp(b.string_bytes()));
+                    }
+
+                    emit(Token::Object, "{");
+                    for (idx, (key, value)) in entries.iter().enumerate() {
+                        if idx > 0 {
+                            emit(Token::Object, ",");
+                        }
+                        if pretty {
+                            newline(flags, level + 1, emit);
+                        }
+                        scalar(key, flags, Token::Key, emit);
+                        emit(Token::Object, ":");
+                        if pretty {
+                            emit(Token::Space, " ");
+                        }
+                        value.dump_tokens_at(flags, level + 1, emit);
+                    }
+                    if pretty && !entries.is_empty() {
+                        newline(flags, level, emit);
+                    }
+                    emit(Token::Object, "}");
+                }
+                jv_kind_JV_KIND_STRING => scalar(self, flags, Token::String, emit),
+                jv_kind_JV_KIND_NUMBER => scalar(self, flags, Token::Number, emit),
+                jv_kind_JV_KIND_TRUE => scalar(self, flags, Token::True, emit),
+                jv_kind_JV_KIND_FALSE => scalar(self, flags, Token::False, emit),
+                jv_kind_JV_KIND_NULL => scalar(self, flags, Token::Null, emit),
+                // Invalid, which never shows up as an output.
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Numbers, strings and so on are written the way jq writes them, including
+/// escaping for `JV_PRINT_ASCII`.
+fn scalar<F>(value: &JV, flags: c_int, token: Token, emit: &mut F)
+where
+    F: FnMut(Token, &str),
+{
+    let mut text = String::new();
+    value.dump_with(flags & jv_print_flags_JV_PRINT_ASCII as c_int, &mut text);
+    emit(token, &text);
+}
+
+fn newline<F>(flags: c_int, level: usize, emit: &mut F)
+where
+    F: FnMut(Token, &str),
+{
+    let mut space = String::from("\n");
+    if flags & jv_print_flags_JV_PRINT_TAB as c_int != 0 {
+        space.extend((0..level).map(|_| '\t'));
+    } else {
+        let width = (flags >> 8 & 7) as usize;
+        space.extend((0..level * width).map(|_| ' '));
+    }
+    emit(Token::Space, &space);
+}
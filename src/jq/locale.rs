@@ -0,0 +1,64 @@
+//! Running programs in the C locale, for `Compiler::c_locale()`.
+//!
+//! The locale is switched with `uselocale()`, which only affects the calling
+//! thread, so it's safe to do while other threads carry on in the process'
+//! locale. On platforms without it (Windows) nothing changes.
+
+/// Puts the thread back in the locale it was in before `enter()` when
+/// dropped.
+pub struct CLocale {
+    #[cfg(unix)]
+    previous: libc::locale_t,
+}
+
+#[cfg(unix)]
+impl CLocale {
+    /// Switch the calling thread to the C locale, or do nothing when it
+    /// can't be created.
+    pub fn enter() -> Option<Self> {
+        let c = C_LOCALE.with(|c| c.0);
+        if c.is_null() {
+            return None;
+        }
+        let previous = unsafe { libc::uselocale(c) };
+        Some(CLocale { previous })
+    }
+}
+
+#[cfg(not(unix))]
+impl CLocale {
+    pub fn enter() -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(unix)]
+impl Drop for CLocale {
+    fn drop(&mut self) {
+        unsafe { libc::uselocale(self.previous) };
+    }
+}
+
+/// A locale created for the thread, freed when the thread exits.
+#[cfg(unix)]
+struct Owned(libc::locale_t);
+
+#[cfg(unix)]
+impl Drop for Owned {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { libc::freelocale(self.0) };
+        }
+    }
+}
+
+#[cfg(unix)]
+thread_local! {
+    static C_LOCALE: Owned = Owned(unsafe {
+        libc::newlocale(
+            libc::LC_ALL_MASK,
+            b"C\0".as_ptr() as *const libc::c_char,
+            std::ptr::null_mut(),
+        )
+    });
+}
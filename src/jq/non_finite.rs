@@ -0,0 +1,200 @@
+//! What happens to numbers in outputs which aren't finite, for
+//! `Compiler::non_finite()`.
+//!
+//! jq's arithmetic happily produces NaN and the infinities (`nan`,
+//! `infinite`, `infinite - infinite`), and writes them in a way json parsers
+//! accept but which loses what they were. The policy is applied to each output as it
+//! comes out of the program, before it's rendered or converted, so every way
+//! of running a program sees the same values.
+
+use super::JV;
+use crate::errors::{Error, Result};
+use jq_sys::{
+    jv_array_get, jv_array_length, jv_array_set, jv_copy, jv_get_kind, jv_kind_JV_KIND_ARRAY,
+    jv_kind_JV_KIND_NUMBER, jv_kind_JV_KIND_OBJECT, jv_number_value, jv_object_iter,
+    jv_object_iter_key, jv_object_iter_next, jv_object_iter_valid, jv_object_iter_value,
+};
+use std::os::raw::c_int;
+
+/// What to do with NaN and the infinities when they show up in a program's
+/// outputs, set with `Compiler::non_finite()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum NonFinite {
+    /// Leave them to jq, which writes NaN as `null` and the infinities as
+    /// the largest finite numbers, `1.7976931348623157e+308` and its
+    /// negative. This is the default.
+    Jq,
+    /// Fail the run with an `Error::Exec` naming the number.
+    Error,
+    /// Replace them with `null`.
+    Null,
+    /// Replace them with the strings `"NaN"`, `"Infinity"` and `"-Infinity"`,
+    /// the names JavaScript gives them.
+    Strings,
+}
+
+// `#[default]` on variants needs a newer Rust than the crate supports.
+#[allow(clippy::derivable_impls)]
+impl Default for NonFinite {
+    fn default() -> Self {
+        NonFinite::Jq
+    }
+}
+
+impl NonFinite {
+    /// Apply the policy to an output, leaving it alone when it has nothing
+    /// to replace.
+    pub(super) fn apply(self, value: JV) -> Result<JV> {
+        if self == NonFinite::Jq {
+            return Ok(value);
+        }
+        Ok(self.replace(&value)?.unwrap_or(value))
+    }
+
+    /// `value` with its non-finite numbers replaced, or `None` when it has
+    /// none, so values which don't need it aren't copied.
+    fn replace(self, value: &JV) -> Result<Option<JV>> {
+        unsafe {
+            #[allow(non_upper_case_globals)]
+            match jv_get_kind(value.ptr) {
+                jv_kind_JV_KIND_NUMBER => {
+                    let number = jv_number_value(value.ptr);
+                    if number.is_finite() {
+                        Ok(None)
+                    } else {
+                        self.replacement(number).map(Some)
+                    }
+                }
+                jv_kind_JV_KIND_ARRAY => {
+                    let len = jv_array_length(jv_copy(value.ptr));
+                    let mut replaced: Option<JV> = None;
+                    for idx in 0..len {
+                        let item = JV {
+                            ptr: jv_array_get(jv_copy(value.ptr), idx),
+                        };
+                        if let Some(item) = self.replace(&item)? {
+                            let array = replaced.take().unwrap_or_else(|| value.clone());
+                            replaced = Some(JV {
+                                ptr: jv_array_set(array.into_raw(), idx, item.into_raw()),
+                            });
+                        }
+                    }
+                    Ok(replaced)
+                }
+                jv_kind_JV_KIND_OBJECT => {
+                    let mut changed = vec![];
+                    // The object iterator functions borrow the object rather
+                    // than consuming it.
+                    let mut iter: c_int = jv_object_iter(value.ptr);
+                    while jv_object_iter_valid(value.ptr, iter) != 0 {
+                        let item = JV {
+                            ptr: jv_object_iter_value(value.ptr, iter),
+                        };
+                        if let Some(item) = self.replace(&item)? {
+                            let key = JV {
+                                ptr: jv_object_iter_key(value.ptr, iter),
+                            };
+                            changed.push((key, item));
+                        }
+                        iter = jv_object_iter_next(value.ptr, iter);
+                    }
+                    if changed.is_empty() {
+                        return Ok(None);
+                    }
+                    let object = changed
+                        .into_iter()
+                        .fold(value.clone(), |object, (key, item)| {
+                            object.object_set(key, item)
+                        });
+                    Ok(Some(object))
+                }
+                _ => Ok(None),
+            }
+        }
+    }
+
+    fn replacement(self, number: f64) -> Result<JV> {
+        let name = if number.is_nan() {
+            "NaN"
+        } else if number > 0.0 {
+            "Infinity"
+        } else {
+            "-Infinity"
+        };
+        match self {
+            NonFinite::Null => Ok(JV::null()),
+            NonFinite::Strings => Ok(JV::string(name)),
+            _ => Err(Error::Exec {
+                reason: format!("The output contains {}, which json can't represent", name),
+                payload: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NonFinite;
+    use crate::{Compiler, Error};
+    use matches::assert_matches;
+
+    const PROGRAM: &str = "[nan, infinite, -infinite, {a: [1, nan]}, 1.5]";
+
+    fn run(policy: NonFinite, program: &str) -> crate::Result<String> {
+        Compiler::new()
+            .non_finite(policy)
+            .compile(program)
+            .unwrap()
+            .run("null")
+    }
+
+    #[test]
+    fn policies() {
+        let max = "1.7976931348623157e+308";
+        assert_eq!(
+            run(NonFinite::Jq, PROGRAM).unwrap(),
+            format!("[null,{},-{},{{\"a\":[1,null]}},1.5]\n", max, max)
+        );
+        assert_eq!(
+            run(NonFinite::Null, PROGRAM).unwrap(),
+            "[null,null,null,{\"a\":[1,null]},1.5]\n"
+        );
+        assert_eq!(
+            run(NonFinite::Strings, PROGRAM).unwrap(),
+            "[\"NaN\",\"Infinity\",\"-Infinity\",{\"a\":[1,\"NaN\"]},1.5]\n"
+        );
+        assert_matches!(
+            run(NonFinite::Error, PROGRAM),
+            Err(Error::Exec { reason, .. }) if reason.contains("NaN")
+        );
+        assert_matches!(
+            run(NonFinite::Error, "-infinite"),
+            Err(Error::Exec { reason, .. }) if reason.contains("-Infinity")
+        );
+    }
+
+    #[test]
+    fn finite_outputs_are_untouched() {
+        let program = r#"{"a": [1, {"b": null}], "c": "NaN"}, 0, -0"#;
+        for policy in &[NonFinite::Error, NonFinite::Null, NonFinite::Strings] {
+            assert_eq!(
+                run(*policy, program).unwrap(),
+                run(NonFinite::Jq, program).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn values_too() {
+        let mut program = Compiler::new()
+            .non_finite(NonFinite::Strings)
+            .compile("infinite")
+            .unwrap();
+        assert_eq!(
+            program.run_values("null").unwrap(),
+            vec![serde_json::json!("Infinity")]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Colors for each kind of token in colored outputs, in the format of jq's
+//! `JQ_COLORS`.
+
+use super::layout::Token;
+use super::JV;
+use crate::errors::{Error, Result};
+use std::fmt;
+use std::os::raw::c_int;
+
+/// The kinds of token `ColorPalette` has a color for, in the order they
+/// appear in `JQ_COLORS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PaletteToken {
+    /// `null`.
+    Null,
+    /// `false`.
+    False,
+    /// `true`.
+    True,
+    /// Numbers.
+    Number,
+    /// Strings, other than keys.
+    String,
+    /// The brackets and commas of arrays.
+    Array,
+    /// The braces, commas and colons of objects.
+    Object,
+    /// The keys of objects.
+    Key,
+}
+
+impl PaletteToken {
+    const ALL: [PaletteToken; 8] = [
+        PaletteToken::Null,
+        PaletteToken::False,
+        PaletteToken::True,
+        PaletteToken::Number,
+        PaletteToken::String,
+        PaletteToken::Array,
+        PaletteToken::Object,
+        PaletteToken::Key,
+    ];
+}
+
+/// How a token is colored, as the parameters of an ANSI "select graphic
+/// rendition" escape code, like `1;34` for bold blue.
+///
+/// ```rust
+/// use jq_rs::TokenStyle;
+///
+/// assert_eq!(TokenStyle::ansi256(208).to_string(), "38;5;208");
+/// assert_eq!(TokenStyle::rgb(255, 128, 0).bold().to_string(), "1;38;2;255;128;0");
+/// assert_eq!(TokenStyle::parse("0;32").unwrap().to_string(), "0;32");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TokenStyle {
+    params: String,
+}
+
+impl TokenStyle {
+    /// Read the parameters of an escape code, which are numbers separated by
+    /// `;`.
+    ///
+    /// Fails with `Error::InvalidColors` when there's anything else.
+    pub fn parse(params: &str) -> Result<Self> {
+        if let Some(c) = params.chars().find(|c| !c.is_ascii_digit() && *c != ';') {
+            return Err(Error::InvalidColors {
+                reason: format!("unexpected {:?} in {:?}", c, params),
+            });
+        }
+        Ok(TokenStyle {
+            params: params.into(),
+        })
+    }
+
+    /// One of the 256 colors of xterm and most other terminals.
+    pub fn ansi256(color: u8) -> Self {
+        TokenStyle {
+            params: format!("38;5;{}", color),
+        }
+    }
+
+    /// A 24-bit color, for terminals which support them.
+    pub fn rgb(red: u8, green: u8, blue: u8) -> Self {
+        TokenStyle {
+            params: format!("38;2;{};{};{}", red, green, blue),
+        }
+    }
+
+    /// The same color, in bold.
+    pub fn bold(self) -> Self {
+        TokenStyle {
+            params: format!("1;{}", self.params),
+        }
+    }
+}
+
+impl fmt::Display for TokenStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.params)
+    }
+}
+
+/// The colors `Compiler::color_output()` uses for each kind of token.
+///
+/// Palettes are read from and written as `JQ_COLORS`, the colors for each
+/// `PaletteToken` in order, separated by `:`. Any which are left off keep
+/// jq's default, which is `1;30:0;39:0;39:0;39:0;32:1;39:1;39:34;1`.
+///
+/// ```rust
+/// use jq_rs::{ColorPalette, Compiler, PaletteToken, TokenStyle};
+///
+/// let palette = ColorPalette::parse("0;31").unwrap()
+///     .set(PaletteToken::Key, TokenStyle::rgb(255, 128, 0));
+/// assert_eq!(palette.to_string(), "0;31:0;39:0;39:0;39:0;32:1;39:1;39:38;2;255;128;0");
+///
+/// let mut program = Compiler::new()
+///     .color_output(true)
+///     .color_palette(palette)
+///     .compile(".")
+///     .unwrap();
+/// assert_eq!(program.run("null").unwrap(), "\u{1b}[0;31mnull\u{1b}[0m\n");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ColorPalette {
+    styles: [TokenStyle; 8],
+}
+
+impl Default for ColorPalette {
+    /// jq's own colors.
+    fn default() -> Self {
+        let style = |params: &str| TokenStyle {
+            params: params.into(),
+        };
+        ColorPalette {
+            styles: [
+                style("1;30"),
+                style("0;39"),
+                style("0;39"),
+                style("0;39"),
+                style("0;32"),
+                style("1;39"),
+                style("1;39"),
+                style("34;1"),
+            ],
+        }
+    }
+}
+
+impl ColorPalette {
+    /// Read a palette in the format of `JQ_COLORS`.
+    ///
+    /// Fails with `Error::InvalidColors` when there are too many colors, or
+    /// one of them isn't valid.
+    pub fn parse(jq_colors: &str) -> Result<Self> {
+        let mut palette = Self::default();
+        if jq_colors.is_empty() {
+            return Ok(palette);
+        }
+        let colors: Vec<&str> = jq_colors.split(':').collect();
+        if colors.len() > PaletteToken::ALL.len() {
+            return Err(Error::InvalidColors {
+                reason: format!(
+                    "expected at most {} colors, found {}",
+                    PaletteToken::ALL.len(),
+                    colors.len()
+                ),
+            });
+        }
+        for (slot, color) in palette.styles.iter_mut().zip(colors) {
+            *slot = TokenStyle::parse(color)?;
+        }
+        Ok(palette)
+    }
+
+    /// Read the palette from the `JQ_COLORS` environment variable, or jq's
+    /// own colors when it isn't set.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("JQ_COLORS") {
+            Ok(jq_colors) => Self::parse(&jq_colors),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Color a kind of token with this style.
+    pub fn set(mut self, token: PaletteToken, style: TokenStyle) -> Self {
+        self.styles[token as usize] = style;
+        self
+    }
+
+    /// The style for a kind of token.
+    pub fn get(&self, token: PaletteToken) -> &TokenStyle {
+        &self.styles[token as usize]
+    }
+}
+
+impl fmt::Display for ColorPalette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, style) in self.styles.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{}", style)?;
+        }
+        Ok(())
+    }
+}
+
+impl JV {
+    /// Push the rendering of the current `JV` into the buffer with each token
+    /// colored from the palette, laid out the way `jv_dump_string` would with
+    /// the same flags.
+    pub fn dump_colored(&self, flags: c_int, palette: &ColorPalette, buf: &mut String) {
+        self.dump_tokens(flags, &mut |token, text| {
+            let token = match token {
+                Token::Null => PaletteToken::Null,
+                Token::False => PaletteToken::False,
+                Token::True => PaletteToken::True,
+                Token::Number => PaletteToken::Number,
+                Token::String => PaletteToken::String,
+                Token::Key => PaletteToken::Key,
+                Token::Array => PaletteToken::Array,
+                Token::Object => PaletteToken::Object,
+                Token::Space => {
+                    buf.push_str(text);
+                    return;
+                }
+            };
+            buf.push_str("\u{1b}[");
+            buf.push_str(&palette.get(token).params);
+            buf.push('m');
+            buf.push_str(text);
+            buf.push_str("\u{1b}[0m");
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ColorPalette, PaletteToken, TokenStyle};
+    use crate::{Compiler, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn jq_colors() {
+        let default = ColorPalette::default();
+        assert_eq!(ColorPalette::parse("").unwrap(), default);
+        assert_eq!(ColorPalette::parse(&default.to_string()).unwrap(), default);
+
+        let palette = ColorPalette::parse("0;31::4;38;5;1").unwrap();
+        assert_eq!(palette.get(PaletteToken::Null).to_string(), "0;31");
+        assert_eq!(palette.get(PaletteToken::False).to_string(), "");
+        assert_eq!(palette.get(PaletteToken::True).to_string(), "4;38;5;1");
+        assert_eq!(
+            palette.get(PaletteToken::Key),
+            default.get(PaletteToken::Key)
+        );
+
+        assert_matches!(
+            ColorPalette::parse("1:2:3:4:5:6:7:8:9"),
+            Err(Error::InvalidColors { .. })
+        );
+        assert_matches!(
+            ColorPalette::parse("1;3x"),
+            Err(Error::InvalidColors { .. })
+        );
+        assert_matches!(
+            TokenStyle::parse("\u{1b}[0m"),
+            Err(Error::InvalidColors { .. })
+        );
+    }
+
+    #[test]
+    fn colored_outputs() {
+        let palette = ColorPalette::default()
+            .set(PaletteToken::Key, TokenStyle::ansi256(1))
+            .set(PaletteToken::Number, TokenStyle::rgb(1, 2, 3));
+        let mut program = Compiler::new()
+            .indent(1)
+            .color_output(true)
+            .color_palette(palette)
+            .compile(".")
+            .unwrap();
+        assert_eq!(
+            program.run(r#"{"a": [1, "b"]}"#).unwrap(),
+            concat!(
+                "\u{1b}[1;39m{\u{1b}[0m\n",
+                " \u{1b}[38;5;1m\"a\"\u{1b}[0m\u{1b}[1;39m:\u{1b}[0m \u{1b}[1;39m[\u{1b}[0m\n",
+                "  \u{1b}[38;2;1;2;3m1\u{1b}[0m\u{1b}[1;39m,\u{1b}[0m\n",
+                "  \u{1b}[0;32m\"b\"\u{1b}[0m\n",
+                " \u{1b}[1;39m]\u{1b}[0m\n",
+                "\u{1b}[1;39m}\u{1b}[0m\n",
+            )
+        );
+
+        // Without `color_output()`, the palette isn't used.
+        let mut program = Compiler::new()
+            .color_palette(ColorPalette::default())
+            .compile(".")
+            .unwrap();
+        assert_eq!(program.run("[null]").unwrap(), "[null]\n");
+    }
+}
@@ -0,0 +1,570 @@
+//! Definitions compiled in ahead of every program.
+//!
+//! libjq 1.6 writes the input of the `stderr` builtin straight to the process'
+//! stderr, with no way to hook it. The prelude replaces `stderr` with a
+//! definition which passes the value to `debug` instead (where we do have a
+//! callback), wrapped in an array tagged with `STDERR_TAG` so the callback can
+//! tell the two apart. It also wraps `implode`, which raises an error for
+//! codepoints libjq can't turn into valid UTF-8.
+//!
+//! Sandboxed programs also get `SANDBOX`, which replaces the builtins reaching
+//! outside of the input with ones raising an error tagged with `POLICY_TAG`,
+//! and shadows `$ENV` with an empty object. Programs run with a fixed time
+//! get a `now` which always gives that time. With the `tz` feature,
+//! `TIMEZONES` replaces `localtime` and `strflocaltime` with definitions
+//! which send `TZ_TAG` to `debug` and read back the offset of the run's time
+//! zone with `input`.
+//!
+//! Variables set with `Scope::env()` are added to `$ENV`, which is shadowed
+//! along with `env` (a C builtin, which would still see the real
+//! environment).
+//!
+//! Variables declared with `Compiler::var()` are bound from an object handed
+//! over for each run. The prelude asks for it by sending `VARS_TAG` to
+//! `debug`, and reads it back with `input` the same way as for `functions`.
+//!
+//! The definitions for functions implemented in Rust (see `functions`) go
+//! next, then the shared definitions from `Compiler::prelude()` last of all.
+//! Those are squashed onto one line like the rest, with any `import` and
+//! `include` directives among them moved up to follow the program's own.
+
+use super::JV;
+use crate::literal::escape_string;
+use jq_sys::{
+    jv_array_get, jv_array_length, jv_copy, jv_get_kind, jv_invalid_get_msg, jv_invalid_has_msg,
+    jv_kind_JV_KIND_ARRAY, jv_kind_JV_KIND_OBJECT, jv_kind_JV_KIND_STRING, jv_object_get,
+    jv_string_length_bytes, jv_string_value,
+};
+
+const STDERR_TAG: &str = "jq-rs:stderr";
+const POLICY_TAG: &str = "jq-rs:policy";
+const VARS_TAG: &str = "jq-rs:vars";
+#[cfg(any(feature = "tz", test))]
+const TZ_TAG: &str = "jq-rs:tz";
+
+/// Kept to one line so the line numbers in compile errors still match the
+/// program as written. libjq 1.6's `implode` builds invalid UTF-8 out of
+/// surrogates and aborts on codepoints it can't encode, so those raise an
+/// error first.
+const PRELUDE: &str = concat!(
+    r#"def stderr: ["jq-rs:stderr", .] | debug | .[1]; "#,
+    r#"def _jq_rs_implode: implode; "#,
+    r#"def implode: if type == "array" and any(.[]; type != "number" or . < 0 or . > 1114111 or (. >= 55296 and . < 57344)) "#,
+    r#"then error("implode input must be an array of codepoints") else _jq_rs_implode end; "#,
+);
+
+/// Also kept to one line. `$ENV` is bound by jq itself while compiling, so
+/// it can't raise an error the way the functions do.
+const SANDBOX: &str = concat!(
+    r#"def env: error({"jq-rs:policy": "env"}); "#,
+    r#"def input_filename: error({"jq-rs:policy": "input_filename"}); "#,
+    r#"def modulemeta: error({"jq-rs:policy": "modulemeta"}); "#,
+    r#"def get_search_list: error({"jq-rs:policy": "get_search_list"}); "#,
+    r#"def get_prog_origin: error({"jq-rs:policy": "get_prog_origin"}); "#,
+    r#"def get_jq_origin: error({"jq-rs:policy": "get_jq_origin"}); "#,
+    r#"{} as $ENV | "#,
+);
+
+/// Also kept to one line. The builtins are kept under other names for runs
+/// without a time zone, which get `null` back. See `crate::timezone::reply()`.
+const TIMEZONES: &str = concat!(
+    r#"def _jq_rs_localtime: localtime; "#,
+    r#"def _jq_rs_strflocaltime($f): strflocaltime($f); "#,
+    r#"def localtime: (["jq-rs:tz", [.]] | debug | input) as $z | "#,
+    r#"if $z == null then _jq_rs_localtime else . + $z[0] | gmtime end; "#,
+    r#"def strflocaltime($f): "#,
+    r#"(if type == "number" then . elif type == "array" then try mktime catch null else null end) as $t | "#,
+    r#"(["jq-rs:tz", [$t, $f]] | debug | input) as $z | "#,
+    r#"if $z == null then _jq_rs_strflocaltime($f) "#,
+    r#"else (if type == "number" then . + $z[0] | gmtime else . end) | strftime($z[1]) end; "#,
+);
+
+/// Options from the `Compiler` which are applied by adding to the prelude.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub sandbox: bool,
+    /// The time `now` gives, in seconds since the epoch.
+    pub fixed_time: Option<f64>,
+    /// Whether `localtime` and `strflocaltime` use the time zone set for
+    /// each run.
+    pub timezones: bool,
+    /// Definitions shared by every program, as written.
+    pub shared: String,
+    /// The names of variables bound for each run, without the `$`.
+    pub vars: Vec<String>,
+    /// Environment variables added to `$ENV`, in order.
+    pub env: Vec<(String, String)>,
+}
+
+/// Add the prelude to `program`, after any `module`, `import` and `include`
+/// directives since jq requires those to come first.
+///
+/// `definitions` go near the end, so the functions registered with a
+/// `Compiler` take the place of any builtins with the same name, and can be
+/// used by the shared definitions which follow them.
+pub fn with_prelude(program: &str, overrides: &Overrides, definitions: &str) -> String {
+    let split = directives(program).last().map_or(0, |last| last.end);
+    let shared = &overrides.shared;
+    let shared_split = directives(shared).last().map_or(0, |last| last.end);
+    let mut out = String::with_capacity(
+        program.len() + PRELUDE.len() + SANDBOX.len() + definitions.len() + shared.len(),
+    );
+    out.push_str(&program[..split]);
+    squash_into(&shared[..shared_split], &mut out);
+    out.push_str(PRELUDE);
+    if let Some(time) = overrides.fixed_time {
+        // The local time zone would make results depend on the machine, so
+        // local times are given in UTC.
+        out.push_str(&format!(
+            "def now: {:?}; def localtime: gmtime; def strflocaltime(f): strftime(f); ",
+            time
+        ));
+    }
+    // After the fixed time, so runs without a zone still get UTC.
+    if overrides.timezones {
+        out.push_str(TIMEZONES);
+    }
+    // These have to go last since they end with a pipe into the program.
+    if overrides.sandbox {
+        out.push_str(SANDBOX);
+    }
+    if !overrides.env.is_empty() {
+        let entries: Vec<String> = overrides
+            .env
+            .iter()
+            .map(|(name, value)| format!("{}: {}", escape_string(name), escape_string(value)))
+            .collect();
+        out.push_str(&format!("($ENV + {{{}}}) as $ENV | ", entries.join(", ")));
+        // The sandbox's `env` raises an error, which is kept.
+        if !overrides.sandbox {
+            out.push_str("def env: $ENV; ");
+        }
+    }
+    if !overrides.vars.is_empty() {
+        let names: Vec<String> = overrides
+            .vars
+            .iter()
+            .map(|name| format!("${}", name))
+            .collect();
+        out.push_str(&format!(
+            r#"(["{}", null] | debug | input) as {{{}}} | "#,
+            VARS_TAG,
+            names.join(", ")
+        ));
+    }
+    out.push_str(definitions);
+    squash_into(&shared[shared_split..], &mut out);
+    out.push_str(&program[split..]);
+    out
+}
+
+/// Take the prelude back out of a message libjq reported while compiling
+/// `program`, which was compiled as `compiled`.
+///
+/// Errors quote the line they're on, and the prelude shares a line with the
+/// start of the program. The spaces jq pads the quoted line with (up to the
+/// column of the error) go too, since they'd no longer point anywhere.
+pub fn strip_from_message(msg: &str, program: &str, compiled: &str) -> String {
+    let split = directives(program).last().map_or(0, |last| last.end);
+    let injected = &compiled[split..compiled.len() - (program.len() - split)];
+    if injected.is_empty() || !msg.contains(injected) {
+        return msg.to_string();
+    }
+    let msg = msg.replace(injected, "");
+    let lines: Vec<&str> = msg.split('\n').map(str::trim_end).collect();
+    lines.join("\n")
+}
+
+/// Push `code` into `out` on a single line, followed by a space. Comments are
+/// dropped and line breaks inside strings are written as `\n`.
+fn squash_into(code: &str, out: &mut String) {
+    if code.trim().is_empty() {
+        return;
+    }
+    // For each string interpolation we're inside of, the number of brackets
+    // opened in it so far.
+    let mut interpolations: Vec<usize> = Vec::new();
+    let mut in_string = false;
+    let mut chars = code.chars();
+    while let Some(c) = chars.next() {
+        if in_string {
+            match c {
+                '"' => in_string = false,
+                '\\' => {
+                    out.push(c);
+                    match chars.next() {
+                        Some('(') => {
+                            interpolations.push(0);
+                            in_string = false;
+                            out.push('(');
+                        }
+                        Some(escaped) => out.push(escaped),
+                        None => {}
+                    }
+                    continue;
+                }
+                '\n' => {
+                    out.push_str("\\n");
+                    continue;
+                }
+                '\r' => {
+                    out.push_str("\\r");
+                    continue;
+                }
+                _ => {}
+            }
+            out.push(c);
+            continue;
+        }
+        match c {
+            '#' => {
+                // Up to the end of the line, which becomes a space below.
+                if chars.by_ref().any(|c| c == '\n') {
+                    out.push(' ');
+                }
+                continue;
+            }
+            '"' => in_string = true,
+            '\n' | '\r' => {
+                out.push(' ');
+                continue;
+            }
+            '(' | '[' | '{' => {
+                if let Some(depth) = interpolations.last_mut() {
+                    *depth += 1;
+                }
+            }
+            ')' if interpolations.last() == Some(&0) => {
+                interpolations.pop();
+                in_string = true;
+            }
+            ')' | ']' | '}' => {
+                if let Some(depth) = interpolations.last_mut() {
+                    *depth = depth.saturating_sub(1);
+                }
+            }
+            _ => {}
+        }
+        out.push(c);
+    }
+    out.push(' ');
+}
+
+/// A `module`, `import` or `include` statement at the start of a program.
+#[derive(Debug, PartialEq)]
+pub struct Directive {
+    pub keyword: &'static str,
+    /// How far into the program the statement ends.
+    pub end: usize,
+    /// The path of the module being imported or included.
+    pub path: Option<String>,
+}
+
+/// The paths of the modules (and data files) the program imports or
+/// includes, as written.
+pub fn imported_modules(program: &str) -> Vec<String> {
+    directives(program)
+        .into_iter()
+        .filter(|directive| directive.keyword != "module")
+        .filter_map(|directive| directive.path)
+        .collect()
+}
+
+/// The directives at the start of the program, in order.
+pub fn directives(program: &str) -> Vec<Directive> {
+    let mut found = Vec::new();
+    let mut end = 0;
+    loop {
+        let rest = skip_trivia(&program[end..]);
+        let keyword = ["module", "import", "include"].iter().find(|keyword| {
+            rest.starts_with(*keyword)
+                && !rest[keyword.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        });
+        let keyword = match keyword {
+            Some(keyword) => *keyword,
+            None => return found,
+        };
+        let start = program.len() - rest.len();
+        match statement_len(rest) {
+            Some(len) => {
+                end = start + len;
+                found.push(Directive {
+                    keyword,
+                    end,
+                    path: first_string(&program[start..end]),
+                });
+            }
+            // Unterminated, so leave it for jq to complain about.
+            None => return found,
+        }
+    }
+}
+
+/// Skip whitespace and comments.
+fn skip_trivia(mut program: &str) -> &str {
+    loop {
+        program = program.trim_start();
+        if program.starts_with('#') {
+            program = program.find('\n').map_or("", |idx| &program[idx..]);
+        } else {
+            return program;
+        }
+    }
+}
+
+/// The length of the statement at the start of `program` including its `;`,
+/// skipping over any string literals. Paths and metadata in directives have
+/// to be constant, so there's no string interpolation to worry about.
+fn statement_len(program: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, c) in program.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return Some(idx + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The contents of the first string literal in a statement. Only `\"` and
+/// `\\` are unescaped, which covers anything sensible in a module path.
+fn first_string(statement: &str) -> Option<String> {
+    let start = statement.find('"')? + 1;
+    let mut contents = String::new();
+    let mut escaped = false;
+    for c in statement[start..].chars() {
+        match c {
+            _ if escaped => {
+                contents.push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            '"' => return Some(contents),
+            _ => contents.push(c),
+        }
+    }
+    None
+}
+
+/// The value the program passed to `stderr`, if `value` is one the prelude
+/// sent to `debug`.
+pub fn unwrap_stderr(value: &JV) -> Option<JV> {
+    unwrap_tagged(value, STDERR_TAG)
+}
+
+/// The time (and format) the prelude sent, if `value` is it asking for the
+/// offset of the run's time zone.
+#[cfg(feature = "tz")]
+pub fn unwrap_timezone_request(value: &JV) -> Option<JV> {
+    unwrap_tagged(value, TZ_TAG)
+}
+
+/// Whether `value` is the prelude asking for the variables for the run.
+pub fn is_vars_request(value: &JV) -> bool {
+    unwrap_tagged(value, VARS_TAG).is_some()
+}
+
+/// The second item of `value`, if it's a pair starting with `expected`.
+fn unwrap_tagged(value: &JV, expected: &str) -> Option<JV> {
+    unsafe {
+        if jv_get_kind(value.ptr) != jv_kind_JV_KIND_ARRAY
+            || jv_array_length(jv_copy(value.ptr)) != 2
+        {
+            return None;
+        }
+        let tag = JV {
+            ptr: jv_array_get(jv_copy(value.ptr), 0),
+        };
+        if jv_get_kind(tag.ptr) != jv_kind_JV_KIND_STRING {
+            return None;
+        }
+        let len = jv_string_length_bytes(jv_copy(tag.ptr)) as usize;
+        let bytes = std::slice::from_raw_parts(jv_string_value(tag.ptr) as *const u8, len);
+        if bytes != expected.as_bytes() {
+            return None;
+        }
+        Some(JV {
+            ptr: jv_array_get(jv_copy(value.ptr), 1),
+        })
+    }
+}
+
+/// The builtin a sandboxed program tried to use, if `value` is the error
+/// raised by one of the definitions in `SANDBOX`.
+pub fn policy_violation(value: &JV) -> Option<String> {
+    unsafe {
+        if jv_invalid_has_msg(jv_copy(value.ptr)) == 0 {
+            return None;
+        }
+        let msg = JV {
+            ptr: jv_invalid_get_msg(jv_copy(value.ptr)),
+        };
+        if jv_get_kind(msg.ptr) != jv_kind_JV_KIND_OBJECT {
+            return None;
+        }
+        let builtin = JV {
+            ptr: jv_object_get(jv_copy(msg.ptr), JV::string(POLICY_TAG).into_raw()),
+        };
+        builtin.as_string().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        directives, imported_modules, squash_into, strip_from_message, with_prelude, Overrides,
+        POLICY_TAG, PRELUDE, SANDBOX, STDERR_TAG, TIMEZONES, TZ_TAG, VARS_TAG,
+    };
+
+    #[test]
+    fn stripped_from_messages() {
+        let program = "include \"a\"; .b c\n| d";
+        let compiled = with_prelude(program, &Overrides::default(), "def f: 1; ");
+        let msg = format!("jq: error: at <top-level>, line 1:\n{}    ", compiled);
+        assert_eq!(
+            strip_from_message(&msg, program, &compiled),
+            "jq: error: at <top-level>, line 1:\ninclude \"a\"; .b c\n| d"
+        );
+        // Messages without it are left alone.
+        let msg = "jq: error: module not found: a\n";
+        assert_eq!(strip_from_message(msg, program, &compiled), msg);
+    }
+
+    #[test]
+    fn tag_matches() {
+        assert!(PRELUDE.contains(&format!("[\"{}\", .]", STDERR_TAG)));
+        let tagged = format!("error({{\"{}\": ", POLICY_TAG);
+        assert_eq!(
+            SANDBOX.matches(&tagged).count(),
+            SANDBOX.matches("def ").count()
+        );
+        assert_eq!(TIMEZONES.matches(&format!("[\"{}\", [", TZ_TAG)).count(), 2);
+        assert!(!TIMEZONES.contains('\n'));
+    }
+
+    #[test]
+    fn goes_after_directives() {
+        assert_eq!(
+            with_prelude(".a", &Overrides::default(), ""),
+            format!("{}.a", PRELUDE)
+        );
+        assert_eq!(
+            with_prelude(
+                "import \"a;b\" as x; # c;\ninclude \"d\";\n.a",
+                &Overrides::default(),
+                ""
+            ),
+            format!("import \"a;b\" as x; # c;\ninclude \"d\";{}\n.a", PRELUDE)
+        );
+        assert_eq!(
+            with_prelude("module {\"x\": \"\\\";\"};.a", &Overrides::default(), ""),
+            format!("module {{\"x\": \"\\\";\"}};{}.a", PRELUDE)
+        );
+        // Not directives.
+        assert_eq!(
+            with_prelude("modules", &Overrides::default(), ""),
+            format!("{}modules", PRELUDE)
+        );
+        assert_eq!(
+            with_prelude("import \"a\"", &Overrides::default(), ""),
+            format!("{}import \"a\"", PRELUDE)
+        );
+    }
+
+    #[test]
+    fn sandbox_goes_before_definitions() {
+        assert_eq!(
+            with_prelude(
+                "include \"a\"; .a",
+                &Overrides {
+                    sandbox: true,
+                    ..Overrides::default()
+                },
+                "def f: 1; "
+            ),
+            format!("include \"a\";{}{}def f: 1;  .a", PRELUDE, SANDBOX)
+        );
+        assert!(!SANDBOX.contains('\n'));
+    }
+
+    #[test]
+    fn vars_go_before_definitions() {
+        let overrides = Overrides {
+            sandbox: true,
+            vars: vec!["a".into(), "b".into()],
+            ..Overrides::default()
+        };
+        assert_eq!(
+            with_prelude("$a", &overrides, "def f: 1; "),
+            format!(
+                "{}{}([\"{}\", null] | debug | input) as {{$a, $b}} | def f: 1; $a",
+                PRELUDE, SANDBOX, VARS_TAG
+            )
+        );
+    }
+
+    #[test]
+    fn env() {
+        let mut overrides = Overrides {
+            env: vec![("A".into(), "1".into()), ("B\"".into(), "\n".into())],
+            ..Overrides::default()
+        };
+        assert_eq!(
+            with_prelude(".", &overrides, ""),
+            format!(
+                "{}($ENV + {{\"A\": \"1\", \"B\\\"\": \"\\n\"}}) as $ENV | def env: $ENV; .",
+                PRELUDE
+            )
+        );
+        overrides.sandbox = true;
+        assert!(!with_prelude(".", &overrides, "").contains("def env: $ENV;"));
+    }
+
+    #[test]
+    fn shared_definitions() {
+        let overrides = Overrides {
+            shared: "import \"m\" as m;\n# helpers\ndef f: 1;\n".into(),
+            ..Overrides::default()
+        };
+        assert_eq!(
+            with_prelude("include \"a\";\nf", &overrides, "def g: 2; "),
+            format!(
+                "include \"a\";import \"m\" as m; {}def g: 2;   def f: 1;  \nf",
+                PRELUDE
+            )
+        );
+    }
+
+    #[test]
+    fn squashed() {
+        let squash = |code: &str| {
+            let mut out = String::new();
+            squash_into(code, &mut out);
+            out
+        };
+        assert_eq!(squash(""), "");
+        assert_eq!(squash("def f:\n  1; # one\n"), "def f:   1;   ");
+        assert_eq!(
+            squash("def f: \"a\n#\\\"\\(\"b\n\" + (\")\")) # c\n\";"),
+            "def f: \"a\\n#\\\"\\(\"b\\n\" + (\")\")) # c\\n\"; "
+        );
+        assert_eq!(squash("1 # no newline"), "1  ");
+    }
+
+    #[test]
+    fn finds_directives() {
+        let found = directives("module {};\n# import \"x\";\nimport \"a\" as a; .");
+        let keywords: Vec<_> = found.iter().map(|found| found.keyword).collect();
+        assert_eq!(keywords, vec!["module", "import"]);
+        assert!(directives("includes").is_empty());
+        assert_eq!(found[0].path, None);
+
+        let program =
+            "import \"a/b\" as a; include \"c\\\"\" {search: \"d\"}; import \"e\" as $e; .";
+        assert_eq!(imported_modules(program), vec!["a/b", "c\"", "e"]);
+    }
+}
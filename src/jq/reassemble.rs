@@ -0,0 +1,185 @@
+//! Putting values back together from the `[path, leaf]` events of jq's
+//! `--stream` mode.
+//!
+//! This does the job of `fromstream(depth | truncate_stream(inputs))`, giving
+//! back each value found `depth` levels down as soon as its last event has
+//! been read. Unlike that idiom in jq 1.6, values which are scalars or empty
+//! arrays and objects aren't lost, since they arrive as a single leaf event
+//! with no closing event after it.
+
+use super::JV;
+use crate::errors::{Error, Result};
+use jq_sys::{
+    jv_array_length, jv_array_slice, jv_copy, jv_dump_string_trunc, jv_get_kind,
+    jv_kind_JV_KIND_ARRAY, jv_kind_JV_KIND_OBJECT, jv_kind_name, jv_object_length, jv_setpath,
+};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// How much of a value jq shows in its error messages, including the `...`
+/// marking that it was cut short.
+const ERRORMSG_LEN: usize = 15;
+
+pub struct Reassembler {
+    depth: usize,
+    /// The value being put together, if its first event has been read.
+    current: Option<JV>,
+}
+
+impl Reassembler {
+    /// Put together the values `depth` levels down. A depth of `1` gives the
+    /// elements of top-level arrays and the values of top-level objects.
+    pub fn new(depth: usize) -> Self {
+        Reassembler {
+            depth,
+            current: None,
+        }
+    }
+
+    /// Read the next event, giving back a value when it's the last event for
+    /// one.
+    ///
+    /// Scalars found above `depth` fail the way `.[]` would on them. Empty
+    /// arrays and objects above `depth` have nothing in them to give back.
+    pub fn push(&mut self, event: JV) -> Result<Option<JV>> {
+        let items = match event.array_items() {
+            Some(items) if !items.is_empty() && items.len() <= 2 => items,
+            _ => return Err(malformed()),
+        };
+        let path = &items[0];
+        let len = unsafe {
+            if jv_get_kind(path.ptr) != jv_kind_JV_KIND_ARRAY {
+                return Err(malformed());
+            }
+            jv_array_length(jv_copy(path.ptr)) as usize
+        };
+
+        match items.get(1) {
+            // A leaf, which is a scalar or an empty array or object.
+            Some(leaf) => {
+                if len < self.depth {
+                    return if is_empty_container(leaf) {
+                        Ok(None)
+                    } else {
+                        Err(cannot_iterate(leaf))
+                    };
+                }
+                if len == self.depth {
+                    return Ok(Some(leaf.clone()));
+                }
+                let rest = JV {
+                    ptr: unsafe {
+                        jv_array_slice(jv_copy(path.ptr), self.depth as i32, len as i32)
+                    },
+                };
+                let current = self.current.take().unwrap_or_else(JV::null);
+                self.current = Some(JV {
+                    ptr: unsafe {
+                        jv_setpath(current.into_raw(), rest.into_raw(), leaf.clone().into_raw())
+                    },
+                });
+                Ok(None)
+            }
+            // The end of the array or object holding the item at `path`,
+            // which finishes the value when it's the one at `depth`.
+            None if len == self.depth + 1 => Ok(self.current.take()),
+            None => Ok(None),
+        }
+    }
+}
+
+fn is_empty_container(value: &JV) -> bool {
+    unsafe {
+        #[allow(non_upper_case_globals)]
+        match jv_get_kind(value.ptr) {
+            jv_kind_JV_KIND_ARRAY => jv_array_length(jv_copy(value.ptr)) == 0,
+            jv_kind_JV_KIND_OBJECT => jv_object_length(jv_copy(value.ptr)) == 0,
+            _ => false,
+        }
+    }
+}
+
+/// The same error jq gives for `.[]` on a scalar.
+fn cannot_iterate(value: &JV) -> Error {
+    let mut buf = [0 as c_char; ERRORMSG_LEN];
+    let (kind, shown) = unsafe {
+        let kind = CStr::from_ptr(jv_kind_name(jv_get_kind(value.ptr)));
+        jv_dump_string_trunc(jv_copy(value.ptr), buf.as_mut_ptr(), buf.len());
+        let shown = CStr::from_ptr(buf.as_ptr());
+        (kind.to_string_lossy(), shown.to_string_lossy())
+    };
+    Error::Exec {
+        reason: format!("Cannot iterate over {} ({})", kind, shown),
+        payload: None,
+    }
+}
+
+fn malformed() -> Error {
+    Error::Exec {
+        reason: "Expected a [path, leaf] or [path] event".into(),
+        payload: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reassembler;
+    use crate::jq::{Parser, JV};
+
+    /// The values `depth` levels down in `input`, or the error.
+    fn reassembled(depth: usize, input: &str) -> Result<Vec<String>, String> {
+        let mut reassembler = Reassembler::new(depth);
+        let mut values = vec![];
+        Parser::streaming()
+            .feed(input.as_bytes(), true, |event| {
+                if let Some(value) = reassembler.push(event)? {
+                    let mut rendered = String::new();
+                    value.dump_into(&mut rendered);
+                    values.push(rendered);
+                }
+                Ok(())
+            })
+            .map_err(|err| err.to_string())?;
+        Ok(values)
+    }
+
+    #[test]
+    fn depths() {
+        let input = r#"[1, [2], {"a": [3, {}]}, [], {}, null] {"x": "y"} []"#;
+        assert_eq!(
+            reassembled(1, input).unwrap(),
+            vec!["1", "[2]", "{\"a\":[3,{}]}", "[]", "{}", "null", "\"y\""]
+        );
+        assert_eq!(
+            reassembled(2, r#"[[1, 2], {"a": [3]}, []] [[{"b": 4}]]"#).unwrap(),
+            vec!["1", "2", "[3]", "{\"b\":4}"]
+        );
+        assert_eq!(
+            reassembled(0, "1 [2, [3]] {}").unwrap(),
+            vec!["1", "[2,[3]]", "{}"]
+        );
+    }
+
+    #[test]
+    fn scalars_above_the_depth() {
+        assert_eq!(
+            reassembled(1, "[1] 2 [3]").unwrap_err(),
+            "Cannot iterate over number (2)"
+        );
+        assert_eq!(
+            reassembled(2, r#"[[1], "abcdefghijklmnop"]"#).unwrap_err(),
+            "Cannot iterate over string (\"abcdefghij...)"
+        );
+    }
+
+    #[test]
+    fn malformed_events() {
+        let mut reassembler = Reassembler::new(1);
+        for bad in &["1", "[]", "[1, 2]", "[[0], 1, 2]"] {
+            let event = Parser::new().parse(bad).unwrap();
+            assert!(reassembler.push(event).is_err(), "{}", bad);
+        }
+        let event = JV::array().array_append(JV::array());
+        assert!(reassembler.push(event).unwrap().is_none());
+    }
+}
@@ -0,0 +1,232 @@
+//! Conversion between jq's `jv` values and `serde_json::Value`.
+
+use super::JV;
+use jq_sys::{
+    jv_array_get, jv_array_length, jv_copy, jv_get_kind, jv_kind_JV_KIND_ARRAY,
+    jv_kind_JV_KIND_FALSE, jv_kind_JV_KIND_NUMBER, jv_kind_JV_KIND_OBJECT, jv_kind_JV_KIND_STRING,
+    jv_kind_JV_KIND_TRUE, jv_null, jv_number_value, jv_object_iter, jv_object_iter_key,
+    jv_object_iter_next, jv_object_iter_valid, jv_object_iter_value,
+};
+use serde_json::{Map, Number, Value};
+use std::os::raw::c_int;
+
+impl JV {
+    /// Build a `serde_json::Value` with the same shape as this `JV`.
+    ///
+    /// The result should match what you'd get by parsing the output of
+    /// `dump_into()` with serde_json.
+    pub fn to_value(&self) -> Value {
+        unsafe {
+            #[allow(non_upper_case_globals)]
+            match jv_get_kind(self.ptr) {
+                jv_kind_JV_KIND_FALSE => Value::Bool(false),
+                jv_kind_JV_KIND_TRUE => Value::Bool(true),
+                jv_kind_JV_KIND_NUMBER => number(jv_number_value(self.ptr)),
+                jv_kind_JV_KIND_STRING => Value::String(self.string_contents()),
+                jv_kind_JV_KIND_ARRAY => {
+                    let len = jv_array_length(jv_copy(self.ptr));
+                    let items = (0..len)
+                        .map(|idx| {
+                            JV {
+                                ptr: jv_array_get(jv_copy(self.ptr), idx),
+                            }
+                            .to_value()
+                        })
+                        .collect();
+                    Value::Array(items)
+                }
+                jv_kind_JV_KIND_OBJECT => {
+                    let mut map = Map::new();
+                    // Unlike most of the jv api, the object iterator functions
+                    // borrow the object rather than consuming it.
+                    let mut iter: c_int = jv_object_iter(self.ptr);
+                    while jv_object_iter_valid(self.ptr, iter) != 0 {
+                        let key = JV {
+                            ptr: jv_object_iter_key(self.ptr, iter),
+                        };
+                        let value = JV {
+                            ptr: jv_object_iter_value(self.ptr, iter),
+                        };
+                        map.insert(key.string_contents(), value.to_value());
+                        iter = jv_object_iter_next(self.ptr, iter);
+                    }
+                    Value::Object(map)
+                }
+                // Null, and invalid (which never shows up as an output).
+                _ => Value::Null,
+            }
+        }
+    }
+
+    /// Build a `JV` with the same shape as a `serde_json::Value`, for inputs
+    /// which didn't start out as json text and the results of functions
+    /// implemented in Rust.
+    ///
+    /// Like jq, every number becomes a double, so integers beyond 2^53 lose
+    /// precision.
+    pub fn from_value(value: &Value) -> JV {
+        match value {
+            Value::Null => JV {
+                ptr: unsafe { jv_null() },
+            },
+            Value::Bool(b) => JV::bool(*b),
+            Value::Number(n) => JV::number(n.as_f64().unwrap_or(f64::NAN)),
+            Value::String(s) => JV::string(s),
+            Value::Array(items) => items.iter().fold(JV::array(), |array, item| {
+                array.array_append(JV::from_value(item))
+            }),
+            Value::Object(map) => map.iter().fold(JV::object(), |object, (key, value)| {
+                object.object_set(JV::string(key), JV::from_value(value))
+            }),
+        }
+    }
+
+    /// Copy the text out of a string `JV`, replacing any invalid UTF-8 the
+    /// same as `as_str_lossy()`.
+    ///
+    /// Safety: the `JV` must be a string.
+    unsafe fn string_contents(&self) -> String {
+        self.as_str_lossy().into_owned()
+    }
+}
+
+/// jq stores every number as a double, but renders those without a fractional
+/// part as integers, which serde_json would then parse as `i64`/`u64`.
+fn number(n: f64) -> Value {
+    if n.is_nan() {
+        // jq renders NaN as `null`.
+        return Value::Null;
+    }
+    // ... and clamps infinities to the largest finite double.
+    let n = if n.is_infinite() {
+        n.signum() * f64::MAX
+    } else {
+        n
+    };
+
+    if n.fract() == 0.0 && !(n == 0.0 && n.is_sign_negative()) {
+        // Every integer below 2^53 is exact, so it renders as itself.
+        if n.abs() < 9_007_199_254_740_992.0 {
+            return Value::from(n as i64);
+        }
+        if let Some(value) = large_integer(n) {
+            return value;
+        }
+    }
+    Number::from_f64(n).map_or(Value::Null, Value::Number)
+}
+
+/// jq renders numbers with the shortest digits which read back as the same
+/// double, padding with zeros up to the decimal point. When that would take
+/// more than 15 zeros it switches to exponent notation (which serde_json reads
+/// as a float).
+///
+/// Past 2^53 the rendered digits can differ from the exact value of the
+/// double, so the integer is rebuilt from the same digits jq would print.
+fn large_integer(n: f64) -> Option<Value> {
+    // Rust also formats with the shortest round trip digits, eg `1.2345e17`.
+    let formatted = format!("{:e}", n.abs());
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e')?);
+    let exponent: i32 = exponent[1..].parse().ok()?;
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+
+    let zeros = exponent + 1 - digits.len() as i32;
+    if zeros > 15 {
+        return None;
+    }
+    let mut magnitude: u64 = digits.parse().ok()?;
+    for _ in 0..zeros {
+        magnitude = magnitude.checked_mul(10)?;
+    }
+
+    if n > 0.0 {
+        Some(Value::from(magnitude))
+    } else if magnitude <= 1 << 63 {
+        Some(Value::from((magnitude as i64).wrapping_neg()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::lone_surrogate;
+    use super::super::{Parser, JV};
+    use serde_json::{json, Value};
+
+    fn convert(input: &str) -> Value {
+        Parser::new().parse(input).unwrap().to_value()
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        let value = lone_surrogate();
+        assert_eq!(value.to_value(), json!("\u{FFFD}\u{FFFD}\u{FFFD}"));
+        let object = JV::object().object_set(lone_surrogate(), JV::number(1.0));
+        assert_eq!(object.to_value(), json!({"\u{FFFD}\u{FFFD}\u{FFFD}": 1}));
+    }
+
+    #[test]
+    fn scalars() {
+        assert_eq!(convert("null"), Value::Null);
+        assert_eq!(convert("true"), json!(true));
+        assert_eq!(convert("false"), json!(false));
+        assert_eq!(convert("\"abc\""), json!("abc"));
+        assert_eq!(convert("\"nul\\u0000byte\""), json!("nul\u{0}byte"));
+    }
+
+    #[test]
+    fn numbers_match_the_rendered_output() {
+        for input in &[
+            "0",
+            "-1",
+            "1.5",
+            "-0.25",
+            "-0",
+            "1e3",
+            "9007199254740993",
+            "1e15",
+            "1e16",
+            "-1e17",
+            "123456789012345678",
+            "12345678901234567890",
+            "-9223372036854775808",
+            "-12345678901234567890",
+            "1e19",
+            "1e300",
+        ] {
+            let value = Parser::new().parse(input).unwrap();
+            let mut rendered = String::new();
+            value.dump_into(&mut rendered);
+            let parsed: Value = serde_json::from_str(&rendered).unwrap();
+            assert_eq!(value.to_value(), parsed, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn nested() {
+        let input = r#"{"a": [1, {"b": null}, []], "c": {}, "d": "e"}"#;
+        let expected: Value = serde_json::from_str(input).unwrap();
+        assert_eq!(convert(input), expected);
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn key_order() {
+        let value = convert(r#"{"b": 1, "a": {"d": 2, "c": 3}}"#);
+        let keys: Vec<_> = value.as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["b", "a"]);
+        let keys: Vec<_> = value["a"].as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["d", "c"]);
+
+        let round_tripped = JV::from_value(&value).to_value();
+        let keys: Vec<_> = round_tripped.as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = json!({"a": [1, -2.5, {"b": null}, []], "c": {}, "d": "e\u{0}f", "g": true});
+        assert_eq!(JV::from_value(&value).to_value(), value);
+    }
+}
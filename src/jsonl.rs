@@ -0,0 +1,156 @@
+//! Processing newline delimited json ([JSON Lines]) a line at a time.
+//!
+//! Unlike `jq_rs::stream`, a line which fails to parse or run doesn't stop the
+//! rest of the input from being processed. The failure is reported with its
+//! line number and processing carries on with the next line, which is what
+//! you want when working through log files.
+//!
+//! Memory use stays proportional to the longest line and the output from it,
+//! since each line's outputs are only written once the line has succeeded.
+//!
+//! ```rust
+//! let input = "{\"level\": \"info\"}\nnot json\n{\"level\": \"warn\"}\n";
+//! let mut output = Vec::new();
+//! let mut program = jq_rs::compile(".level").unwrap();
+//!
+//! let mut errors = Vec::new();
+//! jq_rs::jsonl::transform(input.as_bytes(), &mut program, &mut output, |err| errors.push(err))
+//!     .unwrap();
+//!
+//! assert_eq!(output, b"\"info\"\n\"warn\"\n");
+//! assert_eq!(errors[0].line, Some(2));
+//! ```
+//!
+//! [JSON Lines]: https://jsonlines.org
+
+use crate::cancel::Interrupt;
+use crate::errors::{BatchError, Error, Result};
+use crate::JqProgram;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Run the program on each line of the file at `input`, writing the outputs
+/// to a new file at `output` (replacing it, if it already exists).
+///
+/// Gives back an error for each line which failed. Failing to read or write
+/// the files stops processing with an `Error::Io`.
+pub fn process<P, Q>(input: P, program: &mut JqProgram, output: Q) -> Result<Vec<BatchError>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let reader = BufReader::new(File::open(input)?);
+    let writer = BufWriter::new(File::create(output)?);
+    let mut errors = vec![];
+    transform(reader, program, writer, |err| errors.push(err))?;
+    Ok(errors)
+}
+
+/// Run the program on each line read from `reader`, writing the outputs to
+/// `writer` and handing lines which fail to `on_error`.
+///
+/// Blank lines are skipped. As with `JqProgram::run_lines()`, the `index` of
+/// each error counts the lines holding a value, and `line` is the line number
+/// in the input. A line which isn't valid UTF-8 fails with
+/// `Error::StringConvert`.
+///
+/// Gives back the number of lines which were run, whether they succeeded or
+/// not. Failing to read or write stops processing with an `Error::Io`.
+pub fn transform<R, W, F>(
+    mut reader: R,
+    program: &mut JqProgram,
+    mut writer: W,
+    mut on_error: F,
+) -> Result<usize>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(BatchError),
+{
+    let mut bytes = vec![];
+    let mut rendered = String::with_capacity(program.output_capacity);
+    let interrupt = Interrupt::default();
+    let mut index = 0;
+    let mut line = 0;
+
+    loop {
+        bytes.clear();
+        if reader.read_until(b'\n', &mut bytes)? == 0 {
+            break;
+        }
+        line += 1;
+        let result = match std::str::from_utf8(&bytes) {
+            Ok(text) if text.trim().is_empty() => continue,
+            Ok(text) => {
+                rendered.clear();
+                program.jq.execute(text, &interrupt, &mut rendered)
+            }
+            Err(err) => Err(Error::StringConvert { err: Box::new(err) }),
+        };
+        match result {
+            Ok(()) => writer.write_all(rendered.as_bytes())?,
+            Err(error) => on_error(BatchError {
+                index,
+                line: Some(line),
+                error,
+            }),
+        }
+        index += 1;
+    }
+    writer.flush()?;
+    Ok(index)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{process, transform};
+    use crate::{compile, Error};
+    use matches::assert_matches;
+    use std::fs;
+
+    #[test]
+    fn failures_dont_stop_processing() {
+        let input = b"[1]\n\n\"a\"\n\xff\n[2, 3]\n{\n";
+        let mut output = vec![];
+        let mut errors = vec![];
+        let mut program = compile(".[]").unwrap();
+        let lines = transform(&input[..], &mut program, &mut output, |err| {
+            errors.push(err)
+        })
+        .unwrap();
+
+        assert_eq!(lines, 5);
+        assert_eq!(output, b"1\n2\n3\n");
+        let failed: Vec<_> = errors.iter().map(|err| (err.index, err.line)).collect();
+        assert_eq!(failed, vec![(1, Some(3)), (2, Some(4)), (4, Some(6))]);
+        assert_matches!(errors[0].error, Error::Exec { .. });
+        assert_matches!(errors[1].error, Error::StringConvert { .. });
+        assert_matches!(errors[2].error, Error::Parse { .. });
+    }
+
+    #[test]
+    fn partial_output_is_dropped() {
+        let mut output = vec![];
+        let mut program = compile(".[] | if . == 2 then error else . end").unwrap();
+        transform(&b"[1, 2]\n[3]"[..], &mut program, &mut output, |_| {}).unwrap();
+        assert_eq!(output, b"3\n");
+    }
+
+    #[test]
+    fn files() {
+        let dir = std::env::temp_dir().join(format!("jq-rs-jsonl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("in.jsonl"), dir.join("out.jsonl"));
+        fs::write(&input, "{\"a\": 1}\n{\"a\": 2}\n").unwrap();
+
+        let mut program = compile(".a * 10").unwrap();
+        let errors = process(&input, &mut program, &output).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "10\n20\n");
+
+        let missing = process(dir.join("missing"), &mut program, &output);
+        assert_matches!(missing, Err(Error::Io { .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -7,7 +7,7 @@
 //!
 //! By leveraging [jq] we can extract data from json strings using `jq`'s dsl.
 //!
-//! This crate requires Rust **1.32** or above.
+//! This crate requires Rust **1.72** or above.
 //!
 //! ## Usage
 //!
@@ -54,6 +54,24 @@
 //! );
 //! ```
 //!
+//! When all you need is a single value, `jq_rs::get()` takes a path such as
+//! `.a.b[3].c` in place of a program, and caches the compiled version for next
+//! time.
+//!
+//! For one-off runs which need a few options, `jq_rs::runner()` builds them up
+//! one at a time.
+//!
+//! ```rust
+//! let output = jq_rs::runner()
+//!     .program(".[] | select(.height > $min) | .name")
+//!     .arg("min", 10)
+//!     .raw_output()
+//!     .input(r#"[{"name": "Godzilla", "height": 50}, {"name": "Mothra", "height": 0.5}]"#)
+//!     .run()
+//!     .unwrap();
+//! assert_eq!(output, "Godzilla\n");
+//! ```
+//!
 //! ## A Note on Performance
 //!
 //! While the benchmarks are far from exhaustive, they indicate that much of the
@@ -75,6 +93,74 @@
 //! If you have a need to run the same jq program multiple times it is
 //! _highly recommended_ to retain a pre-compiled `JqProgram` and reuse it.
 //!
+//! A `JqProgram` can be moved between threads, but running it requires
+//! `&mut self`. When a single program needs to be shared, for example as state
+//! in a web server, wrap it in a `SyncJqProgram` which can be run via `&self`.
+//! Alternatively, a `JqWorker` keeps compiled programs on a dedicated thread
+//! and accepts inputs over a channel.
+//!
+//! When one document needs to go through many programs, `jq_rs::parse()` it
+//! once and hand the `ParsedJson` to `JqProgram::run_parsed()` for each,
+//! rather than paying for the parse on every run. `jq_rs::run_many()` does
+//! this for a slice of programs.
+//!
+//! Programs maintained separately can be chained into a `JqPipeline` with
+//! `JqProgram::then()`. Values flow between the stages without being rendered
+//! to text and parsed again.
+//!
+//! Services which see the same programs repeatedly (but don't know them ahead
+//! of time) can use a `ProgramCache`, which keeps pools of compiled programs
+//! keyed by their source and can be shared between threads, dropping the least
+//! recently used ones once it holds too many.
+//! `ProgramCache::save()` writes the sources it holds to a file, and
+//! `ProgramCache::load()` compiles them into the cache at the next startup, so
+//! the first requests don't wait for them to compile.
+//!
+//! Services which route data to programs by name can store them in a
+//! `FilterRegistry`, which compiles each one the first time it's run.
+//!
+//! Settings shared by many programs, like args, additions to `$ENV`, library
+//! paths and registered functions, can be collected in a `Scope` for them to
+//! be compiled against. Scopes are cheap to clone and adjust for each tenant.
+//!
+//! ## Large Inputs
+//!
+//! For inputs too large to comfortably hold in memory, such as newline delimited
+//! json files, the functions in `jq_rs::stream` read from a `std::io::Read` in
+//! chunks and write each output to a `std::io::Write` as soon as it is produced.
+//! With `transform_events()`, a single huge document is broken up into events
+//! the same way as with `jq --stream`, and `transform_elements()` runs the program
+//! on each element of a huge top-level array in turn. `stream::elements()` reads
+//! the values at any depth one at a time, without a program.
+//!
+//! `jq_rs::jsonl` works through newline delimited json a line at a time, reporting
+//! lines which fail (with their line numbers) without stopping, as suits log files.
+//! For several documents which aren't one to a line, such as pretty printed ones,
+//! `JqProgram::run_documents_lenient()` does the same, skipping past documents which
+//! fail to parse.
+//!
+//! ## Async
+//!
+//! With the `async` feature enabled (**off by default**), `jq_rs::run_async()`
+//! and `AsyncJqProgram` run jq programs on a background thread pool so they
+//! don't block the executor. These futures aren't tied to a specific runtime,
+//! so they work the same under tokio, async-std, or smol.
+//!
+//! `JqStreamExt::jq_filter()` runs a program over each item of a `Stream` of
+//! json inputs, taking one at a time so slow consumers push back on the stream.
+//!
+//! The `tower` feature builds on this with `jq_rs::tower`, middleware which runs
+//! a program over the json bodies of requests or responses in axum and other
+//! [tower] based services.
+//!
+//! Likewise, the `actix` feature adds `jq_rs::actix`, whose `Jq<T>` extractor
+//! runs a filter from a `FilterRegistry` over the request body and hands the
+//! [actix-web] handler the output as a typed value.
+//!
+//! On the client side, the `reqwest` feature adds `jq_rs::reqwest::ResponseExt`,
+//! whose `response.jq(program)` streams a [reqwest] response body into jq's
+//! parser and deserializes the program's outputs.
+//!
 //! ## Handling Output
 //!
 //! The return values from jq are _strings_ since there is no certainty that the
@@ -110,11 +196,144 @@
 //! assert_eq!(vec![2009, 2012, 2014, 2016, 2019], parsed);
 //! ```
 //!
-//! Barely any of the options or flags available from the [jq] cli are exposed
-//! currently.
-//! Literally all that is provided is the ability to execute a _jq program_ on a blob
-//! of json.
-//! Please pardon my dust as I sort out the details.
+//! With the `serde_json` feature enabled (**off by default**),
+//! `JqProgram::run_values()` skips the round trip through text, converting the
+//! outputs from jq straight into `serde_json::Value`s. It also adds
+//! `Compiler::register_fn()`, for calling functions written in Rust from programs,
+//! such as looking up application data the input only refers to.
+//!
+//! The `serde` feature goes a step further with `JqProgram::run_as()`, which
+//! deserializes each output into your own types, and
+//! `JqProgram::run_deserialized()`, which hands out each output as a
+//! `serde::Deserializer` for transcoding into other serde formats.
+//!
+//! Similarly, the `bytes` feature adds `JqProgram::run_bytes()` for services
+//! passing request and response bodies around as `bytes::Bytes`.
+//!
+//! With the `yaml` feature enabled (**off by default**), `jq_rs::run_yaml()` and
+//! `JqProgram::run_yaml()` take YAML input, running the program on each document,
+//! and `JqProgram::run_yaml_to_yaml()` renders the outputs back to YAML.
+//! Likewise, the `toml` feature adds `jq_rs::run_toml()` and `JqProgram::run_toml()`.
+//! The `msgpack` and `cbor` features add `JqProgram::run_msgpack()` and
+//! `JqProgram::run_cbor()` for binary inputs, along with `run_msgpack_to_msgpack()`
+//! and `run_cbor_to_cbor()` which encode each output the same way.
+//!
+//! jq can write CSV with `@csv`, but can't read it. With the `csv` feature enabled
+//! (**off by default**), `JqProgram::run_csv()` turns CSV or TSV with a header row
+//! into objects for the program to work on.
+//!
+//! Inputs which need some glue before they're json, like a syslog prefix or a
+//! base64 envelope, can be given it with `Compiler::preprocessor()`. The
+//! `PreProcessor` is handed the raw bytes of each input (each line, for
+//! `jq_rs::jsonl`) just before it's parsed.
+//! Likewise, `Compiler::postprocessor()` sets a `PostProcessor` for each output
+//! once it's rendered, to add a record separator or redact parts of it.
+//!
+//! With the `log` feature enabled (**off by default**), messages jq reports
+//! (including warnings while compiling) are logged at the `warn` level, and values
+//! passed to `debug` are logged at the `debug` level unless a handler has been set
+//! with `JqProgram::set_debug_handler()`. Everything is logged with the `jq_rs`
+//! target.
+//!
+//! With the `otel` feature enabled (**off by default**), compiling and running
+//! programs records [OpenTelemetry] spans named `jq.compile` and `jq.run` with the
+//! globally installed tracer provider. Each carries a hash of the program's text
+//! as `jq.program.hash`, the outcome as `jq.outcome`, the time spent in seconds,
+//! and (for runs) the number and size of the outputs. Failures set the span's
+//! status to an error and record the `ErrorKind` as `error.type`.
+//!
+//! `Compiler::metrics()` reports the number of runs, failures by `ErrorKind`, and
+//! how long each run took and how much it output to your own implementation of
+//! the `Metrics` trait. With the `prometheus` feature enabled (**off by
+//! default**), `PrometheusMetrics` keeps them in a [prometheus] `Registry`.
+//!
+//! `Compiler` exposes the options from the [jq] cli which change how a program is
+//! compiled or how its outputs are rendered, such as `--arg`, `--argjson`, `-r` and
+//! `--indent`.
+//!
+//! ```rust
+//! let mut program = jq_rs::Compiler::new()
+//!     .arg("name", "Mothra")
+//!     .raw_output(true)
+//!     .compile(r#""Hello, \($name)""#)
+//!     .unwrap();
+//!
+//! assert_eq!(program.run("null").unwrap(), "Hello, Mothra\n");
+//! ```
+//!
+//! Like jq, objects keep their keys in the order they were added: the order of the
+//! input, with keys the program adds coming after the ones already there. This is
+//! the default for rendered outputs, and `Compiler::sort_keys()` sorts them instead,
+//! like `-S`. The `serde_json::Map`s in the values from `JqProgram::run_values()`
+//! sort their keys unless serde_json's `preserve_order` feature is on, which the
+//! `preserve_order` feature of this crate turns on.
+//!
+//! `localtime` and `strflocaltime` use the time zone of the machine, from the
+//! process' `TZ`, which can't safely be changed while other threads are running.
+//! With the `tz` feature enabled (**off by default**), `JqProgram::set_timezone()`
+//! picks a `TimeZone` for the program's runs instead, such as the zone of the user
+//! a report is for.
+//!
+//! Numbers are read and written the same way whatever the locale, since libjq
+//! parses and formats them with its own code rather than the C library's. The date
+//! builtins do follow the locale set with `setlocale()`, and with the `locale`
+//! feature enabled (**off by default**), `Compiler::c_locale()` runs programs in the
+//! C locale so they don't.
+//!
+//! jq's arithmetic can produce NaN and the infinities, which it writes as `null` and
+//! `±1.7976931348623157e+308` to keep the output valid json. `Compiler::non_finite()`
+//! picks a `NonFinite` policy to fail the run instead, or to write them as `null` or
+//! as the strings `"NaN"`, `"Infinity"` and `"-Infinity"`.
+//!
+//! When a program has to be built up as text, `format_program!()` works like
+//! `format!()` but writes each argument as a jq literal, so a string from a user
+//! can't break out of its quotes. `escape_string()` does the same for a single
+//! string, and `Query` builds whole programs from parts (fields, comparisons,
+//! `select()` and so on) with the quoting and parentheses taken care of.
+//!
+//! For filters which come from someone you don't trust, `Compiler::sandbox()` stops
+//! programs from reading the environment or importing modules, failing with
+//! `Error::Policy` when they try. `Compiler::max_input_bytes()` refuses inputs over
+//! a size with `Error::InputTooLarge`, `Compiler::max_outputs()` and
+//! `Compiler::max_output_bytes()` put a cap on what each run can produce, and an
+//! `InterruptHandle` from `JqProgram::interrupt_handle()` can stop a run from
+//! another thread between its outputs.
+//!
+//! To vet a program before compiling it at all, `referenced_builtins()` lists the
+//! builtins it calls (`env/0`, `input/0`, etc) for checking against a policy of your
+//! own, and `lint()` points out likely mistakes such as unused definitions and
+//! shadowed variables, for giving feedback to whoever wrote it. With the `serde_json`
+//! feature, `explain()` describes the whole program as a tree of json objects.
+//!
+//! With the `testing` feature enabled (**off by default**), `assert_jq!()` checks the
+//! outputs of a program against the json expected of it for your tests, comparing
+//! values rather than text and showing a diff when they don't match. For snapshot
+//! tests and golden files, `Compiler::stable_output()` renders outputs the same way
+//! whichever version of libjq is linked.
+//!
+//! There is no limit on memory use, since libjq 1.6 has no hooks for it. It calls
+//! `malloc` directly rather than through an allocator which could be swapped out or
+//! counted, and when an allocation fails it aborts the whole process, even with a
+//! handler set via `jv_nomem_handler()`. Programs from untrusted sources which could
+//! build huge values are best run in a separate process with a memory limit set by
+//! the operating system (`setrlimit`, cgroups, etc).
+//!
+//! With the `cli` feature enabled (**off by default**), the crate also builds a
+//! `jq-rs` binary which accepts the common flags of the jq cli.
+//!
+//! The flags themselves are available to other [clap] based clis as
+//! `JqCliOptions`, with the `clap` feature enabled (**off by default**).
+//!
+//! `Compiler::color_output()` colors outputs with ANSI escape codes, like `jq -C`.
+//! With the `termcolor` feature enabled (**off by default**), `write_colored()`
+//! writes them through a `termcolor::WriteColor` instead, so the colors show up on
+//! Windows consoles too and are left out when `NO_COLOR` is set.
+//! `Compiler::color_palette()` swaps jq's colors for a `ColorPalette`, which can use
+//! 256 or 24-bit colors and is read from and written as `JQ_COLORS`.
+//!
+//! `Compiler::html_output()` writes outputs as HTML instead, with each token in a
+//! `<span>` whose class (set with `HtmlClasses`) says what kind of token it is, for
+//! highlighting results on a web page.
 //!
 //! ## Linking to libjq
 //!
@@ -125,6 +344,13 @@
 //! and linked statically to your crate by [jq-sys] and [jq-src]. Using this feature
 //! requires having autotools and gcc in `PATH` in order for the to build to work.
 //!
+//! The regex builtins (`test`, `match`, `capture`, `sub` and `gsub`) need `libjq`
+//! to have been built with oniguruma. Whether it is depends on how [jq-src] builds
+//! the bundled copy, which this crate has no say in, so when regex support matters
+//! it's safest to link a system `libjq` which has it. Without it those builtins fail
+//! with an `Error::Exec` when they're run. `jq_rs::capabilities()` reports
+//! whether they're available, and `jq_rs::version()` which version of jq is linked.
+//!
 //! Without the `bundled` feature, _you_ will need to ensure your crate
 //! can link to `libjq` in order for the bindings to work.
 //!
@@ -133,8 +359,41 @@
 //! See the [jq-sys building docs][jq-sys-building] for details on how to share
 //! hints with the [jq-sys] crate on how to link.
 //!
+//! The bindings in [jq-sys] are generated from the jq 1.6 headers, and jq 1.6 is the
+//! only version this crate is tested against. A system `libjq` from jq 1.7 keeps
+//! the same signatures for the functions used here, so it should link, but outputs
+//! can differ from 1.6 (numbers keep the precision of their input literals, error
+//! messages are worded differently, and there are new builtins). Proper support
+//! for 1.7 is waiting on bindings for it in [jq-sys].
+//!
+//! With a dynamically linked `libjq`, the copy found at runtime can be from a
+//! different release than the one the crate was built against. Calling
+//! `jq_rs::ensure_compatible()` at startup fails with an `Error::UnsupportedVersion`
+//! when it's from anything but jq 1.6 or 1.7, rather than leaving it to show up as
+//! crashes or odd results later on.
+//!
+//! With the `sys` feature enabled (**off by default**), `jq_rs::sys` re-exports the
+//! raw bindings from [jq-sys], so crates which need calls this one doesn't wrap can
+//! make them without depending on a second, possibly different, version of jq-sys.
+//!
+//! WebAssembly targets (`wasm32-wasi` and emscripten) are not supported yet. The
+//! `bundled` feature builds `libjq` with autotools via [jq-src], which doesn't know
+//! how to cross-compile for wasm, so that needs solving in [jq-sys] and [jq-src]
+//! first. Once `libjq` can be linked, note that `JqWorker` and the `async` feature
+//! rely on threads, which wasi doesn't provide.
+//!
+//! Loading `libjq` at runtime (with `dlopen` or similar) isn't offered either.
+//! [jq-sys] always asks the linker for `libjq`, so a binary built against this
+//! crate can't start without it no matter how the symbols are resolved later.
+//!
 //! [jq]: https://github.com/stedolan/jq
 //! [serde_json]: https://github.com/serde-rs/json
+//! [clap]: https://crates.io/crates/clap
+//! [OpenTelemetry]: https://crates.io/crates/opentelemetry
+//! [prometheus]: https://crates.io/crates/prometheus
+//! [tower]: https://crates.io/crates/tower
+//! [actix-web]: https://crates.io/crates/actix-web
+//! [reqwest]: https://crates.io/crates/reqwest
 //! [jq-rs]: https://crates.io/crates/jq-rs
 //! [json-query]: https://crates.io/crates/json-query
 //! [jq-sys]: https://github.com/onelson/jq-sys
@@ -144,16 +403,135 @@
 #![deny(missing_docs)]
 
 extern crate jq_sys;
+/// The raw `libjq` bindings from [jq-sys], the same version this crate is
+/// built on, for calls it doesn't wrap.
+///
+/// Requires the `sys` feature. Everything in here is `unsafe`, and keeping
+/// track of `jv` reference counts is up to the caller.
+///
+/// ```rust
+/// use jq_rs::sys::{jv_free, jv_get_kind, jv_kind_JV_KIND_NUMBER, jv_number};
+///
+/// unsafe {
+///     let value = jv_number(1.5);
+///     assert_eq!(jv_get_kind(value), jv_kind_JV_KIND_NUMBER);
+///     jv_free(value);
+/// }
+/// ```
+///
+/// [jq-sys]: https://github.com/onelson/jq-sys
+#[cfg(feature = "sys")]
+pub extern crate jq_sys as sys;
 #[cfg(test)]
 #[macro_use]
 extern crate serde_json;
 
+#[cfg(feature = "actix")]
+pub mod actix;
+mod arena;
+mod audit;
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+mod binary;
+mod cache;
+mod cancel;
+#[cfg(feature = "clap")]
+mod cli;
+#[cfg(feature = "termcolor")]
+mod color;
+mod compiler;
+#[cfg(feature = "csv")]
+mod delimited;
+mod documents;
 mod errors;
+#[cfg(feature = "serde_json")]
+mod explain;
+mod get;
 mod jq;
+pub mod jsonl;
+mod lint;
+mod literal;
+mod metrics;
+#[cfg(feature = "async")]
+mod nonblocking;
+#[cfg(feature = "otel")]
+mod otel;
+mod parsed;
+mod path;
+mod pipeline;
+mod processors;
+mod query;
+mod registry;
+#[cfg(feature = "reqwest")]
+pub mod reqwest;
+mod runner;
+mod scope;
+pub mod stream;
+mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tz")]
+mod timezone;
+#[cfg(feature = "toml")]
+mod toml;
+#[cfg(feature = "tower")]
+pub mod tower;
+mod version;
+mod worker;
+#[cfg(feature = "yaml")]
+mod yaml;
 
-use std::ffi::CString;
+use cancel::Interrupt;
+use std::hash::{Hash, Hasher};
+use std::result;
+use std::time::{Duration, Instant};
 
-pub use errors::{Error, Result};
+#[cfg(feature = "toml")]
+pub use crate::toml::run_toml;
+pub use arena::OutputArena;
+pub use audit::referenced_builtins;
+pub use cache::{CacheStats, CachedProgram, ProgramCache};
+pub use cancel::{CancellationToken, InterruptHandle};
+#[cfg(feature = "clap")]
+pub use cli::JqCliOptions;
+#[cfg(feature = "termcolor")]
+pub use color::write_colored;
+pub use compiler::Compiler;
+#[cfg(feature = "csv")]
+pub use delimited::CsvOptions;
+pub use documents::DocumentOutput;
+pub use errors::{BatchError, Error, ErrorKind, Result};
+#[cfg(feature = "serde_json")]
+pub use explain::explain;
+pub use get::get;
+#[cfg(feature = "serde")]
+pub use get::get_as;
+#[cfg(feature = "serde")]
+pub use jq::OutputDeserializer;
+pub use jq::{ColorPalette, HtmlClasses, NonFinite, PaletteToken, TokenStyle};
+pub use lint::{lint, LintKind, LintWarning};
+#[doc(hidden)]
+pub use literal::to_literal;
+pub use literal::{escape_string, JqLiteral};
+#[cfg(feature = "prometheus")]
+pub use metrics::PrometheusMetrics;
+pub use metrics::{Metrics, NoopMetrics};
+#[cfg(feature = "async")]
+pub use nonblocking::{run_async, AsyncJqProgram, JqFilter, JqStreamExt};
+pub use parsed::{parse, ParsedJson};
+pub use path::{JqPath, PathSegment};
+pub use pipeline::JqPipeline;
+pub use processors::{PostProcessor, PreProcessor};
+pub use query::Query;
+pub use registry::{FilterRegistry, RegisteredProgram};
+pub use runner::{runner, ArgValue, Runner};
+pub use scope::Scope;
+pub use sync::SyncJqProgram;
+#[cfg(feature = "tz")]
+pub use timezone::TimeZone;
+pub use version::{capabilities, ensure_compatible, version, Capabilities};
+pub use worker::{JqWorker, Pending, WorkerProgram};
+#[cfg(feature = "yaml")]
+pub use yaml::run_yaml;
 
 /// Run a jq program on a blob of json data.
 ///
@@ -165,41 +543,867 @@ pub fn run(program: &str, data: &str) -> Result<String> {
     compile(program)?.run(data)
 }
 
+/// Run several pre-compiled jq programs on the same blob of json data.
+///
+/// The data is only parsed once, then each program gets its own reference to
+/// the parsed value. A failure parsing the data is returned as the outer
+/// `Err`, otherwise there's a result for each program, in the same order as
+/// `programs`.
+///
+/// ```rust
+/// let mut programs = vec![
+///     jq_rs::compile(".name").unwrap(),
+///     jq_rs::compile(".height").unwrap(),
+///     jq_rs::compile(".name.first").unwrap(),
+/// ];
+/// let data = r#"{"name": "Godzilla", "height": 50}"#;
+/// let results = jq_rs::run_many(&mut programs, data).unwrap();
+///
+/// assert_eq!(results[0].as_ref().unwrap(), "\"Godzilla\"\n");
+/// assert_eq!(results[1].as_ref().unwrap(), "50\n");
+/// assert!(results[2].is_err());
+/// ```
+pub fn run_many(programs: &mut [JqProgram], data: &str) -> Result<Vec<Result<String>>> {
+    let parsed = parse(data)?;
+    Ok(programs
+        .iter_mut()
+        .map(|program| program.run_parsed(&parsed))
+        .collect())
+}
+
+/// Everything reported by a run of a jq program via `JqProgram::run_full()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunOutput {
+    /// The rendered outputs, same as returned by `JqProgram::run()`.
+    pub output: String,
+    /// The exit code the jq cli would finish with: `0` unless the program
+    /// halted with another code.
+    pub exit_code: i32,
+    /// True when the program stopped itself with `halt` or `halt_error`
+    /// rather than running to completion.
+    pub halted: bool,
+    /// The value passed to `halt_error`, in the same form as
+    /// `Error::Halted`'s payload.
+    pub halt_payload: Option<String>,
+    /// Everything the program wrote with jq's `stderr` builtin, unless it was
+    /// sent to a handler set with `JqProgram::set_stderr_handler()`.
+    pub stderr: String,
+    /// Timings and counts for the run.
+    pub stats: RunStats,
+}
+
+/// Numbers describing a single run of a jq program, from
+/// `RunOutput::stats` or a handler set with `JqProgram::set_stats_handler()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunStats {
+    /// Time spent parsing the input. Zero when the input was parsed ahead of
+    /// time, or (for `jq_rs::stream`) in pieces as it was read.
+    pub parse_time: Duration,
+    /// Time spent running the program and rendering its outputs.
+    pub eval_time: Duration,
+    /// The number of outputs the program produced.
+    pub outputs: usize,
+    /// The total size of the rendered outputs, including the newline after
+    /// each. Zero when the outputs weren't rendered as text, such as with
+    /// `JqProgram::run_values()`.
+    pub output_bytes: usize,
+}
+
 /// A pre-compiled jq program which can be run against different inputs.
+///
+/// Programs are equal, and hash the same, when they were compiled from the
+/// same source with the same `Compiler` options, so they can be used as keys
+/// in maps and deduplicated in sets. Settings changed after compiling, like
+/// `set_timezone()`, aren't compared, and neither are `Compiler::metrics()`.
+/// Functions added with `Compiler::register_fn()` and pre- and
+/// post-processors are the same only when they're the very same ones, as
+/// for programs compiled with one `Compiler` (or clones of it).
+///
+/// ```rust
+/// use jq_rs::Compiler;
+/// use std::collections::HashSet;
+///
+/// let programs: HashSet<_> = vec![
+///     jq_rs::compile(".a").unwrap(),
+///     jq_rs::compile(".a").unwrap(),
+///     Compiler::new().raw_output(true).compile(".a").unwrap(),
+/// ]
+/// .into_iter()
+/// .collect();
+/// assert_eq!(programs.len(), 2);
+/// ```
 pub struct JqProgram {
     jq: jq::Jq,
+    output_capacity: usize,
+    /// The program as written, before the prelude was added.
+    source: String,
+    /// The options it was compiled with.
+    key: compiler::CompileKey,
+}
+
+/// What the setters on a `JqProgram` can change, other than the handlers,
+/// kept by pools so they can put it back before someone else checks the
+/// program out.
+#[derive(Clone, Copy)]
+pub(crate) struct Settings {
+    limits: jq::Limits,
+    output_capacity: usize,
 }
 
 impl JqProgram {
     /// Runs a json string input against a pre-compiled jq program.
     pub fn run(&mut self, data: &str) -> Result<String> {
+        self.run_interruptible(data, &Interrupt::default())
+    }
+
+    /// Runs a json string input against a pre-compiled jq program, reporting
+    /// how the program finished along with its output.
+    ///
+    /// Unlike `run()`, a program which halts with a non-zero exit code isn't
+    /// treated as a failure. The exit code and `halt_error` payload are
+    /// returned along with the outputs produced before the halt, so `halt`
+    /// and `halt_error` can be used to signal the caller.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(r#".[] | if . > 2 then "too big" | halt_error(3) else . end"#).unwrap();
+    /// let res = program.run_full("[1, 2, 3, 4]").unwrap();
+    ///
+    /// assert_eq!(res.output, "1\n2\n");
+    /// assert!(res.halted);
+    /// assert_eq!(res.exit_code, 3);
+    /// assert_eq!(res.halt_payload.unwrap(), "too big");
+    /// ```
+    pub fn run_full(&mut self, data: &str) -> Result<RunOutput> {
+        let mut res = RunOutput {
+            output: String::with_capacity(self.output_capacity),
+            ..RunOutput::default()
+        };
+        if data.trim().is_empty() {
+            return Ok(res);
+        }
+        let completion = self
+            .jq
+            .execute_full(data, &Interrupt::default(), &mut res.output)?;
+        if let jq::Completion::Halted { exit_code, payload } = completion {
+            res.halted = true;
+            res.exit_code = exit_code;
+            res.halt_payload = payload;
+        }
+        res.stderr = self.jq.take_stderr();
+        res.stats = self.jq.stats();
+        Ok(res)
+    }
+
+    /// Runs a json string input against a pre-compiled jq program, with the
+    /// variables declared by `Compiler::var()` bound to json values.
+    ///
+    /// Each value is given as json text and parsed for the run, failing with
+    /// `Error::Parse` if it's invalid. As with the jq cli's `--argjson`,
+    /// values for variables the program doesn't declare are ignored, and
+    /// declared variables left out are `null`.
+    ///
+    /// ```rust
+    /// use jq_rs::Compiler;
+    ///
+    /// let mut program = Compiler::new()
+    ///     .var("field")
+    ///     .var("default")
+    ///     .compile(".[$field] // $default")
+    ///     .unwrap();
+    /// let data = r#"{"name": "Rodan"}"#;
+    ///
+    /// assert_eq!(program.run_with_vars(data, &[("field", "\"name\"")]).unwrap(), "\"Rodan\"\n");
+    /// assert_eq!(
+    ///     program.run_with_vars(data, &[("field", "\"age\""), ("default", "0")]).unwrap(),
+    ///     "0\n"
+    /// );
+    /// ```
+    pub fn run_with_vars(&mut self, data: &str, vars: &[(&str, &str)]) -> Result<String> {
+        let mut bound = jq::JV::object();
+        for (name, json) in vars {
+            let value = jq::Parser::new().parse(json)?;
+            bound = bound.object_set(jq::JV::string(name), value);
+        }
+        self.jq.set_vars(bound);
+        let res = self.run(data);
+        self.jq.set_vars(jq::JV::object());
+        res
+    }
+
+    /// Runs a json string input against a pre-compiled jq program, stopping
+    /// early with `Error::Cancelled` if the token is cancelled.
+    ///
+    /// The token is checked before the program starts, and between each output
+    /// the program produces. A single long-running step within the program
+    /// can only be stopped with an `InterruptHandle`.
+    ///
+    /// ```rust
+    /// use jq_rs::{CancellationToken, Error};
+    ///
+    /// let mut program = jq_rs::compile(".[]").unwrap();
+    /// let token = CancellationToken::new();
+    /// token.cancel();
+    ///
+    /// match program.run_with_cancel("[1, 2, 3]", &token) {
+    ///     Err(Error::Cancelled) => {}
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn run_with_cancel(&mut self, data: &str, token: &CancellationToken) -> Result<String> {
+        let interrupt = Interrupt {
+            token: Some(token),
+            ..Interrupt::default()
+        };
+        self.run_interruptible(data, &interrupt)
+    }
+
+    /// A handle for stopping runs of this program part way through, from
+    /// another thread. See `InterruptHandle`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.jq.interrupt_handle()
+    }
+
+    /// Runs a json string input against a pre-compiled jq program, giving up
+    /// with `Error::Timeout` once `timeout` has elapsed.
+    ///
+    /// The deadline is checked before the program starts and between each
+    /// output the program produces. A watchdog thread also interrupts the run
+    /// when it's reached, which stops a step calling `debug`, `stderr` or a
+    /// function implemented in Rust part way through (see `InterruptHandle`).
+    /// Other steps, like `[range(1e9)] | length`, run until they're done.
+    ///
+    /// A timeout too long to be counted from now, such as `Duration::MAX`,
+    /// is never reached, so the run goes ahead without one.
+    pub fn run_with_timeout(&mut self, data: &str, timeout: Duration) -> Result<String> {
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => return self.run(data),
+        };
+        let interrupt = Interrupt {
+            deadline: Some(deadline),
+            ..Interrupt::default()
+        };
+        let handle = self.interrupt_handle();
+        match cancel::with_watchdog(handle, timeout, || self.run_interruptible(data, &interrupt)) {
+            (Err(Error::Interrupted), true) => Err(Error::Timeout),
+            (result, _) => result,
+        }
+    }
+
+    /// Runs a json string input against a pre-compiled jq program, appending
+    /// the output to `buf` rather than allocating a new `String`.
+    ///
+    /// Reusing a buffer across runs avoids growing a fresh `String` each time,
+    /// which adds up for programs producing large outputs. When the run fails,
+    /// `buf` is left as it was.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".[]").unwrap();
+    /// let mut buf = String::new();
+    ///
+    /// for input in &["[1, 2]", "[3]"] {
+    ///     buf.clear();
+    ///     program.run_into(input, &mut buf).unwrap();
+    ///     // ... do something with the output.
+    /// }
+    /// assert_eq!(buf, "3\n");
+    /// ```
+    pub fn run_into(&mut self, data: &str, buf: &mut String) -> Result<()> {
+        self.run_interruptible_into(data, &Interrupt::default(), buf)
+    }
+
+    /// Runs a json string input against the pre-compiled jq program,
+    /// gathering each rendered output into a collection of your choosing.
+    ///
+    /// The outputs are added to the collection as they're produced, without
+    /// the newline which would separate them in the output of `run()`. Any
+    /// collection which can be extended with `String`s will do, such as a
+    /// `Vec`, `BTreeSet`, `SmallVec` or one of your own.
+    ///
+    /// ```rust
+    /// use std::collections::BTreeSet;
+    ///
+    /// let mut program = jq_rs::compile(".[].tags[]").unwrap();
+    /// let tags: BTreeSet<String> = program
+    ///     .run_collect(r#"[{"tags": ["b", "a"]}, {"tags": ["a"]}]"#)
+    ///     .unwrap();
+    ///
+    /// assert_eq!(tags.into_iter().collect::<Vec<_>>(), vec!["\"a\"", "\"b\""]);
+    /// ```
+    pub fn run_collect<C>(&mut self, data: &str) -> Result<C>
+    where
+        C: Default + Extend<String>,
+    {
+        // jq hands over outputs one at a time through a callback, so there's
+        // no iterator to give `FromIterator`. Extending as they arrive builds
+        // the collection without holding the outputs anywhere else first.
+        let mut outputs = C::default();
+        if data.trim().is_empty() {
+            return Ok(outputs);
+        }
+        self.jq
+            .execute_split(data, &Interrupt::default(), |output| {
+                outputs.extend(Some(output))
+            })?;
+        Ok(outputs)
+    }
+
+    /// Runs a json string input against a pre-compiled jq program, returning
+    /// each output as a `serde_json::Value`.
+    ///
+    /// This is quicker than parsing the output of `run()` since the values are
+    /// converted directly from jq's representation without ever being
+    /// rendered as text.
+    ///
+    /// Requires the `serde_json` feature.
+    ///
+    /// ```rust
+    /// use serde_json::json;
+    ///
+    /// let mut program = jq_rs::compile(".[] | {name}").unwrap();
+    /// let values = program
+    ///     .run_values(r#"[{"name": "Ghidorah", "heads": 3}, {"name": "Rodan"}]"#)
+    ///     .unwrap();
+    ///
+    /// assert_eq!(
+    ///     values,
+    ///     vec![json!({"name": "Ghidorah"}), json!({"name": "Rodan"})]
+    /// );
+    /// ```
+    #[cfg(feature = "serde_json")]
+    pub fn run_values(&mut self, data: &str) -> Result<Vec<serde_json::Value>> {
+        if data.trim().is_empty() {
+            // Same as `run()`, an empty input gives no outputs.
+            return Ok(vec![]);
+        }
+        self.jq.execute_values(data, &Interrupt::default())
+    }
+
+    /// Runs a json string input against the pre-compiled jq program, handing
+    /// each output to `each` as a `serde::Deserializer`.
+    ///
+    /// The outputs are read straight from jq's representation, so they can be
+    /// deserialized into your own types, or transcoded into another serde
+    /// format, without going through an intermediate `String` or `Value`.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// ```rust
+    /// use serde::Deserialize;
+    ///
+    /// let mut program = jq_rs::compile(".[] | [.name, .heads]").unwrap();
+    /// let mut heads = vec![];
+    /// program
+    ///     .run_deserialized(r#"[{"name": "Ghidorah", "heads": 3}]"#, |output| {
+    ///         heads.push(<(String, u8)>::deserialize(output)?);
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///
+    /// assert_eq!(heads, vec![("Ghidorah".to_string(), 3)]);
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn run_deserialized<F>(&mut self, data: &str, mut each: F) -> Result<()>
+    where
+        F: FnMut(OutputDeserializer<'_>) -> Result<()>,
+    {
+        if data.trim().is_empty() {
+            return Ok(());
+        }
+        self.jq.execute_each(data, &Interrupt::default(), |value| {
+            each(OutputDeserializer::new(value))
+        })
+    }
+
+    /// Runs a json string input against the pre-compiled jq program,
+    /// deserializing each output as a `T`.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".[].year").unwrap();
+    /// let years: Vec<u16> = program
+    ///     .run_as(r#"[{"year": 1954}, {"year": 1956}]"#)
+    ///     .unwrap();
+    ///
+    /// assert_eq!(years, vec![1954, 1956]);
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn run_as<T>(&mut self, data: &str) -> Result<Vec<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let mut values = vec![];
+        self.run_deserialized(data, |output| {
+            values.push(T::deserialize(output)?);
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    /// Runs a json input held as bytes against the pre-compiled jq program,
+    /// returning the output as `bytes::Bytes`.
+    ///
+    /// The input only needs to be valid UTF-8, it isn't copied, and the output
+    /// buffer is handed over to the `Bytes` as is, so results can be passed on
+    /// to hyper, tonic, etc without copying.
+    ///
+    /// Requires the `bytes` feature.
+    ///
+    /// ```rust
+    /// use bytes::Bytes;
+    ///
+    /// let mut program = jq_rs::compile(".name").unwrap();
+    /// let body = Bytes::from_static(br#"{"name": "Mechagodzilla"}"#);
+    ///
+    /// assert_eq!(program.run_bytes(&body).unwrap(), "\"Mechagodzilla\"\n");
+    /// ```
+    #[cfg(feature = "bytes")]
+    pub fn run_bytes(&mut self, data: &[u8]) -> Result<bytes::Bytes> {
+        // No sense checking a body which is too large for UTF-8.
+        self.jq.check_input_bytes(data.len())?;
+        let data = std::str::from_utf8(data)?;
+        self.run(data).map(bytes::Bytes::from)
+    }
+
+    /// Runs an already parsed json document against the pre-compiled jq
+    /// program.
+    ///
+    /// See `ParsedJson` for details.
+    pub fn run_parsed(&mut self, data: &ParsedJson) -> Result<String> {
+        let mut buf = String::with_capacity(self.output_capacity);
+        if let Some(value) = &data.value {
+            self.jq
+                .execute_parsed(value, &Interrupt::default(), &mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    /// Refuse inputs larger than `max` bytes for the runs from now on, or
+    /// take inputs of any size with `None`, replacing the cap set with
+    /// `Compiler::max_input_bytes()`.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile("length").unwrap();
+    /// program.set_max_input_bytes(Some(4));
+    /// assert!(program.run("\"long\"").unwrap_err().is_input_too_large());
+    ///
+    /// program.set_max_input_bytes(None);
+    /// assert_eq!(program.run("\"long\"").unwrap(), "4\n");
+    /// ```
+    pub fn set_max_input_bytes(&mut self, max: Option<usize>) {
+        let mut limits = self.jq.limits();
+        limits.input_bytes = max;
+        self.jq.set_limits(limits);
+    }
+
+    /// Clears out whatever the last run left behind, so it isn't held on to
+    /// until the next run.
+    ///
+    /// Reusing a program after a run which failed is always fine, whatever
+    /// the error: each run starts from a clean slate, so there's no need to
+    /// compile it again. But a run which stops early (failing, halting, or
+    /// going over a limit) leaves libjq holding on to the rest of its work,
+    /// including its input, along with anything the run sent to `stderr`.
+    /// `reset()` lets go of all of that, which is worth doing before putting
+    /// a program aside, say in a pool. Settings such as handlers, limits and
+    /// the time zone are kept.
+    ///
+    /// `FilterRegistry` and `ProgramCache` reset programs as they're handed
+    /// back.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".[] | if . == 2 then error(\"two\") else . end").unwrap();
+    /// assert!(program.run("[1, 2, 3]").is_err());
+    ///
+    /// program.reset();
+    /// assert_eq!(program.run("[1, 3]").unwrap(), "1\n3\n");
+    /// ```
+    pub fn reset(&mut self) {
+        self.jq.reset();
+    }
+
+    /// Frees the program right away, as dropping it would, with an error for
+    /// anything libjq reported while freeing it.
+    ///
+    /// libjq doesn't report failures when freeing a program, so in practice
+    /// this gives back `Ok`, but services which want to be told about
+    /// problems rather than have `Drop` hide them can use it regardless.
+    /// A program is only ever freed once, however it goes.
+    pub fn close(self) -> Result<()> {
+        self.jq.close()
+    }
+
+    /// Sets the number of bytes to allocate up front for the `String` returned
+    /// by `run()` and friends.
+    ///
+    /// When you know roughly how large the output of a program is going to be,
+    /// this avoids reallocating the buffer repeatedly as it grows.
+    pub fn set_output_capacity(&mut self, bytes: usize) {
+        self.output_capacity = bytes;
+    }
+
+    /// The source the program was compiled from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Sets a closure to receive each value the program passes to jq's
+    /// `debug` builtin, rendered as json.
+    ///
+    /// Without a handler, `debug` passes its input along and nothing else
+    /// happens. Unlike the jq cli, values are never written to stderr.
+    ///
+    /// ```rust
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let seen = Arc::new(Mutex::new(vec![]));
+    /// let mut program = jq_rs::compile(".[] | debug | . * 2").unwrap();
+    ///
+    /// let log = seen.clone();
+    /// program.set_debug_handler(move |value| log.lock().unwrap().push(value.to_string()));
+    ///
+    /// assert_eq!(program.run("[1, 2]").unwrap(), "2\n4\n");
+    /// assert_eq!(*seen.lock().unwrap(), vec!["1", "2"]);
+    /// ```
+    ///
+    /// A panic in the handler is carried through to the caller of the run
+    /// once jq has stopped.
+    pub fn set_debug_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        self.jq.set_debug_handler(Box::new(handler));
+    }
+
+    /// Sets a closure to receive each value the program passes to jq's
+    /// `stderr` builtin, rendered as json.
+    ///
+    /// Values passed to `stderr` are never written to the process' stderr.
+    /// Without a handler, they're collected into `RunOutput::stderr` by
+    /// `run_full()` and dropped by the other ways of running the program.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(r#""working" | stderr | empty"#).unwrap();
+    /// assert_eq!(program.run_full("null").unwrap().stderr, "\"working\"");
+    ///
+    /// program.set_stderr_handler(|value| assert_eq!(value, "\"working\""));
+    /// assert_eq!(program.run("null").unwrap(), "");
+    /// ```
+    pub fn set_stderr_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        self.jq.set_stderr_handler(Box::new(handler));
+    }
+
+    /// Sets a closure to receive the `RunStats` for each run of the program.
+    ///
+    /// The closure is called once the program stops, including when it fails
+    /// part way through, but not when the input fails to parse. Batches call
+    /// it for each input, and `jq_rs::stream` for each value read.
+    ///
+    /// ```rust
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let outputs = Arc::new(AtomicUsize::new(0));
+    /// let mut program = jq_rs::compile(".[]").unwrap();
+    ///
+    /// let counter = outputs.clone();
+    /// program.set_stats_handler(move |stats| {
+    ///     counter.fetch_add(stats.outputs, Ordering::Relaxed);
+    /// });
+    ///
+    /// program.run_batch(&["[1, 2]", "[3]"]);
+    /// assert_eq!(outputs.load(Ordering::Relaxed), 3);
+    /// ```
+    pub fn set_stats_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&RunStats) + Send + 'static,
+    {
+        self.jq.set_stats_handler(Box::new(handler));
+    }
+
+    /// Have `localtime` and `strflocaltime` work out local times in
+    /// `timezone` for the runs which follow, rather than in the zone of the
+    /// machine. `None` goes back to the machine's.
+    ///
+    /// This doesn't touch the process' `TZ`, so programs on other threads can
+    /// use other zones at the same time. `%Z` and `%z` in the format given to
+    /// `strflocaltime` are written for the zone too. The other date builtins
+    /// (`gmtime`, `mktime`, `strftime`, `todate` and so on) work in UTC either
+    /// way.
+    ///
+    /// Requires the `tz` feature.
+    ///
+    /// ```rust
+    /// use jq_rs::TimeZone;
+    ///
+    /// let mut program = jq_rs::compile(r#"strflocaltime("%F %H:%M %Z")"#).unwrap();
+    /// program.set_timezone(Some(TimeZone::named("America/New_York").unwrap()));
+    /// assert_eq!(program.run("1500000000").unwrap(), "\"2017-07-13 22:40 EDT\"\n");
+    ///
+    /// program.set_timezone(Some(TimeZone::named("Asia/Tokyo").unwrap()));
+    /// assert_eq!(program.run("1500000000").unwrap(), "\"2017-07-14 11:40 JST\"\n");
+    /// ```
+    #[cfg(feature = "tz")]
+    pub fn set_timezone(&mut self, timezone: Option<TimeZone>) {
+        self.jq.set_timezone(timezone);
+    }
+
+    fn run_interruptible(&mut self, data: &str, interrupt: &Interrupt) -> Result<String> {
+        let mut buf = String::with_capacity(self.output_capacity);
+        self.run_interruptible_into(data, interrupt, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn run_interruptible_into(
+        &mut self,
+        data: &str,
+        interrupt: &Interrupt,
+        buf: &mut String,
+    ) -> Result<()> {
         if data.trim().is_empty() {
             // During work on #4, #7, the parser test which allows us to avoid a memory
             // error shows that an empty input just yields an empty response BUT our
             // implementation would yield a parse error.
-            return Ok("".into());
+            return Ok(());
         }
-        let input = CString::new(data)?;
-        self.jq.execute(input)
+        self.jq.execute(data, interrupt, buf)
+    }
+
+    /// Chain another program onto this one, making a `JqPipeline`.
+    ///
+    /// Running the pipeline gives the same result as compiling `this | next`,
+    /// but lets the two programs be compiled (and maintained) separately.
+    pub fn then(self, next: JqProgram) -> JqPipeline {
+        JqPipeline::new(vec![self, next])
+    }
+
+    /// Runs several json string inputs against the pre-compiled jq program.
+    ///
+    /// Each input is run independently so a failure for one input doesn't
+    /// prevent the rest from being processed. The results are returned in the
+    /// same order as the inputs, and each error says which input it was for.
+    ///
+    /// Outputs are rendered into a scratch buffer which is shared by the whole
+    /// batch, so each result is allocated once at its final size rather than
+    /// being grown as the program produces output.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".name").unwrap();
+    /// let results = program.run_batch(&[r#"{"name": "a"}"#, "{", r#"{"name": "c"}"#]);
+    ///
+    /// assert_eq!(results[0].as_ref().unwrap(), "\"a\"\n");
+    /// assert_eq!(results[1].as_ref().unwrap_err().index, 1);
+    /// assert_eq!(results[2].as_ref().unwrap(), "\"c\"\n");
+    /// ```
+    pub fn run_batch<I>(&mut self, inputs: I) -> Vec<result::Result<String, BatchError>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.run_each(inputs.into_iter().map(|data| (data, None)))
+    }
+
+    /// Runs several json string inputs against the pre-compiled jq program,
+    /// each paired with a key of the caller's choosing, such as a file name
+    /// or message id.
+    ///
+    /// As with `run_batch()`, each input is run independently. The results
+    /// come back in the same order as the inputs, along with their keys.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".id").unwrap();
+    /// let results = program.run_map(vec![("a.json", r#"{"id": 1}"#), ("b.json", "{")]);
+    ///
+    /// assert_eq!(results[0].0, "a.json");
+    /// assert_eq!(results[0].1.as_ref().unwrap(), "1\n");
+    /// assert_eq!(results[1].0, "b.json");
+    /// assert!(results[1].1.as_ref().unwrap_err().is_parse_error());
+    /// ```
+    pub fn run_map<K, I, S>(&mut self, inputs: I) -> Vec<(K, Result<String>)>
+    where
+        I: IntoIterator<Item = (K, S)>,
+        S: AsRef<str>,
+    {
+        let mut scratch = String::with_capacity(self.output_capacity);
+        inputs
+            .into_iter()
+            .map(|(key, data)| {
+                scratch.clear();
+                let res = self
+                    .run_interruptible_into(data.as_ref(), &Interrupt::default(), &mut scratch)
+                    .map(|()| scratch.as_str().to_owned());
+                (key, res)
+            })
+            .collect()
+    }
+
+    /// Runs each line of newline delimited json against the pre-compiled jq
+    /// program, as with `run_batch()`.
+    ///
+    /// Blank lines are skipped, so there's one result for each line holding a
+    /// value. Errors include the line number of the input.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".name").unwrap();
+    /// let results = program.run_lines("{\"name\": \"a\"}\n\n{\n");
+    ///
+    /// assert_eq!(results.len(), 2);
+    /// assert_eq!(results[0].as_ref().unwrap(), "\"a\"\n");
+    /// assert_eq!(results[1].as_ref().unwrap_err().line, Some(3));
+    /// ```
+    pub fn run_lines(&mut self, data: &str) -> Vec<result::Result<String, BatchError>> {
+        let lines = data
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(idx, line)| (line, Some(idx + 1)));
+        self.run_each(lines)
+    }
+
+    /// Like `run_batch()`, but gives back everything the inputs produced
+    /// separately from the errors, for recovering what can be recovered from
+    /// a batch which partly failed.
+    ///
+    /// There's an entry in the outputs for each input. For an input which
+    /// failed, it holds the outputs the program produced before failing
+    /// (which `run_batch()` would throw away), so it can be empty. The errors
+    /// say which input they were for.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".[] | 10 / .").unwrap();
+    /// let (outputs, errors) = program.run_batch_partial(&["[1, 2]", "[5, \"a\", 10]", "{"]);
+    ///
+    /// assert_eq!(outputs, vec!["10\n5\n", "2\n", ""]);
+    /// assert_eq!(errors.len(), 2);
+    /// assert_eq!(errors[0].index, 1);
+    /// assert!(errors[1].error.is_parse_error());
+    /// ```
+    ///
+    /// Streaming with `jq_rs::stream` already writes each output as soon as
+    /// it's produced, so nothing is lost there when a run fails.
+    pub fn run_batch_partial<I>(&mut self, inputs: I) -> (Vec<String>, Vec<BatchError>)
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let interrupt = Interrupt::default();
+        let mut outputs = vec![];
+        let mut errors = vec![];
+        for (index, data) in inputs.into_iter().enumerate() {
+            let data = data.as_ref();
+            let mut output = String::with_capacity(self.output_capacity);
+            if !data.trim().is_empty() {
+                if let Err(error) = self.jq.execute_partial(data, &interrupt, &mut output) {
+                    errors.push(BatchError {
+                        index,
+                        line: None,
+                        error,
+                    });
+                }
+            }
+            outputs.push(output);
+        }
+        (outputs, errors)
+    }
+
+    pub(crate) fn settings(&self) -> Settings {
+        Settings {
+            limits: self.jq.limits(),
+            output_capacity: self.output_capacity,
+        }
+    }
+
+    /// Put back `settings`, and drop any handlers and time zone set since.
+    /// Pools compile their own programs, which never start out with those.
+    pub(crate) fn restore(&mut self, settings: Settings) {
+        self.jq.set_limits(settings.limits);
+        self.output_capacity = settings.output_capacity;
+        self.jq.clear_handlers();
+        #[cfg(feature = "tz")]
+        self.jq.set_timezone(None);
+    }
+
+    fn run_each<I, S>(&mut self, inputs: I) -> Vec<result::Result<String, BatchError>>
+    where
+        I: Iterator<Item = (S, Option<usize>)>,
+        S: AsRef<str>,
+    {
+        let mut scratch = String::with_capacity(self.output_capacity);
+        inputs
+            .enumerate()
+            .map(|(index, (data, line))| {
+                scratch.clear();
+                self.run_interruptible_into(data.as_ref(), &Interrupt::default(), &mut scratch)
+                    .map_err(|error| BatchError { index, line, error })?;
+                Ok(scratch.as_str().to_owned())
+            })
+            .collect()
+    }
+}
+
+impl PartialEq for JqProgram {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source && self.key == other.key
+    }
+}
+
+impl Eq for JqProgram {}
+
+impl Hash for JqProgram {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.source.hash(state);
+        self.key.hash(state);
     }
 }
 
 /// Compile a jq program then reuse it, running several inputs against it.
 pub fn compile(program: &str) -> Result<JqProgram> {
-    let prog = CString::new(program)?;
-    Ok(JqProgram {
-        jq: jq::Jq::compile_program(prog)?,
-    })
+    Compiler::new().compile(program)
+}
+
+/// A compiled program along with everything else jq had to say about it, as
+/// returned by `compile_full()`.
+pub struct CompileOutput {
+    /// The compiled program, same as returned by `compile()`.
+    pub program: JqProgram,
+    /// Messages jq reported while compiling the program which didn't stop
+    /// it from compiling.
+    ///
+    /// libjq 1.6 treats everything it reports while compiling as an error,
+    /// so with it this is always empty. It's here for versions of libjq
+    /// which report warnings.
+    pub warnings: Vec<String>,
+}
+
+/// Like `compile()`, but also hands back any warnings jq reported along the
+/// way, for tools which want to show them to whoever wrote the program.
+///
+/// libjq 1.6 never reports a warning without failing the compile, so the
+/// warnings are always empty with it. See `CompileOutput::warnings`.
+///
+/// ```rust
+/// let compiled = jq_rs::compile_full(".name").unwrap();
+/// assert!(compiled.warnings.is_empty());
+/// ```
+pub fn compile_full(program: &str) -> Result<CompileOutput> {
+    let mut program = compile(program)?;
+    let warnings = program.jq.take_warnings();
+    Ok(CompileOutput { program, warnings })
 }
 
 #[cfg(test)]
 mod test {
 
-    use super::{compile, run, Error};
+    use super::{compile, run, BatchError, Compiler, Error};
     use matches::assert_matches;
-    use serde_json;
+    use std::sync::{Arc, Mutex};
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn reuse_compiled_program() {
         let query = r#"if . == 0 then "zero" elif . == 1 then "one" else "many" end"#;
         let mut prog = compile(&query).unwrap();
@@ -209,6 +1413,564 @@ mod test {
     }
 
     #[test]
+    fn run_batch_isolates_failures() {
+        let mut prog = compile(".[0]").unwrap();
+        let inputs = vec![
+            "[1]".to_string(),
+            "{}".to_string(),
+            "".to_string(),
+            "[[2]]".to_string(),
+        ];
+        let results = prog.run_batch(&inputs);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap(), "1\n");
+        assert_matches!(
+            results[1],
+            Err(BatchError {
+                index: 1,
+                line: None,
+                error: Error::Exec { .. }
+            })
+        );
+        assert_eq!(results[2].as_ref().unwrap(), "");
+        assert_eq!(results[3].as_ref().unwrap(), "[2]\n");
+    }
+
+    #[test]
+    fn run_batch_partial_keeps_outputs() {
+        let mut prog = compile(".[] | if . == null then halt_error(2) else . end").unwrap();
+        let (outputs, errors) = prog.run_batch_partial(vec!["[1, null, 3]", "", "[4]", "[5, "]);
+        assert_eq!(outputs, vec!["1\n", "", "4\n", ""]);
+        assert_eq!(errors.len(), 2);
+        assert_matches!(
+            errors[0],
+            BatchError {
+                index: 0,
+                error: Error::Halted { exit_code: 2, .. },
+                ..
+            }
+        );
+        assert_matches!(
+            errors[1],
+            BatchError {
+                index: 3,
+                error: Error::Parse { .. },
+                ..
+            }
+        );
+    }
+
+    #[test]
+    fn close() {
+        let mut prog = compile(".[0]").unwrap();
+        let handle = prog.interrupt_handle();
+        assert!(prog.run("{}").is_err());
+        prog.close().unwrap();
+        assert!(!handle.interrupt());
+
+        compile(".").unwrap().close().unwrap();
+    }
+
+    #[test]
+    fn run_map_keeps_keys() {
+        let mut prog = compile(".[0]").unwrap();
+        let inputs: std::collections::BTreeMap<u32, String> = vec![
+            (7, "[1]".to_string()),
+            (3, "{}".to_string()),
+            (5, "[[2]]".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let results = prog.run_map(inputs);
+        let keys: Vec<u32> = results.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, vec![3, 5, 7]);
+        assert_matches!(results[0].1, Err(Error::Exec { .. }));
+        assert_eq!(results[1].1.as_ref().unwrap(), "[2]\n");
+        assert_eq!(results[2].1.as_ref().unwrap(), "1\n");
+
+        assert!(prog.run_map(Vec::<((), &str)>::new()).is_empty());
+    }
+
+    #[test]
+    fn run_collect() {
+        let mut prog = compile(".[]").unwrap();
+        let outputs: Vec<String> = prog.run_collect(r#"[1, "a", {"b": [2]}, 1]"#).unwrap();
+        assert_eq!(outputs, vec!["1", "\"a\"", "{\"b\":[2]}", "1"]);
+        let unique: std::collections::HashSet<String> = prog.run_collect("[1, 1, 2]").unwrap();
+        assert_eq!(unique.len(), 2);
+        let empty: Vec<String> = prog.run_collect(" ").unwrap();
+        assert!(empty.is_empty());
+        assert_matches!(
+            prog.run_collect::<Vec<String>>("1"),
+            Err(Error::Exec { .. })
+        );
+
+        let mut raw = Compiler::new().raw_output(true).compile(".[]").unwrap();
+        let outputs: Vec<String> = raw.run_collect(r#"["a\nb", 2]"#).unwrap();
+        assert_eq!(outputs, vec!["a\nb", "2"]);
+    }
+
+    #[test]
+    fn implode_rejects_invalid_codepoints() {
+        // A lone surrogate would otherwise make invalid UTF-8, and the rest
+        // abort the process inside libjq.
+        for program in &[
+            "[55296] | implode",
+            "[-1] | implode",
+            "[1114112] | implode",
+            r#"["a"] | implode"#,
+        ] {
+            let mut json = compile(program).unwrap();
+            assert_matches!(json.run("null"), Err(Error::Exec { .. }));
+            let mut raw = Compiler::new().raw_output(true).compile(program).unwrap();
+            assert_matches!(raw.run("null"), Err(Error::Exec { .. }));
+        }
+        let mut prog = Compiler::new()
+            .raw_output(true)
+            .compile("[104, 105, 128512] | implode")
+            .unwrap();
+        assert_eq!(prog.run("null").unwrap(), "hi\u{1F600}\n");
+        assert_eq!(
+            run(r#""a" | explode | implode"#, "null").unwrap(),
+            "\"a\"\n"
+        );
+    }
+
+    #[test]
+    fn run_lines() {
+        let mut prog = compile(".a").unwrap();
+        let results = prog.run_lines("{\"a\": 1}\n  \n[]\r\n{\"a\": 2}\n{\"a\":\n");
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap(), "1\n");
+        assert_matches!(
+            results[1],
+            Err(BatchError {
+                index: 1,
+                line: Some(3),
+                error: Error::Exec { .. }
+            })
+        );
+        assert_eq!(results[2].as_ref().unwrap(), "2\n");
+        let err = results[3].as_ref().unwrap_err();
+        assert_eq!((err.index, err.line), (3, Some(5)));
+        assert!(err.error.is_parse_error());
+        assert!(err.to_string().starts_with("Line 5: JQ: Parse error"));
+    }
+
+    #[test]
+    fn run_many_parses_once() {
+        use super::run_many;
+
+        let mut programs = vec![compile(".a").unwrap(), compile(".b[]").unwrap()];
+        let results = run_many(&mut programs, r#"{"a": 1, "b": [2, 3]}"#).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap(), "1\n");
+        assert_eq!(results[1].as_ref().unwrap(), "2\n3\n");
+
+        assert_matches!(run_many(&mut programs, "{"), Err(Error::Parse { .. }));
+        assert!(run_many(&mut [], "{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancel_between_outputs() {
+        use super::CancellationToken;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let token = CancellationToken::from(Arc::clone(&flag));
+        let mut prog = compile("range(100000)").unwrap();
+        assert!(prog.run_with_cancel("null", &token).is_ok());
+
+        flag.store(true, Ordering::SeqCst);
+        assert_matches!(prog.run_with_cancel("null", &token), Err(Error::Cancelled));
+
+        // The program is still usable after a cancelled run.
+        assert_eq!(prog.run("[1]").unwrap().lines().count(), 100_000);
+
+        // Cancelled part way through a run, from the debug handler.
+        let token = CancellationToken::new();
+        let mut prog = compile("range(100000) | debug").unwrap();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let (count, cancel) = (Arc::clone(&seen), token.clone());
+        prog.set_debug_handler(move |value| {
+            count.fetch_add(1, Ordering::SeqCst);
+            if value == "10" {
+                cancel.cancel();
+            }
+        });
+        assert_matches!(prog.run_with_cancel("null", &token), Err(Error::Cancelled));
+        // It stops at the check after `10` is output, having seen 0 to 10.
+        assert_eq!(seen.load(Ordering::SeqCst), 11);
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn timeout_between_outputs() {
+        use std::time::Duration;
+
+        let mut prog = compile("range(1000000)").unwrap();
+        assert_matches!(
+            prog.run_with_timeout("null", Duration::from_millis(1)),
+            Err(Error::Timeout)
+        );
+        assert_eq!(
+            prog.run_with_timeout("[1]", Duration::from_secs(60))
+                .unwrap()
+                .lines()
+                .count(),
+            1_000_000
+        );
+        // Too long to add to the current time, so there's no deadline.
+        assert_eq!(
+            compile("range(.)")
+                .unwrap()
+                .run_with_timeout("3", Duration::MAX)
+                .unwrap(),
+            "0\n1\n2\n"
+        );
+    }
+
+    #[test]
+    fn interrupt_between_outputs() {
+        use std::thread;
+        use std::time::Duration;
+
+        let mut prog = compile("if . then range(infinite) else empty end").unwrap();
+        let handle = prog.interrupt_handle();
+        assert!(!handle.interrupt());
+
+        let watchdog = thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(10));
+            if handle.interrupt() {
+                return;
+            }
+        });
+        assert_matches!(prog.run("true"), Err(Error::Interrupted));
+        watchdog.join().unwrap();
+
+        // The interrupt doesn't carry over to the next run.
+        assert_eq!(prog.run("false").unwrap(), "");
+        assert_matches!(prog.run_full("false"), Ok(_));
+    }
+
+    #[test]
+    fn interrupt_within_a_step() {
+        use std::thread;
+        use std::time::Duration;
+
+        // Produces no outputs, but passes through `stderr` on each step.
+        let mut prog = compile("def f: if . < 0 then . else . + 1 | stderr | f end; f").unwrap();
+        let handle = prog.interrupt_handle();
+
+        let watchdog = thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(10));
+            if handle.interrupt() {
+                return;
+            }
+        });
+        assert_matches!(prog.run("0"), Err(Error::Interrupted));
+        watchdog.join().unwrap();
+
+        assert_eq!(prog.run("-1").unwrap(), "-1\n");
+    }
+
+    #[test]
+    fn timeout_within_a_step() {
+        use std::time::Duration;
+
+        let mut prog = compile("[range(1e8) | stderr] | length").unwrap();
+        assert_matches!(
+            prog.run_with_timeout("null", Duration::from_millis(50)),
+            Err(Error::Timeout)
+        );
+        assert_eq!(
+            compile("[range(1e3)] | length")
+                .unwrap()
+                .run_with_timeout("null", Duration::from_secs(60))
+                .unwrap(),
+            "1000\n"
+        );
+    }
+
+    #[test]
+    fn programs_which_halt_can_be_interrupted() {
+        use std::thread;
+        use std::time::Duration;
+
+        // The interrupt lands in the middle of the program halting itself
+        // some of the time, which used to abort the process.
+        let mut prog =
+            compile("def f: if . == 0 then halt else . - 1 | stderr | f end; f").unwrap();
+        let handle = prog.interrupt_handle();
+        let watchdog = thread::spawn(move || {
+            for _ in 0..20 {
+                handle.interrupt();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        for _ in 0..200 {
+            match prog.run("1000") {
+                Ok(output) => assert_eq!(output, ""),
+                Err(err) => assert_matches!(err, Error::Interrupted),
+            }
+        }
+        watchdog.join().unwrap();
+    }
+
+    #[test]
+    fn nul_in_input() {
+        let mut prog = compile(".").unwrap();
+        assert_matches!(prog.run("[1]\0[2]"), Err(Error::StringConvert { .. }));
+        assert_eq!(prog.run("[1]").unwrap(), "[1]\n");
+    }
+
+    #[test]
+    fn run_into_appends() {
+        let mut prog = compile(".[] | .a").unwrap();
+        let mut buf = String::from("start\n");
+        prog.run_into(r#"[{"a": 1}, {"a": 2}]"#, &mut buf).unwrap();
+        assert_eq!(buf, "start\n1\n2\n");
+
+        // Failed runs don't leave partial output behind.
+        assert_matches!(
+            prog.run_into(r#"[{"a": 3}, 4]"#, &mut buf),
+            Err(Error::Exec { .. })
+        );
+        assert_eq!(buf, "start\n1\n2\n");
+    }
+
+    #[test]
+    // The hash only covers the source and options, not the interrupt handle.
+    #[allow(clippy::mutable_key_type)]
+    fn equal_programs() {
+        use std::collections::HashMap;
+
+        let mut programs = HashMap::new();
+        programs.insert(compile(".a").unwrap(), 1);
+        programs.insert(compile(".a").unwrap(), 2);
+        programs.insert(compile(".b").unwrap(), 3);
+        programs.insert(Compiler::new().arg("x", "1").compile(".a").unwrap(), 4);
+        programs.insert(Compiler::new().arg("x", "2").compile(".a").unwrap(), 5);
+        assert_eq!(programs.len(), 4);
+        assert_eq!(programs[&compile(".a").unwrap()], 2);
+
+        let mut program = Compiler::new().indent(2).compile(".a").unwrap();
+        assert!(program != compile(".a").unwrap());
+        program.set_output_capacity(64);
+        assert!(program == Compiler::new().indent(2).compile(".a").unwrap());
+        assert_eq!(program.source(), ".a");
+    }
+
+    #[test]
+    fn equal_programs_with_processors() {
+        use std::borrow::Cow;
+
+        struct Upper;
+
+        impl crate::PostProcessor for Upper {
+            fn process<'a>(&self, output: &'a str) -> crate::Result<Cow<'a, str>> {
+                Ok(Cow::Owned(output.to_uppercase()))
+            }
+        }
+
+        struct Lower;
+
+        impl crate::PostProcessor for Lower {
+            fn process<'a>(&self, output: &'a str) -> crate::Result<Cow<'a, str>> {
+                Ok(Cow::Owned(output.to_lowercase()))
+            }
+        }
+
+        let upper = Compiler::new().postprocessor(Upper);
+        let lower = Compiler::new().postprocessor(Lower);
+        assert!(upper.compile(".a").unwrap() == upper.clone().compile(".a").unwrap());
+        assert!(upper.compile(".a").unwrap() != lower.compile(".a").unwrap());
+        assert!(upper.compile(".a").unwrap() != compile(".a").unwrap());
+        // Even the same type of processor, set separately.
+        assert!(
+            upper.compile(".a").unwrap()
+                != Compiler::new().postprocessor(Upper).compile(".a").unwrap()
+        );
+
+        // Metrics don't change what a program does.
+        let counted = Compiler::new()
+            .metrics(crate::NoopMetrics)
+            .compile(".a")
+            .unwrap();
+        assert!(counted == compile(".a").unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn equal_programs_with_functions() {
+        let one = Compiler::new().register_fn("f", 0, |_| Ok(1.into()));
+        let two = Compiler::new().register_fn("f", 0, |_| Ok(2.into()));
+        assert!(one.compile("f").unwrap() == one.compile("f").unwrap());
+        assert!(one.compile("f").unwrap() != two.compile("f").unwrap());
+
+        let scope = crate::Scope::new().register_fn("f", 0, |_| Ok(1.into()));
+        let scoped = Compiler::new().scope(scope.clone()).compile("f").unwrap();
+        assert!(scoped == Compiler::new().scope(scope).compile("f").unwrap());
+        assert!(scoped != one.compile("f").unwrap());
+    }
+
+    #[test]
+    fn output_capacity() {
+        let mut prog = compile(".").unwrap();
+        prog.set_output_capacity(1024);
+        assert!(prog.run("1").unwrap().capacity() >= 1024);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn run_values_matches_parsed_output() {
+        let data = get_movies().to_string();
+        let mut prog = compile(".movies[] | {title, decade: (.year / 10 | floor * 10)}").unwrap();
+        let parsed: Vec<serde_json::Value> = prog
+            .run(&data)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(prog.run_values(&data).unwrap(), parsed);
+        assert!(prog.run_values(" ").unwrap().is_empty());
+        assert_matches!(prog.run_values("[1]"), Err(Error::Exec { .. }));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn run_bytes() {
+        let mut prog = compile(".[]").unwrap();
+        let input = bytes::Bytes::from_static(b"[1, 2]");
+        assert_eq!(prog.run_bytes(&input).unwrap(), "1\n2\n");
+        assert_eq!(prog.run_bytes(b"").unwrap(), "");
+        assert_matches!(
+            prog.run_bytes(b"[\"\xC1\"]"),
+            Err(Error::StringConvert { .. })
+        );
+    }
+
+    #[test]
+    fn runtime_error_messages() {
+        let reason = |program: &str| match run(program, "[1]") {
+            Err(Error::Exec { reason, .. }) => reason,
+            _ => unreachable!(),
+        };
+        assert_eq!(reason(r#"error("boom")"#), "JQ: Error: boom");
+        assert_eq!(
+            reason(r#"error({"code": 1})"#),
+            r#"JQ: Error (not a string): {"code":1}"#
+        );
+        assert_eq!(
+            reason(".a"),
+            r#"JQ: Error: Cannot index array with string "a""#
+        );
+    }
+
+    #[test]
+    fn runtime_error_payload() {
+        let payload = |program: &str| match run(program, "[1]") {
+            Err(Error::Exec { payload, .. }) => payload,
+            _ => unreachable!(),
+        };
+        assert_eq!(payload(r#"error("boom")"#), None);
+        assert_eq!(payload(".a"), None);
+        assert_eq!(
+            payload(r#"error({"kind": "validation", "fields": ["a"]})"#).unwrap(),
+            r#"{"kind":"validation","fields":["a"]}"#
+        );
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn runtime_error_payload_value() {
+        let err = run(r#"error({"kind": "validation", "at": [0, 1.5]})"#, "null").unwrap_err();
+        assert_eq!(
+            err.payload_value().unwrap(),
+            serde_json::json!({"kind": "validation", "at": [0, 1.5]})
+        );
+        assert!(run(r#"error("boom")"#, "null")
+            .unwrap_err()
+            .payload_value()
+            .is_none());
+    }
+
+    #[test]
+    fn halt() {
+        // A plain halt is a clean exit, keeping the outputs so far.
+        assert_eq!(run("1, halt, 2", "null").unwrap(), "1\n");
+        assert_eq!(run(r#""ok" | halt_error(0)"#, "null").unwrap(), "");
+    }
+
+    #[test]
+    fn halt_error() {
+        let err = run("halt_error(7)", "1").unwrap_err();
+        assert_eq!(err.exit_code(), Some(7));
+
+        let halted = |program: &str| match run(program, r#"{"code": 42}"#) {
+            Err(Error::Halted { exit_code, payload }) => (exit_code, payload),
+            _ => unreachable!(),
+        };
+        assert_eq!(halted("halt_error"), (5, Some(r#"{"code":42}"#.into())));
+        assert_eq!(
+            halted("halt_error(.code)"),
+            (42, Some(r#"{"code":42}"#.into()))
+        );
+        assert_eq!(
+            halted(r#""nope\n" | halt_error(1)"#),
+            (1, Some("nope\n".into()))
+        );
+        assert_eq!(halted("null | halt_error(2)"), (2, None));
+
+        let err = run(r#""nope\n" | halt_error(1)"#, "null").unwrap_err();
+        assert_eq!(err.to_string(), "JQ: Program halted with exit code 1: nope");
+
+        // The same program can run again after halting, with a payload built
+        // by the run, as many times as it likes.
+        let mut prog =
+            compile(r#"if .stop then (.name + "!") | halt_error(3) else .name end"#).unwrap();
+        for _ in 0..3 {
+            assert_matches!(
+                prog.run(r#"{"stop": true, "name": "ann"}"#),
+                Err(Error::Halted { exit_code: 3, payload: Some(ref payload) }) if payload == "ann!"
+            );
+            assert_eq!(prog.run(r#"{"name": "bo"}"#).unwrap(), "\"bo\"\n");
+        }
+    }
+
+    #[test]
+    fn run_full() {
+        let mut prog = compile(".[] | if . == 0 then halt_error(.) else . end").unwrap();
+        let res = prog.run_full("[1, 2]").unwrap();
+        assert_eq!(res.output, "1\n2\n");
+        assert!(!res.halted);
+        assert_eq!(res.exit_code, 0);
+
+        let res = prog.run_full("[1, 0, 2]").unwrap();
+        assert_eq!(res.output, "1\n");
+        assert!(res.halted);
+        assert_eq!(res.exit_code, 0);
+        assert_eq!(res.halt_payload.unwrap(), "0");
+
+        let res = compile("halt").unwrap().run_full("1").unwrap();
+        assert!(res.halted);
+        assert_eq!(res.halt_payload, None);
+
+        let err = compile(".a").unwrap().run_full("[1]").unwrap_err();
+        assert_eq!(err.exit_code(), Some(5));
+    }
+
+    #[test]
+    fn invalid_utf8_output_is_replaced() {
+        // `0xC1` is never valid in UTF-8. jq replaces it as the decoded string
+        // is built, which is what lets the output skip validation.
+        let res = run("@base64d", r#""wQ==""#);
+        assert_eq!(res.unwrap(), "\"\u{fffd}\"\n");
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrow)]
     fn jq_state_is_not_global() {
         let input = r#"{"id": 123, "name": "foo"}"#;
         let query1 = r#".name"#;
@@ -268,6 +2030,181 @@ mod test {
         assert_eq!(res.unwrap(), "1\n2\n3\n".to_string());
     }
 
+    #[test]
+    fn debug_handler() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = seen.clone();
+        let mut prog = compile(r#".a | debug | {b: .} | debug | .b"#).unwrap();
+        // Nothing to hand the values to yet.
+        assert_eq!(prog.run(r#"{"a": "x"}"#).unwrap(), "\"x\"\n");
+
+        prog.set_debug_handler(move |value| log.lock().unwrap().push(value.to_string()));
+        assert_eq!(prog.run(r#"{"a": "y"}"#).unwrap(), "\"y\"\n");
+        assert_eq!(*seen.lock().unwrap(), vec!["\"y\"", r#"{"b":"y"}"#]);
+    }
+
+    #[test]
+    fn debug_handler_panic() {
+        let mut prog = compile("debug").unwrap();
+        prog.set_debug_handler(|value| {
+            if value == "2" {
+                panic!("two");
+            }
+        });
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| prog.run("2")));
+        assert_eq!(res.unwrap_err().downcast_ref(), Some(&"two"));
+        assert_eq!(prog.run("1").unwrap(), "1\n");
+    }
+
+    #[test]
+    fn stderr_handler() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = seen.clone();
+        let mut prog = compile(r#"debug | stderr | {a: .} | stderr | .a"#).unwrap();
+        let res = prog.run_full("[1]").unwrap();
+        assert_eq!(res.output, "[1]\n");
+        assert_eq!(res.stderr, r#"[1]{"a":[1]}"#);
+        // Only the last run is kept.
+        assert_eq!(prog.run_full("2").unwrap().stderr, r#"2{"a":2}"#);
+
+        prog.set_stderr_handler(move |value| log.lock().unwrap().push(value.to_string()));
+        let res = prog.run_full("3").unwrap();
+        assert_eq!((res.output.as_str(), res.stderr.as_str()), ("3\n", ""));
+        assert_eq!(*seen.lock().unwrap(), vec!["3", r#"{"a":3}"#]);
+    }
+
+    #[test]
+    fn stderr_can_be_redefined() {
+        let mut prog = compile(r#"def stderr: "mine"; stderr"#).unwrap();
+        let res = prog.run_full("null").unwrap();
+        assert_eq!(
+            (res.output.as_str(), res.stderr.as_str()),
+            ("\"mine\"\n", "")
+        );
+    }
+
+    #[test]
+    fn compile_error_line_numbers() {
+        let err = compile("1 |\n\n)").err().unwrap();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn compile_errors_leave_out_the_prelude() {
+        let compiler = Compiler::new()
+            .sandbox(true)
+            .var("v")
+            .fixed_time(std::time::UNIX_EPOCH)
+            .prelude("def shared: 1;");
+        for program in &[". aa12312me", "$nope", "foo(1)", "{"] {
+            for compiler in &[Compiler::new(), compiler.clone()] {
+                let reason = match compiler.compile(program) {
+                    Err(Error::InvalidProgram { reason }) => reason,
+                    _ => unreachable!(),
+                };
+                assert!(!reason.contains("jq-rs:"), "{}", reason);
+                assert!(!reason.contains("def "), "{}", reason);
+                assert!(reason.contains("line 1:\n"), "{}", reason);
+            }
+        }
+        let reason = Compiler::new().compile("foo(1)").err().unwrap().to_string();
+        assert!(reason.contains("line 1:\nfoo(1)\n"), "{}", reason);
+    }
+
+    #[test]
+    fn error_kinds() {
+        use crate::ErrorKind;
+
+        let compile = compile(". aa12312me").err().unwrap();
+        assert_eq!(compile.kind(), ErrorKind::Compile);
+        assert!(compile.is_compile_error() && !compile.is_parse_error());
+
+        let parse = run(".", "{").unwrap_err();
+        assert_eq!((parse.kind(), parse.code()), (ErrorKind::Parse, 2));
+        assert!(parse.is_parse_error() && !parse.is_exec_error());
+
+        let exec = run(".a", "[1]").unwrap_err();
+        assert_eq!((exec.kind(), exec.code()), (ErrorKind::Exec, 3));
+        assert!(exec.is_exec_error());
+
+        let halted = run("halt_error(1)", "null").unwrap_err();
+        assert!(halted.is_halted());
+        assert!(Error::Cancelled.is_cancelled());
+        assert!(Error::Timeout.is_timeout());
+        assert!(Error::Interrupted.is_interrupted());
+        assert_eq!(Error::Interrupted.code(), 11);
+    }
+
+    #[test]
+    fn io_error_conversion() {
+        use std::io;
+
+        let err = io::Error::from(run(".", "{").unwrap_err());
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("JQ: Parse error"));
+        // Converting back gives the original error.
+        assert!(Error::from(err).is_parse_error());
+
+        let err = io::Error::from(compile(".[").err().unwrap());
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            io::Error::from(Error::Timeout).kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert_eq!(
+            io::Error::from(run(".a", "[1]").unwrap_err()).kind(),
+            io::ErrorKind::Other
+        );
+
+        // I/O errors come out as they went in.
+        let original = io::Error::new(io::ErrorKind::BrokenPipe, "closed");
+        let err = io::Error::from(Error::from(original));
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(err.to_string(), "closed");
+
+        let err = io::Error::from(Error::from(std::ffi::CString::new("\0").unwrap_err()));
+        assert_matches!(Error::from(err), Error::StringConvert { .. });
+    }
+
+    #[test]
+    fn run_stats() {
+        let mut prog = compile(".[] | tostring").unwrap();
+        let stats = prog.run_full("[1, 22, 333]").unwrap().stats;
+        assert_eq!((stats.outputs, stats.output_bytes), (3, 15));
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = seen.clone();
+        prog.set_stats_handler(move |stats| log.lock().unwrap().push(*stats));
+        prog.run("[1]").unwrap();
+        assert!(prog.run("{").is_err());
+        assert!(prog.run("\"x\"").is_err());
+
+        let seen = seen.lock().unwrap();
+        // Nothing for the input which failed to parse.
+        assert_eq!(seen.len(), 2);
+        assert_eq!((seen[0].outputs, seen[0].output_bytes), (1, 4));
+        assert_eq!((seen[1].outputs, seen[1].output_bytes), (0, 0));
+    }
+
+    #[test]
+    fn compile_full() {
+        // libjq 1.6 has no warnings to give, even for dubious programs.
+        for program in &[
+            ". as [$a, $a] | $a",
+            "def f(a; a): a; f(1; 2)",
+            "1 as $ENV | .",
+        ] {
+            assert!(super::compile_full(program).unwrap().warnings.is_empty());
+        }
+        let mut compiled = super::compile_full(".a").unwrap();
+        assert!(compiled.warnings.is_empty());
+        assert_eq!(compiled.program.run(r#"{"a": 1}"#).unwrap(), "1\n");
+        assert_matches!(
+            super::compile_full(". aa12312me").err(),
+            Some(Error::InvalidProgram { .. })
+        );
+    }
+
     #[test]
     fn compile_error() {
         let res = run(". aa12312me  dsaafsdfsd", "{\"name\": \"test\"}");
@@ -277,19 +2214,49 @@ mod test {
     #[test]
     fn parse_error() {
         let res = run(".", "{1233 invalid json ahoy : est\"}");
-        assert_matches!(res, Err(Error::System { .. }));
+        assert_matches!(res, Err(Error::Parse { .. }));
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let data = "{\n  \"a\": 1,\n  \"b\": tru\n}";
+        match run(".", data) {
+            Err(Error::Parse {
+                reason,
+                line,
+                column,
+                offset,
+                excerpt,
+                caret,
+            }) => {
+                assert_eq!(reason, "Invalid literal");
+                assert_eq!((line, column), (4, 0));
+                // The newline after `tru` is what gave it away.
+                assert_eq!(offset, data.find("tru").unwrap() + 3);
+                assert_eq!((excerpt.as_str(), caret), ("  \"b\": tru", 10));
+            }
+            _ => unreachable!(),
+        }
+
+        let err = run(".", "[1 2]").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "JQ: Parse error: Expected separator between values at line 1, column 5\n\
+             [1 2]\n    ^"
+        );
+        assert_eq!(err.exit_code(), Some(2));
     }
 
     #[test]
     fn just_open_brace() {
         let res = run(".", "{");
-        assert_matches!(res, Err(Error::System { .. }));
+        assert_matches!(res, Err(Error::Parse { .. }));
     }
 
     #[test]
     fn just_close_brace() {
         let res = run(".", "}");
-        assert_matches!(res, Err(Error::System { .. }));
+        assert_matches!(res, Err(Error::Parse { .. }));
     }
 
     #[test]
@@ -301,7 +2268,32 @@ mod test {
         }"#;
 
         let res = run(".", data);
-        assert_matches!(res, Err(Error::System { .. }));
+        assert_matches!(res, Err(Error::Parse { .. }));
+    }
+
+    #[test]
+    fn parse_error_excerpt_is_trimmed() {
+        let data = format!(
+            "[\"{}\",\t\"caf\u{e9}\" }} \"{}\"]",
+            "x".repeat(100),
+            "y".repeat(100)
+        );
+        match run(".", &data) {
+            Err(Error::Parse { excerpt, caret, .. }) => {
+                // Tabs are swapped for spaces to keep the caret lined up.
+                assert_eq!(
+                    excerpt,
+                    format!(
+                        "{}\", \"caf\u{e9}\" }} \"{}",
+                        "x".repeat(21),
+                        "y".repeat(29)
+                    )
+                );
+                assert_eq!(caret, 31);
+                assert_eq!(excerpt.chars().nth(caret), Some('}'));
+            }
+            _ => unreachable!(),
+        }
     }
 
     pub mod mem_errors {
@@ -321,7 +2313,7 @@ mod test {
             let prog = ".[] | .hello";
             let data = "[1,2,3]";
             let res = run(prog, data);
-            assert_matches!(res, Err(Error::System { .. }));
+            assert_matches!(res, Err(Error::Exec { .. }));
         }
 
         #[test]
@@ -329,7 +2321,7 @@ mod test {
             let mut prog = compile(".[] | .hello").unwrap();
             let data = "[1,2,3]";
             let res = prog.run(data);
-            assert_matches!(res, Err(Error::System { .. }));
+            assert_matches!(res, Err(Error::Exec { .. }));
         }
     }
 }
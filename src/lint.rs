@@ -0,0 +1,863 @@
+use crate::audit::{is_ident_start, KEYWORDS};
+use std::fmt;
+
+/// Builtins jq 1.6 documents as deprecated, by name and arity, with what to
+/// use instead.
+const DEPRECATED: &[(&str, usize, &str)] = &[
+    ("leaf_paths", 0, "paths(scalars)"),
+    ("recurse_down", 0, "recurse"),
+];
+
+/// Variables jq defines for every program.
+const GLOBALS: &[&str] = &["ENV", "ARGS", "__loc__"];
+
+/// The kinds of problem `lint()` looks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LintKind {
+    /// A call to a builtin jq has deprecated, such as `leaf_paths`.
+    DeprecatedBuiltin,
+    /// A variable bound with the same name as one already in scope, hiding
+    /// it from the rest of the expression.
+    ShadowedVariable,
+    /// A function defined by the program which is never called, other than
+    /// by itself.
+    UnusedDefinition,
+    /// A variable the program uses without binding it. It has to be passed in
+    /// with `Compiler::arg()` or similar, or the program won't compile.
+    UndefinedVariable,
+}
+
+/// Something `lint()` found in a program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LintWarning {
+    /// What sort of problem this is.
+    pub kind: LintKind,
+    /// A description of the problem, for showing to whoever wrote the
+    /// program.
+    pub message: String,
+    /// The line the problem is on, starting from `1`.
+    pub line: usize,
+    /// How many bytes into the line the problem starts.
+    pub column: usize,
+    /// The byte offset into the program of the same spot as `line` and
+    /// `column`.
+    pub offset: usize,
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (line {}, column {})",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+/// Look over a program for things which are likely mistakes, even though
+/// jq might compile it: calls to deprecated builtins, shadowed variables,
+/// unused definitions and variables which aren't defined by the program.
+///
+/// ```rust
+/// use jq_rs::LintKind;
+///
+/// let warnings = jq_rs::lint("def unused: 1; .[] as $x | $x | $limit");
+/// let kinds: Vec<_> = warnings.iter().map(|warning| warning.kind).collect();
+/// assert_eq!(kinds, vec![LintKind::UnusedDefinition, LintKind::UndefinedVariable]);
+///
+/// assert_eq!(warnings[1].message, "`$limit` isn't defined by the program");
+/// assert_eq!((warnings[1].line, warnings[1].column), (1, 32));
+/// ```
+///
+/// Like `referenced_builtins()`, this works from the text of the program, so
+/// it doesn't know about functions or variables coming from modules, or those
+/// added with `Compiler::arg()` and friends. Warnings are in the order they
+/// appear in the program. A program which doesn't parse gets whatever
+/// warnings can be found in it, so compile it too for the full story.
+pub fn lint(program: &str) -> Vec<LintWarning> {
+    let mut linter = Linter {
+        src: program,
+        tokens: Lexer::tokenize(program),
+        pos: 0,
+        vars: vec![],
+        defs: vec![],
+        table: vec![],
+        bodies: vec![],
+        warnings: vec![],
+    };
+    let end = linter.tokens.len();
+    linter.code(end);
+
+    let unused: Vec<_> = linter
+        .table
+        .iter()
+        .filter(|def| !def.param && !def.used)
+        .map(|def| {
+            (
+                format!("`{}/{}` is never used", def.name, def.arity),
+                def.offset,
+            )
+        })
+        .collect();
+    for (message, offset) in unused {
+        linter.warn(LintKind::UnusedDefinition, message, offset);
+    }
+    linter.warnings.sort_by_key(|warning| warning.offset);
+    linter.warnings
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    /// A name, including keywords.
+    Ident(String),
+    /// `$name`.
+    Var(String),
+    /// The start and end of a string, with any interpolations in between as
+    /// bracketed code.
+    StrOpen,
+    StrClose,
+    /// `?//`, between destructuring alternatives.
+    Alt,
+    /// Anything which doesn't matter for linting, such as numbers, fields,
+    /// formats and names from modules.
+    Atom,
+    /// Any other character.
+    Punct(u8),
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    tok: Tok,
+    offset: usize,
+}
+
+struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    tokens: Vec<Token>,
+}
+
+impl Lexer<'_> {
+    fn tokenize(program: &str) -> Vec<Token> {
+        let mut lexer = Lexer {
+            src: program.as_bytes(),
+            pos: 0,
+            tokens: vec![],
+        };
+        lexer.code(false);
+        lexer.tokens
+    }
+
+    fn push(&mut self, tok: Tok, offset: usize) {
+        self.tokens.push(Token { tok, offset });
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).cloned()
+    }
+
+    fn next_is(&self, test: fn(u8) -> bool) -> bool {
+        match self.peek() {
+            Some(c) => test(c),
+            None => false,
+        }
+    }
+
+    /// Read code up to the end of the program, or when `interpolation` is
+    /// set, up to the `)` closing a string interpolation.
+    fn code(&mut self, interpolation: bool) {
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            let start = self.pos;
+            self.pos += 1;
+            match c {
+                c if c.is_ascii_whitespace() => {}
+                b'#' => {
+                    while self.next_is(|c| c != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                b'"' => self.string(start),
+                b'.' if self.next_is(is_ident_start) => {
+                    self.ident();
+                    self.push(Tok::Atom, start);
+                }
+                b'.' if self.peek() == Some(b'.') => {
+                    self.pos += 1;
+                    self.push(Tok::Atom, start);
+                }
+                b'$' => {
+                    while self.next_is(|c| c.is_ascii_whitespace()) {
+                        self.pos += 1;
+                    }
+                    let name = self.ident();
+                    if self.module_name() {
+                        self.push(Tok::Atom, start);
+                    } else {
+                        self.push(Tok::Var(name), start);
+                    }
+                }
+                b'@' => {
+                    self.ident();
+                    self.push(Tok::Atom, start);
+                }
+                b'0'..=b'9' => {
+                    self.number();
+                    self.push(Tok::Atom, start);
+                }
+                c if is_ident_start(c) => {
+                    self.pos = start;
+                    let name = self.ident();
+                    if self.module_name() {
+                        self.push(Tok::Atom, start);
+                    } else {
+                        self.push(Tok::Ident(name), start);
+                    }
+                }
+                b'?' if self.src[self.pos..].starts_with(b"//") => {
+                    self.pos += 2;
+                    self.push(Tok::Alt, start);
+                }
+                b')' if interpolation && depth == 0 => {
+                    self.push(Tok::Punct(c), start);
+                    return;
+                }
+                _ => {
+                    match c {
+                        b'(' => depth += 1,
+                        b')' => depth -= 1,
+                        _ => {}
+                    }
+                    self.push(Tok::Punct(c), start);
+                }
+            }
+        }
+    }
+
+    /// Read a string up to its closing quote, reading interpolations as
+    /// code.
+    fn string(&mut self, start: usize) {
+        self.push(Tok::StrOpen, start);
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' if self.peek() == Some(b'(') => {
+                    self.push(Tok::Punct(b'('), self.pos);
+                    self.pos += 1;
+                    self.code(true);
+                }
+                b'\\' => self.pos += 1,
+                _ => {}
+            }
+        }
+        self.push(Tok::StrClose, self.pos);
+    }
+
+    /// Skip the rest of a name like `m::f`, after the `m`.
+    fn module_name(&mut self) -> bool {
+        if !self.src[self.pos..].starts_with(b"::") {
+            return false;
+        }
+        self.pos += 2;
+        self.ident();
+        true
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while self.next_is(|c| c.is_ascii_alphanumeric() || c == b'_') {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.src[start..self.pos]).into_owned()
+    }
+
+    fn number(&mut self) {
+        while self.next_is(|c| c.is_ascii_digit() || c == b'.') {
+            self.pos += 1;
+        }
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.pos += 1;
+            }
+            while self.next_is(|c| c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+        }
+    }
+}
+
+/// A function the program defines, or a parameter of one.
+struct Def {
+    name: String,
+    arity: usize,
+    offset: usize,
+    /// Parameters, with whether they're `$` parameters.
+    params: Vec<(String, bool, usize)>,
+    param: bool,
+    used: bool,
+}
+
+enum Kind {
+    /// Brackets, or the whole of the code being linted.
+    Group,
+    /// An object, and whether the next thing in it is a key.
+    Object { expect_key: bool },
+    /// The arguments of a call, with the number of `;` seen so far.
+    Call {
+        name: String,
+        offset: usize,
+        separators: usize,
+    },
+    /// The parameters of a `def`.
+    Params { def: usize },
+    /// The body of a `def`, up to its `;`.
+    Body { def: usize },
+    /// The brackets after `reduce ... as $x` or `foreach ... as $x`, which
+    /// bind the variables after the first `;`.
+    Fold {
+        vars: Vec<(String, usize)>,
+        bound: bool,
+    },
+    /// `if` up to `end`.
+    If,
+}
+
+/// An open bracket or construct, and how many variables and definitions
+/// were in scope when it started.
+struct Frame {
+    kind: Kind,
+    vars: usize,
+    defs: usize,
+    /// Set after `reduce` or `foreach`, for the `as` which follows.
+    fold: bool,
+}
+
+struct Linter<'a> {
+    src: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The variables in scope, innermost last.
+    vars: Vec<String>,
+    /// The definitions in scope, as indexes into `table`, innermost last.
+    defs: Vec<usize>,
+    table: Vec<Def>,
+    /// The definitions whose bodies are being read.
+    bodies: Vec<usize>,
+    warnings: Vec<LintWarning>,
+}
+
+impl Linter<'_> {
+    fn next(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|token| &token.tok)
+    }
+
+    fn frame(&self, kind: Kind) -> Frame {
+        Frame {
+            kind,
+            vars: self.vars.len(),
+            defs: self.defs.len(),
+            fold: false,
+        }
+    }
+
+    /// Drop the variables and definitions which went into scope in `frame`.
+    fn truncate(&mut self, frame: &Frame) {
+        self.vars.truncate(frame.vars);
+        self.defs.truncate(frame.defs);
+    }
+
+    /// Lint the tokens up to `end`.
+    fn code(&mut self, end: usize) {
+        let mut frames = vec![self.frame(Kind::Group)];
+        while self.pos < end {
+            let Token { tok, offset } = self.tokens[self.pos].clone();
+            self.pos += 1;
+            let top = frames.len() - 1;
+
+            if let Kind::Params { def } = frames[top].kind {
+                match tok {
+                    Tok::Ident(name) => self.table[def].params.push((name, false, offset)),
+                    Tok::Var(name) => self.table[def].params.push((name, true, offset)),
+                    Tok::Punct(b')') => {
+                        frames.pop();
+                        self.table[def].arity = self.table[def].params.len();
+                        if self.next() == Some(&Tok::Punct(b':')) {
+                            self.pos += 1;
+                            self.open_body(def, &mut frames);
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            match tok {
+                Tok::Var(name) => self.reference(&name, offset),
+                Tok::Ident(name) => self.ident(name, offset, &mut frames, end),
+                Tok::Punct(b'(') | Tok::Punct(b'[') => frames.push(self.frame(Kind::Group)),
+                Tok::Punct(b'{') => frames.push(self.frame(Kind::Object { expect_key: true })),
+                Tok::Punct(b')') | Tok::Punct(b']') | Tok::Punct(b'}') if top > 0 => {
+                    let frame = frames.pop().expect("not the outermost frame");
+                    self.close(frame);
+                }
+                Tok::Punct(b';') => self.separator(&mut frames),
+                Tok::Punct(b',') => {
+                    if let Kind::Object { expect_key } = &mut frames[top].kind {
+                        *expect_key = true;
+                        self.vars.truncate(frames[top].vars);
+                        self.defs.truncate(frames[top].defs);
+                    }
+                }
+                Tok::Punct(b':') => {
+                    if let Kind::Object { expect_key } = &mut frames[top].kind {
+                        *expect_key = false;
+                    }
+                }
+                _ => {}
+            }
+        }
+        while let Some(frame) = frames.pop() {
+            self.close(frame);
+        }
+    }
+
+    fn close(&mut self, frame: Frame) {
+        self.truncate(&frame);
+        match frame.kind {
+            Kind::Call {
+                name,
+                offset,
+                separators,
+            } => self.call(&name, separators + 1, offset),
+            Kind::Body { def } => {
+                self.bodies.pop();
+                self.defs.push(def);
+            }
+            _ => {}
+        }
+    }
+
+    fn separator(&mut self, frames: &mut Vec<Frame>) {
+        let top = frames.len() - 1;
+        match &mut frames[top].kind {
+            Kind::Body { .. } => {
+                let frame = frames.pop().expect("checked above");
+                self.close(frame);
+            }
+            Kind::Call { separators, .. } => {
+                *separators += 1;
+                self.vars.truncate(frames[top].vars);
+                self.defs.truncate(frames[top].defs);
+            }
+            Kind::Fold { vars, bound } => {
+                let first = !std::mem::replace(bound, true);
+                let vars = vars.clone();
+                self.vars.truncate(frames[top].vars);
+                self.defs.truncate(frames[top].defs);
+                for (name, offset) in vars {
+                    if first {
+                        self.bind(name, offset);
+                    } else {
+                        self.vars.push(name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ident(&mut self, name: String, offset: usize, frames: &mut Vec<Frame>, end: usize) {
+        let top = frames.len() - 1;
+        if let Kind::Object { expect_key: true } = frames[top].kind {
+            return;
+        }
+        match name.as_str() {
+            "def" => self.def(frames),
+            "reduce" | "foreach" => frames[top].fold = true,
+            "as" => {
+                let vars = self.pattern(end);
+                if !std::mem::replace(&mut frames[top].fold, false) {
+                    for (name, offset) in vars {
+                        self.bind(name, offset);
+                    }
+                } else if self.next() == Some(&Tok::Punct(b'(')) {
+                    self.pos += 1;
+                    frames.push(self.frame(Kind::Fold { vars, bound: false }));
+                }
+            }
+            "label" | "break" => {
+                // Labels are only ever used by `break`, which jq checks.
+                if let Some(Tok::Var(_)) = self.next() {
+                    self.pos += 1;
+                }
+            }
+            "import" | "include" | "module" => {
+                // Directives bind names for modules, which aren't checked.
+                while self.pos < end && self.next() != Some(&Tok::Punct(b';')) {
+                    self.pos += 1;
+                }
+            }
+            "if" => frames.push(self.frame(Kind::If)),
+            "then" | "elif" | "else" => {
+                if let Kind::If = frames[top].kind {
+                    self.vars.truncate(frames[top].vars);
+                    self.defs.truncate(frames[top].defs);
+                }
+            }
+            "end" => {
+                if let Kind::If = frames[top].kind {
+                    let frame = frames.pop().expect("checked above");
+                    self.close(frame);
+                }
+            }
+            _ if KEYWORDS.contains(&name.as_str()) => {}
+            _ if self.next() == Some(&Tok::Punct(b'(')) => {
+                self.pos += 1;
+                frames.push(self.frame(Kind::Call {
+                    name,
+                    offset,
+                    separators: 0,
+                }));
+            }
+            _ => self.call(&name, 0, offset),
+        }
+    }
+
+    fn def(&mut self, frames: &mut Vec<Frame>) {
+        let (name, offset) = match self.tokens.get(self.pos) {
+            Some(Token {
+                tok: Tok::Ident(name),
+                offset,
+            }) => (name.clone(), *offset),
+            _ => return,
+        };
+        self.pos += 1;
+        let def = self.table.len();
+        self.table.push(Def {
+            name,
+            arity: 0,
+            offset,
+            params: vec![],
+            param: false,
+            used: false,
+        });
+        match self.next() {
+            Some(Tok::Punct(b'(')) => {
+                self.pos += 1;
+                frames.push(self.frame(Kind::Params { def }));
+            }
+            Some(Tok::Punct(b':')) => {
+                self.pos += 1;
+                self.open_body(def, frames);
+            }
+            _ => {}
+        }
+    }
+
+    /// Start reading the body of `def`, with it and its parameters in scope.
+    fn open_body(&mut self, def: usize, frames: &mut Vec<Frame>) {
+        frames.push(self.frame(Kind::Body { def }));
+        self.bodies.push(def);
+        self.defs.push(def);
+        for (name, var, offset) in self.table[def].params.clone() {
+            self.defs.push(self.table.len());
+            self.table.push(Def {
+                name: name.clone(),
+                arity: 0,
+                offset,
+                params: vec![],
+                param: true,
+                used: false,
+            });
+            if var {
+                self.bind(name, offset);
+            }
+        }
+    }
+
+    /// Read a destructuring pattern after `as`, giving back the variables it
+    /// binds.
+    fn pattern(&mut self, end: usize) -> Vec<(String, usize)> {
+        let mut vars = vec![];
+        loop {
+            self.pattern_term(end, &mut vars);
+            if self.next() != Some(&Tok::Alt) {
+                break;
+            }
+            self.pos += 1;
+        }
+        // Alternatives bind the same variables as each other.
+        let mut seen = vec![];
+        vars.retain(|(name, _)| {
+            let first = !seen.contains(name);
+            seen.push(name.clone());
+            first
+        });
+        vars
+    }
+
+    fn pattern_term(&mut self, end: usize, vars: &mut Vec<(String, usize)>) {
+        let Token { tok, offset } = match self.tokens.get(self.pos) {
+            Some(token) if self.pos < end => token.clone(),
+            _ => return,
+        };
+        self.pos += 1;
+        match tok {
+            Tok::Var(name) => vars.push((name, offset)),
+            Tok::Punct(b'[') => {
+                while self.pos < end {
+                    match self.next() {
+                        Some(Tok::Punct(b']')) => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(Tok::Punct(b',')) => self.pos += 1,
+                        _ => self.pattern_term(end, vars),
+                    }
+                }
+            }
+            Tok::Punct(b'{') => {
+                while self.pos < end {
+                    match self.next().cloned() {
+                        Some(Tok::Punct(b'}')) => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(Tok::Var(name)) => {
+                            vars.push((name, self.tokens[self.pos].offset));
+                            self.pos += 1;
+                        }
+                        Some(Tok::StrOpen) => self.string_key(end),
+                        Some(Tok::Punct(b'(')) => {
+                            let close = self.matching(end);
+                            self.pos += 1;
+                            self.code(close);
+                            self.pos = close + 1;
+                        }
+                        Some(Tok::Punct(b':')) => {
+                            self.pos += 1;
+                            self.pattern_term(end, vars);
+                        }
+                        _ => self.pos += 1,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Lint the interpolations in a string used as an object key in a
+    /// pattern.
+    fn string_key(&mut self, end: usize) {
+        self.pos += 1;
+        while self.pos < end {
+            match self.next() {
+                Some(Tok::StrClose) => {
+                    self.pos += 1;
+                    return;
+                }
+                Some(Tok::Punct(b'(')) => {
+                    let close = self.matching(end);
+                    self.pos += 1;
+                    self.code(close);
+                    self.pos = close + 1;
+                }
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    /// The index of the bracket closing the one at `self.pos`.
+    fn matching(&self, end: usize) -> usize {
+        let mut depth = 0;
+        for idx in self.pos..end {
+            match self.tokens[idx].tok {
+                Tok::Punct(b'(') | Tok::Punct(b'[') | Tok::Punct(b'{') => depth += 1,
+                Tok::Punct(b')') | Tok::Punct(b']') | Tok::Punct(b'}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return idx;
+                    }
+                }
+                _ => {}
+            }
+        }
+        end
+    }
+
+    fn bind(&mut self, name: String, offset: usize) {
+        if self.vars.contains(&name) {
+            let message = format!("`${}` shadows another variable of the same name", name);
+            self.warn(LintKind::ShadowedVariable, message, offset);
+        }
+        self.vars.push(name);
+    }
+
+    fn reference(&mut self, name: &str, offset: usize) {
+        if !self.vars.iter().any(|var| var == name) && !GLOBALS.contains(&name) {
+            let message = format!("`${}` isn't defined by the program", name);
+            self.warn(LintKind::UndefinedVariable, message, offset);
+        }
+    }
+
+    fn call(&mut self, name: &str, arity: usize, offset: usize) {
+        let table = &self.table;
+        let def = self
+            .defs
+            .iter()
+            .rev()
+            .find(|&&def| table[def].name == name && table[def].arity == arity);
+        match def {
+            // Functions calling themselves don't count as being used.
+            Some(&def) if !self.bodies.contains(&def) => self.table[def].used = true,
+            Some(_) => {}
+            None => {
+                if let Some((_, _, instead)) = DEPRECATED
+                    .iter()
+                    .find(|(deprecated, params, _)| *deprecated == name && *params == arity)
+                {
+                    let message = format!("`{}` is deprecated, use `{}` instead", name, instead);
+                    self.warn(LintKind::DeprecatedBuiltin, message, offset);
+                }
+            }
+        }
+    }
+
+    fn warn(&mut self, kind: LintKind, message: String, offset: usize) {
+        let before = &self.src[..offset];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        self.warnings.push(LintWarning {
+            kind,
+            message,
+            line: before.matches('\n').count() + 1,
+            column: offset - line_start,
+            offset,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lint, LintKind};
+    use crate::compile;
+
+    fn warnings(program: &str) -> Vec<(LintKind, String)> {
+        lint(program)
+            .into_iter()
+            .map(|warning| (warning.kind, warning.message))
+            .collect()
+    }
+
+    #[test]
+    fn clean() {
+        for program in &[
+            ".[] | select(.a) | {a, b: .c}",
+            "def f: def g: 3; g; f",
+            "def f(g; $x): g, $x, x; f(.; 1)",
+            "def fac: if . <= 1 then 1 else . * (. - 1 | fac) end; fac",
+            ". as $k | . as [$a, {b: $b, $c, \"d\\($k)\": $d, ($k): [$e]}] | [$a, $b, $c, $d, $e]",
+            ". as [$a] ?// $a | $a",
+            "reduce .[] as $x (0; . + $x)",
+            "foreach .[] as [$x] (0; . + $x; [$x])",
+            "label $out | .[] | if . then break $out else . end",
+            "{loc: $__loc__, env: $ENV, args: $ARGS}",
+            "\"\\(. as $x | $x) and $x\" as $x | $x",
+            "(1 as $x | $x), (2 as $x | $x)",
+            "{if: 1, then: 2} | .if",
+            "# $nope\n. # leaf_paths\n",
+        ] {
+            assert!(compile(program).is_ok(), "program: {}", program);
+            assert_eq!(warnings(program), vec![], "program: {}", program);
+        }
+    }
+
+    #[test]
+    fn deprecated() {
+        assert_eq!(
+            warnings("[leaf_paths], [recurse_down], recurse_down(.)"),
+            vec![
+                (
+                    LintKind::DeprecatedBuiltin,
+                    "`leaf_paths` is deprecated, use `paths(scalars)` instead".into()
+                ),
+                (
+                    LintKind::DeprecatedBuiltin,
+                    "`recurse_down` is deprecated, use `recurse` instead".into()
+                ),
+            ]
+        );
+        assert!(warnings("def leaf_paths: 1; leaf_paths").is_empty());
+    }
+
+    #[test]
+    fn shadowed() {
+        let shadowed = |program: &str| {
+            let program = compile(program).map(|_| program).unwrap();
+            lint(program)
+                .into_iter()
+                .filter(|warning| warning.kind == LintKind::ShadowedVariable)
+                .map(|warning| warning.offset)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shadowed(". as $x | 1 as $x | $x"), vec![15]);
+        assert_eq!(shadowed(". as $x | def f($x): $x; f(1)"), vec![16]);
+        assert_eq!(
+            shadowed(". as $x | foreach .[] as $x (0; $x; $x)"),
+            vec![25]
+        );
+        assert_eq!(shadowed(". as $x | reduce .[] as $x (0; $x)"), vec![24]);
+        assert_eq!(shadowed(". as [$x, $y] | . as {a: [$y]} | $x"), vec![26]);
+        assert_eq!(
+            shadowed("limit(1 as $x | $x; . as $x | $x)"),
+            Vec::<usize>::new()
+        );
+    }
+
+    #[test]
+    fn unused() {
+        assert_eq!(
+            warnings("def a: 1; def b(f): f; def c: c; def d($x): 1; b(a), d"),
+            vec![
+                (LintKind::UnusedDefinition, "`c/0` is never used".into()),
+                (LintKind::UnusedDefinition, "`d/1` is never used".into()),
+            ]
+        );
+        assert_eq!(
+            warnings("def f: 1; (def f: 2; f), f(1)"),
+            vec![(LintKind::UnusedDefinition, "`f/0` is never used".into())]
+        );
+    }
+
+    #[test]
+    fn undefined() {
+        let undefined = |program: &str| {
+            assert!(compile(program).is_err(), "program: {}", program);
+            lint(program)
+                .into_iter()
+                .filter(|warning| warning.kind == LintKind::UndefinedVariable)
+                .map(|warning| warning.offset)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(undefined("$a"), vec![0]);
+        assert_eq!(undefined("(. as $a | $a), $a"), vec![16]);
+        assert_eq!(undefined("reduce .[] as $a ($a; .)"), vec![18]);
+        assert_eq!(undefined("def f($a): $a; $a"), vec![15]);
+        assert_eq!(undefined("if . as $a | $a then $a else 1 end"), vec![21]);
+        assert_eq!(undefined("\"\\($a)\""), vec![3]);
+        // Keys in patterns can't see the variables the pattern binds.
+        assert_eq!(undefined(". as [$a, {($a): $b}] | $b"), vec![12]);
+    }
+
+    #[test]
+    fn positions() {
+        let warning = &lint("1,\n  2,\n  $a")[0];
+        assert_eq!((warning.line, warning.column, warning.offset), (3, 2, 10));
+        assert_eq!(
+            warning.to_string(),
+            "`$a` isn't defined by the program (line 3, column 2)"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Splicing Rust values into program text.
+//!
+//! Binding values with `Compiler::arg()` is the safest way to get them into a
+//! program, but sometimes the program text itself has to be built up. Values
+//! spliced in with `format!()` can break out of the string they were meant to
+//! be in, so `format_program!()` renders each of them as a jq literal instead.
+
+use std::fmt::Write;
+
+/// Quote `text` as a jq string literal, including the surrounding quotes, so
+/// it can be spliced into a program as a value.
+///
+/// Backslashes are escaped along with the quotes, so the text can't start a
+/// string interpolation either.
+///
+/// ```rust
+/// let input = r#"Godzilla" | env | ""#;
+/// let program = format!(".name == {}", jq_rs::escape_string(input));
+/// assert_eq!(program, r#".name == "Godzilla\" | env | \"""#);
+/// ```
+pub fn escape_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    write_string(text, &mut out);
+    out
+}
+
+fn write_string(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    let _ = write!(out, "\\u{:04x}", unit);
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Values which can be written into a program as jq literals, for
+/// `format_program!()`.
+pub trait JqLiteral {
+    /// Append the literal to `out`.
+    fn write_literal(&self, out: &mut String);
+}
+
+impl JqLiteral for str {
+    fn write_literal(&self, out: &mut String) {
+        write_string(self, out);
+    }
+}
+
+impl JqLiteral for String {
+    fn write_literal(&self, out: &mut String) {
+        write_string(self, out);
+    }
+}
+
+impl JqLiteral for char {
+    fn write_literal(&self, out: &mut String) {
+        write_string(self.encode_utf8(&mut [0; 4]), out);
+    }
+}
+
+impl JqLiteral for bool {
+    fn write_literal(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+macro_rules! integer_literals {
+    ($($ty:ty)*) => {
+        $(
+            impl JqLiteral for $ty {
+                #[allow(unused_comparisons)]
+                fn write_literal(&self, out: &mut String) {
+                    // Negative numbers are wrapped up so they can't be read
+                    // as a subtraction from whatever comes before them.
+                    if *self < 0 {
+                        let _ = write!(out, "({})", self);
+                    } else {
+                        let _ = write!(out, "{}", self);
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_literals!(i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);
+
+impl JqLiteral for f64 {
+    fn write_literal(&self, out: &mut String) {
+        if self.is_nan() {
+            out.push_str("nan");
+        } else if self.is_infinite() {
+            out.push_str(if *self > 0.0 {
+                "infinite"
+            } else {
+                "(-infinite)"
+            });
+        } else if self.is_sign_negative() {
+            let _ = write!(out, "({:?})", self);
+        } else {
+            let _ = write!(out, "{:?}", self);
+        }
+    }
+}
+
+impl JqLiteral for f32 {
+    fn write_literal(&self, out: &mut String) {
+        f64::from(*self).write_literal(out);
+    }
+}
+
+/// `None` is written as `null`.
+impl<T: JqLiteral> JqLiteral for Option<T> {
+    fn write_literal(&self, out: &mut String) {
+        match self {
+            Some(value) => value.write_literal(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: JqLiteral> JqLiteral for [T] {
+    fn write_literal(&self, out: &mut String) {
+        out.push('[');
+        for (idx, item) in self.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            item.write_literal(out);
+        }
+        out.push(']');
+    }
+}
+
+impl<T: JqLiteral> JqLiteral for Vec<T> {
+    fn write_literal(&self, out: &mut String) {
+        self.as_slice().write_literal(out);
+    }
+}
+
+impl<T: JqLiteral + ?Sized> JqLiteral for &T {
+    fn write_literal(&self, out: &mut String) {
+        (**self).write_literal(out);
+    }
+}
+
+/// Requires the `serde_json` feature.
+#[cfg(feature = "serde_json")]
+impl JqLiteral for serde_json::Value {
+    fn write_literal(&self, out: &mut String) {
+        // Json is valid jq, apart from negative numbers needing to be
+        // wrapped up like any others.
+        match self {
+            serde_json::Value::Number(n) if n.to_string().starts_with('-') => {
+                let _ = write!(out, "({})", n);
+            }
+            serde_json::Value::String(s) => write_string(s, out),
+            serde_json::Value::Array(items) => items.write_literal(out),
+            serde_json::Value::Object(map) => {
+                out.push('{');
+                for (idx, (key, value)) in map.iter().enumerate() {
+                    if idx > 0 {
+                        out.push(',');
+                    }
+                    write_string(key, out);
+                    out.push(':');
+                    value.write_literal(out);
+                }
+                out.push('}');
+            }
+            other => {
+                let _ = write!(out, "{}", other);
+            }
+        }
+    }
+}
+
+/// Used by `format_program!()`.
+#[doc(hidden)]
+pub fn to_literal<T: JqLiteral + ?Sized>(value: &T) -> String {
+    let mut out = String::new();
+    value.write_literal(&mut out);
+    out
+}
+
+/// Like `format!()`, but each argument is written into the program as a jq
+/// literal (see `JqLiteral`), so strings are quoted and escaped.
+///
+/// Arguments are only accepted by position, and should be formatted with
+/// plain `{}`.
+///
+/// ```rust
+/// use jq_rs::format_program;
+///
+/// let name = r#"Godzilla" or true or ""#;
+/// let program = format_program!(".[] | select(.name == {} and .year < {})", name, 1960);
+/// let mut program = jq_rs::compile(&program).unwrap();
+///
+/// let data = r#"[{"name": "Godzilla", "year": 1954}, {"name": "Rodan", "year": 1956}]"#;
+/// assert_eq!(program.run(data).unwrap(), "");
+/// ```
+#[macro_export]
+macro_rules! format_program {
+    ($fmt:expr $(, $arg:expr)* $(,)?) => {
+        format!($fmt $(, $crate::to_literal(&$arg))*)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape_string;
+    use crate::{compile, Compiler};
+
+    fn eval(program: &str) -> String {
+        compile(program).unwrap().run("null").unwrap()
+    }
+
+    #[test]
+    fn strings_come_back_unchanged() {
+        for text in &[
+            "",
+            "plain",
+            "quote \" and backslash \\",
+            "\\(env)",
+            "new\nline\ttab\r",
+            "nul \u{0} bell \u{7} del \u{7f} next line \u{85}",
+            "caf\u{e9} \u{1f996}",
+        ] {
+            let mut program = Compiler::new()
+                .arg("text", *text)
+                .compile(&format!("{} == $text", escape_string(text)))
+                .unwrap();
+            assert_eq!(program.run("null").unwrap(), "true\n", "text: {:?}", text);
+        }
+    }
+
+    #[test]
+    fn literals() {
+        let program = format_program!(
+            "[{}, {}, {}, {}, {}, {}, {}, {}, {}]",
+            "a",
+            'b',
+            true,
+            -3,
+            u64::MAX,
+            -0.5,
+            Some(vec![1.5f32]),
+            None::<i32>,
+            &["c".to_string()][..]
+        );
+        assert_eq!(
+            eval(&program),
+            "[\"a\",\"b\",true,-3,18446744073709552000,-0.5,[1.5],null,[\"c\"]]\n"
+        );
+        assert_eq!(
+            eval(&format_program!(
+                "[{}, {}, {}] | map(isnan, isinfinite)",
+                f64::NAN,
+                f64::INFINITY,
+                f64::NEG_INFINITY
+            )),
+            "[true,false,false,true,false,true]\n"
+        );
+        // Negative numbers can't turn into a subtraction.
+        assert!(compile("1 -1").is_ok());
+        assert!(compile(&format_program!("1 {}", -1)).is_err());
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn json_values() {
+        let value = serde_json::json!({"a\"": [-1, 2.5, null, {"\\(b)": false}]});
+        assert_eq!(eval(&format_program!("{} == .", value)), "false\n");
+        assert_eq!(
+            eval(&format_program!("{} | tojson", value)),
+            format!("{}\n", serde_json::to_string(&value.to_string()).unwrap())
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use crate::errors::ErrorKind;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where the numbers for each run of a program are reported, for exporting
+/// to a metrics system. Set with `Compiler::metrics()`.
+///
+/// Every method does nothing unless overridden, so an implementation only
+/// needs the ones it has a use for. Each run counts as one run, and the time
+/// it took (parsing its input included) and the size of its rendered outputs
+/// are observed whether or not it failed. Inputs which fail to parse never
+/// reach jq, so they aren't counted.
+///
+/// With the `prometheus` feature, `PrometheusMetrics` reports to a
+/// `prometheus::Registry`.
+///
+/// ```rust
+/// use jq_rs::{Compiler, ErrorKind, Metrics};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Counts {
+///     runs: AtomicUsize,
+///     errors: AtomicUsize,
+/// }
+///
+/// impl Metrics for Counts {
+///     fn count_run(&self) {
+///         self.runs.fetch_add(1, Ordering::Relaxed);
+///     }
+///
+///     fn count_error(&self, _kind: ErrorKind) {
+///         self.errors.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// let counts = Arc::new(Counts::default());
+/// let mut program = Compiler::new()
+///     .metrics(counts.clone())
+///     .compile(".[0]")
+///     .unwrap();
+///
+/// program.run_batch(&["[1]", "{}", "[2]"]);
+/// assert_eq!(counts.runs.load(Ordering::Relaxed), 3);
+/// assert_eq!(counts.errors.load(Ordering::Relaxed), 1);
+/// ```
+pub trait Metrics: Send + Sync {
+    /// A run has finished, whether it succeeded or not.
+    fn count_run(&self) {}
+
+    /// A run has failed. A program which halts with a non-zero exit code
+    /// counts as an `ErrorKind::Halted`.
+    fn count_error(&self, _kind: ErrorKind) {}
+
+    /// How long a run took.
+    fn observe_duration(&self, _duration: Duration) {}
+
+    /// The total size of a run's rendered outputs, in bytes.
+    fn observe_output_bytes(&self, _bytes: usize) {}
+}
+
+impl<M: Metrics + ?Sized> Metrics for Arc<M> {
+    fn count_run(&self) {
+        (**self).count_run()
+    }
+
+    fn count_error(&self, kind: ErrorKind) {
+        (**self).count_error(kind)
+    }
+
+    fn observe_duration(&self, duration: Duration) {
+        (**self).observe_duration(duration)
+    }
+
+    fn observe_output_bytes(&self, bytes: usize) {
+        (**self).observe_output_bytes(bytes)
+    }
+}
+
+/// `Metrics` which go nowhere, used when none have been set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// The `Metrics` a program reports to, shared between all the programs
+/// compiled with the same `Compiler`.
+#[derive(Clone)]
+pub(crate) struct SharedMetrics(pub Arc<dyn Metrics>);
+
+impl Default for SharedMetrics {
+    fn default() -> Self {
+        SharedMetrics(Arc::new(NoopMetrics))
+    }
+}
+
+impl fmt::Debug for SharedMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+/// `Metrics` kept in a `prometheus::Registry`.
+///
+/// These are registered:
+///
+/// - `jq_runs_total`, a counter of runs.
+/// - `jq_errors_total`, a counter of failed runs with a `kind` label naming
+///   the `ErrorKind`.
+/// - `jq_run_duration_seconds`, a histogram of how long runs took.
+/// - `jq_output_bytes`, a histogram of the size of runs' outputs.
+///
+/// Requires the `prometheus` feature.
+///
+/// ```rust
+/// use jq_rs::{Compiler, PrometheusMetrics};
+/// use prometheus::Registry;
+///
+/// let registry = Registry::new();
+/// let metrics = PrometheusMetrics::register(&registry).unwrap();
+/// let mut program = Compiler::new().metrics(metrics).compile(".a").unwrap();
+/// program.run(r#"{"a": 1}"#).unwrap();
+///
+/// let families = registry.gather();
+/// assert!(families.iter().any(|family| family.name() == "jq_runs_total"));
+/// ```
+#[cfg(feature = "prometheus")]
+#[derive(Clone, Debug)]
+pub struct PrometheusMetrics {
+    runs: prometheus::IntCounter,
+    errors: prometheus::IntCounterVec,
+    duration: prometheus::Histogram,
+    output_bytes: prometheus::Histogram,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    /// Create the metrics and register them with `registry`.
+    ///
+    /// Fails if metrics with the same names are already registered there.
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts};
+
+        let runs = IntCounter::new("jq_runs_total", "Runs of jq programs.")?;
+        let errors = IntCounterVec::new(
+            Opts::new("jq_errors_total", "Runs of jq programs which failed."),
+            &["kind"],
+        )?;
+        let duration = Histogram::with_opts(HistogramOpts::new(
+            "jq_run_duration_seconds",
+            "How long runs of jq programs took, parsing the input included.",
+        ))?;
+        let output_bytes = Histogram::with_opts(
+            HistogramOpts::new(
+                "jq_output_bytes",
+                "The size of the rendered outputs of runs of jq programs.",
+            )
+            // From 64 bytes up to 16MiB.
+            .buckets(prometheus::exponential_buckets(64.0, 4.0, 10)?),
+        )?;
+
+        registry.register(Box::new(runs.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(output_bytes.clone()))?;
+        Ok(PrometheusMetrics {
+            runs,
+            errors,
+            duration,
+            output_bytes,
+        })
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Metrics for PrometheusMetrics {
+    fn count_run(&self) {
+        self.runs.inc();
+    }
+
+    fn count_error(&self, kind: ErrorKind) {
+        self.errors
+            .with_label_values(&[format!("{:?}", kind)])
+            .inc();
+    }
+
+    fn observe_duration(&self, duration: Duration) {
+        self.duration.observe(duration.as_secs_f64());
+    }
+
+    fn observe_output_bytes(&self, bytes: usize) {
+        self.output_bytes.observe(bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use crate::{Compiler, ErrorKind};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Recorded {
+        runs: Mutex<usize>,
+        errors: Mutex<Vec<ErrorKind>>,
+        durations: Mutex<Vec<Duration>>,
+        output_bytes: Mutex<Vec<usize>>,
+    }
+
+    impl Metrics for Recorded {
+        fn count_run(&self) {
+            *self.runs.lock().unwrap() += 1;
+        }
+
+        fn count_error(&self, kind: ErrorKind) {
+            self.errors.lock().unwrap().push(kind);
+        }
+
+        fn observe_duration(&self, duration: Duration) {
+            self.durations.lock().unwrap().push(duration);
+        }
+
+        fn observe_output_bytes(&self, bytes: usize) {
+            self.output_bytes.lock().unwrap().push(bytes);
+        }
+    }
+
+    #[test]
+    fn each_run_is_reported() {
+        let recorded = Arc::new(Recorded::default());
+        let compiler = Compiler::new().metrics(recorded.clone());
+        let mut program = compiler.compile(".[]").unwrap();
+        let mut halting = compiler.compile("halt_error(3)").unwrap();
+
+        program.run("[1, 22]").unwrap();
+        program.run("{").unwrap_err();
+        program.run("1").unwrap_err();
+        halting.run("\"bye\"").unwrap_err();
+
+        assert_eq!(*recorded.runs.lock().unwrap(), 3);
+        assert_eq!(
+            *recorded.errors.lock().unwrap(),
+            vec![ErrorKind::Exec, ErrorKind::Halted]
+        );
+        assert_eq!(recorded.durations.lock().unwrap().len(), 3);
+        assert_eq!(*recorded.output_bytes.lock().unwrap(), vec![5, 0, 0]);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus() {
+        use super::PrometheusMetrics;
+        use prometheus::Registry;
+
+        let registry = Registry::new();
+        let metrics = PrometheusMetrics::register(&registry).unwrap();
+        assert!(PrometheusMetrics::register(&registry).is_err());
+
+        let mut program = Compiler::new().metrics(metrics).compile(".a").unwrap();
+        program.run(r#"{"a": 1}"#).unwrap();
+        program.run("[]").unwrap_err();
+
+        let families = registry.gather();
+        let family = |name: &str| {
+            families
+                .iter()
+                .find(|family| family.name() == name)
+                .unwrap()
+        };
+        let runs = family("jq_runs_total");
+        assert_eq!(runs.get_metric()[0].get_counter().get_value(), 2.0);
+        let errors = family("jq_errors_total").get_metric();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].get_label()[0].value(), "Exec");
+        let durations = family("jq_run_duration_seconds").get_metric();
+        assert_eq!(durations[0].get_histogram().get_sample_count(), 2);
+        let output_bytes = family("jq_output_bytes").get_metric();
+        assert_eq!(output_bytes[0].get_histogram().get_sample_sum(), 2.0);
+    }
+}
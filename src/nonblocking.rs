@@ -0,0 +1,251 @@
+//! Async wrappers which move jq work off of the executor's threads.
+//!
+//! jq programs run synchronously and can take a while, so rather than running
+//! them inside a future (and stalling the executor) the work is handed to the
+//! [blocking] thread pool. This pool isn't tied to any particular runtime so
+//! the same futures work with tokio, async-std, smol, etc.
+//!
+//! [blocking]: https://crates.io/crates/blocking
+
+use crate::errors::Result;
+use crate::SyncJqProgram;
+use blocking::Task;
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Run a jq program on a blob of json data without blocking the executor.
+///
+/// This is the async counterpart to `jq_rs::run()`, and like it, the program is
+/// compiled on every call.
+pub async fn run_async(program: &str, data: &str) -> Result<String> {
+    let program = program.to_string();
+    let data = data.to_string();
+    blocking::unblock(move || crate::run(&program, &data)).await
+}
+
+/// A pre-compiled jq program which can be run from async code.
+///
+/// Cloning is cheap and all clones share the same compiled program. Runs are
+/// serialized just like with `SyncJqProgram`.
+///
+/// ```rust
+/// use jq_rs::AsyncJqProgram;
+///
+/// # futures_lite::future::block_on(async {
+/// let program = AsyncJqProgram::compile(".name").await.unwrap();
+/// let output = program.run(r#"{"name": "Mothra"}"#).await.unwrap();
+/// assert_eq!(output, "\"Mothra\"\n");
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct AsyncJqProgram {
+    inner: Arc<SyncJqProgram>,
+}
+
+impl AsyncJqProgram {
+    /// Compile a jq program without blocking the executor.
+    pub async fn compile(program: &str) -> Result<Self> {
+        let program = program.to_string();
+        blocking::unblock(move || SyncJqProgram::compile(&program))
+            .await
+            .map(Self::from)
+    }
+
+    /// Runs a json string input against the pre-compiled jq program.
+    pub async fn run(&self, data: &str) -> Result<String> {
+        let inner = Arc::clone(&self.inner);
+        let data = data.to_string();
+        blocking::unblock(move || inner.run(&data)).await
+    }
+
+    /// Start running the program on `data` in the background.
+    fn spawn<D>(&self, data: D) -> Task<Result<String>>
+    where
+        D: AsRef<[u8]> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        blocking::unblock(move || inner.run(std::str::from_utf8(data.as_ref())?))
+    }
+}
+
+impl From<SyncJqProgram> for AsyncJqProgram {
+    fn from(program: SyncJqProgram) -> Self {
+        AsyncJqProgram {
+            inner: Arc::new(program),
+        }
+    }
+}
+
+impl From<Arc<SyncJqProgram>> for AsyncJqProgram {
+    fn from(inner: Arc<SyncJqProgram>) -> Self {
+        AsyncJqProgram { inner }
+    }
+}
+
+/// Adds `jq_filter()` to streams of json inputs, such as messages from a
+/// Kafka or NATS consumer.
+///
+/// This is for `futures_core::Stream`, the trait the `futures` and
+/// `tokio-stream` crates use, and is implemented for all of them.
+///
+/// ```rust
+/// use futures_lite::stream::{self, StreamExt};
+/// use jq_rs::{AsyncJqProgram, JqStreamExt};
+///
+/// # futures_lite::future::block_on(async {
+/// let program = AsyncJqProgram::compile(".user.id").await.unwrap();
+/// let messages = stream::iter(vec![r#"{"user": {"id": 1}}"#, r#"{"user": {"id": 2}}"#]);
+///
+/// let ids: Vec<_> = messages.jq_filter(program).collect().await;
+/// assert_eq!(ids[0].as_ref().unwrap(), "1\n");
+/// assert_eq!(ids[1].as_ref().unwrap(), "2\n");
+/// # });
+/// ```
+pub trait JqStreamExt: Stream + Sized {
+    /// Run `program` over each item of the stream, yielding the output for
+    /// each one.
+    ///
+    /// Items can be anything holding json as bytes, like `String`, `Vec<u8>`
+    /// or `bytes::Bytes`. The output for an item is the same as
+    /// `AsyncJqProgram::run()` gives, so a program which fails on one item
+    /// (or an item which isn't valid UTF-8) yields an `Err` for it, and the
+    /// stream carries on with the next.
+    ///
+    /// Items are taken from the stream one at a time: the next isn't polled
+    /// for until the output for the last has been yielded, so a slow consumer
+    /// holds up the stream feeding it rather than letting work pile up.
+    fn jq_filter(self, program: AsyncJqProgram) -> JqFilter<Self>
+    where
+        Self::Item: AsRef<[u8]> + Send + 'static,
+    {
+        JqFilter {
+            stream: self,
+            program,
+            running: None,
+        }
+    }
+}
+
+impl<S: Stream> JqStreamExt for S {}
+
+/// The stream returned by `JqStreamExt::jq_filter()`.
+pub struct JqFilter<S> {
+    stream: S,
+    program: AsyncJqProgram,
+    /// The run for the item which was last taken from the stream, until its
+    /// output has been yielded.
+    running: Option<Task<Result<String>>>,
+}
+
+impl<S> Stream for JqFilter<S>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]> + Send + 'static,
+{
+    type Item = Result<String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<String>>> {
+        // Only `stream` is structurally pinned. It's never moved out of, and
+        // the other fields are `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        loop {
+            if let Some(running) = this.running.as_mut() {
+                let output = match Pin::new(running).poll(cx) {
+                    Poll::Ready(output) => output,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.running = None;
+                return Poll::Ready(Some(output));
+            }
+            match stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => this.running = Some(this.program.spawn(item)),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let running = self.running.is_some() as usize;
+        let (lower, upper) = self.stream.size_hint();
+        (
+            lower.saturating_add(running),
+            upper.and_then(|upper| upper.checked_add(running)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_async, AsyncJqProgram, JqStreamExt};
+    use crate::Error;
+    use futures_core::Stream;
+    use futures_lite::future::block_on;
+    use futures_lite::stream::{self, StreamExt};
+    use matches::assert_matches;
+
+    #[test]
+    fn one_off() {
+        let res = block_on(run_async(".[]", "[1,2]"));
+        assert_eq!(res.unwrap(), "1\n2\n");
+    }
+
+    #[test]
+    fn compile_error() {
+        let res = block_on(AsyncJqProgram::compile(". aa12312me  dsaafsdfsd"));
+        assert_matches!(res.err(), Some(Error::InvalidProgram { .. }));
+    }
+
+    #[test]
+    fn clones_share_a_program() {
+        block_on(async {
+            let program = AsyncJqProgram::compile(". * 10").await.unwrap();
+            let other = program.clone();
+            assert_eq!(program.run("1").await.unwrap(), "10\n");
+            assert_eq!(other.run("2").await.unwrap(), "20\n");
+        });
+    }
+
+    #[test]
+    fn filters_a_stream() {
+        block_on(async {
+            let program = AsyncJqProgram::compile(".a").await.unwrap();
+            let inputs = vec![
+                b"{\"a\": 1}".to_vec(),
+                b"[]".to_vec(),
+                vec![0xff],
+                b"{\"a\": [2]}".to_vec(),
+            ];
+            let filtered = stream::iter(inputs).jq_filter(program);
+            assert_eq!(filtered.size_hint(), (4, Some(4)));
+
+            let outputs: Vec<_> = filtered.collect().await;
+            assert_eq!(outputs.len(), 4);
+            assert_eq!(outputs[0].as_ref().unwrap(), "1\n");
+            assert_matches!(outputs[1], Err(Error::Exec { .. }));
+            assert_matches!(outputs[2], Err(Error::StringConvert { .. }));
+            assert_eq!(outputs[3].as_ref().unwrap(), "[2]\n");
+        });
+    }
+
+    #[test]
+    fn pulls_one_item_at_a_time() {
+        block_on(async {
+            let program = AsyncJqProgram::compile(".").await.unwrap();
+            let mut pulled = 0;
+            let inputs = stream::iter(1..=3).map(|n| {
+                pulled += 1;
+                n.to_string()
+            });
+            let mut filtered = inputs.jq_filter(program);
+            assert_eq!(filtered.next().await.unwrap().unwrap(), "1\n");
+            drop(filtered.next().await);
+            drop(filtered);
+            assert_eq!(pulled, 2);
+        });
+    }
+}
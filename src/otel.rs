@@ -0,0 +1,162 @@
+//! OpenTelemetry spans for compiling and running programs, with the `otel`
+//! feature.
+//!
+//! Spans go to the tracer provider installed with
+//! `opentelemetry::global::set_tracer_provider()`, under the `jq-rs`
+//! instrumentation scope, as children of the current context. They're
+//! recorded once the work is done, so their start times are worked back from
+//! how long it took.
+
+use crate::cache::program_hash;
+use crate::errors::{Error, Result};
+use crate::jq::Completion;
+use crate::RunStats;
+use opentelemetry::trace::{Span, SpanKind, Status, Tracer};
+use opentelemetry::{global, InstrumentationScope, KeyValue};
+use std::time::{Duration, SystemTime};
+
+/// Record a `jq.compile` span for compiling `program`, which started at
+/// `started`.
+pub(crate) fn record_compile<T>(program: &str, started: SystemTime, result: &Result<T>) {
+    let ended = SystemTime::now();
+    let mut attributes = vec![KeyValue::new("jq.program.hash", program_hash(program))];
+    let outcome = match result {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+    attributes.push(KeyValue::new("jq.outcome", outcome));
+    let duration = ended.duration_since(started).unwrap_or_default();
+    attributes.push(seconds("jq.compile.duration", duration));
+    record(
+        "jq.compile",
+        started,
+        ended,
+        attributes,
+        result.as_ref().err(),
+    );
+}
+
+/// Record a `jq.run` span for a run of the program with the given hash.
+pub(crate) fn record_run(program_hash: &str, stats: &RunStats, result: &Result<Completion>) {
+    let ended = SystemTime::now();
+    let started = ended
+        .checked_sub(stats.parse_time + stats.eval_time)
+        .unwrap_or(ended);
+    record(
+        "jq.run",
+        started,
+        ended,
+        run_attributes(program_hash, stats, result),
+        result.as_ref().err(),
+    );
+}
+
+fn run_attributes(
+    program_hash: &str,
+    stats: &RunStats,
+    result: &Result<Completion>,
+) -> Vec<KeyValue> {
+    let mut attributes = vec![KeyValue::new("jq.program.hash", program_hash.to_string())];
+    match result {
+        Ok(Completion::Finished) => attributes.push(KeyValue::new("jq.outcome", "ok")),
+        Ok(Completion::Halted { exit_code, .. }) => {
+            attributes.push(KeyValue::new("jq.outcome", "halted"));
+            attributes.push(KeyValue::new("jq.exit_code", i64::from(*exit_code)));
+        }
+        Err(_) => attributes.push(KeyValue::new("jq.outcome", "error")),
+    }
+    attributes.push(seconds("jq.run.parse.duration", stats.parse_time));
+    attributes.push(seconds("jq.run.eval.duration", stats.eval_time));
+    attributes.push(KeyValue::new("jq.run.outputs", stats.outputs as i64));
+    attributes.push(KeyValue::new(
+        "jq.run.output_bytes",
+        stats.output_bytes as i64,
+    ));
+    attributes
+}
+
+/// A duration in seconds, which is the unit OpenTelemetry's conventions
+/// use for them.
+fn seconds(key: &'static str, duration: Duration) -> KeyValue {
+    KeyValue::new(key, duration.as_secs_f64())
+}
+
+fn record(
+    name: &'static str,
+    started: SystemTime,
+    ended: SystemTime,
+    mut attributes: Vec<KeyValue>,
+    error: Option<&Error>,
+) {
+    let scope = InstrumentationScope::builder("jq-rs")
+        .with_version(env!("CARGO_PKG_VERSION"))
+        .build();
+    let tracer = global::tracer_with_scope(scope);
+    if let Some(error) = error {
+        attributes.push(KeyValue::new("error.type", format!("{:?}", error.kind())));
+    }
+    let mut span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Internal)
+        .with_start_time(started)
+        .with_attributes(attributes)
+        .start(&tracer);
+    if let Some(error) = error {
+        span.set_status(Status::error(error.to_string()));
+    }
+    span.end_with_timestamp(ended);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{program_hash, run_attributes};
+    use crate::errors::Error;
+    use crate::jq::Completion;
+    use crate::RunStats;
+    use opentelemetry::{Key, Value};
+    use std::time::Duration;
+
+    fn get(attributes: &[opentelemetry::KeyValue], key: &'static str) -> Option<Value> {
+        attributes
+            .iter()
+            .find(|kv| kv.key == Key::from_static_str(key))
+            .map(|kv| kv.value.clone())
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        assert_eq!(program_hash(""), "cbf29ce484222325");
+        assert_eq!(program_hash(".a"), program_hash(".a"));
+        assert_ne!(program_hash(".a"), program_hash(".b"));
+    }
+
+    #[test]
+    fn run_outcomes() {
+        let stats = RunStats {
+            parse_time: Duration::from_millis(250),
+            eval_time: Duration::from_millis(500),
+            outputs: 2,
+            output_bytes: 10,
+        };
+
+        let attributes = run_attributes("abc", &stats, &Ok(Completion::Finished));
+        assert_eq!(get(&attributes, "jq.program.hash"), Some("abc".into()));
+        assert_eq!(get(&attributes, "jq.outcome"), Some("ok".into()));
+        assert_eq!(get(&attributes, "jq.run.parse.duration"), Some(0.25.into()));
+        assert_eq!(get(&attributes, "jq.run.eval.duration"), Some(0.5.into()));
+        assert_eq!(get(&attributes, "jq.run.outputs"), Some(2.into()));
+        assert_eq!(get(&attributes, "jq.run.output_bytes"), Some(10.into()));
+        assert_eq!(get(&attributes, "jq.exit_code"), None);
+
+        let halted = Completion::Halted {
+            exit_code: 5,
+            payload: None,
+        };
+        let attributes = run_attributes("abc", &stats, &Ok(halted));
+        assert_eq!(get(&attributes, "jq.outcome"), Some("halted".into()));
+        assert_eq!(get(&attributes, "jq.exit_code"), Some(5.into()));
+
+        let attributes = run_attributes("abc", &stats, &Err(Error::Interrupted));
+        assert_eq!(get(&attributes, "jq.outcome"), Some("error".into()));
+    }
+}
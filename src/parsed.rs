@@ -0,0 +1,79 @@
+use crate::errors::Result;
+use crate::jq::{Parser, JV};
+
+/// A json document which has already been parsed by jq.
+///
+/// Parsing the input is part of the cost of every run, so when one document
+/// needs to go through several different programs, parse it once with
+/// `jq_rs::parse()` and hand it to `JqProgram::run_parsed()` for each of them.
+///
+/// ```rust
+/// let doc = jq_rs::parse(r#"{"name": "Gamera", "year": 1965}"#).unwrap();
+///
+/// let mut name = jq_rs::compile(".name").unwrap();
+/// let mut year = jq_rs::compile(".year").unwrap();
+///
+/// assert_eq!(name.run_parsed(&doc).unwrap(), "\"Gamera\"\n");
+/// assert_eq!(year.run_parsed(&doc).unwrap(), "1965\n");
+/// ```
+///
+/// Cloning is cheap since the parsed value is reference counted. Those counts
+/// aren't atomic, which is why a `ParsedJson` can't be sent to other threads.
+#[derive(Clone)]
+pub struct ParsedJson {
+    /// `None` for an empty (or all whitespace) document.
+    pub(crate) value: Option<JV>,
+}
+
+/// Parse a blob of json data so it can be run through several programs.
+///
+/// As with `jq_rs::run()`, only the first value in the data is used. An empty
+/// input parses successfully, and programs run against it produce no output.
+pub fn parse(data: &str) -> Result<ParsedJson> {
+    let value = if data.trim().is_empty() {
+        None
+    } else {
+        Some(Parser::new().parse(data)?)
+    };
+    Ok(ParsedJson { value })
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use crate::{compile, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn run_many_programs() {
+        let doc = parse(r#"{"a": 1, "b": [2, 3]}"#).unwrap();
+        let mut a = compile(".a").unwrap();
+        let mut b = compile(".b[]").unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(a.run_parsed(&doc).unwrap(), "1\n");
+            assert_eq!(b.run_parsed(&doc).unwrap(), "2\n3\n");
+        }
+    }
+
+    #[test]
+    fn empty_document() {
+        let doc = parse("  \n").unwrap();
+        assert_eq!(compile(".").unwrap().run_parsed(&doc).unwrap(), "");
+    }
+
+    #[test]
+    fn parse_error() {
+        assert_matches!(parse("{").err(), Some(Error::Parse { .. }));
+    }
+
+    #[test]
+    fn runtime_error_leaves_the_document_usable() {
+        let doc = parse("[1]").unwrap();
+        assert_matches!(
+            compile(".a").unwrap().run_parsed(&doc),
+            Err(Error::Exec { .. })
+        );
+        assert_eq!(compile(".[0]").unwrap().run_parsed(&doc).unwrap(), "1\n");
+    }
+}
@@ -0,0 +1,375 @@
+use crate::errors::{Error, Result};
+use crate::jq::Parser;
+use crate::literal::escape_string;
+use crate::query::Query;
+use std::fmt;
+
+/// One step along a `JqPath`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    /// A field of an object.
+    Key(String),
+    /// An item of an array. Negative indexes count back from the end.
+    Index(i64),
+}
+
+/// A path to a value inside a json document, for converting between the
+/// ways of writing one down:
+///
+/// - JSON Pointers ([RFC 6901]), such as `/kaiju/0/name`
+/// - jq path expressions, such as `.kaiju[0].name`
+/// - the arrays jq's `path()`, `getpath()` and friends use, such as
+///   `["kaiju",0,"name"]`
+///
+/// ```rust
+/// use jq_rs::JqPath;
+///
+/// let path = JqPath::from_pointer("/kaiju/0/first name").unwrap();
+/// assert_eq!(path.to_expression(), r#".kaiju[0]."first name""#);
+/// assert_eq!(path.to_array(), r#"["kaiju",0,"first name"]"#);
+///
+/// let path = JqPath::parse(r#".a["b/c"][1]"#).unwrap();
+/// assert_eq!(path.to_pointer().unwrap(), "/a/b~1c/1");
+/// ```
+///
+/// JSON Pointers don't say whether a segment like `0` is an array index or an
+/// object key, so segments made up of digits (without leading zeros) are
+/// taken to be indexes. Going the other way, a key made up of digits comes
+/// out looking like an index.
+///
+/// [RFC 6901]: https://tools.ietf.org/html/rfc6901
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct JqPath {
+    segments: Vec<PathSegment>,
+}
+
+impl JqPath {
+    /// A path made up of `segments`, in order. With none, the path is to the
+    /// whole document.
+    pub fn new(segments: Vec<PathSegment>) -> Self {
+        JqPath { segments }
+    }
+
+    /// The steps along the path.
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    /// Read a JSON Pointer, such as `/a/0/b`. The empty pointer is the whole
+    /// document.
+    pub fn from_pointer(pointer: &str) -> Result<Self> {
+        if pointer.is_empty() {
+            return Ok(JqPath::default());
+        }
+        if !pointer.starts_with('/') {
+            return Err(invalid("JSON Pointers start with `/`"));
+        }
+        let segments = pointer[1..]
+            .split('/')
+            .map(|token| {
+                if let Some(index) = array_index(token) {
+                    return Ok(PathSegment::Index(index));
+                }
+                let mut key = String::with_capacity(token.len());
+                let mut chars = token.chars();
+                while let Some(c) = chars.next() {
+                    match (c, c == '~') {
+                        (_, false) => key.push(c),
+                        (_, true) => match chars.next() {
+                            Some('0') => key.push('~'),
+                            Some('1') => key.push('/'),
+                            _ => {
+                                return Err(invalid(
+                                    "`~` in a JSON Pointer must be followed by `0` or `1`",
+                                ))
+                            }
+                        },
+                    }
+                }
+                Ok(PathSegment::Key(key))
+            })
+            .collect::<Result<_>>()?;
+        Ok(JqPath { segments })
+    }
+
+    /// Write the path as a JSON Pointer.
+    ///
+    /// Fails for negative indexes, which pointers have no way to express.
+    pub fn to_pointer(&self) -> Result<String> {
+        let mut out = String::new();
+        for segment in &self.segments {
+            out.push('/');
+            match segment {
+                PathSegment::Key(key) => out.push_str(&key.replace('~', "~0").replace('/', "~1")),
+                PathSegment::Index(index) if *index >= 0 => out.push_str(&index.to_string()),
+                PathSegment::Index(index) => {
+                    return Err(invalid(&format!(
+                        "JSON Pointers can't count back from the end of an array, as `{}` does",
+                        index
+                    )))
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Read a jq path expression made up of fields and indexes, such as
+    /// `.a[0]."b c"` or `.["a"][-1]`. Anything more involved, such as `.[]`,
+    /// slices or function calls, is rejected.
+    pub fn parse(expression: &str) -> Result<Self> {
+        let mut rest = expression.trim();
+        if !rest.starts_with('.') {
+            return Err(invalid("Path expressions start with `.`"));
+        }
+        if rest == "." {
+            return Ok(JqPath::default());
+        }
+
+        let mut segments = vec![];
+        while !rest.is_empty() {
+            let dot = rest.starts_with('.');
+            if dot {
+                rest = &rest[1..];
+            }
+            // jq 1.6 only allows `.[` at the start, as in `.[0]`, rather than
+            // after a field, as in `.a.[0]`.
+            if rest.starts_with('[') && (!dot || segments.is_empty()) {
+                let (segment, after) = bracket(&rest[1..])?;
+                segments.push(segment);
+                rest = after;
+            } else if dot && rest.starts_with('"') {
+                let (key, after) = string(rest)?;
+                segments.push(PathSegment::Key(key));
+                rest = after;
+            } else if dot {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let name = &rest[..len];
+                if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+                    return Err(invalid(&format!("Unexpected {:?} in the path", rest)));
+                }
+                segments.push(PathSegment::Key(name.to_string()));
+                rest = &rest[len..];
+            } else {
+                return Err(invalid(&format!("Unexpected {:?} in the path", rest)));
+            }
+            rest = rest.trim_start();
+        }
+        Ok(JqPath { segments })
+    }
+
+    /// Write the path as a jq path expression, quoting keys which aren't
+    /// plain identifiers.
+    pub fn to_expression(&self) -> String {
+        self.segments
+            .iter()
+            .fold(Query::identity(), |query, segment| match segment {
+                PathSegment::Key(key) => query.then_field(key),
+                PathSegment::Index(index) => query.index(*index),
+            })
+            .build()
+    }
+
+    /// Read a path in the form jq's `path()` gives, a json array of keys and
+    /// indexes such as `["a",0,"b"]`.
+    pub fn from_array(json: &str) -> Result<Self> {
+        let value = Parser::new().parse(json)?;
+        let items = value
+            .array_items()
+            .ok_or_else(|| invalid("Path arrays have to be arrays"))?;
+        let segments = items
+            .iter()
+            .map(|item| match item.as_number() {
+                Some(n) if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 => {
+                    Ok(PathSegment::Index(n as i64))
+                }
+                Some(n) => Err(invalid(&format!("{} isn't an array index", n))),
+                None => item
+                    .as_string()
+                    .map(PathSegment::Key)
+                    .map_err(|_| invalid("Path arrays can only hold strings and numbers")),
+            })
+            .collect::<Result<_>>()?;
+        Ok(JqPath { segments })
+    }
+
+    /// Write the path as a json array, the form jq's `path()` gives.
+    pub fn to_array(&self) -> String {
+        let items: Vec<String> = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                PathSegment::Key(key) => escape_string(key),
+                PathSegment::Index(index) => index.to_string(),
+            })
+            .collect();
+        format!("[{}]", items.join(","))
+    }
+}
+
+impl From<Vec<PathSegment>> for JqPath {
+    fn from(segments: Vec<PathSegment>) -> Self {
+        JqPath::new(segments)
+    }
+}
+
+/// Written as a jq path expression.
+impl fmt::Display for JqPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_expression())
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidPath {
+        reason: reason.to_string(),
+    }
+}
+
+/// The index a JSON Pointer segment stands for, following the `array-index`
+/// rule of RFC 6901.
+fn array_index(token: &str) -> Option<i64> {
+    let digits = !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit());
+    if digits && (token == "0" || !token.starts_with('0')) {
+        token.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Read the contents of `[...]`, up to and including the `]`.
+fn bracket(rest: &str) -> Result<(PathSegment, &str)> {
+    let rest = rest.trim_start();
+    let (segment, rest) = if rest.starts_with('"') {
+        let (key, after) = string(rest)?;
+        (PathSegment::Key(key), after)
+    } else {
+        let len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+            .unwrap_or(rest.len());
+        let index = rest[..len]
+            .parse()
+            .map_err(|_| invalid(&format!("Unexpected {:?} in the path", rest)))?;
+        (PathSegment::Index(index), &rest[len..])
+    };
+    let rest = rest.trim_start();
+    if !rest.starts_with(']') {
+        return Err(invalid("Expected `]` in the path"));
+    }
+    Ok((segment, &rest[1..]))
+}
+
+/// Read a quoted string from the start of `rest`, giving back its contents and
+/// what comes after it.
+fn string(rest: &str) -> Result<(String, &str)> {
+    let mut escaped = false;
+    for (idx, c) in rest.char_indices().skip(1) {
+        match c {
+            '(' if escaped => return Err(invalid("Paths can't use string interpolation")),
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => {
+                // Apart from interpolation, jq strings are written the same
+                // way as json strings.
+                let key = Parser::new()
+                    .parse(&rest[..=idx])
+                    .and_then(|value| value.as_string())
+                    .map_err(|err| invalid(&format!("Invalid string in the path: {}", err)))?;
+                return Ok((key, &rest[idx + 1..]));
+            }
+            _ => {}
+        }
+    }
+    Err(invalid("Unterminated string in the path"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{JqPath, PathSegment};
+    use crate::compile;
+
+    #[test]
+    fn pointers() {
+        // The examples from RFC 6901.
+        for (pointer, expected) in &[
+            ("", "[]"),
+            ("/foo", r#"["foo"]"#),
+            ("/foo/0", r#"["foo",0]"#),
+            ("/", r#"[""]"#),
+            ("/a~1b", r#"["a/b"]"#),
+            ("/c%d", r#"["c%d"]"#),
+            ("/ ", r#"[" "]"#),
+            ("/m~0n", r#"["m~n"]"#),
+            ("/~01", r#"["~1"]"#),
+            ("/01/-", r#"["01","-"]"#),
+        ] {
+            let path = JqPath::from_pointer(pointer).unwrap();
+            assert_eq!(path.to_array(), *expected, "pointer: {:?}", pointer);
+            assert_eq!(path.to_pointer().unwrap(), *pointer);
+        }
+
+        for pointer in &["a", "/~", "/~2"] {
+            let res = JqPath::from_pointer(pointer);
+            assert!(res.unwrap_err().is_invalid_path(), "pointer: {:?}", pointer);
+        }
+        let from_end = JqPath::new(vec![PathSegment::Index(-1)]);
+        assert!(from_end.to_pointer().unwrap_err().is_invalid_path());
+    }
+
+    #[test]
+    fn expressions_match_jq() {
+        for expression in &[
+            ".",
+            ".a",
+            ".a.b_2[0]",
+            r#"."a b"[-1]"#,
+            r#".["x\"é"][3]."y""#,
+            ".[0].a",
+            " .a [ 1 ] ",
+        ] {
+            let path = JqPath::parse(expression).unwrap();
+            let from_jq = compile(&format!("path({}) | tojson", expression))
+                .unwrap()
+                .run("null")
+                .unwrap();
+            let array: String = serde_json::from_str(&from_jq).unwrap();
+            assert_eq!(path.to_array(), array, "expression: {:?}", expression);
+            assert_eq!(JqPath::from_array(&array).unwrap(), path);
+            assert_eq!(JqPath::parse(&path.to_expression()).unwrap(), path);
+        }
+
+        for expression in &[
+            "",
+            "a",
+            ".a[]",
+            ".a[1:2]",
+            ".a | .b",
+            ".1",
+            r#"."\(env)""#,
+            ".a[0",
+            ".a..b",
+            ".a.[0]",
+        ] {
+            let res = JqPath::parse(expression);
+            assert!(
+                res.unwrap_err().is_invalid_path(),
+                "expression: {:?}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn arrays() {
+        let path = JqPath::from_array(r#"["a", 0, "b~/"]"#).unwrap();
+        assert_eq!(path.to_expression(), r#".a[0]."b~/""#);
+        assert_eq!(path.to_pointer().unwrap(), "/a/0/b~0~1");
+        assert_eq!(path.to_string(), path.to_expression());
+
+        for array in &["{}", "[null]", "[1.5]", r#"[{"start": 1}]"#] {
+            assert!(JqPath::from_array(array).unwrap_err().is_invalid_path());
+        }
+        assert!(JqPath::from_array("[").unwrap_err().is_parse_error());
+    }
+}
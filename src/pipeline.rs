@@ -0,0 +1,185 @@
+use crate::cancel::Interrupt;
+use crate::errors::Result;
+use crate::jq::{Completion, Format, Parser, JV};
+use crate::{JqProgram, ParsedJson};
+
+/// Several pre-compiled jq programs chained together, like `a | b | c`.
+///
+/// Each output of a stage is fed straight into the next stage as a jq value,
+/// so only the outputs of the final stage are rendered as text.
+///
+/// ```rust
+/// let select = jq_rs::compile(".kaiju[] | select(.year < 1960)").unwrap();
+/// let name = jq_rs::compile(".name").unwrap();
+/// let mut pipeline = select.then(name);
+///
+/// let data = r#"{"kaiju": [
+///     {"name": "Godzilla", "year": 1954},
+///     {"name": "Mothra", "year": 1961},
+///     {"name": "Rodan", "year": 1956}
+/// ]}"#;
+///
+/// assert_eq!(pipeline.run(data).unwrap(), "\"Godzilla\"\n\"Rodan\"\n");
+/// ```
+pub struct JqPipeline {
+    stages: Vec<JqProgram>,
+}
+
+impl JqPipeline {
+    /// Chain the programs together, in order.
+    ///
+    /// A pipeline with no stages passes its input through unchanged, like `.`.
+    pub fn new(stages: Vec<JqProgram>) -> Self {
+        JqPipeline { stages }
+    }
+
+    /// Add another stage to the end of the pipeline.
+    pub fn then(mut self, next: JqProgram) -> Self {
+        self.stages.push(next);
+        self
+    }
+
+    /// Runs a json string input through every stage of the pipeline.
+    pub fn run(&mut self, data: &str) -> Result<String> {
+        if let Some(first) = self.stages.first() {
+            first.jq.check_input_bytes(data.len())?;
+        }
+        if data.trim().is_empty() {
+            return Ok(String::new());
+        }
+        let value = Parser::new().parse(data)?;
+        self.run_value(value)
+    }
+
+    /// Runs an already parsed json document through every stage of the
+    /// pipeline.
+    pub fn run_parsed(&mut self, data: &ParsedJson) -> Result<String> {
+        match &data.value {
+            Some(value) => self.run_value(value.clone()),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Give back the programs making up the pipeline.
+    pub fn into_stages(self) -> Vec<JqProgram> {
+        self.stages
+    }
+
+    fn run_value(&mut self, value: JV) -> Result<String> {
+        let mut buf = String::new();
+        // Outputs are rendered the way the final stage would render them.
+        let format = self
+            .stages
+            .last()
+            .map_or_else(Format::default, |last| last.jq.format());
+        let (_, completion) = run_stages(
+            &mut self.stages,
+            value,
+            &Interrupt::default(),
+            &mut |output| format.render(&output, &mut buf),
+        )?;
+        completion.into_result()?;
+        Ok(buf)
+    }
+}
+
+impl From<JqProgram> for JqPipeline {
+    fn from(program: JqProgram) -> Self {
+        JqPipeline::new(vec![program])
+    }
+}
+
+/// Run the value through the first stage, sending each of its outputs through
+/// the rest. Gives back the number of bytes rendered by the final stage, and
+/// how the stages finished.
+///
+/// Like `a | b` in jq, a halt in any stage stops the whole pipeline, whatever
+/// its exit code, so the stages before it are halted the same way.
+fn run_stages(
+    stages: &mut [JqProgram],
+    value: JV,
+    interrupt: &Interrupt,
+    emit: &mut dyn FnMut(JV) -> Result<usize>,
+) -> Result<(usize, Completion)> {
+    match stages.split_first_mut() {
+        Some((first, rest)) => {
+            let handle = first.jq.interrupt_handle();
+            let completion = first.jq.start(value, interrupt, |output| {
+                let (bytes, completion) = run_stages(rest, output, interrupt, emit)?;
+                if let Completion::Halted { exit_code, payload } = completion {
+                    handle.halt(exit_code, payload.as_deref());
+                }
+                Ok(bytes)
+            })?;
+            Ok((first.jq.stats().output_bytes, completion))
+        }
+        None => Ok((emit(value)?, Completion::Finished)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::JqPipeline;
+    use crate::{compile, parse, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn matches_a_single_program() {
+        let data = r#"{"a": [{"b": [1, 2]}, {"b": [3]}]}"#;
+        let mut pipeline = JqPipeline::new(vec![
+            compile(".a[]").unwrap(),
+            compile(".b[]").unwrap(),
+            compile(". * 10, . + 1").unwrap(),
+        ]);
+        let expected = compile(".a[] | .b[] | (. * 10, . + 1)")
+            .unwrap()
+            .run(data)
+            .unwrap();
+
+        assert_eq!(pipeline.run(data).unwrap(), expected);
+        // Again, to make sure nothing was left behind by the first run.
+        assert_eq!(pipeline.run(data).unwrap(), expected);
+    }
+
+    #[test]
+    fn no_stages() {
+        let mut pipeline = JqPipeline::new(vec![]);
+        assert_eq!(pipeline.run(r#"{"a":1}"#).unwrap(), "{\"a\":1}\n");
+        assert_eq!(pipeline.run("").unwrap(), "");
+    }
+
+    #[test]
+    fn error_in_a_later_stage() {
+        let mut pipeline = compile(".[]").unwrap().then(compile(".a").unwrap());
+        assert_matches!(pipeline.run(r#"[{"a": 1}, 2]"#), Err(Error::Exec { .. }));
+        assert_eq!(pipeline.run(r#"[{"a": 1}]"#).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn halt_in_a_later_stage() {
+        // Same as `.[] | if . == 2 then halt else . end | . * 10`.
+        let mut pipeline = compile(".[]")
+            .unwrap()
+            .then(compile("if . == 2 then halt else . end").unwrap())
+            .then(compile(". * 10").unwrap());
+        assert_eq!(pipeline.run("[1, 2, 3]").unwrap(), "10\n");
+
+        let mut pipeline = compile(".[]")
+            .unwrap()
+            .then(compile(r#"if . == 2 then "stop" | halt_error(3) else . end"#).unwrap());
+        assert_matches!(
+            pipeline.run("[1, 2, 3]"),
+            Err(Error::Halted { exit_code: 3, payload: Some(ref payload) }) if payload == "stop"
+        );
+        // Each stage can run again afterwards.
+        assert_eq!(pipeline.run("[1, 3]").unwrap(), "1\n3\n");
+    }
+
+    #[test]
+    fn parsed_input() {
+        let doc = parse("[1, 2]").unwrap();
+        let mut pipeline = compile(".[]").unwrap().then(compile(". + 1").unwrap());
+        assert_eq!(pipeline.run_parsed(&doc).unwrap(), "2\n3\n");
+        assert_eq!(pipeline.run_parsed(&doc).unwrap(), "2\n3\n");
+    }
+}
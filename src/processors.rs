@@ -0,0 +1,305 @@
+use crate::errors::Result;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// Glue applied to each raw input before it's parsed, such as stripping a
+/// syslog prefix or unwrapping a base64 envelope. Set with
+/// `Compiler::preprocessor()`.
+///
+/// It's handed each input given to `JqProgram::run()` and the other methods
+/// which parse a single input, each line for `JqProgram::run_lines()` and
+/// `jq_rs::jsonl`, and each input of a batch. The streaming functions in
+/// `jq_rs::stream` don't know where one input ends and the next begins, so
+/// it isn't used for them. Input size limits are checked before it runs,
+/// and what it gives back has to be valid UTF-8.
+///
+/// An error fails the run for that input the same way a parse error would.
+///
+/// ```rust
+/// use jq_rs::{Compiler, PreProcessor};
+/// use std::borrow::Cow;
+///
+/// /// Drops everything up to the first `{`, like the `<13>Oct 16 12:00:00 host app: `
+/// /// syslog puts in front of each record.
+/// struct SyslogPrefix;
+///
+/// impl PreProcessor for SyslogPrefix {
+///     fn process<'a>(&self, input: &'a [u8]) -> jq_rs::Result<Cow<'a, [u8]>> {
+///         let start = input.iter().position(|&b| b == b'{').unwrap_or(0);
+///         Ok(Cow::Borrowed(&input[start..]))
+///     }
+/// }
+///
+/// let mut program = Compiler::new()
+///     .preprocessor(SyslogPrefix)
+///     .compile(".level")
+///     .unwrap();
+/// let record = r#"<13>Oct 16 12:00:00 host app: {"level": "warn"}"#;
+/// assert_eq!(program.run(record).unwrap(), "\"warn\"\n");
+/// ```
+pub trait PreProcessor: Send + Sync {
+    /// Transform one raw input, borrowing from it when the result is a
+    /// part of it (or all of it).
+    fn process<'a>(&self, input: &'a [u8]) -> Result<Cow<'a, [u8]>>;
+}
+
+impl<P: PreProcessor + ?Sized> PreProcessor for Arc<P> {
+    fn process<'a>(&self, input: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        (**self).process(input)
+    }
+}
+
+/// The `PreProcessor` a program's inputs go through, shared between all the
+/// programs compiled with the same `Compiler`.
+#[derive(Clone)]
+pub(crate) struct SharedPreProcessor(pub Arc<dyn PreProcessor>);
+
+impl SharedPreProcessor {
+    /// Run `input` through the pre-processor, as text for the parser.
+    pub fn apply<'a>(&self, input: &'a str) -> Result<Cow<'a, str>> {
+        Ok(match self.0.process(input.as_bytes())? {
+            Cow::Borrowed(bytes) => Cow::Borrowed(std::str::from_utf8(bytes)?),
+            Cow::Owned(bytes) => {
+                Cow::Owned(String::from_utf8(bytes).map_err(|err| err.utf8_error())?)
+            }
+        })
+    }
+}
+
+impl fmt::Debug for SharedPreProcessor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PreProcessor")
+    }
+}
+
+/// Applied to each output once it's been rendered as text, before it's added
+/// to the results, such as appending a record separator, wrapping it in an
+/// envelope or redacting parts of it. Set with `Compiler::postprocessor()`.
+///
+/// It's used wherever outputs are rendered as text: `JqProgram::run()` and
+/// the methods like it, `JqProgram::run_collect()`, `jq_rs::stream`, and
+/// pipelines (with the final stage's). The output it's handed is rendered
+/// with the program's options, so it ends with a newline unless
+/// `Compiler::join_output()` is on, which is handy for a separator of your
+/// own. `run_collect()` leaves the newline out regardless. Outputs turned into
+/// values, like with `JqProgram::run_values()`, aren't rendered, so it isn't
+/// used for them.
+///
+/// An error fails the run, the same as an error raised by the program.
+///
+/// ```rust
+/// use jq_rs::{Compiler, PostProcessor};
+/// use std::borrow::Cow;
+///
+/// /// Ends each record with an ASCII record separator.
+/// struct RecordSeparator;
+///
+/// impl PostProcessor for RecordSeparator {
+///     fn process<'a>(&self, output: &'a str) -> jq_rs::Result<Cow<'a, str>> {
+///         Ok(Cow::Owned(format!("{}\x1e", output)))
+///     }
+/// }
+///
+/// let mut program = Compiler::new()
+///     .join_output(true)
+///     .postprocessor(RecordSeparator)
+///     .compile(".[]")
+///     .unwrap();
+/// assert_eq!(program.run("[1, 2]").unwrap(), "1\x1e2\x1e");
+/// ```
+pub trait PostProcessor: Send + Sync {
+    /// Transform one rendered output, borrowing from it when it's left as
+    /// it is.
+    fn process<'a>(&self, output: &'a str) -> Result<Cow<'a, str>>;
+}
+
+impl<P: PostProcessor + ?Sized> PostProcessor for Arc<P> {
+    fn process<'a>(&self, output: &'a str) -> Result<Cow<'a, str>> {
+        (**self).process(output)
+    }
+}
+
+/// The `PostProcessor` a program's outputs go through, shared between all
+/// the programs compiled with the same `Compiler`.
+#[derive(Clone)]
+pub(crate) struct SharedPostProcessor(pub Arc<dyn PostProcessor>);
+
+impl SharedPostProcessor {
+    /// Run the output rendered into `buf` from `start` on through the
+    /// post-processor, replacing it with what comes back.
+    pub fn apply(&self, buf: &mut String, start: usize) -> Result<()> {
+        let rendered = &buf[start..];
+        let processed = match self.0.process(rendered)? {
+            Cow::Borrowed(output) if std::ptr::eq(output, rendered) => return Ok(()),
+            output => output.into_owned(),
+        };
+        buf.truncate(start);
+        buf.push_str(&processed);
+        Ok(())
+    }
+}
+
+impl PartialEq for SharedPostProcessor {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedPostProcessor {}
+
+impl fmt::Debug for SharedPostProcessor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PostProcessor")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PostProcessor, PreProcessor};
+    use crate::{BatchError, Compiler, Error, Result};
+    use matches::assert_matches;
+    use std::borrow::Cow;
+
+    /// Inputs are a length in hex followed by that many bytes of json, then
+    /// anything at all.
+    struct Framed;
+
+    impl PreProcessor for Framed {
+        fn process<'a>(&self, input: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+            let invalid = || Error::Io {
+                err: std::io::Error::new(std::io::ErrorKind::InvalidData, "bad frame"),
+            };
+            let header = input.get(..2).ok_or_else(invalid)?;
+            let len = std::str::from_utf8(header)
+                .ok()
+                .and_then(|header| usize::from_str_radix(header, 16).ok())
+                .ok_or_else(invalid)?;
+            let body = input.get(2..2 + len).ok_or_else(invalid)?;
+            Ok(Cow::Borrowed(body))
+        }
+    }
+
+    /// Swaps single quotes for double ones, which always needs a copy.
+    struct SingleQuotes;
+
+    impl PreProcessor for SingleQuotes {
+        fn process<'a>(&self, input: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+            let swapped = input
+                .iter()
+                .map(|&b| if b == b'\'' { b'"' } else { b })
+                .collect();
+            Ok(Cow::Owned(swapped))
+        }
+    }
+
+    #[test]
+    fn borrowed_and_owned() {
+        let mut program = Compiler::new().preprocessor(Framed).compile(".a").unwrap();
+        assert_eq!(program.run("07{\"a\":1}trailer").unwrap(), "1\n");
+        assert_matches!(program.run("zz{}"), Err(Error::Io { .. }));
+
+        let mut program = Compiler::new()
+            .preprocessor(SingleQuotes)
+            .compile(".a")
+            .unwrap();
+        assert_eq!(program.run("{'a': 'b'}").unwrap(), "\"b\"\n");
+    }
+
+    #[test]
+    fn each_line_and_batch_input() {
+        let mut program = Compiler::new().preprocessor(Framed).compile(".").unwrap();
+        let outputs: Vec<_> = program
+            .run_batch(&["011 ", "xx", "02[]"])
+            .into_iter()
+            .map(|res| res.map_err(|BatchError { index, .. }| index))
+            .collect();
+        assert_eq!(
+            outputs,
+            vec![Ok("1\n".to_string()), Err(1), Ok("[]\n".to_string())]
+        );
+
+        let mut output = vec![];
+        let mut errors = vec![];
+        crate::jsonl::transform(&b"011\nxx\n012\n"[..], &mut program, &mut output, |err| {
+            errors.push(err.line)
+        })
+        .unwrap();
+        assert_eq!(output, b"1\n2\n");
+        assert_eq!(errors, vec![Some(2)]);
+    }
+
+    #[test]
+    fn outputs_must_be_utf8() {
+        struct Latin1;
+
+        impl PreProcessor for Latin1 {
+            fn process<'a>(&self, _input: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+                Ok(Cow::Owned(b"\"\xe9\"".to_vec()))
+            }
+        }
+
+        let mut program = Compiler::new().preprocessor(Latin1).compile(".").unwrap();
+        assert_matches!(program.run("null"), Err(Error::StringConvert { .. }));
+    }
+
+    /// Replaces digits with `#`, and refuses outputs mentioning secrets.
+    struct Redact;
+
+    impl PostProcessor for Redact {
+        fn process<'a>(&self, output: &'a str) -> Result<Cow<'a, str>> {
+            if output.contains("secret") {
+                return Err(Error::Policy {
+                    reason: "secrets can't be output".into(),
+                });
+            }
+            if output.bytes().any(|b| b.is_ascii_digit()) {
+                let redacted = output
+                    .chars()
+                    .map(|c| if c.is_ascii_digit() { '#' } else { c });
+                return Ok(Cow::Owned(redacted.collect()));
+            }
+            Ok(Cow::Borrowed(output))
+        }
+    }
+
+    /// Leaves the newline after each output off.
+    struct Trimmed;
+
+    impl PostProcessor for Trimmed {
+        fn process<'a>(&self, output: &'a str) -> Result<Cow<'a, str>> {
+            Ok(Cow::Borrowed(output.trim_end()))
+        }
+    }
+
+    #[test]
+    fn rendered_outputs() {
+        let mut program = Compiler::new()
+            .postprocessor(Redact)
+            .compile(".[]")
+            .unwrap();
+        assert_eq!(
+            program.run(r#"["a", {"pin": 1234}]"#).unwrap(),
+            "\"a\"\n{\"pin\":####}\n"
+        );
+        assert_matches!(program.run(r#"["a", "secret"]"#), Err(Error::Policy { .. }));
+        assert_eq!(
+            program.run_collect::<Vec<_>>(r#"["a", 12]"#).unwrap(),
+            vec!["\"a\"".to_string(), "##".to_string()]
+        );
+
+        let mut program = Compiler::new()
+            .postprocessor(Trimmed)
+            .compile(".[]")
+            .unwrap();
+        assert_eq!(program.run("[1, [2]]").unwrap(), "1[2]");
+    }
+
+    #[test]
+    fn streamed_outputs() {
+        let mut program = Compiler::new().postprocessor(Redact).compile(".").unwrap();
+        let mut output = vec![];
+        crate::stream::transform(&b"1 \"a\""[..], &mut program, &mut output).unwrap();
+        assert_eq!(output, b"#\n\"a\"\n");
+    }
+}
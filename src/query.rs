@@ -0,0 +1,418 @@
+use crate::literal::{escape_string, to_literal, JqLiteral};
+use std::fmt;
+use std::ops::{Add, Div, Mul, Not, Sub};
+
+/// How tightly each kind of expression binds, loosest first, following jq's
+/// grammar. Operands which bind more loosely than their operator are wrapped
+/// in parentheses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Pipe,
+    Comma,
+    Alternative,
+    Or,
+    And,
+    Compare,
+    Sum,
+    Product,
+    /// Paths, literals, calls and anything else which can be followed by
+    /// `.field`, `[index]` or `?`.
+    Term,
+}
+
+/// A jq program built up from parts, rather than by pasting strings together.
+///
+/// Field names and values are quoted as needed, and parentheses are added
+/// wherever jq's precedence rules would otherwise change the meaning, so
+/// input from users can be used for either without it being read as part of
+/// the program.
+///
+/// ```rust
+/// use jq_rs::Query;
+///
+/// let name = r#"Godzilla" or true or ""#;
+/// let query = Query::field("kaiju")
+///     .iterate()
+///     .pipe(Query::select(Query::field("name").eq(Query::value(name))))
+///     .pipe(Query::field("year"));
+/// assert_eq!(
+///     query.build(),
+///     r#".kaiju[] | select(.name == "Godzilla\" or true or \"") | .year"#
+/// );
+///
+/// let mut program = jq_rs::compile(&query.build()).unwrap();
+/// assert_eq!(program.run(r#"{"kaiju": [{"name": "Godzilla", "year": 1954}]}"#).unwrap(), "");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+    text: String,
+    prec: Prec,
+}
+
+impl Query {
+    fn new<T: Into<String>>(text: T, prec: Prec) -> Self {
+        Query {
+            text: text.into(),
+            prec,
+        }
+    }
+
+    /// `.`, the input itself.
+    pub fn identity() -> Self {
+        Query::new(".", Prec::Term)
+    }
+
+    /// `.name`, a field of the input.
+    pub fn field(name: &str) -> Self {
+        Query::identity().then_field(name)
+    }
+
+    /// `..`, the input and everything inside it.
+    pub fn recurse() -> Self {
+        Query::new("..", Prec::Term)
+    }
+
+    /// A constant value, such as a string or number.
+    pub fn value<T: JqLiteral + ?Sized>(value: &T) -> Self {
+        Query::new(to_literal(value), Prec::Term)
+    }
+
+    /// `select(condition)`, passing the input through only when the condition
+    /// is true.
+    pub fn select(condition: Query) -> Self {
+        Query::call("select", vec![condition])
+    }
+
+    /// `map(query)`, running the query on each item of an array.
+    pub fn map(query: Query) -> Self {
+        Query::call("map", vec![query])
+    }
+
+    /// `[query]`, collecting the outputs of the query into an array.
+    pub fn array(query: Query) -> Self {
+        Query::new(format!("[{}]", query.text), Prec::Term)
+    }
+
+    /// `{"key": value, ...}`, building an object.
+    pub fn object<'a, I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, Query)>,
+    {
+        let entries: Vec<String> = entries
+            .into_iter()
+            .map(|(key, value)| format!("{}: {}", escape_string(key), value.wrapped(Prec::Term)))
+            .collect();
+        Query::new(format!("{{{}}}", entries.join(", ")), Prec::Term)
+    }
+
+    /// A call to a builtin (or a function defined elsewhere), such as
+    /// `length` or `test(regex)`.
+    ///
+    /// # Panics
+    ///
+    /// When `name` isn't a valid jq identifier, since it's written into the
+    /// program as it is.
+    pub fn call(name: &str, args: Vec<Query>) -> Self {
+        assert!(is_ident(name), "{:?} isn't a valid jq function name", name);
+
+        if args.is_empty() {
+            return Query::new(name, Prec::Term);
+        }
+        let args: Vec<String> = args.into_iter().map(|arg| arg.text).collect();
+        Query::new(format!("{}({})", name, args.join("; ")), Prec::Term)
+    }
+
+    /// `query.name`, a field of each output.
+    pub fn then_field(self, name: &str) -> Self {
+        let field = if is_ident(name) {
+            name.to_string()
+        } else {
+            escape_string(name)
+        };
+        self.postfix(|base| format!("{}.{}", base, field))
+    }
+
+    /// `query[index]`, an item of each output. Negative indexes count back
+    /// from the end.
+    pub fn index(self, index: i64) -> Self {
+        self.postfix(|base| format!("{}[{}]", base, index))
+    }
+
+    /// `query[from:to]`, part of each output, either end of which can be left
+    /// open.
+    pub fn slice(self, from: Option<i64>, to: Option<i64>) -> Self {
+        let bound = |end: Option<i64>| end.map_or_else(String::new, |end| end.to_string());
+        self.postfix(|base| format!("{}[{}:{}]", base, bound(from), bound(to)))
+    }
+
+    /// `query[]`, every item of each output.
+    pub fn iterate(self) -> Self {
+        self.postfix(|base| format!("{}[]", base))
+    }
+
+    /// `query?`, ignoring errors.
+    pub fn optional(self) -> Self {
+        let text = format!("{}?", self.wrapped(Prec::Term));
+        Query::new(text, Prec::Term)
+    }
+
+    /// `query | next`, running `next` on each output.
+    pub fn pipe(self, next: Query) -> Self {
+        // `|` is the loosest, so nothing on either side needs wrapping.
+        Query::new(format!("{} | {}", self.text, next.text), Prec::Pipe)
+    }
+
+    /// `query, other`, the outputs of both.
+    pub fn comma(self, other: Query) -> Self {
+        self.binary(",", other, Prec::Comma)
+    }
+
+    /// `query // other`, the outputs of `query` which aren't `false` or
+    /// `null`, or if there are none, the outputs of `other`.
+    pub fn alternative(self, other: Query) -> Self {
+        self.binary(" //", other, Prec::Alternative)
+    }
+
+    /// `query or other`.
+    pub fn or(self, other: Query) -> Self {
+        self.binary(" or", other, Prec::Or)
+    }
+
+    /// `query and other`.
+    pub fn and(self, other: Query) -> Self {
+        self.binary(" and", other, Prec::And)
+    }
+
+    /// `query == other`.
+    pub fn eq(self, other: Query) -> Self {
+        self.binary(" ==", other, Prec::Compare)
+    }
+
+    /// `query != other`.
+    pub fn ne(self, other: Query) -> Self {
+        self.binary(" !=", other, Prec::Compare)
+    }
+
+    /// `query < other`.
+    pub fn lt(self, other: Query) -> Self {
+        self.binary(" <", other, Prec::Compare)
+    }
+
+    /// `query <= other`.
+    pub fn le(self, other: Query) -> Self {
+        self.binary(" <=", other, Prec::Compare)
+    }
+
+    /// `query > other`.
+    pub fn gt(self, other: Query) -> Self {
+        self.binary(" >", other, Prec::Compare)
+    }
+
+    /// `query >= other`.
+    pub fn ge(self, other: Query) -> Self {
+        self.binary(" >=", other, Prec::Compare)
+    }
+
+    /// The jq program.
+    pub fn build(&self) -> String {
+        self.text.clone()
+    }
+
+    fn postfix<F: FnOnce(&str) -> String>(self, add: F) -> Self {
+        let text = if self.text == "." {
+            // `.` followed by a field or index is written without the `.`.
+            add("")
+        } else {
+            add(&self.wrapped(Prec::Term))
+        };
+        // `.[0]` on its own would read as a field called `[0]`, or not at
+        // all, so the identity is put back.
+        let text = if text.starts_with('[') {
+            format!(".{}", text)
+        } else {
+            text
+        };
+        Query::new(text, Prec::Term)
+    }
+
+    /// All of jq's binary operators are either left associative or can't be
+    /// chained at all, so the right side is wrapped unless it binds more
+    /// tightly.
+    fn binary(self, op: &str, other: Query, prec: Prec) -> Self {
+        let left = if self.prec >= prec && !(prec == Prec::Compare && self.prec == prec) {
+            self.text
+        } else {
+            format!("({})", self.text)
+        };
+        let right = if other.prec > prec {
+            other.text
+        } else {
+            format!("({})", other.text)
+        };
+        Query::new(format!("{}{} {}", left, op, right), prec)
+    }
+
+    fn wrapped(&self, prec: Prec) -> String {
+        if self.prec >= prec {
+            self.text.clone()
+        } else {
+            format!("({})", self.text)
+        }
+    }
+}
+
+/// `query + other`, which also joins strings, arrays and objects.
+impl Add for Query {
+    type Output = Query;
+
+    fn add(self, other: Query) -> Query {
+        self.binary(" +", other, Prec::Sum)
+    }
+}
+
+/// `query - other`.
+impl Sub for Query {
+    type Output = Query;
+
+    fn sub(self, other: Query) -> Query {
+        self.binary(" -", other, Prec::Sum)
+    }
+}
+
+/// `query * other`.
+impl Mul for Query {
+    type Output = Query;
+
+    fn mul(self, other: Query) -> Query {
+        self.binary(" *", other, Prec::Product)
+    }
+}
+
+/// `query / other`, which also splits strings.
+impl Div for Query {
+    type Output = Query;
+
+    fn div(self, other: Query) -> Query {
+        self.binary(" /", other, Prec::Product)
+    }
+}
+
+/// `query | not`.
+impl Not for Query {
+    type Output = Query;
+
+    fn not(self) -> Query {
+        self.pipe(Query::call("not", vec![]))
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn is_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Query;
+    use crate::compile;
+
+    fn run(query: &Query, data: &str) -> String {
+        compile(&query.build()).unwrap().run(data).unwrap()
+    }
+
+    #[test]
+    fn paths() {
+        let query = Query::field("a").then_field("b c").index(-1).iterate();
+        assert_eq!(query.build(), r#".a."b c"[-1][]"#);
+        assert_eq!(Query::identity().index(0).build(), ".[0]");
+        assert_eq!(Query::identity().iterate().build(), ".[]");
+        assert_eq!(Query::field("a").slice(Some(1), None).build(), ".a[1:]");
+        assert_eq!(run(&query, r#"{"a": {"b c": [[0], [1, 2]]}}"#), "1\n2\n");
+        assert_eq!(
+            Query::select(Query::identity())
+                .then_field("x")
+                .optional()
+                .build(),
+            "select(.).x?"
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let a = Query::field("a");
+        let b = Query::field("b");
+        let c = Query::field("c");
+
+        let sum_then_product = (a.clone() + b.clone()) * c.clone();
+        assert_eq!(sum_then_product.build(), "(.a + .b) * .c");
+        assert_eq!(run(&sum_then_product, r#"{"a": 1, "b": 2, "c": 3}"#), "9\n");
+
+        let grouped = a.clone() - (b.clone() - c.clone());
+        assert_eq!(grouped.build(), ".a - (.b - .c)");
+        assert_eq!(run(&grouped, r#"{"a": 1, "b": 2, "c": 3}"#), "2\n");
+
+        assert_eq!(
+            a.clone().eq(b.clone()).eq(c.clone()).build(),
+            "(.a == .b) == .c"
+        );
+        assert_eq!(
+            (!a.clone().pipe(b.clone()).or(c.clone())).build(),
+            "(.a | .b) or .c | not"
+        );
+        assert_eq!(
+            a.clone().comma(b.clone()).then_field("d").build(),
+            "(.a, .b).d"
+        );
+        assert_eq!(
+            (Query::value(&-1) - Query::value(&-2)).build(),
+            "(-1) - (-2)"
+        );
+    }
+
+    #[test]
+    fn values_and_constructors() {
+        let query = Query::object(vec![
+            (
+                "name\"",
+                Query::field("a").alternative(Query::value("none")),
+            ),
+            (
+                "all",
+                Query::array(Query::field("b").iterate().comma(Query::value(&3))),
+            ),
+            ("n", Query::call("length", vec![])),
+            (
+                "first",
+                Query::call("limit", vec![Query::value(&1), Query::field("b").iterate()]),
+            ),
+        ]);
+        assert_eq!(
+            query.build(),
+            r#"{"name\"": (.a // "none"), "all": [.b[], 3], "n": length, "first": limit(1; .b[])}"#
+        );
+        assert_eq!(
+            run(
+                &query.pipe(Query::map(Query::identity())),
+                r#"{"b": [1], "a": null}"#
+            ),
+            "[\"none\",[1,3],2,1]\n"
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_function_name() {
+        Query::call("env) | (x", vec![]);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::errors::{Error, Result};
+use crate::{Compiler, JqProgram, Settings};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+/// A thread-safe set of jq programs stored under names, for services which
+/// route data to filters by name.
+///
+/// Programs are compiled the first time they're run rather than when they're
+/// added, so a registry with many filters is cheap to set up. Like
+/// `ProgramCache`, each filter keeps a pool of compiled instances which grows
+/// to match the number of threads running it at once.
+///
+/// ```rust
+/// use jq_rs::FilterRegistry;
+///
+/// let registry = FilterRegistry::new();
+/// registry.add("extract-user", ".user.name");
+/// registry.add("count-items", ".items | length");
+///
+/// let event = r#"{"user": {"name": "Mothra"}, "items": [1, 2]}"#;
+/// assert_eq!(registry.run("extract-user", event).unwrap(), "\"Mothra\"\n");
+/// assert_eq!(registry.run("count-items", event).unwrap(), "2\n");
+/// assert!(registry.run("missing", event).unwrap_err().is_unknown_filter());
+/// ```
+pub struct FilterRegistry {
+    compiler: Compiler,
+    filters: RwLock<HashMap<String, Arc<Filter>>>,
+}
+
+struct Filter {
+    source: String,
+    pool: Mutex<Vec<JqProgram>>,
+}
+
+impl FilterRegistry {
+    /// Create an empty registry, compiling programs with the same options as
+    /// `jq_rs::compile()`.
+    pub fn new() -> Self {
+        Self::with_compiler(Compiler::new())
+    }
+
+    /// Create an empty registry which compiles programs with `compiler`.
+    pub fn with_compiler(compiler: Compiler) -> Self {
+        FilterRegistry {
+            compiler,
+            filters: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Store a program under `name`, replacing any program already stored
+    /// under it.
+    ///
+    /// The program isn't compiled until it's first run, so mistakes in it are
+    /// reported by `run()` and `checkout()`.
+    pub fn add<N, S>(&self, name: N, program: S)
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        let filter = Arc::new(Filter {
+            source: program.into(),
+            pool: Mutex::new(Vec::new()),
+        });
+        self.write().insert(name.into(), filter);
+    }
+
+    /// Remove the program stored under `name`, returning whether there was
+    /// one.
+    pub fn remove(&self, name: &str) -> bool {
+        self.write().remove(name).is_some()
+    }
+
+    /// Whether a program is stored under `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.read().contains_key(name)
+    }
+
+    /// The names of the stored programs, in sorted order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.read().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Run the program stored under `name` on a blob of json data.
+    ///
+    /// Fails with `Error::UnknownFilter` when there's no program by that
+    /// name.
+    pub fn run(&self, name: &str, data: &str) -> Result<String> {
+        self.checkout(name)?.run(data)
+    }
+
+    /// Take a compiled instance of the program stored under `name` out of
+    /// the registry, compiling it if none are available.
+    ///
+    /// The instance goes back to the registry when the `RegisteredProgram`
+    /// is dropped.
+    pub fn checkout(&self, name: &str) -> Result<RegisteredProgram> {
+        let filter = self
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::UnknownFilter { name: name.into() })?;
+        let pooled = filter
+            .pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let program = match pooled {
+            Some(program) => program,
+            // Compiling is slow, so it's done without holding any lock.
+            None => self.compiler.compile(&filter.source)?,
+        };
+        Ok(RegisteredProgram {
+            filter,
+            settings: program.settings(),
+            program: Some(program),
+        })
+    }
+
+    // The map is only ever inserted into or removed from, so a panic while
+    // the lock is held can't leave it in a broken state.
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, Arc<Filter>>> {
+        self.filters.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, Arc<Filter>>> {
+        self.filters.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for FilterRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A compiled program checked out of a `FilterRegistry`.
+///
+/// Derefs to `JqProgram`. The program goes back into the registry when this
+/// is dropped, unless the filter it came from has since been replaced or
+/// removed. Settings changed through it (limits, handlers, the time zone and
+/// so on) are put back as they were first, the same as for `CachedProgram`.
+pub struct RegisteredProgram {
+    filter: Arc<Filter>,
+    program: Option<JqProgram>,
+    settings: Settings,
+}
+
+impl Deref for RegisteredProgram {
+    type Target = JqProgram;
+
+    fn deref(&self) -> &JqProgram {
+        self.program
+            .as_ref()
+            .expect("program is only taken on drop")
+    }
+}
+
+impl DerefMut for RegisteredProgram {
+    fn deref_mut(&mut self) -> &mut JqProgram {
+        self.program
+            .as_mut()
+            .expect("program is only taken on drop")
+    }
+}
+
+impl Drop for RegisteredProgram {
+    fn drop(&mut self) {
+        if let Some(mut program) = self.program.take() {
+            program.reset();
+            program.restore(self.settings);
+            self.filter
+                .pool
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(program);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FilterRegistry;
+    use crate::{Compiler, Error};
+    use matches::assert_matches;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn add_and_remove() {
+        let registry = FilterRegistry::new();
+        registry.add("b", ".b");
+        registry.add("a", ".a");
+        assert_eq!(registry.names(), vec!["a", "b"]);
+        assert_eq!(registry.run("a", r#"{"a": 1}"#).unwrap(), "1\n");
+
+        registry.add("a", ".a + 1");
+        assert_eq!(registry.run("a", r#"{"a": 1}"#).unwrap(), "2\n");
+
+        assert!(registry.remove("a"));
+        assert!(!registry.remove("a"));
+        assert!(!registry.contains("a"));
+        assert_matches!(
+            registry.run("a", "{}"),
+            Err(Error::UnknownFilter { ref name }) if name == "a"
+        );
+    }
+
+    #[test]
+    fn compiled_lazily() {
+        let registry = FilterRegistry::new();
+        registry.add("broken", "[");
+        assert_matches!(
+            registry.run("broken", "{}"),
+            Err(Error::InvalidProgram { .. })
+        );
+
+        let registry = FilterRegistry::with_compiler(Compiler::new().raw_output(true));
+        registry.add("name", ".name");
+        {
+            let mut a = registry.checkout("name").unwrap();
+            let mut b = registry.checkout("name").unwrap();
+            assert_eq!(a.run(r#"{"name": "a"}"#).unwrap(), "a\n");
+            assert_eq!(b.run(r#"{"name": "b"}"#).unwrap(), "b\n");
+        }
+        let filters = registry.read();
+        let pool = filters["name"].pool.lock().unwrap();
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn settings_do_not_carry_over_to_the_next_checkout() {
+        let registry = FilterRegistry::with_compiler(Compiler::new().max_input_bytes(8));
+        registry.add("f", "debug | stderr");
+        {
+            let mut a = registry.checkout("f").unwrap();
+            a.set_max_input_bytes(None);
+            a.set_debug_handler(|_| panic!("debug handler carried over"));
+            a.set_stderr_handler(|_| panic!("stderr handler carried over"));
+            a.set_stats_handler(|_| panic!("stats handler carried over"));
+        }
+        assert_eq!(registry.run("f", "[1]").unwrap(), "[1]\n");
+        // The limit goes back to the one it was compiled with.
+        assert_matches!(
+            registry.run("f", "[1, 2, 3, 4]"),
+            Err(Error::InputTooLarge { .. })
+        );
+    }
+
+    #[test]
+    fn replaced_while_checked_out() {
+        let registry = FilterRegistry::new();
+        registry.add("f", "1");
+        let mut old = registry.checkout("f").unwrap();
+        registry.add("f", "2");
+        assert_eq!(old.run("null").unwrap(), "1\n");
+        drop(old);
+        assert_eq!(registry.run("f", "null").unwrap(), "2\n");
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let registry = Arc::new(FilterRegistry::new());
+        registry.add("a", ".a");
+        registry.add("b", ".b");
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let registry = Arc::clone(&registry);
+                thread::spawn(move || {
+                    let name = if i % 2 == 0 { "a" } else { "b" };
+                    registry.run(name, r#"{"a": 1, "b": 2}"#).unwrap()
+                })
+            })
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let expected = if i % 2 == 0 { "1\n" } else { "2\n" };
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Running jq programs over the bodies of [reqwest] responses.
+//!
+//! `ResponseExt` adds `jq()` to `reqwest::Response`, which streams the body
+//! into jq's parser as it's downloaded and deserializes each output of the
+//! program as a `T`. The body doesn't need to be held in memory all at once,
+//! and if it holds several json values (like json lines), the program runs
+//! over each of them in turn.
+//!
+//! The program runs on the task awaiting the response, as each value is
+//! finished. For programs which take a long time over each value, running an
+//! `AsyncJqProgram` over the collected body keeps them off the executor.
+//!
+//! Requires the `reqwest` feature.
+//!
+//! ```rust,no_run
+//! # extern crate reqwest_crate as reqwest;
+//! use jq_rs::reqwest::ResponseExt;
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Item {
+//!     id: u64,
+//!     name: String,
+//! }
+//!
+//! async fn items(client: &reqwest::Client) -> jq_rs::Result<Vec<Item>> {
+//!     let response = client
+//!         .get("https://example.com/items")
+//!         .send()
+//!         .await
+//!         .and_then(|response| response.error_for_status())
+//!         .map_err(jq_rs::reqwest::to_error)?;
+//!     response.jq(".items[] | {id, name}").await
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! [reqwest]: https://crates.io/crates/reqwest
+
+use crate::cancel::Interrupt;
+use crate::errors::{Error, Result};
+use crate::jq::{OutputDeserializer, Parser};
+use crate::JqProgram;
+use reqwest_crate::Response;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::pin::Pin;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Runs jq programs over the body of a `reqwest::Response`. See the module
+/// docs.
+///
+/// The status of the response isn't looked at, so to treat error statuses
+/// as failures, check it first (say with `error_for_status()`).
+pub trait ResponseExt {
+    /// Compile `program` and run it over the body, deserializing each
+    /// output as a `T`.
+    ///
+    /// A body which fails to download is an `Error::Io`, and the rest of the
+    /// failures are the same as for `JqProgram::run_as()`. An empty body
+    /// gives no outputs.
+    fn jq<T>(self, program: &str) -> BoxFuture<'static, Result<Vec<T>>>
+    where
+        T: DeserializeOwned + Send + 'static;
+
+    /// Like `jq()`, with a program which has already been compiled.
+    fn jq_with<'a, T>(self, program: &'a mut JqProgram) -> BoxFuture<'a, Result<Vec<T>>>
+    where
+        T: DeserializeOwned + Send + 'a;
+}
+
+impl ResponseExt for Response {
+    fn jq<T>(self, program: &str) -> BoxFuture<'static, Result<Vec<T>>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let program = crate::compile(program);
+        Box::pin(async move {
+            let mut program = program?;
+            run(self, &mut program).await
+        })
+    }
+
+    fn jq_with<'a, T>(self, program: &'a mut JqProgram) -> BoxFuture<'a, Result<Vec<T>>>
+    where
+        T: DeserializeOwned + Send + 'a,
+    {
+        Box::pin(run(self, program))
+    }
+}
+
+/// Convert an error from reqwest into an `Error::Io`, which is what `jq()`
+/// gives back when the body fails to download.
+pub fn to_error(err: reqwest_crate::Error) -> Error {
+    std::io::Error::new(std::io::ErrorKind::Other, err).into()
+}
+
+async fn run<T>(mut response: Response, program: &mut JqProgram) -> Result<Vec<T>>
+where
+    T: DeserializeOwned,
+{
+    let mut parser = Parser::new();
+    let mut values = vec![];
+    let mut read = 0;
+    loop {
+        let chunk = response.chunk().await.map_err(to_error)?;
+        let is_last = chunk.is_none();
+        let chunk = chunk.unwrap_or_default();
+        read += chunk.len();
+        program.jq.check_input_bytes(read)?;
+        parser.feed(&chunk, is_last, |value| {
+            program
+                .jq
+                .start(value, &Interrupt::default(), |output| {
+                    values.push(T::deserialize(OutputDeserializer::new(&output))?);
+                    Ok(0)
+                })?
+                .into_result()
+        })?;
+        if is_last {
+            return Ok(values);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ResponseExt;
+    use crate::errors::Error;
+    use futures_lite::future::block_on;
+    use matches::assert_matches;
+    use reqwest_crate::Response;
+    use serde::Deserialize;
+
+    fn response(body: &'static str) -> Response {
+        Response::from(http::Response::new(body))
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Item {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn typed_outputs() {
+        let body = r#"{"items": [{"id": 1, "name": "a", "extra": true}, {"id": 2, "name": "b"}]}"#;
+        let items: Vec<Item> = block_on(response(body).jq(".items[] | {id, name}")).unwrap();
+        assert_eq!(
+            items,
+            vec![
+                Item {
+                    id: 1,
+                    name: "a".to_string()
+                },
+                Item {
+                    id: 2,
+                    name: "b".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn each_value_in_the_body() {
+        let mut program = crate::compile(".a").unwrap();
+        let values: Vec<u32> =
+            block_on(response("{\"a\": 1}\n{\"a\": 2}\n").jq_with(&mut program)).unwrap();
+        assert_eq!(values, vec![1, 2]);
+
+        let values: Vec<u32> = block_on(response("").jq_with(&mut program)).unwrap();
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    fn failures() {
+        let result = block_on(response("{}").jq::<u32>("["));
+        assert_matches!(result, Err(Error::InvalidProgram { .. }));
+        let result = block_on(response("{").jq::<u32>("."));
+        assert_matches!(result, Err(Error::Parse { .. }));
+        let result = block_on(response("[]").jq::<u32>(".a"));
+        assert_matches!(result, Err(Error::Exec { .. }));
+        let result = block_on(response("\"a\"").jq::<u32>("."));
+        assert_matches!(result, Err(Error::StringConvert { .. }));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::errors::{Error, Result};
+use crate::Compiler;
+
+/// Start building a one-off run of a jq program, one option at a time.
+///
+/// ```rust
+/// let output = jq_rs::runner()
+///     .program(r#""\($greeting), \(.name)""#)
+///     .arg("greeting", "Hello")
+///     .raw_output()
+///     .input(r#"{"name": "Mothra"}"#)
+///     .run()
+///     .unwrap();
+/// assert_eq!(output, "Hello, Mothra\n");
+/// ```
+pub fn runner() -> Runner {
+    Runner::default()
+}
+
+/// A one-off run of a jq program, built up by `jq_rs::runner()`.
+///
+/// The program is compiled by `run()` and thrown away afterwards, so
+/// programs run more than once are better off compiled with a `Compiler`
+/// and kept.
+#[derive(Clone, Debug, Default)]
+pub struct Runner {
+    compiler: Compiler,
+    program: Option<String>,
+    input: Option<String>,
+}
+
+impl Runner {
+    /// The jq program to run.
+    pub fn program<P: Into<String>>(mut self, program: P) -> Self {
+        self.program = Some(program.into());
+        self
+    }
+
+    /// Bind `$name` to `value`. Strings are bound as they are, like
+    /// `--arg`, and numbers, bools and json values like `--argjson`.
+    pub fn arg<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: ArgValue,
+    {
+        self.compiler = value.bind(name.into(), self.compiler);
+        self
+    }
+
+    /// Bind `$name` to a json value, like `--argjson name json`.
+    pub fn arg_json<N, V>(mut self, name: N, json: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.compiler = self.compiler.arg_json(name, json);
+        self
+    }
+
+    /// Write outputs which are strings as plain text, like `-r`.
+    pub fn raw_output(mut self) -> Self {
+        self.compiler = self.compiler.raw_output(true);
+        self
+    }
+
+    /// Use the options set on `compiler` for everything not covered here,
+    /// such as `Compiler::indent()` or `Compiler::sandbox()`.
+    ///
+    /// Options set on the runner before this are replaced.
+    pub fn compiler(mut self, compiler: Compiler) -> Self {
+        self.compiler = compiler;
+        self
+    }
+
+    /// The json to run the program on. Without any, the program runs on
+    /// `null`, like `jq -n`.
+    pub fn input<D: Into<String>>(mut self, data: D) -> Self {
+        self.input = Some(data.into());
+        self
+    }
+
+    /// Compile the program and run it, giving back the outputs the same as
+    /// `jq_rs::run()`.
+    ///
+    /// Fails with an `Error::InvalidProgram` when no program was given.
+    pub fn run(self) -> Result<String> {
+        let program = self.program.ok_or_else(|| Error::InvalidProgram {
+            reason: "No program was given to the runner".into(),
+        })?;
+        let input = self.input.as_deref().unwrap_or("null");
+        self.compiler.compile(&program)?.run(input)
+    }
+}
+
+/// Values `Runner::arg()` can bind a variable to.
+pub trait ArgValue {
+    /// Bind `$name` to the value in `compiler`'s args.
+    fn bind(self, name: String, compiler: Compiler) -> Compiler;
+}
+
+impl ArgValue for &str {
+    fn bind(self, name: String, compiler: Compiler) -> Compiler {
+        compiler.arg(name, self)
+    }
+}
+
+impl ArgValue for String {
+    fn bind(self, name: String, compiler: Compiler) -> Compiler {
+        compiler.arg(name, self)
+    }
+}
+
+impl ArgValue for bool {
+    fn bind(self, name: String, compiler: Compiler) -> Compiler {
+        compiler.arg_json(name, self.to_string())
+    }
+}
+
+macro_rules! integer_args {
+    ($($ty:ty)*) => {
+        $(
+            impl ArgValue for $ty {
+                fn bind(self, name: String, compiler: Compiler) -> Compiler {
+                    compiler.arg_json(name, self.to_string())
+                }
+            }
+        )*
+    };
+}
+
+integer_args!(i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);
+
+/// NaN is bound as `null` and the infinities as the largest finite numbers,
+/// the way jq writes them.
+impl ArgValue for f64 {
+    fn bind(self, name: String, compiler: Compiler) -> Compiler {
+        let json = if self.is_nan() {
+            "null".to_string()
+        } else if self.is_infinite() {
+            format!("{:?}", f64::MAX.copysign(self))
+        } else {
+            format!("{:?}", self)
+        };
+        compiler.arg_json(name, json)
+    }
+}
+
+impl ArgValue for f32 {
+    fn bind(self, name: String, compiler: Compiler) -> Compiler {
+        f64::from(self).bind(name, compiler)
+    }
+}
+
+/// Requires the `serde_json` feature.
+#[cfg(feature = "serde_json")]
+impl ArgValue for serde_json::Value {
+    fn bind(self, name: String, compiler: Compiler) -> Compiler {
+        compiler.arg_json(name, self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::runner;
+    use crate::{Compiler, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn args() {
+        let output = runner()
+            .program("[$s, $i, $n, $f, $b, $inf, $nan, $j]")
+            .arg("s", "1")
+            .arg("i", 1)
+            .arg("n", -2_i64)
+            .arg("f", 1.5)
+            .arg("b", true)
+            .arg("inf", f64::INFINITY)
+            .arg("nan", f64::NAN)
+            .arg_json("j", "{\"a\": [1]}")
+            .run()
+            .unwrap();
+        assert_eq!(
+            output,
+            "[\"1\",1,-2,1.5,true,1.7976931348623157e+308,null,{\"a\":[1]}]\n"
+        );
+    }
+
+    #[test]
+    fn input_and_options() {
+        let run = runner().program(".a").input(r#"{"a": "b"}"#);
+        assert_eq!(run.clone().run().unwrap(), "\"b\"\n");
+        assert_eq!(run.raw_output().run().unwrap(), "b\n");
+
+        // Without an input the program runs on null.
+        assert_eq!(runner().program(".").run().unwrap(), "null\n");
+
+        let output = runner()
+            .arg("x", "replaced")
+            .compiler(Compiler::new().indent(1))
+            .program("[.]")
+            .input("1")
+            .run()
+            .unwrap();
+        assert_eq!(output, "[\n 1\n]\n");
+    }
+
+    #[test]
+    fn failures() {
+        assert_matches!(runner().run(), Err(Error::InvalidProgram { .. }));
+        assert_matches!(
+            runner().program("[").run(),
+            Err(Error::InvalidProgram { .. })
+        );
+        assert_matches!(
+            runner().program(".").input("{").run(),
+            Err(Error::Parse { .. })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn json_args() {
+        let output = runner()
+            .program("$v.a")
+            .arg("v", serde_json::json!({"a": [1, "b"]}))
+            .run()
+            .unwrap();
+        assert_eq!(output, "[1,\"b\"]\n");
+    }
+}
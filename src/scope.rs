@@ -0,0 +1,189 @@
+use crate::compiler::Arg;
+use crate::jq::Functions;
+use std::sync::Arc;
+
+/// Settings shared by programs which run in the same environment: their
+/// `$name` arguments, additions to `$ENV`, module search paths and
+/// registered functions.
+///
+/// A scope is compiled against with `Compiler::scope()`. It's reference
+/// counted, so cloning one is cheap, and changing a clone only copies the
+/// settings then. This suits services which set up a base scope once and
+/// adjust it for each tenant.
+///
+/// ```rust
+/// use jq_rs::{Compiler, Scope};
+///
+/// let base = Scope::new()
+///     .arg("service", "billing")
+///     .register_const("limits", r#"{"max": 10}"#);
+/// let tenant = base.clone().env("TENANT", "acme");
+///
+/// let mut program = Compiler::new()
+///     .scope(tenant)
+///     .compile(r#"[$service, $ENV.TENANT, limits.max]"#)
+///     .unwrap();
+/// assert_eq!(program.run("null").unwrap(), "[\"billing\",\"acme\",10]\n");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    settings: Arc<Settings>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Settings {
+    pub args: Vec<(String, Arg)>,
+    pub env: Vec<(String, String)>,
+    pub library_paths: Vec<String>,
+    /// Values for `register_const()`, as json.
+    pub constants: Vec<(String, String)>,
+    pub functions: Functions,
+}
+
+impl Scope {
+    /// Start with an empty scope.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind `$name` to a string value, like `Compiler::arg()`.
+    pub fn arg<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.edit()
+            .args
+            .push((name.into(), Arg::String(value.into())));
+        self
+    }
+
+    /// Bind `$name` to a json value, like `Compiler::arg_json()`.
+    pub fn arg_json<N, V>(mut self, name: N, json: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.edit().args.push((name.into(), Arg::Json(json.into())));
+        self
+    }
+
+    /// Add an environment variable to `$ENV` (and `env`), or replace the
+    /// value of one which is already there.
+    ///
+    /// The real environment is still visible alongside, unless the program
+    /// is compiled in the `Compiler::sandbox()`. There `$ENV` only holds the
+    /// variables added here, and `env` still isn't allowed.
+    pub fn env<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.edit().env.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add a directory to search for modules, like `Compiler::library_path()`.
+    pub fn library_path<P: Into<String>>(mut self, directory: P) -> Self {
+        self.edit().library_paths.push(directory.into());
+        self
+    }
+
+    /// Define `name` as a constant, like `Compiler::register_const()`.
+    pub fn register_const<N, V>(mut self, name: N, json: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.edit().constants.push((name.into(), json.into()));
+        self
+    }
+
+    /// Make a function implemented in Rust callable from programs, like
+    /// `Compiler::register_fn()`.
+    ///
+    /// Requires the `serde_json` feature.
+    #[cfg(feature = "serde_json")]
+    pub fn register_fn<N, F>(mut self, name: N, arity: usize, function: F) -> Self
+    where
+        N: Into<String>,
+        F: Fn(&[serde_json::Value]) -> std::result::Result<serde_json::Value, String>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let function = crate::compiler::json_function(function);
+        self.edit().functions.register(name.into(), arity, function);
+        self
+    }
+
+    pub(crate) fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// The settings, copied first if they're shared with another scope.
+    fn edit(&mut self) -> &mut Settings {
+        Arc::make_mut(&mut self.settings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Scope;
+    use crate::Compiler;
+    use std::sync::Arc;
+
+    #[test]
+    fn clones_share_settings() {
+        let base = Scope::new().arg("a", "1");
+        let tenant = base.clone();
+        assert!(Arc::ptr_eq(&base.settings, &tenant.settings));
+
+        let tenant = tenant.arg("a", "2");
+        assert!(!Arc::ptr_eq(&base.settings, &tenant.settings));
+        assert_eq!(base.settings().args.len(), 1);
+        assert_eq!(tenant.settings().args.len(), 2);
+    }
+
+    #[test]
+    fn compiler_settings_go_on_top() {
+        let scope = Scope::new()
+            .arg("a", "scope")
+            .arg_json("b", "[1]")
+            .register_const("c", "1")
+            .register_const("d", "2");
+        let compiler = Compiler::new()
+            .scope(scope)
+            .arg("a", "compiler")
+            .register_const("d", "3");
+        let mut program = compiler.compile("[$a, $b, c, d]").unwrap();
+        assert_eq!(program.run("null").unwrap(), "[\"compiler\",[1],1,3]\n");
+
+        let mut program = compiler.compile("$ARGS.named | keys").unwrap();
+        assert_eq!(program.run("null").unwrap(), "[\"a\",\"b\"]\n");
+    }
+
+    #[test]
+    fn env() {
+        std::env::set_var("JQ_RS_SCOPE_TEST", "real");
+        let scope = Scope::new()
+            .env("JQ_RS_SCOPE_ADDED", "a")
+            .env("JQ_RS_SCOPE_ADDED", "b");
+        let program = "[$ENV.JQ_RS_SCOPE_TEST, $ENV.JQ_RS_SCOPE_ADDED, env.JQ_RS_SCOPE_ADDED]";
+
+        let mut open = Compiler::new()
+            .scope(scope.clone())
+            .compile(program)
+            .unwrap();
+        assert_eq!(open.run("null").unwrap(), "[\"real\",\"b\",\"b\"]\n");
+
+        let sandbox = Compiler::new().scope(scope).sandbox(true);
+        let mut program = sandbox.compile("$ENV").unwrap();
+        assert_eq!(
+            program.run("null").unwrap(),
+            "{\"JQ_RS_SCOPE_ADDED\":\"b\"}\n"
+        );
+        let mut program = sandbox.compile("env").unwrap();
+        assert!(program.run("null").unwrap_err().is_policy_error());
+    }
+}
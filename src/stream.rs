@@ -0,0 +1,525 @@
+//! Processing inputs too large to hold in memory.
+//!
+//! The functions here read their input in fixed size chunks, run the program
+//! on each value as soon as it has been parsed, and write each output as soon
+//! as it's produced. Memory use stays proportional to the chunk size plus the
+//! largest single value, rather than the size of the whole input or output.
+//!
+//! That makes them a good fit for newline delimited json (or any other
+//! sequence of values), and for single huge documents when paired with jq's
+//! `--stream` mode via `transform_events()`, or broken up into their elements
+//! with `transform_elements()`. Without a program at all, `elements()` reads
+//! the values at any depth one at a time.
+//!
+//! ```rust
+//! let input = r#"
+//! {"name": "Godzilla", "height": 50}
+//! {"name": "Mothra", "height": 0.5}
+//! "#;
+//! let mut output = Vec::new();
+//! let mut program = jq_rs::compile(".name").unwrap();
+//!
+//! jq_rs::stream::transform(input.as_bytes(), &mut program, &mut output).unwrap();
+//! assert_eq!(output, b"\"Godzilla\"\n\"Mothra\"\n");
+//! ```
+
+use crate::cancel::Interrupt;
+use crate::errors::{Error, Result};
+use crate::jq::{Completion, Parser, Reassembler, JV};
+use crate::JqProgram;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+
+/// Number of bytes read from the input at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Run the program on each json value read from `reader`, writing the outputs
+/// to `writer` as they're produced.
+///
+/// Each output is written with its own call to `write_all()`, so wrapping
+/// `writer` in a `BufWriter` is a good idea when it's a file or socket.
+///
+/// Processing stops at the first error, which could come from reading,
+/// parsing, running the program, or writing. Anything written before that
+/// point is left in `writer`.
+pub fn transform<R, W>(reader: R, program: &mut JqProgram, writer: W) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    run(Parser::new(), None, reader, program, writer)
+}
+
+/// Like `transform()`, but the input is broken up into `[path, leaf]` events,
+/// the same as `jq --stream`.
+///
+/// The program runs once per event, so this works for a single document of
+/// any size. Use jq's `fromstream()` and `truncate_stream()` in the program to
+/// put the interesting parts back together.
+///
+/// ```rust
+/// let input = r#"{"kaiju": [{"name": "Godzilla"}, {"name": "Rodan"}]}"#;
+/// let mut output = Vec::new();
+/// let leaves = r#"select(length == 2 and .[0][-1] == "name") | .[1]"#;
+/// let mut program = jq_rs::compile(leaves).unwrap();
+///
+/// jq_rs::stream::transform_events(input.as_bytes(), &mut program, &mut output).unwrap();
+/// assert_eq!(output, b"\"Godzilla\"\n\"Rodan\"\n");
+/// ```
+pub fn transform_events<R, W>(reader: R, program: &mut JqProgram, writer: W) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    run(Parser::streaming(), None, reader, program, writer)
+}
+
+/// Like `transform()`, but the program runs on each element of the arrays
+/// in the input (and each value of the objects) rather than on the arrays
+/// themselves.
+///
+/// This gives the same outputs as running `.[] | program` on each value,
+/// but only one element is held in memory at a time, so it works for a
+/// top-level array of any size. It takes the place of reading the input
+/// with `jq --stream` and putting the elements back together with
+/// `fromstream(1 | truncate_stream(inputs))`, which in jq 1.6 also drops any
+/// elements which are numbers, strings, `null`s, booleans or empty.
+///
+/// Values in the input which aren't arrays or objects fail with
+/// `Error::Exec`, the same as `.[]` would.
+///
+/// ```rust
+/// let input = r#"[{"name": "Godzilla"}, {"name": "Rodan"}, 3]"#;
+/// let mut output = Vec::new();
+/// let mut program = jq_rs::compile(".name? // .").unwrap();
+///
+/// jq_rs::stream::transform_elements(input.as_bytes(), &mut program, &mut output).unwrap();
+/// assert_eq!(output, b"\"Godzilla\"\n\"Rodan\"\n3\n");
+/// ```
+pub fn transform_elements<R, W>(reader: R, program: &mut JqProgram, writer: W) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let reassembler = Reassembler::new(1);
+    run(
+        Parser::streaming(),
+        Some(reassembler),
+        reader,
+        program,
+        writer,
+    )
+}
+
+/// Read the values `depth` levels down in the input one at a time, each as
+/// compact json, without holding more than one of them in memory.
+///
+/// A depth of `1` gives the elements of top-level arrays and the values of
+/// top-level objects, `2` the elements of those, and so on. `0` gives the
+/// top-level values themselves. This is what `fromstream(depth |
+/// truncate_stream(inputs))` does with `jq --stream`, except that values
+/// which are numbers, strings, `null`s, booleans or empty aren't dropped.
+///
+/// The iterator stops after the first error, which could come from reading
+/// or parsing the input, or from a value above `depth` which isn't an array
+/// or object (failing with `Error::Exec`, as `.[]` would).
+///
+/// ```rust
+/// let input = r#"{"kaiju": [{"name": "Godzilla"}, {"name": "Rodan"}]}"#;
+/// let kaiju: Vec<String> = jq_rs::stream::elements(input.as_bytes(), 2)
+///     .collect::<Result<_, _>>()
+///     .unwrap();
+///
+/// assert_eq!(kaiju, vec![r#"{"name":"Godzilla"}"#, r#"{"name":"Rodan"}"#]);
+/// ```
+pub fn elements<R: Read>(reader: R, depth: usize) -> Elements<R> {
+    Elements {
+        reader,
+        parser: Parser::streaming(),
+        reassembler: Reassembler::new(depth),
+        chunk: vec![],
+        pending: VecDeque::new(),
+        error: None,
+        finished: false,
+    }
+}
+
+/// The values at some depth in an input, from `elements()`.
+pub struct Elements<R> {
+    reader: R,
+    parser: Parser,
+    reassembler: Reassembler,
+    chunk: Vec<u8>,
+    /// Values put together from the last chunk which haven't been handed out
+    /// yet.
+    pending: VecDeque<JV>,
+    /// An error to hand out once the values before it have been.
+    error: Option<Error>,
+    finished: bool,
+}
+
+impl<R: Read> Elements<R> {
+    /// Give back each value as a `serde_json::Value` rather than as json
+    /// text.
+    ///
+    /// Requires the `serde_json` feature.
+    ///
+    /// ```rust
+    /// use serde_json::json;
+    ///
+    /// let input = r#"[{"name": "Godzilla"}, 3]"#;
+    /// let values: Vec<_> = jq_rs::stream::elements(input.as_bytes(), 1)
+    ///     .values()
+    ///     .collect::<Result<_, _>>()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(values, vec![json!({"name": "Godzilla"}), json!(3)]);
+    /// ```
+    #[cfg(feature = "serde_json")]
+    pub fn values(self) -> ElementValues<R> {
+        ElementValues { elements: self }
+    }
+
+    fn next_jv(&mut self) -> Option<Result<JV>> {
+        loop {
+            if let Some(value) = self.pending.pop_front() {
+                return Some(Ok(value));
+            }
+            if let Some(err) = self.error.take() {
+                return Some(Err(err));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(err) = self.read_chunk() {
+                self.error = Some(err);
+                self.finished = true;
+            }
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<()> {
+        if self.chunk.is_empty() {
+            self.chunk = vec![0; CHUNK_SIZE];
+        }
+        let len = loop {
+            match self.reader.read(&mut self.chunk) {
+                Ok(len) => break len,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        };
+        let is_last = len == 0;
+        self.finished = is_last;
+
+        let reassembler = &mut self.reassembler;
+        let pending = &mut self.pending;
+        self.parser.feed(&self.chunk[..len], is_last, |event| {
+            if let Some(value) = reassembler.push(event)? {
+                pending.push_back(value);
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<R: Read> Iterator for Elements<R> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        self.next_jv().map(|value| {
+            let mut rendered = String::new();
+            value?.dump_into(&mut rendered);
+            Ok(rendered)
+        })
+    }
+}
+
+/// The values at some depth in an input as `serde_json::Value`s, from
+/// `Elements::values()`.
+///
+/// Requires the `serde_json` feature.
+#[cfg(feature = "serde_json")]
+pub struct ElementValues<R> {
+    elements: Elements<R>,
+}
+
+#[cfg(feature = "serde_json")]
+impl<R: Read> Iterator for ElementValues<R> {
+    type Item = Result<serde_json::Value>;
+
+    fn next(&mut self) -> Option<Result<serde_json::Value>> {
+        self.elements
+            .next_jv()
+            .map(|value| value.map(|value| value.to_value()))
+    }
+}
+
+/// Run the program on each value read by `parser`, or on the values put back
+/// together from the events it reads when there's a `reassembler`.
+fn run<R, W>(
+    mut parser: Parser,
+    mut reassembler: Option<Reassembler>,
+    mut reader: R,
+    program: &mut JqProgram,
+    mut writer: W,
+) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut chunk = vec![0; CHUNK_SIZE];
+    // Each output is rendered here before being written, reusing the buffer.
+    let mut rendered = String::with_capacity(program.output_capacity);
+    let interrupt = Interrupt::default();
+    let format = program.jq.format();
+    let mut halted = None;
+    let mut read = 0;
+
+    loop {
+        let len = match reader.read(&mut chunk) {
+            Ok(len) => len,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let is_last = len == 0;
+        // The cap on input covers everything read, not each value.
+        read += len;
+        program.jq.check_input_bytes(read)?;
+
+        parser.feed(&chunk[..len], is_last, |value| {
+            if halted.is_some() {
+                // The rest of the chunk has to be parsed regardless.
+                return Ok(());
+            }
+            let value = match &mut reassembler {
+                Some(reassembler) => match reassembler.push(value)? {
+                    Some(value) => value,
+                    None => return Ok(()),
+                },
+                None => value,
+            };
+            let completion = program.jq.start(value, &interrupt, |output| {
+                rendered.clear();
+                format.render(&output, &mut rendered)?;
+                writer.write_all(rendered.as_bytes())?;
+                Ok(rendered.len())
+            })?;
+            if let Completion::Halted { .. } = completion {
+                halted = Some(completion);
+            }
+            Ok(())
+        })?;
+
+        // Like the jq cli, a halt stops processing of the remaining input.
+        if let Some(completion) = halted {
+            writer.flush()?;
+            return completion.into_result();
+        }
+        if is_last {
+            return Ok(writer.flush()?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{elements, transform, transform_elements, transform_events};
+    use crate::{compile, Error};
+    use matches::assert_matches;
+    use std::io::{self, Read, Write};
+
+    /// Hands out the input a single byte at a time, so every value (and
+    /// multi-byte character) is split between reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((byte, rest)) if !buf.is_empty() => {
+                    buf[0] = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn transformed(program: &str, input: &str) -> String {
+        let mut output = vec![];
+        let mut prog = compile(program).unwrap();
+        transform(Trickle(input.as_bytes()), &mut prog, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn values_split_between_reads() {
+        let input = "{\"a\": \"caf\u{e9}\"}\n[1, 2]\n\"\u{1f996}\"\n12345";
+        assert_eq!(
+            transformed(".", input),
+            "{\"a\":\"caf\u{e9}\"}\n[1,2]\n\"\u{1f996}\"\n12345\n"
+        );
+    }
+
+    #[test]
+    fn each_value_runs_separately() {
+        assert_eq!(transformed(".[]", "[1, 2] [] [3]"), "1\n2\n3\n");
+        assert_eq!(transformed(".", ""), "");
+    }
+
+    #[test]
+    fn events() {
+        let input = r#"{"a": [1, {"b": 2}]}"#;
+        let mut output = vec![];
+        let mut prog = compile("tojson").unwrap();
+        transform_events(Trickle(input.as_bytes()), &mut prog, &mut output).unwrap();
+
+        let expected = compile("tostream | tojson").unwrap().run(input).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn each_element() {
+        let input = r#"[1, {"a": [2, {}]}, [], "b"] {"c": null} [] [[3]]"#;
+        let mut output = vec![];
+        let mut prog = compile("tojson").unwrap();
+        transform_elements(Trickle(input.as_bytes()), &mut prog, &mut output).unwrap();
+
+        let mut expected = compile(".[] | tojson").unwrap();
+        let mut wanted = vec![];
+        transform(input.as_bytes(), &mut expected, &mut wanted).unwrap();
+        assert_eq!(output, wanted);
+
+        let mut output = vec![];
+        let res = transform_elements(&b"[1] 2 [3]"[..], &mut prog, &mut output);
+        assert_matches!(res, Err(Error::Exec { ref reason, .. }) if reason == "Cannot iterate over number (2)");
+        assert_eq!(output, b"\"1\"\n");
+    }
+
+    #[test]
+    fn elements_at_depths() {
+        let input = r#"{"a": [1, {"b": "c"}], "d": []} [[true, "\u00e9"]]"#;
+        let read = |depth| {
+            elements(Trickle(input.as_bytes()), depth)
+                .collect::<crate::Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(
+            read(0),
+            vec![r#"{"a":[1,{"b":"c"}],"d":[]}"#, r#"[[true,"é"]]"#]
+        );
+        assert_eq!(read(1), vec![r#"[1,{"b":"c"}]"#, "[]", r#"[true,"é"]"#]);
+        assert_eq!(read(2), vec!["1", r#"{"b":"c"}"#, "true", r#""é""#]);
+        let deep = elements(&br#"[[[1]], [{"a": 2}, []]]"#[..], 3);
+        assert_eq!(
+            deep.collect::<crate::Result<Vec<_>>>().unwrap(),
+            vec!["1", "2"]
+        );
+        assert!(elements(&b""[..], 1).next().is_none());
+    }
+
+    #[test]
+    fn elements_stop_at_an_error() {
+        let mut values = elements(&b"[1, 2] [3, }"[..], 1);
+        assert_eq!(values.next().unwrap().unwrap(), "1");
+        assert_eq!(values.next().unwrap().unwrap(), "2");
+        assert_eq!(values.next().unwrap().unwrap(), "3");
+        assert_matches!(values.next(), Some(Err(Error::Parse { .. })));
+        assert!(values.next().is_none());
+
+        let mut values = elements(&b"[1] 2"[..], 1);
+        assert_eq!(values.next().unwrap().unwrap(), "1");
+        assert_matches!(values.next(), Some(Err(Error::Exec { .. })));
+        assert!(values.next().is_none());
+    }
+
+    #[test]
+    fn halt_stops_the_stream() {
+        assert_eq!(
+            transformed("if . == 2 then halt else . end", "1 2 3"),
+            "1\n"
+        );
+
+        let mut output = vec![];
+        let mut prog = compile("if . == 2 then halt_error(1) else . end").unwrap();
+        let res = transform(&b"1 2 3"[..], &mut prog, &mut output);
+        assert_matches!(res, Err(Error::Halted { exit_code: 1, .. }));
+        assert_eq!(output, b"1\n");
+    }
+
+    #[test]
+    fn stops_at_a_parse_error() {
+        let mut output = vec![];
+        let mut prog = compile(".").unwrap();
+        let res = transform(&b"1 2 } 3"[..], &mut prog, &mut output);
+        assert_matches!(res, Err(Error::Parse { .. }));
+        assert_eq!(output, b"1\n2\n");
+    }
+
+    #[test]
+    fn input_limit_covers_the_whole_stream() {
+        let mut output = vec![];
+        let mut prog = crate::Compiler::new()
+            .max_input_bytes(4)
+            .compile(".")
+            .unwrap();
+        let res = transform(Trickle(b"1 2 3"), &mut prog, &mut output);
+        assert_matches!(res, Err(Error::InputTooLarge { bytes: 5, max: 4 }));
+        assert_eq!(output, b"1\n2\n");
+    }
+
+    #[test]
+    fn parse_error_location_spans_reads() {
+        let input = "{\"a\": 1}\n{\"b\": 2}\n{\"c\": 3,,}\n";
+        let mut prog = compile(".").unwrap();
+        let res = transform(Trickle(input.as_bytes()), &mut prog, io::sink());
+        match res {
+            Err(Error::Parse {
+                line,
+                column,
+                offset,
+                ..
+            }) => {
+                assert_eq!((line, column), (3, 9));
+                assert_eq!(offset, input.find(",,").unwrap() + 1);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn read_error_from_another_program() {
+        /// Fails like a reader which runs a jq program of its own.
+        struct Failing;
+
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(Error::Timeout.into())
+            }
+        }
+
+        let mut prog = compile(".").unwrap();
+        let res = transform(Failing, &mut prog, io::sink());
+        assert_matches!(res, Err(Error::Timeout));
+    }
+
+    #[test]
+    fn write_error() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut prog = compile(".").unwrap();
+        let res = transform(&b"1"[..], &mut prog, Broken);
+        assert_matches!(res, Err(Error::Io { .. }));
+    }
+}
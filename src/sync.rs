@@ -0,0 +1,136 @@
+use crate::errors::{BatchError, Result};
+use crate::JqProgram;
+use std::result;
+use std::sync::{Mutex, PoisonError};
+
+/// A pre-compiled jq program which can be shared between threads.
+///
+/// `JqProgram::run()` needs `&mut self` since the underlying jq state is
+/// mutated as each input is processed. `SyncJqProgram` guards that state with
+/// a `Mutex` so it can be run via `&self`, making it easy to stash in an `Arc`
+/// and hand out to several threads (or web request handlers).
+///
+/// Runs are serialized: only one thread at a time can be evaluating the
+/// program. If you need parallel evaluation, compile one program per thread.
+///
+/// ```rust
+/// use jq_rs::SyncJqProgram;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let program = Arc::new(SyncJqProgram::compile(".name").unwrap());
+///
+/// let handles: Vec<_> = (0..4)
+///     .map(|i| {
+///         let program = Arc::clone(&program);
+///         thread::spawn(move || program.run(&format!(r#"{{"name": {}}}"#, i)).unwrap())
+///     })
+///     .collect();
+///
+/// for (i, handle) in handles.into_iter().enumerate() {
+///     assert_eq!(handle.join().unwrap(), format!("{}\n", i));
+/// }
+/// ```
+pub struct SyncJqProgram {
+    inner: Mutex<JqProgram>,
+}
+
+impl SyncJqProgram {
+    /// Compile a jq program which can be shared between threads.
+    pub fn compile(program: &str) -> Result<Self> {
+        crate::compile(program).map(Self::from)
+    }
+
+    /// Runs a json string input against the pre-compiled jq program.
+    ///
+    /// Blocks while another thread is running the program.
+    pub fn run(&self, data: &str) -> Result<String> {
+        // A panic while the lock was held can't leave the jq state in a
+        // worse place than a failed run would, and the next run resets it
+        // via `jq_start()` anyway.
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .run(data)
+    }
+
+    /// Runs several json string inputs against the pre-compiled jq program,
+    /// returning a result for each.
+    ///
+    /// The lock is taken once for the whole batch rather than once per input,
+    /// so other threads wait until every input has been processed.
+    pub fn run_batch<I>(&self, inputs: I) -> Vec<result::Result<String, BatchError>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .run_batch(inputs)
+    }
+
+    /// Unwraps the inner `JqProgram`.
+    pub fn into_inner(self) -> JqProgram {
+        self.inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl From<JqProgram> for SyncJqProgram {
+    fn from(program: JqProgram) -> Self {
+        SyncJqProgram {
+            inner: Mutex::new(program),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SyncJqProgram;
+    use crate::Error;
+    use matches::assert_matches;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SyncJqProgram>();
+    }
+
+    #[test]
+    fn run_from_many_threads() {
+        let program = Arc::new(SyncJqProgram::compile(".[] | . * 2").unwrap());
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let program = Arc::clone(&program);
+                thread::spawn(move || program.run(&format!("[{}, {}]", i, i + 1)).unwrap())
+            })
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(
+                handle.join().unwrap(),
+                format!("{}\n{}\n", i * 2, (i + 1) * 2)
+            );
+        }
+    }
+
+    #[test]
+    fn run_batch() {
+        let program = SyncJqProgram::compile(".a").unwrap();
+        let results = program.run_batch(vec![r#"{"a": 1}"#, "[]", r#"{"a": 2}"#]);
+        assert_eq!(results[0].as_ref().unwrap(), "1\n");
+        assert_matches!(results[1].as_ref().unwrap_err().error, Error::Exec { .. });
+        assert_eq!(results[2].as_ref().unwrap(), "2\n");
+    }
+
+    #[test]
+    fn errors_do_not_poison() {
+        let program = SyncJqProgram::compile(".name").unwrap();
+        assert_matches!(program.run("{"), Err(Error::Parse { .. }));
+        assert_eq!(program.run(r#"{"name": 1}"#).unwrap(), "1\n");
+    }
+}
@@ -0,0 +1,188 @@
+//! Helpers for testing jq programs, with the `testing` feature.
+//!
+//! `assert_jq!()` runs a program and checks its outputs against the json
+//! expected of it. Outputs are compared as values rather than as text, so
+//! the order of keys, spacing and the way numbers are written (`1.0` vs `1`,
+//! say) don't matter. When they differ, the panic shows a line by line diff of
+//! the two, pretty printed.
+//!
+//! ```rust
+//! use jq_rs::assert_jq;
+//!
+//! assert_jq!(".kaiju | map(.name)", r#"{"kaiju": [{"name": "Godzilla"}]}"#, r#"["Godzilla"]"#);
+//!
+//! // Programs with more than one output expect each in turn.
+//! assert_jq!(".[] | {n: ., double: (. * 2)}", "[1, 2]", r#"
+//!     {"double": 2, "n": 1}
+//!     {"double": 4.0, "n": 2}
+//! "#);
+//! ```
+
+use crate::Compiler;
+
+/// Run `program` on `input`, and panic unless its outputs are the json values
+/// in `expected`, compared structurally.
+///
+/// Any program compile errors, and errors from the run, fail the assertion.
+/// See the `testing` module for more.
+///
+/// Requires the `testing` feature.
+#[macro_export]
+macro_rules! assert_jq {
+    ($program:expr, $input:expr, $expected:expr $(,)?) => {
+        if let Err(message) = $crate::testing::check(&$program, &$input, &$expected) {
+            panic!("{}", message);
+        }
+    };
+}
+
+/// Used by `assert_jq!()`, giving back the panic message when the outputs
+/// don't match.
+#[doc(hidden)]
+pub fn check<P, I, E>(program: &P, input: &I, expected: &E) -> Result<(), String>
+where
+    P: AsRef<str> + ?Sized,
+    I: AsRef<str> + ?Sized,
+    E: AsRef<str> + ?Sized,
+{
+    let (program, input, expected) = (program.as_ref(), input.as_ref(), expected.as_ref());
+    let fail = |what: &str, err: crate::Error| {
+        format!(
+            "assertion failed: {}\n  program: {}\n    error: {}",
+            what, program, err
+        )
+    };
+
+    // Canonical json is the same text for the same value, however it was
+    // written to begin with.
+    let canonical = Compiler::new().canonical_output(true);
+    let actual = canonical
+        .clone()
+        .compile(program)
+        .and_then(|mut program| program.run(input))
+        .map_err(|err| fail("the program failed", err))?;
+    let wanted =
+        each_value(canonical, expected).map_err(|err| fail("invalid expected json", err))?;
+    if actual == wanted {
+        return Ok(());
+    }
+
+    let pretty = |outputs: &str| {
+        let compiler = Compiler::new().indent(2).sort_keys(true);
+        each_value(compiler, outputs).unwrap_or_else(|_| outputs.into())
+    };
+    let (actual, wanted) = (pretty(&actual), pretty(&wanted));
+    let mut message = format!(
+        "assertion failed: outputs don't match\n  program: {}\n--- expected\n+++ actual\n",
+        program
+    );
+    for (sign, line) in diff(&wanted, &actual) {
+        message.push(sign);
+        message.push_str(line);
+        message.push('\n');
+    }
+    Err(message)
+}
+
+/// Render each json value in `values` as `compiler` would.
+fn each_value(compiler: Compiler, values: &str) -> crate::Result<String> {
+    let mut program = compiler.compile(".")?;
+    let mut rendered = vec![];
+    crate::stream::transform(values.as_bytes(), &mut program, &mut rendered)?;
+    // Outputs from jq are always valid UTF-8.
+    Ok(String::from_utf8_lossy(&rendered).into_owned())
+}
+
+/// The lines of `a` and `b`, marked with `-` when they're only in `a`, `+`
+/// when they're only in `b` and a space when they're in both.
+fn diff<'a>(a: &'a str, b: &'a str) -> Vec<(char, &'a str)> {
+    let a: Vec<&str> = a.lines().collect();
+    let b: Vec<&str> = b.lines().collect();
+
+    // The length of the longest common subsequence of `a[i..]` and `b[j..]`.
+    let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(('-', a[i]));
+            i += 1;
+        } else {
+            lines.push(('+', b[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, diff};
+
+    #[test]
+    fn structural() {
+        assert_jq!(".", r#"{"b": 1.0, "a": [1e2]}"#, r#"{"a": [100], "b": 1}"#);
+        assert_jq!(".[]", "[1, 2]", "1 2");
+        assert_jq!(String::from("empty"), "null", "");
+    }
+
+    #[test]
+    fn mismatches() {
+        let message = check(".[]", r#"[{"a": 1, "b": 2}]"#, r#"{"a": 1, "b": 3}"#).unwrap_err();
+        assert_eq!(
+            message,
+            concat!(
+                "assertion failed: outputs don't match\n",
+                "  program: .[]\n",
+                "--- expected\n",
+                "+++ actual\n",
+                " {\n",
+                "   \"a\": 1,\n",
+                "-  \"b\": 3\n",
+                "+  \"b\": 2\n",
+                " }\n",
+            )
+        );
+
+        let message = check(".[", "null", "null").unwrap_err();
+        assert!(message.starts_with("assertion failed: the program failed\n"));
+        let message = check(".", "null", "{").unwrap_err();
+        assert!(message.starts_with("assertion failed: invalid expected json\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "outputs don't match")]
+    fn panics() {
+        assert_jq!(".a", r#"{"a": 1}"#, "2");
+    }
+
+    #[test]
+    fn line_diff() {
+        assert_eq!(
+            diff("a\nb\nc\nd", "a\nc\nx\nd\ne"),
+            vec![
+                (' ', "a"),
+                ('-', "b"),
+                (' ', "c"),
+                ('+', "x"),
+                (' ', "d"),
+                ('+', "e")
+            ]
+        );
+        assert!(diff("", "").is_empty());
+    }
+}
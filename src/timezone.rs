@@ -0,0 +1,209 @@
+//! Time zones for `localtime` and `strflocaltime`, with the `tz` feature.
+//!
+//! libjq works out local times with the C library, which uses the zone in the
+//! process' `TZ`. Changing that for each run isn't thread-safe, so instead
+//! the prelude redefines the two builtins to ask for the UTC offset of the
+//! run's zone, the same way functions implemented in Rust are called. They
+//! then work in UTC shifted by that offset. Runs without a zone are passed on
+//! to the builtins.
+
+use crate::errors::{Error, Result};
+use crate::jq::JV;
+use std::fmt;
+use std::sync::Arc;
+
+/// A time zone for `localtime` and `strflocaltime` to use instead of the
+/// machine's, set for a program's runs with `JqProgram::set_timezone()`.
+///
+/// Zones are loaded from the system's tz database, so it's best to load each
+/// once and keep it around. Clones are cheap, and share the loaded zone.
+///
+/// Requires the `tz` feature.
+#[derive(Clone)]
+pub struct TimeZone {
+    zone: Arc<tz::TimeZone>,
+    name: String,
+}
+
+impl TimeZone {
+    /// Load the zone with an IANA name like `"Europe/Berlin"`, or described
+    /// by a POSIX `TZ` string like `"CET-1CEST,M3.5.0,M10.5.0/3"`.
+    ///
+    /// Fails with an `Error::Io` when there's no such zone.
+    pub fn named(name: &str) -> Result<Self> {
+        // Names are paths into the tz database, which mustn't lead out of it.
+        if name.contains("..") {
+            return Err(unknown(name, "the name can't contain `..`"));
+        }
+        let zone = tz::TimeZone::from_posix_tz(name).map_err(|err| unknown(name, err))?;
+        Ok(TimeZone {
+            zone: Arc::new(zone),
+            name: name.to_string(),
+        })
+    }
+
+    /// Coordinated Universal Time.
+    pub fn utc() -> Self {
+        // tz-rs' own UTC has no designation, which would leave `%Z` empty.
+        let utc = tz::LocalTimeType::new(0, false, Some(b"UTC"))
+            .ok()
+            .and_then(|utc| tz::TimeZone::new(vec![], vec![utc], vec![], None).ok())
+            .unwrap_or_else(tz::TimeZone::utc);
+        TimeZone {
+            zone: Arc::new(utc),
+            name: "UTC".to_string(),
+        }
+    }
+
+    /// A zone which is always `seconds` ahead of UTC (or behind, when
+    /// negative). `%Z` writes it the same way as `%z`, like `+0530`.
+    pub fn fixed(seconds: i32) -> Result<Self> {
+        let zone =
+            tz::TimeZone::fixed(seconds).map_err(|err| unknown(&seconds.to_string(), err))?;
+        Ok(TimeZone {
+            zone: Arc::new(zone),
+            name: numeric_offset(seconds),
+        })
+    }
+
+    /// The name the zone was loaded with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TimeZone").field(&self.name).finish()
+    }
+}
+
+fn unknown<E: fmt::Display>(name: &str, err: E) -> Error {
+    let reason = format!("Unknown time zone {:?}: {}", name, err);
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, reason).into()
+}
+
+/// The answer to the prelude asking for the offset of the run's zone, which
+/// sends the time in seconds and (for `strflocaltime`) the format.
+///
+/// This is `null` when the run has no zone, or when the time isn't a number,
+/// so the builtin raises the same error it would have. Otherwise it's the
+/// offset in seconds, followed by the format with `%Z` and `%z` written out
+/// for the zone, since jq's own `strftime` only knows about UTC.
+pub(crate) fn reply(timezone: Option<&TimeZone>, request: &JV) -> JV {
+    let timezone = match timezone {
+        Some(timezone) => timezone,
+        None => return JV::null(),
+    };
+    let items = request.array_items().unwrap_or_default();
+    let time = match items.first().and_then(JV::as_number) {
+        Some(time) => time,
+        None => return JV::null(),
+    };
+    let local = match timezone.zone.find_local_time_type(time.floor() as i64) {
+        Ok(local) => local,
+        Err(err) => return JV::invalid_with_msg(&format!("Can't find the local time: {}", err)),
+    };
+    let mut reply = JV::array().array_append(JV::number(f64::from(local.ut_offset())));
+    if let Some(format) = items.get(1).and_then(|format| format.as_string().ok()) {
+        reply = reply.array_append(JV::string(&with_zone(&format, local)));
+    }
+    reply
+}
+
+/// Write out the `%Z` and `%z` conversions in `format` for `local`, leaving
+/// the rest for `strftime`.
+fn with_zone(format: &str, local: &tz::LocalTimeType) -> String {
+    let mut out = String::with_capacity(format.len());
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Z') if local.time_zone_designation().is_empty() => {
+                out.push_str(&numeric_offset(local.ut_offset()))
+            }
+            Some('Z') => out.push_str(&local.time_zone_designation().replace('%', "%%")),
+            Some('z') => out.push_str(&numeric_offset(local.ut_offset())),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// An offset from UTC written like `+0530`.
+fn numeric_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.unsigned_abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::TimeZone;
+    use crate::{Compiler, Error};
+    use matches::assert_matches;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const BERLIN: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    #[test]
+    fn local_times() {
+        let mut program =
+            crate::compile(r#"localtime, (localtime | mktime), strflocaltime("%F %T %Z %z %%Z")"#)
+                .unwrap();
+        program.set_timezone(Some(TimeZone::named(BERLIN).unwrap()));
+        assert_eq!(
+            program.run("1500000000.5").unwrap(),
+            "[2017,6,14,4,40,0.5,5,194]\n1500007200\n\"2017-07-14 04:40:00 CEST +0200 %Z\"\n"
+        );
+        // Winter time.
+        let mut program = crate::compile(r#"strflocaltime("%H:%M %Z")"#).unwrap();
+        program.set_timezone(Some(TimeZone::named(BERLIN).unwrap()));
+        assert_eq!(program.run("1485000000").unwrap(), "\"13:00 CET\"\n");
+        // Broken down times are already local.
+        assert_eq!(
+            program.run("[2017,6,14,4,40,0,5,194]").unwrap(),
+            "\"04:40 CEST\"\n"
+        );
+    }
+
+    #[test]
+    fn only_for_the_runs_with_a_zone() {
+        let mut program = Compiler::new()
+            .fixed_time(UNIX_EPOCH + Duration::from_secs(1_500_000_000))
+            .compile(r#"now | strflocaltime("%H:%M %Z")"#)
+            .unwrap();
+        assert_eq!(program.run("null").unwrap(), "\"02:40 UTC\"\n");
+
+        program.set_timezone(Some(TimeZone::fixed(-(5 * 3600 + 1800)).unwrap()));
+        assert_eq!(program.run("null").unwrap(), "\"21:10 -0530\"\n");
+        program.set_timezone(Some(TimeZone::utc()));
+        assert_eq!(program.run("null").unwrap(), "\"02:40 UTC\"\n");
+        program.set_timezone(None);
+        assert_eq!(program.run("null").unwrap(), "\"02:40 UTC\"\n");
+    }
+
+    #[test]
+    fn errors_match_the_builtins() {
+        let mut program =
+            crate::compile(r#"try localtime catch ., try strflocaltime("%H") catch ."#).unwrap();
+        let without = program.run("\"a\"").unwrap();
+        program.set_timezone(Some(TimeZone::utc()));
+        assert_eq!(program.run("\"a\"").unwrap(), without);
+    }
+
+    #[test]
+    fn unknown_zones() {
+        assert_matches!(TimeZone::named("Nowhere/Special"), Err(Error::Io { .. }));
+        assert_matches!(TimeZone::named("../../etc/passwd"), Err(Error::Io { .. }));
+        assert_eq!(TimeZone::named(BERLIN).unwrap().name(), BERLIN);
+        assert_eq!(TimeZone::fixed(3600).unwrap().name(), "+0100");
+    }
+}
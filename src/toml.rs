@@ -0,0 +1,121 @@
+use crate::cancel::Interrupt;
+use crate::errors::{Error, Result};
+use crate::jq::{excerpt, JV};
+use crate::JqProgram;
+use toml::Value;
+
+impl JqProgram {
+    /// Runs the program on a TOML document, rendering the outputs as json.
+    ///
+    /// Dates and times become strings in RFC 3339 format, the same way they
+    /// were written in the document.
+    ///
+    /// Requires the `toml` feature.
+    ///
+    /// ```rust
+    /// let manifest = r#"
+    /// [package]
+    /// name = "jq-rs"
+    ///
+    /// [dependencies]
+    /// jq-sys = "0.2.*"
+    /// "#;
+    ///
+    /// let mut program = jq_rs::compile(".dependencies | keys[]").unwrap();
+    /// assert_eq!(program.run_toml(manifest).unwrap(), "\"jq-sys\"\n");
+    /// ```
+    pub fn run_toml(&mut self, data: &str) -> Result<String> {
+        let value: Value = data.parse().map_err(|err| parse_error(data, &err))?;
+        let mut buf = String::with_capacity(self.output_capacity);
+        self.jq
+            .execute_parsed(&to_jv(&value), &Interrupt::default(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Run a jq program on a TOML document, rendering the outputs as json.
+///
+/// Requires the `toml` feature. See `JqProgram::run_toml()` for details.
+pub fn run_toml(program: &str, data: &str) -> Result<String> {
+    crate::compile(program)?.run_toml(data)
+}
+
+fn to_jv(value: &Value) -> JV {
+    match value {
+        Value::String(s) => JV::string(s),
+        Value::Integer(n) => JV::number(*n as f64),
+        Value::Float(n) => JV::number(*n),
+        Value::Boolean(b) => JV::bool(*b),
+        Value::Datetime(datetime) => JV::string(&datetime.to_string()),
+        Value::Array(items) => items
+            .iter()
+            .fold(JV::array(), |array, item| array.array_append(to_jv(item))),
+        Value::Table(table) => table.iter().fold(JV::object(), |object, (key, value)| {
+            object.object_set(JV::string(key), to_jv(value))
+        }),
+    }
+}
+
+/// Report a TOML problem the same way as a json one from jq.
+fn parse_error(data: &str, err: &toml::de::Error) -> Error {
+    let reason = err.message().trim_end().to_string();
+    match err.span() {
+        Some(span) => {
+            let offset = span.start;
+            let before = &data[..offset];
+            let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+            let (excerpt, caret) = excerpt(data.as_bytes(), offset);
+            Error::Parse {
+                reason,
+                line: before.matches('\n').count() + 1,
+                column: offset - line_start + 1,
+                offset,
+                excerpt,
+                caret,
+            }
+        }
+        None => Error::Parse {
+            reason,
+            line: 0,
+            column: 0,
+            offset: 0,
+            excerpt: String::new(),
+            caret: 0,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_toml;
+    use crate::Error;
+
+    #[test]
+    fn values() {
+        let data = r#"
+            title = "config"
+            port = 8080
+            ratio = 0.5
+            enabled = true
+            started = 1979-05-27T07:32:00Z
+            hosts = ["a", "b"]
+
+            [owner]
+            name = "Tom"
+        "#;
+        assert_eq!(
+            run_toml("[.title, .port, .ratio, .enabled, .started, .hosts, .owner]", data).unwrap(),
+            "[\"config\",8080,0.5,true,\"1979-05-27T07:32:00Z\",[\"a\",\"b\"],{\"name\":\"Tom\"}]\n"
+        );
+        assert_eq!(run_toml(".", "").unwrap(), "{}\n");
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let data = "a = 1\nb = [1, 2\nc = 3\n";
+        match run_toml(".", data) {
+            Err(Error::Parse { line, column, .. }) => assert_eq!((line, column), (3, 1)),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! [tower] middleware which runs a jq program over the json bodies of
+//! requests or responses, for API gateways and axum/hyper services.
+//!
+//! `TransformRequestLayer` rewrites request bodies before they reach the
+//! service, and `TransformResponseLayer` rewrites the bodies the service
+//! responds with. Bodies can be any `http_body::Body`, and are read as their
+//! frames stream in. The program then runs on the [blocking] thread pool, as
+//! with `AsyncJqProgram`, so the executor isn't held up by it.
+//!
+//! Failures are answered with an HTTP error rather than the service's error
+//! type, so the layers can wrap services which never fail (such as axum
+//! routers). A request body which can't be read or parsed gets a
+//! `400 Bad Request`, as does one the program fails on, and a response body
+//! which can't be transformed is replaced with a `500 Internal Server Error`.
+//! Either way the body of the error response is the reason, as plain text.
+//!
+//! Requires the `tower` feature.
+//!
+//! ```rust
+//! use bytes::Bytes;
+//! use http::{Request, Response};
+//! use http_body_util::{BodyExt, Full};
+//! use jq_rs::tower::TransformResponseLayer;
+//! use jq_rs::AsyncJqProgram;
+//! use std::convert::Infallible;
+//! use std::future::{ready, Ready};
+//! use std::task::{Context, Poll};
+//! use tower_layer::Layer;
+//! use tower_service::Service;
+//!
+//! /// Stands in for the service being wrapped.
+//! #[derive(Clone)]
+//! struct Users;
+//!
+//! impl Service<Request<Full<Bytes>>> for Users {
+//!     type Response = Response<Full<Bytes>>;
+//!     type Error = Infallible;
+//!     type Future = Ready<Result<Self::Response, Infallible>>;
+//!
+//!     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+//!         Poll::Ready(Ok(()))
+//!     }
+//!
+//!     fn call(&mut self, _request: Request<Full<Bytes>>) -> Self::Future {
+//!         let body = r#"{"users": [{"id": 1, "name": "Mothra", "secret": "..."}]}"#;
+//!         ready(Ok(Response::new(Full::from(body))))
+//!     }
+//! }
+//!
+//! # futures_lite::future::block_on(async {
+//! let program = AsyncJqProgram::compile(".users | map({id, name})").await.unwrap();
+//! let mut service = TransformResponseLayer::new(program).layer(Users);
+//!
+//! let response = service.call(Request::new(Full::default())).await.unwrap();
+//! let body = response.into_body().collect().await.unwrap().to_bytes();
+//! assert_eq!(body, "[{\"id\":1,\"name\":\"Mothra\"}]\n");
+//! # });
+//! ```
+//!
+//! [tower]: https://crates.io/crates/tower
+//! [blocking]: https://crates.io/crates/blocking
+
+use crate::AsyncJqProgram;
+use bytes::Bytes;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderValue, Request, Response, StatusCode};
+use http_body::Body;
+use http_body_util::{BodyExt, Either, Full};
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Applies `TransformRequest`, which runs a jq program over each request
+/// body before handing the request to the service.
+#[derive(Clone)]
+pub struct TransformRequestLayer {
+    program: AsyncJqProgram,
+}
+
+impl TransformRequestLayer {
+    /// Transform request bodies with `program`.
+    pub fn new(program: AsyncJqProgram) -> Self {
+        TransformRequestLayer { program }
+    }
+}
+
+impl<S> Layer<S> for TransformRequestLayer {
+    type Service = TransformRequest<S>;
+
+    fn layer(&self, inner: S) -> TransformRequest<S> {
+        TransformRequest {
+            inner,
+            program: self.program.clone(),
+        }
+    }
+}
+
+/// Runs a jq program over each request body before handing the request to
+/// the wrapped service. See `TransformRequestLayer`.
+///
+/// The service is handed the program's output as a `Full<Bytes>` body.
+/// Requests which fail to transform are answered with a `400 Bad Request`
+/// without reaching it.
+#[derive(Clone)]
+pub struct TransformRequest<S> {
+    inner: S,
+    program: AsyncJqProgram,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TransformRequest<S>
+where
+    S: Service<Request<Full<Bytes>>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Body + Send + 'static,
+    ReqBody::Data: Send,
+    ReqBody::Error: Display,
+{
+    type Response = Response<Either<ResBody, Full<Bytes>>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        // The clone hasn't been polled for readiness, so it's swapped for
+        // the one which has.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let program = self.program.clone();
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let output = match transform(&program, body).await {
+                Ok(output) => output,
+                Err(reason) => {
+                    return Ok(error_response(StatusCode::BAD_REQUEST, reason).map(Either::Right))
+                }
+            };
+            parts.headers.remove(CONTENT_LENGTH);
+            let request = Request::from_parts(parts, Full::new(output));
+            let response = inner.call(request).await?;
+            Ok(response.map(Either::Left))
+        })
+    }
+}
+
+/// Applies `TransformResponse`, which runs a jq program over the body of
+/// each response from the service.
+#[derive(Clone)]
+pub struct TransformResponseLayer {
+    program: AsyncJqProgram,
+}
+
+impl TransformResponseLayer {
+    /// Transform response bodies with `program`.
+    pub fn new(program: AsyncJqProgram) -> Self {
+        TransformResponseLayer { program }
+    }
+}
+
+impl<S> Layer<S> for TransformResponseLayer {
+    type Service = TransformResponse<S>;
+
+    fn layer(&self, inner: S) -> TransformResponse<S> {
+        TransformResponse {
+            inner,
+            program: self.program.clone(),
+        }
+    }
+}
+
+/// Runs a jq program over the body of each response from the wrapped
+/// service. See `TransformResponseLayer`.
+///
+/// The status and headers of the response are kept, apart from
+/// `Content-Length`. Responses which fail to transform are replaced with a
+/// `500 Internal Server Error`.
+#[derive(Clone)]
+pub struct TransformResponse<S> {
+    inner: S,
+    program: AsyncJqProgram,
+}
+
+impl<S, Req, ResBody> Service<Req> for TransformResponse<S>
+where
+    S: Service<Req, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: Body + Send + 'static,
+    ResBody::Data: Send,
+    ResBody::Error: Display,
+{
+    type Response = Response<Full<Bytes>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let response = self.inner.call(request);
+        let program = self.program.clone();
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+            match transform(&program, body).await {
+                Ok(output) => {
+                    parts.headers.remove(CONTENT_LENGTH);
+                    Ok(Response::from_parts(parts, Full::new(output)))
+                }
+                Err(reason) => Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, reason)),
+            }
+        })
+    }
+}
+
+/// Read `body` and run `program` on it, giving back the reason when either
+/// fails.
+async fn transform<B>(program: &AsyncJqProgram, body: B) -> Result<Bytes, String>
+where
+    B: Body,
+    B::Error: Display,
+{
+    let data = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(err) => return Err(format!("Failed to read the body: {}", err)),
+    };
+    let data = std::str::from_utf8(&data).map_err(|err| err.to_string())?;
+    match program.run(data).await {
+        Ok(output) => Ok(Bytes::from(output)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn error_response(status: StatusCode, reason: String) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(reason)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TransformRequestLayer, TransformResponseLayer};
+    use crate::AsyncJqProgram;
+    use bytes::Bytes;
+    use futures_lite::future::block_on;
+    use http::header::CONTENT_LENGTH;
+    use http::{Request, Response, StatusCode};
+    use http_body_util::{BodyExt, Full};
+    use std::convert::Infallible;
+    use std::future::{ready, Ready};
+    use std::task::{Context, Poll};
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    /// Responds with the body of the request.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<Request<Full<Bytes>>> for Echo {
+        type Response = Response<Full<Bytes>>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<Full<Bytes>>) -> Self::Future {
+            let mut response = Response::new(request.into_body());
+            response.headers_mut().insert(CONTENT_LENGTH, 100.into());
+            ready(Ok(response))
+        }
+    }
+
+    fn request(body: &'static str) -> Request<Full<Bytes>> {
+        Request::new(Full::from(body))
+    }
+
+    fn program(program: &str) -> AsyncJqProgram {
+        block_on(AsyncJqProgram::compile(program)).unwrap()
+    }
+
+    fn body<B: http_body::Body>(response: Response<B>) -> Bytes
+    where
+        B::Error: std::fmt::Debug,
+    {
+        block_on(response.into_body().collect()).unwrap().to_bytes()
+    }
+
+    #[test]
+    fn requests() {
+        let mut service = TransformRequestLayer::new(program(".a")).layer(Echo);
+
+        let response = block_on(service.call(request(r#"{"a": [1]}"#))).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response), "[1]\n");
+
+        let response = block_on(service.call(request("[1]"))).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!body(response).is_empty());
+
+        let response = block_on(service.call(request("{"))).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn responses() {
+        let mut service = TransformResponseLayer::new(program(".[0]")).layer(Echo);
+
+        let response = block_on(service.call(request("[[1, 2]]"))).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        assert_eq!(body(response), "[1,2]\n");
+
+        let response = block_on(service.call(request("{}"))).unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use crate::compile;
+use crate::errors::{Error, Result};
+
+/// Builtins which first appeared in each release, newest first.
+const RELEASES: &[(&str, &str)] = &[("1.8", "toarray"), ("1.7", "pick(.)"), ("1.6", "halt")];
+
+/// The releases of jq this crate works with.
+pub(crate) const SUPPORTED: &[&str] = &["1.6", "1.7"];
+
+/// What the linked `libjq` is able to do, as reported by `capabilities()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// The regex builtins (`test`, `match`, `sub`, etc) work, meaning `libjq`
+    /// was built with oniguruma.
+    pub regex: bool,
+    /// Numbers keep the precision of their input literals rather than being
+    /// rounded to doubles, which needs jq 1.7 or later built with decNumber.
+    pub decnumber: bool,
+    /// `libjq` was built and linked by the `bundled` feature, rather than
+    /// coming from the system.
+    pub bundled: bool,
+}
+
+/// The version of the linked `libjq`, such as `"1.6"`.
+///
+/// `libjq` doesn't report its own version, so this is worked out from which
+/// builtins are available. Only the minor version can be told apart, and
+/// anything older than 1.6 is reported as `"1.5"`.
+///
+/// Each call compiles a few small programs, so hang on to the result rather
+/// than calling this repeatedly.
+pub fn version() -> &'static str {
+    RELEASES
+        .iter()
+        .find(|(_, builtin)| compile(builtin).is_ok())
+        .map_or("1.5", |(release, _)| release)
+}
+
+/// Check the linked `libjq` is from a release of jq this crate works with,
+/// failing with a descriptive `Error::UnsupportedVersion` when it isn't.
+///
+/// When `libjq` is linked dynamically, the one found at runtime can be from a
+/// different release than the one built against, which otherwise only shows
+/// up as crashes or odd results. Calling this once at startup turns that into
+/// an error saying what's wrong. jq 1.6 and 1.7 are supported, though 1.6 is
+/// the only one the crate is tested against, and anything older than 1.6 is
+/// reported as `"1.5"`.
+///
+/// ```rust
+/// jq_rs::ensure_compatible().expect("the linked libjq isn't supported");
+/// ```
+///
+/// Like `version()`, each call compiles a few small programs to find out.
+pub fn ensure_compatible() -> Result<()> {
+    check(version())
+}
+
+fn check(version: &str) -> Result<()> {
+    if SUPPORTED.contains(&version) {
+        Ok(())
+    } else {
+        Err(Error::UnsupportedVersion {
+            version: version.to_string(),
+        })
+    }
+}
+
+/// Check which optional parts of jq the linked `libjq` supports, for
+/// applications which need to know before accepting a program (such as one
+/// using `test()`).
+///
+/// ```rust
+/// let caps = jq_rs::capabilities();
+/// if !caps.regex {
+///     println!("jq was built without regex support");
+/// }
+/// ```
+///
+/// Like `version()`, each call runs a few small programs to find out.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        regex: succeeds(r#"test("a")"#, "\"a\""),
+        decnumber: succeeds("if have_decnum then . else error end", "null"),
+        bundled: cfg!(feature = "bundled"),
+    }
+}
+
+fn succeeds(program: &str, data: &str) -> bool {
+    compile(program).and_then(|mut prog| prog.run(data)).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{capabilities, check, ensure_compatible, version};
+    use crate::Error;
+    use matches::assert_matches;
+
+    #[test]
+    fn linked_libjq() {
+        assert_eq!(version(), "1.6");
+        ensure_compatible().unwrap();
+        let caps = capabilities();
+        assert!(!caps.decnumber);
+        assert_eq!(caps.bundled, cfg!(feature = "bundled"));
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        check("1.7").unwrap();
+        for old_or_new in &["1.5", "1.8"] {
+            let err = check(old_or_new).unwrap_err();
+            assert_matches!(&err, Error::UnsupportedVersion { version } if version == old_or_new);
+            assert!(err.is_unsupported_version());
+            assert!(err.to_string().contains("needs jq 1.6 or 1.7"));
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use crate::errors::{Error, Result};
+use crate::JqProgram;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+const ERR_WORKER_GONE: &str = "JQ: Worker thread is no longer running";
+
+enum Job {
+    Compile {
+        program: String,
+        // Handed to the `WorkerProgram` so it can submit jobs of its own.
+        sender: Sender<Job>,
+        reply: Sender<Result<WorkerProgram>>,
+    },
+    Run {
+        id: usize,
+        data: String,
+        reply: Sender<Result<String>>,
+    },
+    Release {
+        id: usize,
+    },
+    Shutdown,
+}
+
+fn worker_gone() -> Error {
+    Error::Exec {
+        reason: ERR_WORKER_GONE.into(),
+        payload: None,
+    }
+}
+
+/// Owns compiled jq programs on a dedicated OS thread.
+///
+/// Programs are compiled and run on the worker thread, with jobs submitted
+/// over a channel. Each submission returns a `Pending` handle for the result,
+/// so callers never touch the jq state directly.
+///
+/// Jobs are processed one at a time, in the order they were submitted.
+///
+/// ```rust
+/// use jq_rs::JqWorker;
+///
+/// let worker = JqWorker::spawn().unwrap();
+/// let program = worker.compile(".name").wait().unwrap();
+///
+/// let first = program.run(r#"{"name": "Eleven"}"#);
+/// let second = program.run(r#"{"name": "Dustin"}"#);
+///
+/// assert_eq!(first.wait().unwrap(), "\"Eleven\"\n");
+/// assert_eq!(second.wait().unwrap(), "\"Dustin\"\n");
+/// ```
+pub struct JqWorker {
+    sender: Sender<Job>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl JqWorker {
+    /// Starts a new worker thread.
+    pub fn spawn() -> Result<Self> {
+        let (sender, receiver) = channel();
+        let thread = thread::Builder::new()
+            .name("jq-rs-worker".into())
+            .spawn(move || work(receiver))
+            .map_err(|e| Error::Exec {
+                reason: format!("JQ: Failed to spawn worker thread: {}", e),
+                payload: None,
+            })?;
+
+        Ok(JqWorker {
+            sender,
+            thread: Some(thread),
+        })
+    }
+
+    /// Compile a jq program on the worker thread.
+    ///
+    /// The resulting `WorkerProgram` is a handle used to submit inputs to the
+    /// compiled program. The program is released when the handle is dropped.
+    pub fn compile(&self, program: &str) -> Pending<WorkerProgram> {
+        let (reply, receiver) = channel();
+        let job = Job::Compile {
+            program: program.to_string(),
+            sender: self.sender.clone(),
+            reply,
+        };
+        if self.sender.send(job).is_err() {
+            return Pending::failed();
+        }
+        Pending { receiver }
+    }
+}
+
+impl Drop for JqWorker {
+    fn drop(&mut self) {
+        // Outstanding `WorkerProgram` handles keep the channel open, so the
+        // thread has to be told to stop explicitly.
+        let _ = self.sender.send(Job::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A handle to a jq program compiled and owned by a `JqWorker`.
+pub struct WorkerProgram {
+    id: usize,
+    sender: Sender<Job>,
+}
+
+impl WorkerProgram {
+    /// Submits a json string input to be run against the program.
+    pub fn run(&self, data: &str) -> Pending<String> {
+        let (reply, receiver) = channel();
+        let job = Job::Run {
+            id: self.id,
+            data: data.to_string(),
+            reply,
+        };
+        if self.sender.send(job).is_err() {
+            return Pending::failed();
+        }
+        Pending { receiver }
+    }
+}
+
+impl Drop for WorkerProgram {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Job::Release { id: self.id });
+    }
+}
+
+/// The eventual result of a job submitted to a `JqWorker`.
+pub struct Pending<T> {
+    receiver: Receiver<Result<T>>,
+}
+
+impl<T> Pending<T> {
+    fn failed() -> Self {
+        let (reply, receiver) = channel();
+        let _ = reply.send(Err(worker_gone()));
+        Pending { receiver }
+    }
+
+    /// Blocks until the job has been processed by the worker.
+    pub fn wait(self) -> Result<T> {
+        self.receiver.recv().unwrap_or_else(|_| Err(worker_gone()))
+    }
+
+    /// Returns the result if the job has been processed, without blocking.
+    pub fn try_wait(&mut self) -> Option<Result<T>> {
+        match self.receiver.try_recv() {
+            Ok(res) => Some(res),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(worker_gone())),
+        }
+    }
+}
+
+fn work(receiver: Receiver<Job>) {
+    let mut programs: HashMap<usize, JqProgram> = HashMap::new();
+    let mut next_id = 0;
+
+    for job in receiver {
+        match job {
+            Job::Compile {
+                program,
+                sender,
+                reply,
+            } => {
+                let res = crate::compile(&program).map(|program| {
+                    let id = next_id;
+                    next_id += 1;
+                    programs.insert(id, program);
+                    WorkerProgram { id, sender }
+                });
+                let _ = reply.send(res);
+            }
+            Job::Run { id, data, reply } => {
+                let res = match programs.get_mut(&id) {
+                    Some(program) => program.run(&data),
+                    None => Err(Error::Unknown),
+                };
+                let _ = reply.send(res);
+            }
+            Job::Release { id } => {
+                programs.remove(&id);
+            }
+            Job::Shutdown => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::JqWorker;
+    use crate::Error;
+    use matches::assert_matches;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn compile_error() {
+        let worker = JqWorker::spawn().unwrap();
+        let res = worker.compile(". aa12312me  dsaafsdfsd").wait();
+        assert_matches!(res.err(), Some(Error::InvalidProgram { .. }));
+    }
+
+    #[test]
+    fn several_programs() {
+        let worker = JqWorker::spawn().unwrap();
+        let name = worker.compile(".name").wait().unwrap();
+        let id = worker.compile(".id").wait().unwrap();
+        let input = r#"{"id": 123, "name": "foo"}"#;
+
+        let res1 = name.run(input);
+        let res2 = id.run(input);
+        assert_eq!(res2.wait().unwrap(), "123\n");
+        assert_eq!(res1.wait().unwrap(), "\"foo\"\n");
+    }
+
+    #[test]
+    fn programs_can_be_shared_between_threads() {
+        let worker = JqWorker::spawn().unwrap();
+        let program = Arc::new(worker.compile(". + 1").wait().unwrap());
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let program = Arc::clone(&program);
+                thread::spawn(move || program.run(&i.to_string()).wait().unwrap())
+            })
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), format!("{}\n", i + 1));
+        }
+    }
+
+    #[test]
+    fn run_after_worker_dropped() {
+        let worker = JqWorker::spawn().unwrap();
+        let program = worker.compile(".").wait().unwrap();
+        drop(worker);
+        assert_matches!(program.run("{}").wait(), Err(Error::Exec { .. }));
+    }
+}
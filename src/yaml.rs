@@ -0,0 +1,171 @@
+use crate::cancel::Interrupt;
+use crate::errors::{Error, Result};
+use crate::jq::{excerpt, JV};
+use crate::JqProgram;
+use serde::Deserialize;
+use serde_json::Value;
+
+impl JqProgram {
+    /// Runs the program on each document in a YAML stream, rendering the
+    /// outputs as json.
+    ///
+    /// Documents are separated by `---`, as in a file of Kubernetes manifests,
+    /// and each one is a separate input to the program, like a file with
+    /// several json values given to the jq cli.
+    ///
+    /// Requires the `yaml` feature.
+    ///
+    /// ```rust
+    /// let manifests = r#"
+    /// kind: Deployment
+    /// metadata:
+    ///   name: web
+    /// ---
+    /// kind: Service
+    /// metadata:
+    ///   name: web-svc
+    /// "#;
+    ///
+    /// let mut program = jq_rs::compile(".metadata.name").unwrap();
+    /// assert_eq!(program.run_yaml(manifests).unwrap(), "\"web\"\n\"web-svc\"\n");
+    /// ```
+    pub fn run_yaml(&mut self, data: &str) -> Result<String> {
+        let mut buf = String::with_capacity(self.output_capacity);
+        for doc in documents(data)? {
+            self.jq
+                .execute_parsed(&doc, &Interrupt::default(), &mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    /// Like `run_yaml()`, with each output rendered back to YAML as a
+    /// document of its own.
+    ///
+    /// Requires the `yaml` feature.
+    ///
+    /// ```rust
+    /// let mut program = jq_rs::compile(".spec | {replicas}").unwrap();
+    /// let output = program.run_yaml_to_yaml("spec: {replicas: 3, paused: false}").unwrap();
+    /// assert_eq!(output, "replicas: 3\n");
+    /// ```
+    pub fn run_yaml_to_yaml(&mut self, data: &str) -> Result<String> {
+        let mut outputs = vec![];
+        for doc in documents(data)? {
+            outputs.extend(self.jq.collect_values(doc, &Interrupt::default())?);
+        }
+        let mut buf = String::with_capacity(self.output_capacity);
+        for (idx, output) in outputs.iter().enumerate() {
+            if idx > 0 {
+                buf.push_str("---\n");
+            }
+            let rendered = serde_yaml::to_string(output)
+                .map_err(|err| Error::StringConvert { err: Box::new(err) })?;
+            buf.push_str(&rendered);
+        }
+        Ok(buf)
+    }
+}
+
+/// Run a jq program on each document in a YAML stream, rendering the outputs
+/// as json.
+///
+/// Requires the `yaml` feature. See `JqProgram::run_yaml()` for details.
+pub fn run_yaml(program: &str, data: &str) -> Result<String> {
+    crate::compile(program)?.run_yaml(data)
+}
+
+/// Parse each document of a YAML stream into a `JV`.
+fn documents(data: &str) -> Result<Vec<JV>> {
+    if data.trim().is_empty() {
+        // Same as `run()`, an empty input gives no outputs.
+        return Ok(vec![]);
+    }
+    serde_yaml::Deserializer::from_str(data)
+        .map(|doc| {
+            Value::deserialize(doc)
+                .map(|value| JV::from_value(&value))
+                .map_err(|err| parse_error(data, &err))
+        })
+        .collect()
+}
+
+/// Report a YAML problem the same way as a json one from jq.
+fn parse_error(data: &str, err: &serde_yaml::Error) -> Error {
+    let msg = err.to_string();
+    // serde_yaml ends its messages with the location, when it has one.
+    let reason = match msg.rfind(" at line ") {
+        Some(idx) if err.location().is_some() => msg[..idx].to_string(),
+        _ => msg,
+    };
+    match err.location() {
+        Some(location) => {
+            let offset = location.index();
+            let line_start = data[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+            let (excerpt, caret) = excerpt(data.as_bytes(), offset);
+            Error::Parse {
+                reason,
+                line: location.line(),
+                column: offset - line_start + 1,
+                offset,
+                excerpt,
+                caret,
+            }
+        }
+        None => Error::Parse {
+            reason,
+            line: 0,
+            column: 0,
+            offset: 0,
+            excerpt: String::new(),
+            caret: 0,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_yaml;
+    use crate::{compile, Error};
+    use matches::assert_matches;
+
+    #[test]
+    fn documents_run_separately() {
+        let data = "a: 1\n---\na: 2\n---\n[x, y]\n";
+        assert_eq!(
+            run_yaml("type", data).unwrap(),
+            "\"object\"\n\"object\"\n\"array\"\n"
+        );
+        assert_eq!(run_yaml(".", "").unwrap(), "");
+    }
+
+    #[test]
+    fn scalars() {
+        let data = "{n: 1.5, i: -3, t: yes, s: 'yes', z: ~, q: \"\\u00e9\"}";
+        assert_eq!(
+            run_yaml("[.n, .i, .t, .s, .z, .q]", data).unwrap(),
+            "[1.5,-3,\"yes\",\"yes\",null,\"\u{e9}\"]\n"
+        );
+    }
+
+    #[test]
+    fn back_to_yaml() {
+        let mut program = compile(".items[] | {name}").unwrap();
+        let output = program
+            .run_yaml_to_yaml("items: [{name: a, x: 1}, {name: b}]")
+            .unwrap();
+        assert_eq!(output, "name: a\n---\nname: b\n");
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let data = "a: 1\nb: [1, 2\nc: 3\n";
+        match run_yaml(".", data) {
+            Err(Error::Parse { line, offset, .. }) => {
+                assert_eq!(line, 3);
+                assert!(offset > data.find('c').unwrap() - 1);
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_matches!(run_yaml(".", "{1: [}"), Err(Error::Parse { .. }));
+    }
+}
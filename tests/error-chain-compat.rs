@@ -1,3 +1,6 @@
+// error-chain expands to calls of the deprecated `description` and `cause` methods.
+#![allow(deprecated)]
+
 extern crate jq_rs;
 #[macro_use]
 extern crate error_chain;